@@ -0,0 +1,178 @@
+//! Setup that runs the first time pyflow is used: create its directories, and make sure
+//! its `bin` directory is on `PATH`.
+
+use crate::util::{self, abort};
+use crossterm::Color;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time,
+};
+
+/// Shells we know how to add a `PATH` entry for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Other,
+}
+
+impl Shell {
+    /// Find the shell from the `SHELL` environment variable, eg `/usr/bin/zsh`.
+    fn from_path(path: &str) -> Self {
+        match Path::new(path).file_name().and_then(|n| n.to_str()) {
+            Some("bash") => Self::Bash,
+            Some("zsh") => Self::Zsh,
+            Some("fish") => Self::Fish,
+            _ => Self::Other,
+        }
+    }
+
+    /// The startup file to add the `PATH` entry to, relative to the home directory.
+    fn rc_file(self) -> Option<&'static str> {
+        match self {
+            Self::Bash => Some(".bashrc"),
+            Self::Zsh => Some(".zshrc"),
+            Self::Fish => Some(".config/fish/config.fish"),
+            Self::Other => None,
+        }
+    }
+
+    /// The line that adds `bin_path` to `PATH` in this shell.
+    fn path_line(self, bin_path: &Path) -> String {
+        match self {
+            Self::Fish => format!("set -gx PATH \"{}\" $PATH", bin_path.display()),
+            _ => format!("export PATH=\"{}:$PATH\"", bin_path.display()),
+        }
+    }
+}
+
+fn is_on_path(bin_path: &Path) -> bool {
+    match env::var_os("PATH") {
+        Some(path_var) => env::split_paths(&path_var).any(|p| p == bin_path),
+        None => false,
+    }
+}
+
+/// Create a directory used by pyflow. On Unix, only the current user may write to it, since it
+/// holds downloaded code that we later run.
+fn create_dir(path: &Path) {
+    if fs::create_dir_all(path).is_err() {
+        abort(&format!(
+            "Problem creating the directory {:?}. Check that you have permission to write to it.",
+            path
+        ));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).ok();
+    }
+}
+
+/// Add `bin_path` to `PATH`: offer to edit the shell's startup file, or explain how to do it.
+#[allow(unused_variables)]
+fn setup_path(bin_path: &Path, prompt_timeout: Option<time::Duration>) {
+    #[cfg(target_os = "windows")]
+    {
+        util::print_color(
+            &format!(
+                "To run tools pyflow installs, add {} to your `PATH`. Eg in Powershell: \
+                 `setx PATH \"$env:PATH;{}\"`",
+                bin_path.display(),
+                bin_path.display()
+            ),
+            Color::DarkYellow,
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let shell = Shell::from_path(&env::var("SHELL").unwrap_or_default());
+        let line = shell.path_line(bin_path);
+        let home = directories::BaseDirs::new().map(|d| d.home_dir().to_owned());
+
+        if let (Some(rc), Some(home)) = (shell.rc_file(), home) {
+            let rc_path: PathBuf = home.join(rc);
+            if util::confirm(
+                &format!(
+                    "{} isn't on your `PATH`. Add it to {}?",
+                    bin_path.display(),
+                    rc_path.display()
+                ),
+                prompt_timeout,
+            ) {
+                let appended = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&rc_path)
+                    .and_then(|mut f| writeln!(f, "\n# Added by pyflow\n{}", line));
+                match appended {
+                    Ok(_) => util::print_color(
+                        &format!("Updated {}. Open a new shell to use it.", rc_path.display()),
+                        Color::Green,
+                    ),
+                    Err(_) => abort(&format!("Problem writing to {}", rc_path.display())),
+                }
+                return;
+            }
+        }
+
+        util::print_color(
+            &format!(
+                "To run tools pyflow installs, add this line to your shell's startup file:\n{}",
+                line
+            ),
+            Color::DarkYellow,
+        );
+    }
+}
+
+/// Run on the first invocation, ie when the `bin` directory doesn't exist yet: create the
+/// directories pyflow uses, and check that `bin` is on `PATH`.
+pub fn first_run(pyflow_path: &Path, dirs: &[&Path], prompt_timeout: Option<time::Duration>) {
+    let bin_path = pyflow_path.join("bin");
+    if bin_path.exists() {
+        return;
+    }
+
+    create_dir(pyflow_path);
+    for dir in dirs {
+        create_dir(dir);
+    }
+    create_dir(&bin_path);
+
+    if !is_on_path(&bin_path) {
+        setup_path(&bin_path, prompt_timeout);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_detection() {
+        assert_eq!(Shell::from_path("/bin/bash"), Shell::Bash);
+        assert_eq!(Shell::from_path("/usr/local/bin/zsh"), Shell::Zsh);
+        assert_eq!(Shell::from_path("/usr/bin/fish"), Shell::Fish);
+        assert_eq!(Shell::from_path("/bin/tcsh"), Shell::Other);
+        assert_eq!(Shell::from_path(""), Shell::Other);
+    }
+
+    #[test]
+    fn path_lines() {
+        let bin = Path::new("/home/hiro/.local/share/pyflow/bin");
+        assert_eq!(
+            Shell::Bash.path_line(bin),
+            "export PATH=\"/home/hiro/.local/share/pyflow/bin:$PATH\""
+        );
+        assert_eq!(
+            Shell::Fish.path_line(bin),
+            "set -gx PATH \"/home/hiro/.local/share/pyflow/bin\" $PATH"
+        );
+    }
+}
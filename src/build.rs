@@ -1,8 +1,21 @@
-use crate::{dep_types::Req, util};
+use crate::{
+    commands,
+    dep_types::{Constraint, DependencyError, Req, ReqType, Version},
+    util,
+};
 use crossterm::Color;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
 use regex::Regex;
+use ring::digest;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::{env, fs, path::PathBuf, process::Command};
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 // https://packaging.python.org/tutorials/packaging-projects/
 
@@ -150,11 +163,288 @@ fn create_dummy_setup(cfg: &crate::Config, filename: &str) {
     };
 }
 
+/// Details of how a package was built, embedded in its wheels' `.dist-info` as
+/// `pyflow-build-info.toml`, for reproducibility audits, and debugging deployed artifacts.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct BuildInfo {
+    pyflow_version: String,
+    lock_sha256: Option<String>,
+    python_version: Option<String>,
+    platform: String,
+    /// UTC, from `SOURCE_DATE_EPOCH` if set, so identical inputs give identical artifacts.
+    built_at: String,
+}
+
+/// The build time, in seconds since the Unix epoch: `SOURCE_DATE_EPOCH` if it's set, else now.
+fn build_timestamp(source_date_epoch: Option<&str>) -> u64 {
+    match source_date_epoch.and_then(|s| s.trim().parse().ok()) {
+        Some(t) => t,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    }
+}
+
+/// Convert seconds since the Unix epoch to a UTC (year, month, day, hour, minute, second).
+fn civil_time(secs: u64) -> (u16, u8, u8, u8, u8, u8) {
+    // Convert days to a civil date; see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = secs % 86_400;
+    (
+        year as u16,
+        month as u8,
+        day as u8,
+        (time / 3600) as u8,
+        (time % 3600 / 60) as u8,
+        (time % 60) as u8,
+    )
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time, eg `2019-11-14T08:30:00Z`.
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(secs);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+fn find_build_info(paths: &util::Paths, lock_path: &Path) -> BuildInfo {
+    let lock_sha256 = fs::read(lock_path)
+        .ok()
+        .map(|data| HEXLOWER.encode(digest::digest(&digest::SHA256, &data).as_ref()));
+    let python_version = paths
+        .bin
+        .join("python")
+        .to_str()
+        .and_then(commands::find_py_version)
+        .map(|v| v.to_string2());
+
+    BuildInfo {
+        pyflow_version: env!("CARGO_PKG_VERSION").to_owned(),
+        lock_sha256,
+        python_version,
+        platform: format!("{}-{}", env::consts::OS, env::consts::ARCH),
+        built_at: format_timestamp(build_timestamp(
+            env::var("SOURCE_DATE_EPOCH").ok().as_deref(),
+        )),
+    }
+}
+
+/// A line of a wheel's `RECORD` file, which lists each file with its hash and size.
+fn record_line(name: &str, data: &[u8]) -> String {
+    format!(
+        "{},sha256={},{}",
+        name,
+        BASE64URL_NOPAD.encode(digest::digest(&digest::SHA256, data).as_ref()),
+        data.len()
+    )
+}
+
+/// A file in a wheel, with the details we keep when rewriting it.
+struct WheelEntry {
+    name: String,
+    data: Vec<u8>,
+    mode: Option<u32>,
+    modified: zip::DateTime,
+}
+
+fn read_wheel(path: &Path) -> io::Result<Vec<WheelEntry>> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    let mut result = vec![];
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        result.push(WheelEntry {
+            name: file.name().to_owned(),
+            data,
+            mode: file.unix_mode(),
+            modified: file.last_modified(),
+        });
+    }
+    Ok(result)
+}
+
+fn write_wheel(path: &Path, entries: &[WheelEntry]) -> io::Result<()> {
+    let mut writer = zip::ZipWriter::new(fs::File::create(path)?);
+    for entry in entries {
+        let mut options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(entry.modified);
+        if let Some(mode) = entry.mode {
+            options = options.unix_permissions(mode);
+        }
+        writer.start_file(entry.name.as_str(), options)?;
+        writer.write_all(&entry.data)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Add `pyflow-build-info.toml` to a wheel's `.dist-info` directory, and list it in `RECORD`.
+fn embed_build_info(entries: &mut Vec<WheelEntry>, info: &BuildInfo) -> io::Result<()> {
+    let info_name = match entries
+        .iter()
+        .find(|e| e.name.ends_with(".dist-info/RECORD"))
+    {
+        Some(record) => record.name.replace("RECORD", "pyflow-build-info.toml"),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The wheel has no RECORD",
+            ))
+        }
+    };
+    let info_data = toml::to_string(info)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+        .into_bytes();
+
+    // Replace it, if it's from an earlier run.
+    entries.retain(|e| e.name != info_name);
+    let record_i = entries
+        .iter()
+        .position(|e| e.name.ends_with(".dist-info/RECORD"))
+        .unwrap();
+
+    let record = String::from_utf8_lossy(&entries[record_i].data).to_string();
+    let mut lines: Vec<String> = record
+        .lines()
+        .filter(|l| !l.starts_with(&format!("{},", info_name)))
+        .map(str::to_owned)
+        .collect();
+    lines.push(record_line(&info_name, &info_data));
+    entries[record_i].data = (lines.join("\n") + "\n").into_bytes();
+
+    let modified = entries[record_i].modified;
+    entries.push(WheelEntry {
+        name: info_name,
+        data: info_data,
+        mode: Some(0o644),
+        modified,
+    });
+    Ok(())
+}
+
+/// Make a wheel's contents depend only on its files: sort them, with `.dist-info` last as the
+/// wheel spec recommends, and `RECORD`'s lines too; give them all the same timestamp, if
+/// `timestamp` is set; and make permissions 644, or 755 for executables.
+fn normalize_wheel(entries: &mut Vec<WheelEntry>, timestamp: Option<u64>) {
+    let sort_key = |name: &str| {
+        let dist_info = name.contains(".dist-info/");
+        (
+            dist_info,
+            dist_info && name.ends_with("/RECORD"),
+            name.to_owned(),
+        )
+    };
+    entries.sort_by_key(|e| sort_key(&e.name));
+
+    // Zip timestamps can't be before 1980.
+    let modified = timestamp.map(|t| {
+        let (year, month, day, hour, minute, second) = civil_time(t.max(315_532_800));
+        zip::DateTime::from_date_and_time(year, month, day, hour, minute, second)
+            .unwrap_or_default()
+    });
+
+    for entry in entries.iter_mut() {
+        if let Some(m) = modified {
+            entry.modified = m;
+        }
+        entry.mode = Some(match entry.mode {
+            Some(mode) if mode & 0o111 != 0 => 0o755,
+            _ => 0o644,
+        });
+
+        if entry.name.ends_with(".dist-info/RECORD") {
+            let record = String::from_utf8_lossy(&entry.data).to_string();
+            let mut lines: Vec<&str> = record.lines().filter(|l| !l.is_empty()).collect();
+            lines.sort_by_key(|l| {
+                let name = l.split(',').next().unwrap_or_default();
+                sort_key(name)
+            });
+            entry.data = (lines.join("\n") + "\n").into_bytes();
+        }
+    }
+}
+
+/// Add build info to a wheel we've built, and make it reproducible.
+fn finish_wheel(path: &Path, info: &BuildInfo, timestamp: Option<u64>) -> io::Result<()> {
+    let mut entries = read_wheel(path)?;
+    embed_build_info(&mut entries, info)?;
+    normalize_wheel(&mut entries, timestamp);
+    write_wheel(path, &entries)
+}
+
+/// Replace path dependencies with pins to the version at that path, since a built package can't
+/// refer to a path on this machine. Eg within a workspace, the members it depends on are published
+/// with it, at the versions in their `pyproject.toml`.
+fn pin_path_deps(
+    reqs: &[Req],
+    find_version: impl Fn(&Path) -> Option<Version>,
+) -> Result<Vec<Req>, DependencyError> {
+    let mut result = vec![];
+    for req in reqs {
+        let path = match &req.path {
+            Some(p) => PathBuf::from(p),
+            None => {
+                result.push(req.clone());
+                continue;
+            }
+        };
+        let version = match find_version(&path) {
+            Some(v) => v,
+            None => {
+                return Err(DependencyError::new(&format!(
+                    "Can't find the version of {}, at {:?}, to publish this package with. Set \
+                     `version` in its `pyproject.toml`",
+                    req.name, path
+                )))
+            }
+        };
+        let mut pinned = req.clone();
+        pinned.path = None;
+        pinned.constraints = vec![Constraint::new(ReqType::Exact, version)];
+        result.push(pinned);
+    }
+    Ok(result)
+}
+
+/// Find wheels in `dist` modified since `since`, ie the ones this build created.
+fn find_new_wheels(since: SystemTime) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = match fs::read_dir("dist") {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("whl"))
+            .filter(|p| {
+                fs::metadata(p)
+                    .and_then(|m| m.modified())
+                    .map_or(false, |m| m >= since)
+            })
+            .collect(),
+        Err(_) => vec![],
+    };
+    result.sort();
+    result
+}
+
 pub fn build(
     lockpacks: &[crate::dep_types::LockPackage],
     paths: &util::Paths,
     cfg: &crate::Config,
+    lock_path: &Path,
     _extras: &[String],
+    net: &crate::net::Settings,
 ) {
     for lp in lockpacks.iter() {
         if lp.rename.is_some() {
@@ -174,7 +464,7 @@ pub fn build(
     // for now, it's easier to install using pip
     // todo: Install using own tools instead of pip; this is the last dependence on pip.
     let output = Command::new(paths.bin.join("python"))
-        .args(&["-m", "pip", "install", "twine"])
+        .args(&["-m", "pip", "install", "twine", "wheel"])
         .output()
         .expect("Problem installing Twine");
     util::check_command_output(&output, "failed to install twine");
@@ -192,7 +482,17 @@ pub fn build(
     //    )
     //    .expect("Problem installing `twine`");
 
-    create_dummy_setup(cfg, dummy_setup_fname);
+    let mut cfg = cfg.clone();
+    cfg.reqs = match pin_path_deps(&cfg.reqs, |path| {
+        crate::Config::from_file(&path.join("pyproject.toml"), net).and_then(|c| c.version)
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            util::abort(&e.details);
+            unreachable!()
+        }
+    };
+    create_dummy_setup(&cfg, dummy_setup_fname);
 
     util::set_pythonpath(&[paths.lib.to_owned()]);
     println!("🛠️️ Building the package...");
@@ -205,10 +505,26 @@ pub fn build(
         util::check_command_output(&output, "failed to run build script");
     }
 
-    //    Command::new(paths.bin.join("python"))
-    //        .args(&[dummy_setup_fname, "sdist", "bdist_wheel"])
-    //        .status()
-    //        .expect("Problem building");
+    // Allow for filesystems with coarse modification times.
+    let build_start = SystemTime::now() - std::time::Duration::from_secs(2);
+    let output = Command::new(paths.bin.join("python"))
+        .args(&[dummy_setup_fname, "sdist", "bdist_wheel"])
+        .output()
+        .expect("Problem building");
+    util::check_command_output(&output, "failed to build the package");
+
+    let info = find_build_info(paths, lock_path);
+    let source_date_epoch = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.trim().parse().ok());
+    for wheel in find_new_wheels(build_start) {
+        if finish_wheel(&wheel, &info, source_date_epoch).is_err() {
+            util::print_color(
+                &format!("Problem adding build info to {:?}", wheel),
+                Color::DarkYellow,
+            );
+        }
+    }
 
     util::print_color("Build complete.", Color::Green);
 
@@ -290,6 +606,7 @@ pub mod test {
             extras: HashMap::new(),
             repo_url: None,
             build: None,
+            ..Default::default()
         };
 
         let expected = r#"import setuptools
@@ -325,6 +642,66 @@ setuptools.setup(
         assert_eq!(expected, &cfg_to_setup(&cfg));
     }
 
+    #[test]
+    fn timestamps() {
+        assert_eq!(build_timestamp(Some("1573720200")), 1_573_720_200);
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_573_720_200), "2019-11-14T08:30:00Z");
+        assert_eq!(format_timestamp(1_582_977_599), "2020-02-29T11:59:59Z");
+    }
+
+    #[test]
+    fn wheel_normalization() {
+        let entry = |name: &str, data: &str, mode| WheelEntry {
+            name: name.into(),
+            data: data.as_bytes().to_vec(),
+            mode,
+            modified: zip::DateTime::default(),
+        };
+        let mut entries = vec![
+            entry(
+                "demo-0.1.dist-info/RECORD",
+                "demo/b.py,sha256=b,1\ndemo-0.1.dist-info/RECORD,,\ndemo/a.py,sha256=a,1\n",
+                Some(0o664),
+            ),
+            entry("demo/b.py", "b", Some(0o775)),
+            entry("demo-0.1.dist-info/METADATA", "", None),
+            entry("demo/a.py", "a", Some(0o600)),
+        ];
+        normalize_wheel(&mut entries, Some(1_573_720_200));
+
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "demo/a.py",
+                "demo/b.py",
+                "demo-0.1.dist-info/METADATA",
+                "demo-0.1.dist-info/RECORD"
+            ]
+        );
+        let modes: Vec<Option<u32>> = entries.iter().map(|e| e.mode).collect();
+        assert_eq!(
+            modes,
+            vec![Some(0o644), Some(0o755), Some(0o644), Some(0o644)]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&entries[3].data),
+            "demo/a.py,sha256=a,1\ndemo/b.py,sha256=b,1\ndemo-0.1.dist-info/RECORD,,\n"
+        );
+        assert!(entries
+            .iter()
+            .all(|e| (e.modified.year(), e.modified.month(), e.modified.hour()) == (2019, 11, 8)));
+    }
+
+    #[test]
+    fn record_lines() {
+        assert_eq!(
+            record_line("pkg/__init__.py", b""),
+            "pkg/__init__.py,sha256=47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU,0"
+        );
+    }
+
     #[test]
     fn py_list() {
         let expected = r#"[
@@ -345,6 +722,30 @@ setuptools.setup(
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn path_dep_pins() {
+        let mut core = Req::new("acme-core".into(), vec![]);
+        core.path = Some("../core".into());
+        let numpy = Req::new(
+            "numpy".into(),
+            vec![Constraint::new(Caret, Version::new(1, 16, 4))],
+        );
+
+        let pinned = pin_path_deps(&[core.clone(), numpy.clone()], |p| {
+            if p == Path::new("../core") {
+                Some(Version::new(0, 3, 1))
+            } else {
+                None
+            }
+        })
+        .unwrap();
+        assert_eq!(pinned[0].to_setup_py_string(), "acme-core==0.3.1");
+        assert_eq!(pinned[0].path, None);
+        assert_eq!(pinned[1], numpy);
+
+        assert!(pin_path_deps(&[core], |_| None).is_err());
+    }
+
     // todo: Re-impl if you end up using this
     //    #[test]
     //    fn py_dict() {
@@ -0,0 +1,204 @@
+//! The output of source package builds, kept in the environment's `build-logs` folder so a
+//! failure can be looked into later, with `pyflow logs`, without building it again. The last few
+//! builds of each package are kept. Files are named eg `numpy-1.17.0.1700000000000.failed.log`:
+//! the package, its version, when it was built in milliseconds, and whether it succeeded.
+
+use crate::{dep_types::Version, util};
+use crossterm::Color;
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DIR: &str = "build-logs";
+
+/// How many builds of each package to keep logs for.
+const KEEP_PER_PACKAGE: usize = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    name: String,
+    version: String,
+    millis: u128,
+    failed: bool,
+    path: PathBuf,
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+fn parse_filename(path: &Path) -> Option<Entry> {
+    let filename = path.file_name()?.to_str()?.strip_suffix(".log")?;
+    let mut parts = filename.rsplitn(3, '.');
+    let failed = match parts.next()? {
+        "ok" => false,
+        "failed" => true,
+        _ => return None,
+    };
+    let millis = parts.next()?.parse().ok()?;
+    let (name, version) = parts.next()?.split_once('-')?;
+    Some(Entry {
+        name: name.to_owned(),
+        version: version.to_owned(),
+        millis,
+        failed,
+        path: path.to_owned(),
+    })
+}
+
+/// The logs kept in an environment, newest first.
+fn entries(env_path: &Path) -> Vec<Entry> {
+    let mut result: Vec<Entry> = fs::read_dir(env_path.join(DIR))
+        .map(|e| {
+            e.filter_map(Result::ok)
+                .filter_map(|e| parse_filename(&e.path()))
+                .collect()
+        })
+        .unwrap_or_default();
+    result.sort_by_key(|e| Reverse(e.millis));
+    result
+}
+
+/// Save a build's output, and remove the package's oldest logs past `KEEP_PER_PACKAGE`. Logs are
+/// a convenience, so problems writing them are ignored.
+pub fn save(env_path: &Path, name: &str, version: &Version, log: &str, succeeded: bool) {
+    let name = util::standardize_name(name);
+    let dir = env_path.join(DIR);
+    if fs::create_dir_all(&dir).is_err() {
+        return;
+    }
+    let filename = format!(
+        "{}-{}.{}.{}.log",
+        name,
+        version.to_string2(),
+        now_millis(),
+        if succeeded { "ok" } else { "failed" }
+    );
+    fs::write(dir.join(filename), log).ok();
+
+    for old in entries(env_path)
+        .into_iter()
+        .filter(|e| e.name == name)
+        .skip(KEEP_PER_PACKAGE)
+    {
+        fs::remove_file(old.path).ok();
+    }
+}
+
+/// Eg `5 minutes ago`.
+fn age(millis: u128, now: u128) -> String {
+    let secs = now.saturating_sub(millis) / 1_000;
+    let (count, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3_599 => (secs / 60, "minute"),
+        3_600..=86_399 => (secs / 3_600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+fn describe(entry: &Entry, now: u128) -> String {
+    format!(
+        "{} {}, {} {}",
+        entry.name,
+        entry.version,
+        if entry.failed { "failed" } else { "built" },
+        age(entry.millis, now)
+    )
+}
+
+/// `pyflow logs`: show the newest log for `package`, or the newest failed build's with
+/// `last_failure`. With neither, list the logs that are kept.
+pub fn show(env_path: &Path, package: Option<&str>, last_failure: bool) {
+    let now = now_millis();
+    let entries = entries(env_path);
+    if package.is_none() && !last_failure {
+        if entries.is_empty() {
+            println!("No build logs yet. They're saved when packages are built from source.");
+        }
+        for entry in &entries {
+            println!("{}", describe(entry, now));
+        }
+        return;
+    }
+
+    let found = entries.iter().find(|e| {
+        package.is_none_or(|p| util::compare_names(p, &e.name)) && (e.failed || !last_failure)
+    });
+    let entry = match found {
+        Some(e) => e,
+        None => {
+            util::abort(&match (package, last_failure) {
+                (Some(p), true) => format!("No failed builds of {} are logged", p),
+                (Some(p), false) => format!("No builds of {} are logged", p),
+                _ => "No failed builds are logged".into(),
+            });
+            unreachable!()
+        }
+    };
+
+    let log = fs::read_to_string(&entry.path).unwrap_or_default();
+    util::print_color(
+        &format!("{} ({:?})", describe(entry, now), entry.path),
+        if entry.failed {
+            Color::DarkYellow
+        } else {
+            Color::DarkBlue
+        },
+    );
+    print!("{}", log);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn save_and_rotate() {
+        let dir = util::TempDir::new("build-logs");
+        let env_path = dir.path();
+        let version = Version::from_str("1.17.0").unwrap();
+        for i in 0..5 {
+            save(env_path, "NumPy", &version, &format!("build {}", i), i != 4);
+            // Keep the timestamps distinct.
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        save(env_path, "six", &version, "built", true);
+
+        let found = entries(env_path);
+        let numpy: Vec<&Entry> = found.iter().filter(|e| e.name == "numpy").collect();
+        assert_eq!(numpy.len(), KEEP_PER_PACKAGE);
+        assert!(numpy[0].failed);
+        assert_eq!(numpy[0].version, "1.17.0");
+        assert_eq!(fs::read_to_string(&numpy[0].path).unwrap(), "build 4");
+        assert_eq!(fs::read_to_string(&numpy[2].path).unwrap(), "build 2");
+        assert_eq!(found[0].name, "six");
+    }
+
+    #[test]
+    fn log_filenames() {
+        let entry = parse_filename(Path::new("typed_ast-1.4.0rc1.1700000000000.ok.log")).unwrap();
+        assert_eq!(entry.name, "typed_ast");
+        assert_eq!(entry.version, "1.4.0rc1");
+        assert_eq!(entry.millis, 1_700_000_000_000);
+        assert!(!entry.failed);
+        assert!(parse_filename(Path::new("notes.txt")).is_none());
+        assert!(parse_filename(Path::new("six-1.0.0.1700000000000.pending.log")).is_none());
+
+        assert_eq!(age(0, 1_000), "1 second ago");
+        assert_eq!(age(0, 5 * 60_000), "5 minutes ago");
+        assert_eq!(age(0, 30 * 3_600_000), "1 day ago");
+    }
+}
@@ -104,6 +104,37 @@ pub fn create_venv2(py_alias: &Path, lib_path: &Path, name: &str) -> Result<(),
     Ok(())
 }
 
+/// Check if a Python alias, eg `pypy3`, runs PyPy.
+pub fn is_pypy(alias: &str) -> bool {
+    Command::new(alias)
+        .args(&["-c", "import sys;print(sys.implementation.name)"])
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim() == "pypy")
+        .unwrap_or(false)
+}
+
+/// Find the ABI tag a wheel's compiled code must match to load in the interpreter at `bin_path`,
+/// eg `cp38`, `cp37m`, or `cp313t` for a free-threaded build. Returns `None` for interpreters
+/// other than CPython, or if we can't run it.
+pub fn find_py_abi(bin_path: &Path) -> Option<String> {
+    let script = "import sys,sysconfig as s;v=s.get_config_var;i=sys.version_info;\
+                  print('' if sys.implementation.name!='cpython' else 'cp%d%d'%i[:2]\
+                  +('t' if v('Py_GIL_DISABLED') else '')\
+                  +('d' if hasattr(sys,'gettotalrefcount') else '')\
+                  +('m' if i<(3,8) and v('WITH_PYMALLOC') else ''))";
+    let output = Command::new(bin_path.join("python"))
+        .args(&["-c", script])
+        .output()
+        .ok()?;
+
+    let abi = std::str::from_utf8(&output.stdout).ok()?.trim().to_owned();
+    if abi.is_empty() {
+        None
+    } else {
+        Some(abi)
+    }
+}
+
 pub fn run_python(
     bin_path: &Path,
     lib_paths: &[PathBuf],
@@ -119,21 +150,97 @@ pub fn run_python(
     Ok(())
 }
 
+/// Like `run_python`, but report whether the command succeeded, eg so a failing test run
+/// can fail CI.
+pub fn run_python_status(
+    bin_path: &Path,
+    lib_paths: &[PathBuf],
+    args: &[String],
+) -> Result<bool, Box<dyn Error>> {
+    util::set_pythonpath(lib_paths);
+    Ok(Command::new(bin_path.join("python"))
+        .args(args)
+        .status()?
+        .success())
+}
+
+/// Clone a repo into `dest_path`.
 pub fn download_git_repo(repo: &str, dest_path: &Path) -> Result<(), Box<dyn Error>> {
     // todo: Download directly instead of using git clone?
-    // todo: Suppress this output.
-    if Command::new("git").arg("--version").status().is_err() {
+    if Command::new("git").arg("--version").output().is_err() {
         util::abort("Can't find Git on the PATH. Is it installed?");
     }
 
     let output = Command::new("git")
-        .current_dir(dest_path)
-        .args(&["clone", repo])
+        .arg("clone")
+        .arg(repo)
+        .arg(dest_path)
         .output()?;
     util::check_command_output(&output, "cloning repo");
     Ok(())
 }
 
+/// Check out `target`, eg a commit, or `origin/main`, in a clone. We only fetch if it's a
+/// branch, or we don't have it yet, so a locked commit doesn't need the network.
+pub fn checkout_git_repo(
+    repo_path: &Path,
+    target: &str,
+    fetch: bool,
+) -> Result<(), Box<dyn Error>> {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .current_dir(repo_path)
+            .args(args)
+            .output()
+    };
+
+    let have_it = git(&[
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        &format!("{}^{{commit}}", target),
+    ])?
+    .status
+    .success();
+    if fetch || !have_it {
+        let output = git(&["fetch", "--tags", "--force", "origin"])?;
+        util::check_command_output(&output, "fetching repo");
+    }
+
+    let output = git(&["checkout", "--quiet", "--detach", target])?;
+    util::check_command_output(&output, "checking out repo");
+    Ok(())
+}
+
+/// The commit a clone has checked out.
+pub fn git_head(repo_path: &Path) -> Option<String> {
+    Command::new("git")
+        .current_dir(repo_path)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_owned())
+}
+
+/// Generate `egg-info` metadata for a legacy project by running its `setup.py`. `-I` keeps the
+/// user's site-packages and Python environment variables from affecting the result.
+pub fn run_egg_info(
+    py_alias: &str,
+    proj_dir: &Path,
+    egg_base: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let output = Command::new(py_alias)
+        .args(&["-I", "setup.py", "-q", "egg_info", "--egg-base"])
+        .arg(egg_base)
+        .current_dir(proj_dir)
+        .output()?;
+    util::check_command_output_with(&output, |s| {
+        util::abort(&format!("Problem running `setup.py egg_info`: {}", s))
+    });
+    Ok(())
+}
+
 /// Initialize a new git repo.
 pub fn git_init(dir: &Path) -> Result<(), Box<dyn Error>> {
     let output = Command::new("git")
@@ -1,37 +1,55 @@
 use std::str::FromStr;
 
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take};
+use nom::bytes::complete::{tag, take, take_till};
 use nom::character::complete::{digit1, space0, space1};
-use nom::combinator::{flat_map, map, map_parser, map_res, opt, value};
+use nom::combinator::{map, map_parser, map_res, opt, value};
 use nom::multi::separated_list;
 use nom::sequence::{delimited, preceded, separated_pair, tuple};
 use nom::{AsChar, IResult, InputTakeAtPosition};
 
-use crate::dep_types::{Constraint, Extras, Req, ReqType, Version, VersionModifier};
-use crate::util::Os;
-
-enum ExtrasPart {
-    Extra(String),
-    SysPlatform(ReqType, Os),
-    PythonVersion(Constraint),
-}
+use crate::dep_types::{Constraint, Marker, MarkerValue, Req, ReqType, Version, VersionModifier};
 
 pub fn parse_req(input: &str) -> IResult<&str, Req> {
-    // eg saturn = ">=0.3.4", as in pyproject.toml
+    // eg saturn = ">=0.3.4", as in pyproject.toml, or `requests[socks]` or
+    // `saturn @ https://..` from the CLI
     map(
         alt((
-            separated_pair(
-                parse_package_name,
-                tuple((space0, tag("="), space0)),
-                delimited(quote, parse_constraints, quote),
+            map(
+                separated_pair(
+                    tuple((parse_package_name, opt(parse_install_with_extras))),
+                    tuple((space0, tag("="), space0)),
+                    delimited(quote, parse_constraints, quote),
+                ),
+                |(x, constraints)| (x, constraints, None),
+            ),
+            map(
+                separated_pair(
+                    tuple((parse_package_name, opt(parse_install_with_extras))),
+                    tuple((space0, tag("@"), space0)),
+                    parse_url,
+                ),
+                |(x, url)| (x, vec![], Some(url)),
+            ),
+            map(
+                tuple((parse_package_name, opt(parse_install_with_extras))),
+                |x| (x, vec![], None),
             ),
-            map(parse_package_name, |x| (x, vec![])),
         )),
-        |(name, constraints)| Req::new(name.to_string(), constraints),
+        |((name, install_with_extras), constraints, url)| {
+            let mut r = Req::new(name.to_string(), constraints);
+            r.install_with_extras = install_with_extras;
+            r.url = url.map(ToOwned::to_owned);
+            r
+        },
     )(input)
 }
 
+/// A direct reference's URL, eg `https://example.com/saturn-0.3.4-py3-none-any.whl`.
+fn parse_url(input: &str) -> IResult<&str, &str> {
+    input.split_at_position1_complete(char::is_whitespace, nom::error::ErrorKind::Tag)
+}
+
 pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
     // eg saturn (>=0.3.4) or argon2-cffi (>=16.1.0) ; extra == 'argon2'
     // Note: We specify what chars are acceptable in a name instead of using
@@ -46,19 +64,19 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
                     preceded(space0, delimited(tag("("), parse_constraints, tag(")"))),
                     preceded(space1, parse_constraints),
                 )),
-                opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+                opt(preceded(tuple((space0, tag(";"), space0)), parse_marker)),
             )),
             map(
                 tuple((
                     tuple((parse_package_name, opt(parse_install_with_extras))),
-                    opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+                    opt(preceded(tuple((space0, tag(";"), space0)), parse_marker)),
                 )),
                 |(x, y)| (x, vec![], y),
             ),
         )),
-        |((name, install_with_extras), constraints, extras_opt)| {
-            let mut r = if let Some(extras) = extras_opt {
-                Req::new_with_extras(name.to_string(), constraints, extras)
+        |((name, install_with_extras), constraints, marker_opt)| {
+            let mut r = if let Some(marker) = marker_opt {
+                Req::new_with_marker(name.to_string(), constraints, marker)
             } else {
                 Req::new(name.to_string(), constraints)
             };
@@ -70,8 +88,16 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
 
 pub fn parse_pip_str(input: &str) -> IResult<&str, Req> {
     map(
-        tuple((parse_package_name, opt(parse_constraint))),
-        |(name, constraint)| Req::new(name.to_string(), constraint.into_iter().collect()),
+        tuple((
+            parse_package_name,
+            opt(parse_install_with_extras),
+            opt(parse_constraint),
+        )),
+        |(name, install_with_extras, constraint)| {
+            let mut r = Req::new(name.to_string(), constraint.into_iter().collect());
+            r.install_with_extras = install_with_extras;
+            r
+        },
     )(input)
 }
 
@@ -119,77 +145,106 @@ fn parse_install_with_extras(input: &str) -> IResult<&str, Vec<String>> {
     map(
         delimited(
             tag("["),
-            separated_list(tag(","), parse_package_name),
+            separated_list(tuple((space0, tag(","), space0)), parse_package_name),
             tag("]"),
         ),
         |extras| extras.iter().map(|x| x.to_string()).collect(),
     )(input)
 }
 
-pub fn parse_extras(input: &str) -> IResult<&str, Extras> {
+/// Parse a PEP 508 environment marker, eg
+/// `python_version < "3.8" or (sys_platform == "win32" and extra == 'test')`.
+pub fn parse_marker(input: &str) -> IResult<&str, Marker> {
     map(
-        separated_list(
-            delimited(space0, tag("and"), space0),
-            delimited(
-                opt(preceded(tag("("), space0)),
-                parse_extra_part,
-                opt(preceded(space0, tag(")"))),
-            ),
-        ),
-        |ps| {
-            let mut extra = None;
-            let mut sys_platform = None;
-            let mut python_version = None;
-
-            for p in ps {
-                match p {
-                    ExtrasPart::Extra(s) => extra = Some(s),
-                    ExtrasPart::SysPlatform(r, o) => sys_platform = Some((r, o)),
-                    ExtrasPart::PythonVersion(c) => python_version = Some(c),
-                }
-            }
-
-            Extras {
-                extra,
-                sys_platform,
-                python_version,
+        separated_list(delimited(space0, tag("or"), space0), parse_marker_and),
+        |mut ms| {
+            if ms.len() == 1 {
+                ms.remove(0)
+            } else {
+                Marker::Or(ms)
             }
         },
     )(input)
 }
 
-fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
-    flat_map(
-        alt((tag("extra"), tag("sys_platform"), tag("python_version"))),
-        |type_| {
-            move |input: &str| match type_ {
-                "extra" => map(
-                    preceded(
-                        separated_pair(space0, tag("=="), space0),
-                        delimited(quote, parse_package_name, quote),
-                    ),
-                    |x| ExtrasPart::Extra(x.to_string()),
-                )(input),
-                "sys_platform" => map(
-                    tuple((
-                        delimited(space0, tag("=="), space0),
-                        delimited(quote, parse_package_name, quote),
-                    )),
-                    |(_, o)| ExtrasPart::SysPlatform(ReqType::Exact, Os::from_str(o).unwrap()),
-                )(input),
-                "python_version" => map(
-                    tuple((
-                        delimited(space0, parse_req_type, space0),
-                        delimited(quote, parse_version, quote),
-                    )),
-                    |(r, v)| ExtrasPart::PythonVersion(Constraint::new(r, v)),
-                )(input),
-                _ => panic!("Found unexpected"),
+fn parse_marker_and(input: &str) -> IResult<&str, Marker> {
+    map(
+        separated_list(delimited(space0, tag("and"), space0), parse_marker_atom),
+        |mut ms| {
+            if ms.len() == 1 {
+                ms.remove(0)
+            } else {
+                Marker::And(ms)
             }
         },
     )(input)
 }
 
+fn parse_marker_atom(input: &str) -> IResult<&str, Marker> {
+    alt((
+        delimited(
+            tuple((tag("("), space0)),
+            parse_marker,
+            tuple((space0, tag(")"))),
+        ),
+        map(
+            tuple((
+                parse_marker_value,
+                delimited(space0, parse_marker_op, space0),
+                parse_marker_value,
+            )),
+            |(lhs, op, rhs)| Marker::Compare(lhs, op.to_string(), rhs),
+        ),
+    ))(input)
+}
+
+fn parse_marker_op(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag("==="),
+        tag("=="),
+        tag("!="),
+        tag("<="),
+        tag(">="),
+        tag("~="),
+        tag("<"),
+        tag(">"),
+        // Locks from older versions of pyflow can have these.
+        tag("^"),
+        tag("~"),
+        value("not in", tuple((tag("not"), space1, tag("in")))),
+        tag("in"),
+    ))(input)
+}
+
+fn parse_marker_value(input: &str) -> IResult<&str, MarkerValue> {
+    alt((
+        map(
+            alt((
+                tag("python_full_version"),
+                tag("python_version"),
+                tag("os_name"),
+                tag("sys_platform"),
+                tag("platform_release"),
+                tag("platform_system"),
+                tag("platform_version"),
+                tag("platform_machine"),
+                tag("platform_python_implementation"),
+                tag("implementation_name"),
+                tag("implementation_version"),
+                tag("extra"),
+            )),
+            |v: &str| MarkerValue::Variable(v.to_string()),
+        ),
+        map(
+            alt((
+                delimited(tag("\""), take_till(|c| c == '"'), tag("\"")),
+                delimited(tag("'"), take_till(|c| c == '\''), tag("'")),
+            )),
+            |v: &str| MarkerValue::Literal(v.to_string()),
+        ),
+    ))(input)
+}
+
 pub fn parse_constraints(input: &str) -> IResult<&str, Vec<Constraint>> {
     separated_list(tuple((space0, tag(","), space0)), parse_constraint)(input)
 }
@@ -280,7 +335,8 @@ fn parse_modifier_version(input: &str) -> IResult<&str, VersionModifier> {
 mod tests {
     use rstest::rstest;
 
-    use crate::dep_types::{Version, VersionModifier};
+    use crate::dep_types::{Extras, Version, VersionModifier};
+    use crate::util::Os;
 
     use super::*;
 
@@ -383,7 +439,8 @@ mod tests {
             Ok(("", Extras{
                 extra: Some("test".to_string()),
                 sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
+                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)}),
+                implementation_name: None
             }))
         ),
        case(
@@ -391,7 +448,8 @@ mod tests {
             Ok(("", Extras{
                 extra: None,
                 sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
+                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)}),
+                implementation_name: None
             }))
         ),
        case(
@@ -399,7 +457,8 @@ mod tests {
             Ok(("", Extras{
                 extra: None,
                 sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
+                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)}),
+                implementation_name: None
             }))
         ),
         case(
@@ -407,7 +466,8 @@ mod tests {
             Ok(("", Extras{
                 extra: None,
                 sys_platform: None,
-                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)})
+                python_version: Some(Constraint{ type_: ReqType::Exact, version: Version::new(2, 7, 0)}),
+                implementation_name: None
             }))
         ),
         case(
@@ -415,17 +475,45 @@ mod tests {
             Ok(("", Extras{
                 extra: None,
                 sys_platform: Some((ReqType::Exact, Os::Windows32)),
-                python_version: Some(Constraint{ type_: ReqType::Lt, version: Version::new(3, 6, 0)})
+                python_version: Some(Constraint{ type_: ReqType::Lt, version: Version::new(3, 6, 0)}),
+                implementation_name: None
+            }))
+        ),
+        case(
+            "platform_python_implementation != \"PyPy\" and python_version >= \"3.8\"",
+            Ok(("", Extras{
+                extra: None,
+                sys_platform: None,
+                python_version: Some(Constraint{ type_: ReqType::Gte, version: Version::new(3, 8, 0)}),
+                implementation_name: Some((ReqType::Ne, "pypy".to_string()))
             }))
         ),
     )]
     fn test_parse_extras(input: &str, expected: IResult<&str, Extras>) {
-        assert_eq!(parse_extras(input), expected);
+        let parsed = parse_marker(input).map(|(rest, m)| (rest, Extras::from_marker(&m)));
+        assert_eq!(parsed, expected);
     }
 
     #[rstest(input, expected,
         case("saturn = \">=0.3.4\"", Ok(("", Req::new("saturn".to_string(), vec![Constraint::new(ReqType::Gte, Version::new(0, 3, 4))])))),
         case("saturn", Ok(("", Req::new("saturn".to_string(), vec![])))),
+        case("requests[socks, security] = \"^2.31\"", Ok(("", Req {
+            install_with_extras: Some(vec!["socks".to_string(), "security".to_string()]),
+            ..Req::new("requests".to_string(), vec![Constraint::new(ReqType::Caret, Version::new(2, 31, 0))])
+        }))),
+        case("uvicorn[standard]", Ok(("", Req {
+            install_with_extras: Some(vec!["standard".to_string()]),
+            ..Req::new("uvicorn".to_string(), vec![])
+        }))),
+        case("saturn @ https://example.com/saturn-0.3.4-py3-none-any.whl", Ok(("", Req {
+            url: Some("https://example.com/saturn-0.3.4-py3-none-any.whl".to_string()),
+            ..Req::new("saturn".to_string(), vec![])
+        }))),
+        case("uvicorn[standard]@https://example.com/uvicorn-0.30.0.tar.gz", Ok(("", Req {
+            install_with_extras: Some(vec!["standard".to_string()]),
+            url: Some("https://example.com/uvicorn-0.30.0.tar.gz".to_string()),
+            ..Req::new("uvicorn".to_string(), vec![])
+        }))),
     )]
     fn test_parse_req(input: &str, expected: IResult<&str, Req>) {
         assert_eq!(parse_req(input), expected);
@@ -1,15 +1,31 @@
 use crate::{
     dep_types::{
-        self, Constraint, Dependency, DependencyError, Package, Rename, Req, ReqType, Version,
+        self, Constraint, Dependency, DependencyError, MarkerEnv, Package, Rename, Req, ReqType,
+        Version,
     },
-    util,
+    net::{self, NetError},
+    policy, util,
+    warnings::{self, WarningCode},
 };
 
+mod solver;
+
+use solver::Provider;
+
 use crossterm::Color;
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::str::FromStr;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread, time,
+};
 
 #[derive(Debug, Deserialize)]
 struct WarehouseInfo {
@@ -17,6 +33,13 @@ struct WarehouseInfo {
     requires_dist: Option<Vec<String>>,
     requires_python: Option<String>,
     version: String,
+    #[serde(default)]
+    yanked: bool,
+    yanked_reason: Option<String>,
+    license: Option<String>,
+    license_expression: Option<String>,
+    #[serde(default)]
+    classifiers: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -36,6 +59,13 @@ pub struct WarehouseRelease {
     pub requires_python: Option<String>,
     pub url: String,
     pub dependencies: Option<Vec<String>>,
+    #[serde(default)]
+    pub yanked: bool,
+    #[serde(default)]
+    pub yanked_reason: Option<String>,
+    #[serde(default)]
+    pub size: u64, // The archive's size, in bytes.
+    pub upload_time: Option<String>, // eg `2019-05-16T14:12:07`
 }
 
 /// Only deserialize the info we need to resolve dependencies etc.
@@ -46,6 +76,21 @@ struct WarehouseData {
     urls: Vec<WarehouseRelease>,
 }
 
+/// Data about a single release, from eg `https://pypi.org/pypi/requests/2.22.0/json`.
+#[derive(Debug, Deserialize)]
+struct WarehouseVersionData {
+    info: WarehouseInfo,
+    urls: Vec<WarehouseRelease>,
+    #[serde(default)]
+    vulnerabilities: Vec<WarehouseVulnerability>,
+}
+
+/// A known vulnerability in a release, from the warehouse's advisory data.
+#[derive(Debug, Deserialize)]
+struct WarehouseVulnerability {
+    id: String, // eg `PYSEC-2023-74`
+}
+
 /// Format a name based on how it's listed on `PyPi`. Ie capitalize or convert - to _'
 /// a required.
 fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version>)>) -> String {
@@ -55,18 +100,491 @@ fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version
     }
 }
 
+/// A package index other than PyPI, from `[tool.pyflow.sources]`, with the same JSON API, eg at
+/// `{url}/pypi/requests/json`. Its packages, by name or prefix like `acme-*`, are only looked for
+/// there, so a package with the same name on PyPI can't take their place.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub url: String,
+    pub packages: Vec<String>,
+}
+
+/// Where packages are looked for: PyPI, and the sources in `[tool.pyflow.sources]`, and which of
+/// their versions may be used.
+#[derive(Clone, Debug, Default)]
+pub struct Index {
+    pub sources: Vec<Source>,
+    /// Cache package data from the warehouse in this directory, so it's only downloaded again
+    /// when it's changed.
+    pub cache: Option<PathBuf>,
+    pub prereleases: PrereleasePolicy,
+    /// Constraints set in `[tool.pyflow.overrides]`, eg `urllib3 = "1.26.18"`. They replace the
+    /// ones the project, and its dependencies, require those packages with.
+    pub overrides: Vec<Req>,
+    /// Constraints from constraints files, eg `urllib3<2`. As with pip's, they limit the versions
+    /// of packages that are required, without requiring them.
+    pub constraints: Vec<Req>,
+    /// The proxy, timeouts and user agent to reach it with.
+    pub net: net::Settings,
+    /// The Python implementation we're resolving for, which decides which requirements with an
+    /// `implementation_name` marker apply.
+    pub implementation: util::Implementation,
+    /// Warnings silenced in `[tool.pyflow.warnings]`.
+    pub warnings: Vec<warnings::Suppression>,
+    pub yanked: Yanked,
+}
+
+impl Index {
+    /// The source a package comes from, unless it's PyPI.
+    pub fn source_for(&self, name: &str) -> Option<&Source> {
+        self.sources
+            .iter()
+            .find(|s| internal_prefix(name, &s.packages).is_some())
+    }
+
+    /// The URL of the index a package comes from, eg `https://pypi.org`.
+    pub fn url(&self, name: &str) -> String {
+        self.source_for(name).map_or_else(
+            || "https://pypi.org".to_owned(),
+            |s| s.url.trim_end_matches('/').to_owned(),
+        )
+    }
+
+    /// The URL of the simple API, per PEP 503, of the index a package comes from, as installers
+    /// like pip take it, eg `https://pypi.org/simple`.
+    pub fn simple_url(&self, name: &str) -> String {
+        format!("{}/simple", self.url(name))
+    }
+
+    /// The warehouse API's URL for a package, or one of its releases, on the index it comes
+    /// from.
+    pub fn warehouse_url(&self, name: &str, version: Option<&Version>) -> String {
+        let index = self.url(name);
+        match version {
+            Some(v) => format!("{}/pypi/{}/{}/json", index, name, v.to_string2()),
+            None => format!("{}/pypi/{}/json", index, name),
+        }
+    }
+
+    /// The key to cache a package's data from the warehouse under, eg `requests-2.31.0`. Those
+    /// from other sources are kept apart, in case PyPI has a package with the same name.
+    fn cache_key(&self, name: &str, version: Option<&Version>) -> String {
+        let mut result = util::standardize_name(name);
+        if let Some(source) = self.source_for(name) {
+            result = format!("{}--{}", util::standardize_name(&source.name), result);
+        }
+        if let Some(v) = version {
+            result = format!("{}-{}", result, v.to_string2());
+        }
+        result
+    }
+}
+
+/// Which packages may resolve to pre-releases. By default they don't, unless their
+/// constraints name one, eg `>=2.0.0b1`.
+#[derive(Clone, Debug, Default)]
+pub struct PrereleasePolicy {
+    /// From `--pre`.
+    pub allow: bool,
+    /// From `[tool.pyflow.prereleases]`, eg `black = true`. These take precedence over `allow`.
+    pub overrides: HashMap<String, bool>,
+}
+
+impl PrereleasePolicy {
+    fn allows(&self, req: &Req) -> bool {
+        if req.constraints.iter().any(|c| c.version.is_prerelease()) {
+            return true;
+        }
+        self.overrides
+            .iter()
+            .find(|(name, _)| util::compare_names(name, &req.name))
+            .map_or(self.allow, |(_, allow)| *allow)
+    }
+}
+
+/// Replace reqs' constraints with overrides', where set.
+fn apply_overrides(reqs: &[Req], overrides: &[Req]) -> Vec<Req> {
+    reqs.iter()
+        .map(|req| {
+            match overrides
+                .iter()
+                .find(|o| util::compare_names(&o.name, &req.name))
+            {
+                Some(o) => Req {
+                    constraints: o.constraints.clone(),
+                    ..req.clone()
+                },
+                None => req.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Add the constraints that apply to the target to reqs on the same packages, to be merged with
+/// theirs.
+fn apply_constraints(index: &Index, reqs: &[Req], os: util::Os, py_vers: &Version) -> Vec<Req> {
+    let marker_env = MarkerEnv::new(os, py_vers, index.implementation);
+    let mut result = reqs.to_vec();
+    for req in reqs {
+        for c in index.constraints.iter().filter(|c| {
+            util::compare_names(&c.name, &req.name)
+                && req_applies(c, os, py_vers, index.implementation, &marker_env)
+        }) {
+            result.push(Req {
+                constraints: c.constraints.clone(),
+                ..req.clone()
+            });
+        }
+    }
+    result
+}
+
+/// Versions that have been yanked, per PEP 592, and why, recorded as we get each package's
+/// versions, and those we've warned about. Clones of an `Index` share them.
+#[derive(Clone, Debug, Default)]
+pub struct Yanked {
+    /// By the package's `Index::cache_key`, so those from other sources are kept apart.
+    reasons: Arc<Mutex<HashMap<String, HashMap<Version, String>>>>,
+    /// So we only warn once for each.
+    warned: Arc<Mutex<HashSet<(String, Version)>>>,
+}
+
+/// If a release has been yanked, why: a release is yanked when all its files are.
+pub fn yank_reason(files: &[WarehouseRelease]) -> Option<String> {
+    if files.is_empty() || !files.iter().all(|f| f.yanked) {
+        return None;
+    }
+    Some(
+        files
+            .iter()
+            .find_map(|f| f.yanked_reason.clone().filter(|r| !r.is_empty()))
+            .unwrap_or_else(|| "no reason given".to_owned()),
+    )
+}
+
+fn record_yanked(index: &Index, name: &str, releases: &HashMap<String, Vec<WarehouseRelease>>) {
+    let yanked: HashMap<Version, String> = releases
+        .iter()
+        .filter_map(|(v, files)| Some((Version::from_str(v).ok()?, yank_reason(files)?)))
+        .collect();
+    if let Ok(mut y) = index.yanked.reasons.lock() {
+        y.insert(index.cache_key(name, None), yanked);
+    }
+}
+
+fn yanked_versions(index: &Index, name: &str) -> HashMap<Version, String> {
+    index
+        .yanked
+        .reasons
+        .lock()
+        .ok()
+        .and_then(|y| y.get(&index.cache_key(name, None)).cloned())
+        .unwrap_or_default()
+}
+
+/// Leave out yanked versions, unless they're the only match for an exact pin, eg `==2.0.1`, as
+/// PEP 592 describes.
+fn skip_yanked(
+    versions: Vec<Version>,
+    req: &Req,
+    yanked: &HashMap<Version, String>,
+) -> Vec<Version> {
+    let (kept, yanked_versions): (Vec<Version>, Vec<Version>) =
+        versions.into_iter().partition(|v| !yanked.contains_key(v));
+    let pinned = req.constraints.len() == 1 && req.constraints[0].type_ == ReqType::Exact;
+    if pinned && !kept.iter().any(|v| is_compat(&req.constraints, v)) {
+        return kept.into_iter().chain(yanked_versions).collect();
+    }
+    kept
+}
+
+/// Warn that a version's been yanked, with why, once per version.
+pub fn warn_yanked(index: &Index, name: &str, version: &Version, reason: &str) {
+    let key = (index.cache_key(name, None), *version);
+    match index.yanked.warned.lock() {
+        Ok(mut w) => {
+            if !w.insert(key) {
+                return;
+            }
+        }
+        Err(_) => return,
+    }
+    warnings::warn(
+        &index.warnings,
+        WarningCode::Yanked,
+        Some(name),
+        &format!(
+            "{} {} has been yanked from PyPI: {}",
+            name,
+            version.to_string2(),
+            reason
+        ),
+    );
+}
+
+/// Warn about resolved versions that have been yanked; they're only picked for exact pins.
+fn warn_yanked_resolved(index: &Index, resolved: &[Package]) {
+    for package in resolved {
+        if let Some(reason) = yanked_versions(index, &package.name).get(&package.version) {
+            warn_yanked(index, &package.name, &package.version, reason);
+        }
+    }
+}
+
+/// The requirements overrides broke: ones an overridden package's resolved version doesn't meet.
+/// Returns the package's name, and a description, for each.
+fn override_violations(
+    overrides: &[Req],
+    top_reqs: &[Req],
+    deps: &[Dependency],
+    resolved: &[Package],
+) -> Vec<(String, String)> {
+    let mut parents: Vec<(String, &[Req])> = vec![("pyproject.toml".into(), top_reqs)];
+    for dep in deps {
+        if resolved.iter().any(|p| p.id == dep.id) {
+            parents.push((
+                format!("{} {}", dep.name, dep.version.to_string2()),
+                &dep.reqs,
+            ));
+        }
+    }
+
+    let mut result = vec![];
+    for o in overrides {
+        let package = match resolved
+            .iter()
+            .find(|p| util::compare_names(&p.name, &o.name))
+        {
+            Some(p) => p,
+            None => continue,
+        };
+        for (parent, reqs) in &parents {
+            for req in reqs
+                .iter()
+                .filter(|r| util::compare_names(&r.name, &o.name))
+            {
+                if !is_compat(&req.constraints, &package.version) {
+                    let constraints: Vec<String> = req
+                        .constraints
+                        .iter()
+                        .map(|c| c.to_string2(false, true))
+                        .collect();
+                    result.push((
+                        package.name.clone(),
+                        format!(
+                            "{} {} is set in `[tool.pyflow.overrides]`, though {} requires {}",
+                            package.name,
+                            package.version.to_string2(),
+                            parent,
+                            constraints.join(", ")
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    result
+}
+
+/// The versions a req may resolve to. If a package only has pre-releases, we use them anyway,
+/// as pip does.
+fn allowed_versions(versions: Vec<Version>, allow_prereleases: bool) -> Vec<Version> {
+    if allow_prereleases || versions.iter().all(Version::is_prerelease) {
+        return versions;
+    }
+    versions
+        .into_iter()
+        .filter(|v| !v.is_prerelease())
+        .collect()
+}
+
+/// What we need to ask the server if a cached response is still current.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Fetch a URL, with the response cached under `key`. If we've cached it, the server's asked if
+/// it's changed, and if it hasn't (`304 Not Modified`), we use the cached copy.
+fn get_cached(index: &Index, url: &str, key: &str) -> Result<String, NetError> {
+    let read_problem = |e: reqwest::Error| NetError::new(url, &e);
+    let cache_dir = match &index.cache {
+        Some(d) => d,
+        None => return net::get(&index.net, url)?.text().map_err(read_problem),
+    };
+    let body_path = cache_dir.join(format!("{}.json", key));
+    let validators_path = cache_dir.join(format!("{}.toml", key));
+
+    let cached: Option<(String, Validators)> = match (
+        fs::read_to_string(&body_path),
+        fs::read_to_string(&validators_path),
+    ) {
+        (Ok(body), Ok(v)) => toml::from_str(&v).ok().map(|v| (body, v)),
+        _ => None,
+    };
+
+    let resp = net::send(&index.net, url, |client| {
+        let mut req = client.get(url);
+        if let Some((_, v)) = &cached {
+            if let Some(etag) = &v.etag {
+                req = req.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(modified) = &v.last_modified {
+                req = req.header(reqwest::header::IF_MODIFIED_SINCE, modified.as_str());
+            }
+        }
+        req
+    })?;
+
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        if let Some((body, _)) = cached {
+            return Ok(body);
+        }
+    }
+
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|h: &reqwest::header::HeaderValue| h.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    };
+    let mut resp = resp;
+    let body = resp.text().map_err(read_problem)?;
+
+    // Caching is best-effort; if we can't write, we'll just download it again next time.
+    if validators.etag.is_some() || validators.last_modified.is_some() {
+        if let Ok(v) = toml::to_string(&validators) {
+            fs::create_dir_all(cache_dir).ok();
+            if fs::write(&body_path, &body).is_ok() {
+                fs::write(&validators_path, v).ok();
+            }
+        }
+    }
+    Ok(body)
+}
+
 /// Fetch data about a package from the [Pypi Warehouse](https://warehouse.pypa.io/api-reference/json/).
-fn get_warehouse_data(name: &str) -> Result<WarehouseData, reqwest::Error> {
-    let url = format!("https://pypi.org/pypi/{}/json", name);
-    let resp = reqwest::get(&url)?.json()?;
-    Ok(resp)
+fn get_warehouse_data(index: &Index, name: &str) -> Result<WarehouseData, NetError> {
+    let url = index.warehouse_url(name, None);
+    let body = get_cached(index, &url, &index.cache_key(name, None))?;
+    match serde_json::from_str(&body) {
+        Ok(data) => Ok(data),
+        // Eg if the cached copy's corrupted; fetch it fresh.
+        Err(_) => net::get(&index.net, &url)?
+            .json()
+            .map_err(|e| NetError::new(&url, &e)),
+    }
+}
+
+/// Print the metadata we have for a release, as received: from the warehouse, and from the
+/// pydeps cache the resolver uses. For diagnosing unexpected resolution results.
+pub fn show_metadata(index: &Index, name: &str, version: Option<&Version>) {
+    let version = match version {
+        Some(v) => *v,
+        None => match get_version_info(index, name) {
+            Ok((_, v, _)) => v,
+            Err(_) => {
+                util::abort_with(
+                    util::ExitCode::Resolution,
+                    &format!("Can't find {} on PyPi", name),
+                );
+                unreachable!()
+            }
+        },
+    };
+
+    let url = index.warehouse_url(name, Some(&version));
+    let data: WarehouseVersionData = match net::get(&index.net, &url)
+        .and_then(|mut r| r.json().map_err(|e| NetError::new(&url, &e)))
+    {
+        Ok(d) => d,
+        Err(e) => {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem fetching the release's metadata: {}", e),
+            );
+            unreachable!()
+        }
+    };
+    let info = &data.info;
+
+    util::print_color(&format!("{} {}", info.name, info.version), Color::Cyan);
+    println!("Source: {}", url);
+    println!(
+        "requires-python: {}",
+        info.requires_python.as_deref().unwrap_or("(none)")
+    );
+    if info.yanked {
+        util::print_color(
+            &format!(
+                "Yanked: {}",
+                info.yanked_reason.as_deref().unwrap_or("(no reason given)")
+            ),
+            Color::DarkYellow,
+        );
+    }
+
+    println!("requires-dist:");
+    let requires_dist = info.requires_dist.clone().unwrap_or_default();
+    if requires_dist.is_empty() {
+        println!("  (none)");
+    }
+    for req in &requires_dist {
+        println!("  {}", req);
+    }
+
+    println!("Files:");
+    for rel in &data.urls {
+        println!(
+            "  {}{}{}\n    type: {}, python: {}, requires-python: {}{}\n    sha256: {}",
+            util::fg(Color::Cyan),
+            rel.filename,
+            util::fg(Color::Reset),
+            rel.packagetype,
+            rel.python_version,
+            rel.requires_python.as_deref().unwrap_or("(none)"),
+            if rel.yanked { ", yanked" } else { "" },
+            rel.digests.sha256.to_lowercase()
+        );
+    }
+
+    // This is what `guess_graph` resolves with, and may differ from the warehouse's data.
+    let mut query = HashMap::new();
+    query.insert(name.to_owned(), vec![version]);
+    println!("Resolver dependency data (pydeps):");
+    match get_req_cache_multiple(index, &query) {
+        Ok(caches) if !caches.is_empty() => {
+            for cache in caches {
+                println!(
+                    "  version: {}, requires-python: {}",
+                    cache.version,
+                    cache.requires_python.as_deref().unwrap_or("(none)")
+                );
+                for req in &cache.requires_dist {
+                    println!("    {}", req);
+                }
+            }
+        }
+        Ok(_) => println!("  (none found)"),
+        Err(e) => println!("  Problem fetching: {}", e),
+    }
 }
 
 /// Find the latest version of a package by querying the warehouse.  Also return
 /// a vec of the versions found, so we can reuse this later without fetching a second time.
 /// Return name to, so we get correct capitalization.
-pub fn get_version_info(name: &str) -> Result<(String, Version, Vec<Version>), DependencyError> {
-    let data = get_warehouse_data(name)?;
+pub fn get_version_info(
+    index: &Index,
+    name: &str,
+) -> Result<(String, Version, Vec<Version>), DependencyError> {
+    let data = get_warehouse_data(index, name)?;
+    record_yanked(index, name, &data.releases);
 
     let all_versions = data
         .releases
@@ -89,25 +607,170 @@ pub fn get_version_info(name: &str) -> Result<(String, Version, Vec<Version>), D
 }
 
 /// Get release data from the warehouse, ie the file url, name, and hash.
+/// The files of a release. It's empty if the release isn't on the warehouse, eg since it's been
+/// deleted.
 pub fn get_warehouse_release(
+    index: &Index,
     name: &str,
     version: &Version,
-) -> Result<Vec<WarehouseRelease>, reqwest::Error> {
-    let data = get_warehouse_data(name)?;
+) -> Result<Vec<WarehouseRelease>, NetError> {
+    let data = get_warehouse_data(index, name)?;
+    Ok(find_release(&data.releases, version)
+        .cloned()
+        .unwrap_or_default())
+}
 
+/// Find a version's files among the warehouse's releases.
+fn find_release<'a>(
+    releases: &'a HashMap<String, Vec<WarehouseRelease>>,
+    version: &Version,
+) -> Option<&'a Vec<WarehouseRelease>> {
     // If there are 0s in the version, and unable to find one, try 1 and 2 digit versions on Pypi.
-    let mut release_data = data.releases.get(&version.to_string2());
+    let mut release_data = releases.get(&version.to_string2());
     if release_data.is_none() && version.patch == 0 {
-        release_data = data.releases.get(&version.to_string_med());
+        release_data = releases.get(&version.to_string_med());
         if release_data.is_none() && version.minor == 0 {
-            release_data = data.releases.get(&version.to_string_short());
+            release_data = releases.get(&version.to_string_short());
+        }
+    }
+    release_data
+}
+
+/// Convert a date, eg `2019-05-16`, or a timestamp starting with one, to days since the Unix
+/// epoch.
+pub fn days_from_date(date: &str) -> Option<i64> {
+    let mut parts = date.get(..10)?.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // See http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// The date a release was uploaded, eg `2019-05-16`: that of its first file.
+fn upload_date(files: &[WarehouseRelease]) -> Option<String> {
+    files
+        .iter()
+        .filter_map(|f| f.upload_time.as_ref())
+        .filter_map(|t| t.get(..10))
+        .min()
+        .map(str::to_owned)
+}
+
+/// How current a locked package is, and if its version has known vulnerabilities.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Health {
+    pub name: String,
+    pub locked: Version,
+    pub latest: Version,
+    pub locked_uploaded: Option<String>, // Dates, eg `2019-05-16`.
+    pub latest_uploaded: Option<String>,
+    pub vulnerabilities: Vec<String>, // Advisory ids.
+}
+
+impl Health {
+    /// The number of days between the locked version's release and the latest one's.
+    pub fn days_behind(&self) -> Option<i64> {
+        if self.locked >= self.latest {
+            return Some(0);
         }
+        let locked = days_from_date(self.locked_uploaded.as_ref()?)?;
+        let latest = days_from_date(self.latest_uploaded.as_ref()?)?;
+        Some((latest - locked).max(0))
     }
 
-    let release_data = release_data
-        .unwrap_or_else(|| panic!("Unable to find a release for {} = \"{}\"", name, version));
+    /// The number of days since the latest version was released; a long time suggests the
+    /// package isn't maintained.
+    pub fn latest_age(&self, today: i64) -> Option<i64> {
+        Some((today - days_from_date(self.latest_uploaded.as_ref()?)?).max(0))
+    }
+}
+
+/// Today, in days since the Unix epoch.
+pub fn today() -> i64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64 / 86_400)
+}
+
+/// Order packages by which to upgrade first: those with vulnerabilities, then those furthest
+/// behind.
+pub fn sort_by_priority(health: &mut [Health]) {
+    health.sort_by(|a, b| {
+        b.vulnerabilities
+            .len()
+            .cmp(&a.vulnerabilities.len())
+            .then(b.days_behind().cmp(&a.days_behind()))
+            .then(a.name.cmp(&b.name))
+    });
+}
+
+/// Find how current a locked package is, from the warehouse.
+pub fn find_health(index: &Index, name: &str, locked: &Version) -> Result<Health, DependencyError> {
+    let data = get_warehouse_data(index, name)?;
+    let latest = Version::from_str(&data.info.version).unwrap_or_else(|_| {
+        data.releases
+            .keys()
+            .filter_map(|v| Version::from_str(v).ok())
+            .max()
+            .unwrap_or(*locked)
+    });
+    let release_date = |v: &Version| find_release(&data.releases, v).and_then(|f| upload_date(f));
+
+    let url = index.warehouse_url(name, Some(locked));
+    let key = index.cache_key(name, Some(locked));
+    let vulnerabilities = match get_cached(index, &url, &key)
+        .ok()
+        .and_then(|body| serde_json::from_str::<WarehouseVersionData>(&body).ok())
+    {
+        Some(d) => d.vulnerabilities.into_iter().map(|v| v.id).collect(),
+        None => vec![],
+    };
+
+    Ok(Health {
+        name: data.info.name.clone(),
+        locked: *locked,
+        latest,
+        locked_uploaded: release_date(locked),
+        latest_uploaded: release_date(&latest),
+        vulnerabilities,
+    })
+}
 
-    Ok(release_data.clone())
+/// What `[tool.pyflow.policy]` checks about a release from the warehouse: when it was uploaded,
+/// its licenses, and whether its files have published hashes.
+pub fn policy_facts(
+    index: &Index,
+    name: &str,
+    version: &Version,
+) -> Result<policy::Facts, DependencyError> {
+    let url = index.warehouse_url(name, Some(version));
+    let key = index.cache_key(name, Some(version));
+    let data: WarehouseVersionData = serde_json::from_str(&get_cached(index, &url, &key)?)
+        .map_err(|_| DependencyError::new(&format!("Problem reading {}", url)))?;
+
+    Ok(policy::Facts {
+        name: data.info.name.clone(),
+        version: version.to_string(),
+        source: url,
+        uploaded: upload_date(&data.urls),
+        licenses: policy::licenses(
+            data.info.license_expression.as_deref(),
+            data.info.license.as_deref(),
+            &data.info.classifiers,
+        ),
+        hashed: !data.urls.is_empty() && data.urls.iter().all(|f| !f.digests.sha256.is_empty()),
+    })
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -136,28 +799,92 @@ struct MultipleBody {
     packages: HashMap<String, Vec<String>>,
 }
 
+/// A release's requirements, from the warehouse of the source it comes from: pydeps only has
+/// those of packages on PyPI.
+fn get_source_req_cache(
+    index: &Index,
+    name: &str,
+    version: &Version,
+) -> Result<ReqCache, NetError> {
+    let url = index.warehouse_url(name, Some(version));
+    let body = get_cached(index, &url, &index.cache_key(name, Some(version)))?;
+    let info = match serde_json::from_str::<WarehouseVersionData>(&body) {
+        Ok(d) => d.info,
+        Err(_) => {
+            net::get(&index.net, &url)?
+                .json::<WarehouseVersionData>()
+                .map_err(|e| NetError::new(&url, &e))?
+                .info
+        }
+    };
+    Ok(ReqCache {
+        name: Some(info.name),
+        version: info.version,
+        requires_python: info.requires_python,
+        requires_dist: info.requires_dist.unwrap_or_default(),
+    })
+}
+
 /// Fetch items from multiple packages; cuts down on API calls.
 fn get_req_cache_multiple(
+    index: &Index,
     packages: &HashMap<String, Vec<Version>>,
-) -> Result<Vec<ReqCache>, reqwest::Error> {
+) -> Result<Vec<ReqCache>, NetError> {
     // input tuple is name, min version, max version.
     // parse strings here.
+    let mut from_sources = vec![];
     let mut packages2 = HashMap::new();
     for (name, versions) in packages.iter() {
+        if index.source_for(name).is_some() {
+            for version in versions {
+                from_sources.push(get_source_req_cache(index, name, version)?);
+            }
+            continue;
+        }
         let versions = versions.iter().map(Version::to_string2).collect();
         packages2.insert(name.to_owned(), versions);
     }
+    if packages2.is_empty() {
+        return Ok(from_sources);
+    }
 
     let url = "https://pydeps.herokuapp.com/multiple/";
     //                let url = "http://localhost:8000/multiple/";
 
-    Ok(reqwest::Client::new()
-        .post(url)
-        .json(&MultipleBody {
-            packages: packages2,
+    let body = MultipleBody {
+        packages: packages2,
+    };
+    let mut result: Vec<ReqCache> =
+        net::send(&index.net, url, |client| client.post(url).json(&body))?
+            .json()
+            .map_err(|e| NetError::new(url, &e))?;
+    result.append(&mut from_sources);
+    Ok(result)
+}
+
+/// Check a package's `requires_python`, eg `>=3.6, <4`, against a Python version. We can't parse
+/// every format used, eg `!=3.0.*`; assume those are compatible.
+pub fn supports_python(requires_python: Option<&str>, py_vers: &Version) -> bool {
+    match requires_python.map(Constraint::from_str_multiple) {
+        Some(Ok(constrs)) => is_compat(&constrs, py_vers),
+        _ => true,
+    }
+}
+
+/// Find the `internal_prefixes` entry a package name falls under, if any. Entries ending in
+/// `*` match by prefix, eg `acme-*`; others match the whole name.
+pub fn internal_prefix<'a>(name: &str, prefixes: &'a [String]) -> Option<&'a str> {
+    let name = util::standardize_name(name);
+    prefixes
+        .iter()
+        .find(|p| {
+            if p.ends_with('*') {
+                name.starts_with(&util::standardize_name(p.trim_end_matches('*')))
+            } else {
+                name == util::standardize_name(p)
+            }
         })
-        .send()?
-        .json()?)
+        .map(String::as_str)
 }
 
 /// Helper fn for `guess_graph`.
@@ -170,35 +897,93 @@ fn is_compat(constraints: &[Constraint], vers: &Version) -> bool {
     true
 }
 
+/// How many packages' versions we query the warehouse for at once.
+const CONCURRENT_QUERIES: usize = 8;
+
+/// Get version info for packages we don't have it for yet, several at once, so a slow index
+/// doesn't make each query wait for the last. Failures are left out of the cache, to be reported
+/// when they're queried again.
+fn prefetch_version_info(
+    index: &Index,
+    reqs: &[Req],
+    vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
+) {
+    let mut names: Vec<&str> = reqs
+        .iter()
+        .map(|r| r.name.as_str())
+        .filter(|n| !vers_cache.contains_key(*n))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    if names.len() < 2 {
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let found = Mutex::new(vec![]);
+    thread::scope(|s| {
+        for _ in 0..CONCURRENT_QUERIES.min(names.len()) {
+            s.spawn(|| {
+                while let Some(name) = names.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Ok(info) = get_version_info(index, name) {
+                        found.lock().unwrap().push(((*name).to_owned(), info));
+                    }
+                }
+            });
+        }
+    });
+    vers_cache.extend(found.into_inner().unwrap());
+}
+
 /// Pull data on pydeps for a req. Only pull what we need.
 /// todo: Group all reqs and pull with a single call to pydeps to improve speed?
 fn fetch_req_data(
+    index: &Index,
     reqs: &[Req],
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
 ) -> Result<Vec<ReqCache>, DependencyError> {
     // Narrow-down our list of versions to query.
 
+    prefetch_version_info(index, reqs, vers_cache);
     let mut query_data = HashMap::new();
     for req in reqs {
         // todo: cache version info; currently may get this multiple times.
         let (_, latest_version, all_versions) = match vers_cache.get(&req.name) {
             Some(c) => c.clone(),
             None => {
-                if let Ok(data) = get_version_info(&req.name) {
-                    vers_cache.insert(req.name.clone(), data.clone());
-                    data
-                } else {
-                    util::abort(&format!(
-                        "Can't get version info for the dependency `{}`. \
-                         Is it spelled correctly? Is the internet connection ok?",
-                        &req.name
-                    ));
-                    ("".to_string(), Version::new(0, 0, 0), vec![]) // match-compatibility placeholder
+                match get_version_info(index, &req.name) {
+                    Ok(data) => {
+                        vers_cache.insert(req.name.clone(), data.clone());
+                        data
+                    }
+                    Err(e) => {
+                        util::abort_with(
+                            util::ExitCode::Network,
+                            &format!(
+                                "Can't get version info for the dependency `{}`. \
+                                 Is it spelled correctly?\n{}",
+                                &req.name, e.details
+                            ),
+                        );
+                        ("".to_string(), Version::new(0, 0, 0), vec![]) // match-compatibility placeholder
+                    }
                 }
             }
         };
 
-        let mut max_v_to_query = latest_version;
+        let allow_prereleases = index.prereleases.allows(req);
+        let all_versions = skip_yanked(
+            allowed_versions(all_versions, allow_prereleases),
+            req,
+            &yanked_versions(index, &req.name),
+        );
+        // The warehouse's latest version is its latest full release, so it'd rule out newer
+        // pre-releases.
+        let mut max_v_to_query = if allow_prereleases {
+            all_versions.iter().copied().max().unwrap_or(latest_version)
+        } else {
+            latest_version
+        };
 
         // Find the maximum version compatible with the constraints.
         // todo: May need to factor in additional constraints here, and put
@@ -232,12 +1017,56 @@ fn fetch_req_data(
         return Ok(vec![]);
     }
 
-    Ok(get_req_cache_multiple(&query_data)?)
+    Ok(get_req_cache_multiple(index, &query_data)?)
+}
+
+/// Sometimes requirements are specified on separate lines; combine them if so, or we'll
+/// have problems resolving.
+fn merge_reqs(reqs: &[Req]) -> Vec<Req> {
+    let mut result: Vec<Req> = vec![];
+    for req in reqs {
+        match result.iter_mut().find(|r| r.name == req.name) {
+            Some(c) => {
+                c.constraints.extend(req.constraints.iter().cloned());
+                // If one is specified with an extra and the other without, keep
+                // the version without the extra. This is probably bad specification, but
+                // we have to work around it.
+                if req.extra.is_none() && c.extra.is_some() {
+                    c.extra = None
+                }
+                // todo: Should merge sys_platform, python_version, install_with_extras too.
+            }
+            None => result.push(req.clone()),
+        }
+    }
+    result
+}
+
+/// Whether a requirement applies to our platform and Python version, and to the extras its
+/// parent is installed with, in `marker_env`.
+fn req_applies(
+    req: &Req,
+    os: util::Os,
+    py_vers: &Version,
+    implementation: util::Implementation,
+    marker_env: &MarkerEnv,
+) -> bool {
+    req.extra
+        .as_ref()
+        .map_or(true, |ex| marker_env.extras.contains(ex))
+        && dep_types::platform_matches(req.sys_platform, os)
+        && req
+            .python_version
+            .as_ref()
+            .map_or(true, |v| v.is_compatible(py_vers))
+        && dep_types::implementation_matches(&req.implementation_name, implementation)
+        && req.marker.as_ref().map_or(true, |m| m.evaluate(marker_env))
 }
 
 // Build a graph: Start by assuming we can pick the newest compatible dependency at each step.
 // If unable to resolve this way, subsequently run this with additional deconfliction reqs.
 fn guess_graph(
+    index: &Index,
     parent_id: u32,
     reqs: &[Req],
     locked: &[crate::Package],
@@ -249,60 +1078,21 @@ fn guess_graph(
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
     reqs_searched: &mut Vec<Req>,
 ) -> Result<(), DependencyError> {
-    // Sometimes requirements are specified on separate lines; combine them if so, or we'll
-    // have problems resolving.
-
-    let mut cleaned_reqs: Vec<Req> = vec![];
-    for req in reqs {
-        let names: Vec<String> = cleaned_reqs.iter().map(|cr| cr.name.clone()).collect();
-        if names.contains(&req.name) {
-            for c in cleaned_reqs.iter_mut() {
-                if c.name == req.name {
-                    for constr in req.constraints.iter() {
-                        c.constraints.push(constr.clone());
-                    }
-                    // If one is specified with an extra and the other without, keep
-                    // the version without the extra. This is probably bad specification, but
-                    // we have to work around it.
-                    if req.extra.is_none() && c.extra.is_some() {
-                        c.extra = None
-                    }
-                    // todo: Should merge sys_platform, python_version, install_with_extras too.
-                }
-            }
-
-            continue;
-        }
-        cleaned_reqs.push(req.clone());
-    }
+    let cleaned_reqs = merge_reqs(&apply_constraints(
+        index,
+        &apply_overrides(reqs, &index.overrides),
+        os,
+        py_vers,
+    ));
 
+    let mut marker_env = MarkerEnv::new(os, py_vers, index.implementation);
+    marker_env.extras = extras.to_vec();
     let reqs: Vec<&Req> = cleaned_reqs
         .iter()
         // If we've already satisfied this req, don't query it again. Otherwise we'll make extra
         // http calls, and could end up in infinite loops.
         .filter(|r| !reqs_searched.contains(*r))
-        .filter(|r| match &r.extra {
-            Some(ex) => extras.contains(ex),
-            None => true,
-        })
-        .filter(|r| match r.sys_platform {
-            Some((rt, os_)) => match rt {
-                // A specified win32 req could apply to 64-bit windows too.
-                ReqType::Exact => {
-                    os_ == os || (os_ == util::Os::Windows32 && os == util::Os::Windows)
-                }
-                ReqType::Ne => os_ != os,
-                _ => {
-                    util::abort("Reqtypes for Os must be == or !=");
-                    unreachable!()
-                }
-            },
-            None => true,
-        })
-        .filter(|r| match &r.python_version {
-            Some(v) => v.is_compatible(py_vers),
-            None => true,
-        })
+        .filter(|r| req_applies(r, os, py_vers, index.implementation, &marker_env))
         .collect();
 
     let mut non_locked_reqs = vec![];
@@ -319,7 +1109,14 @@ fn guess_graph(
                 continue;
             }
 
-            if is_compat(&req.constraints, &package.version) {
+            // If it's newly required with an extra, we need the extra's dependencies too.
+            let extras_locked = req.install_with_extras.iter().flatten().all(|e| {
+                package
+                    .extras
+                    .iter()
+                    .any(|locked| util::compare_names(locked, e))
+            });
+            if is_compat(&req.constraints, &package.version) && extras_locked {
                 locked_reqs.push((*req).clone());
                 found_in_locked = true;
                 break;
@@ -331,19 +1128,23 @@ fn guess_graph(
     }
 
     // Single http call here to pydeps for all this package's reqs, plus version calls for each req.
-    let mut query_data = if let Ok(d) = fetch_req_data(&non_locked_reqs, vers_cache) {
-        d
-    } else {
-        util::abort(&format!(
-            "Aborting graph creation: Problem getting dependency data\n \
-             Reqs: {:#?}
-             It's taking a long time to get dependency data - this \
-             usually suggests that the dependency tree is being newly \
-             built. Please try again in a few minutes, and if the error \
-             still occurs, consider opening an issue on github.",
-            &reqs
-        ));
-        unreachable!()
+    let mut query_data = match fetch_req_data(index, &non_locked_reqs, vers_cache) {
+        Ok(d) => d,
+        Err(e) => {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!(
+                    "Aborting graph creation: Problem getting dependency data: {}\n \
+                 Reqs: {:#?}
+                 It's taking a long time to get dependency data - this \
+                 usually suggests that the dependency tree is being newly \
+                 built. Please try again in a few minutes, and if the error \
+                 still occurs, consider opening an issue on github.",
+                    e.details, &reqs
+                ),
+            );
+            unreachable!()
+        }
     };
 
     // Now add info from lock packs for data we didn't query. The purpose of passing locks
@@ -384,6 +1185,29 @@ fn guess_graph(
             .filter(|d| util::compare_names(d.name.as_ref().unwrap(), &req.name))
             .collect();
 
+        // Prefer releases that support our Python version. If none claim to, use the others
+        // anyway; `requires_python` is often missing or imprecise.
+        let py_compat: Vec<&ReqCache> = query_result
+            .iter()
+            .filter(|r| supports_python(r.requires_python.as_deref(), py_vers))
+            .copied()
+            .collect();
+        let query_result = if py_compat.is_empty() && !query_result.is_empty() {
+            warnings::warn(
+                &index.warnings,
+                WarningCode::NoPythonSupport,
+                Some(&req.name),
+                &format!(
+                    "No release of {} matching your constraints declares support for Python {}",
+                    req.name,
+                    py_vers.to_string_no_patch()
+                ),
+            );
+            query_result
+        } else {
+            py_compat
+        };
+
         let deps: Vec<Dependency> = query_result
             .into_iter()
             // Our query data should already be compat, but QC here.
@@ -403,7 +1227,10 @@ fn guess_graph(
             .collect();
 
         if deps.is_empty() {
-            util::abort(&format!("Can't find a compatible package for {:?}", &req));
+            util::abort_with(
+                util::ExitCode::Resolution,
+                &format!("Can't find a compatible package for {:?}", &req),
+            );
         }
 
         let newest_compat = deps
@@ -414,6 +1241,7 @@ fn guess_graph(
         result.push(newest_compat.clone());
 
         if let Err(e) = guess_graph(
+            index,
             newest_compat.id,
             &newest_compat.reqs,
             locked,
@@ -426,13 +1254,14 @@ fn guess_graph(
             reqs_searched,
         ) {
             println!("Problem pulling dependency info for {}", &req.name);
-            util::abort(&e.details)
+            util::abort_with(util::ExitCode::Resolution, &e.details)
         }
     }
     Ok(())
 }
 
 fn find_constraints(
+    overrides: &[Req],
     all_reqs: &[Req],
     all_deps: &[Dependency],
     relevant_deps: &[Dependency],
@@ -452,9 +1281,7 @@ fn find_constraints(
             },
         };
 
-        for req in parent
-            .clone()
-            .reqs
+        for req in apply_overrides(&parent.reqs, overrides)
             .iter()
             .filter(|r| util::compare_names(&r.name, &dep.name))
         {
@@ -464,21 +1291,79 @@ fn find_constraints(
     result
 }
 
-/// We've determined we need to add all the included packages, and renamed all but one.
-fn make_renamed_packs(
-    _vers_cache: &HashMap<String, (String, Version, Vec<Version>)>,
-    deps: &[Dependency],
-    //    all_deps: &[Dependency],
+/// Find the environment markers a package is required with. If multiple parents require it with
+/// different markers, or any without, we don't restrict it.
+fn find_markers(all_reqs: &[Req], all_deps: &[Dependency], name: &str) -> Option<String> {
+    let mut markers = vec![];
+    for dep in all_deps
+        .iter()
+        .filter(|d| util::compare_names(&d.name, name))
+    {
+        let parent_reqs = match all_deps.iter().find(|d| d.id == dep.parent) {
+            Some(p) => &p.reqs,
+            None => all_reqs, // ie top-level
+        };
+        for req in parent_reqs
+            .iter()
+            .filter(|r| util::compare_names(&r.name, name))
+        {
+            markers.push(req.markers());
+        }
+    }
+
+    match markers.first() {
+        Some(first) if markers.iter().all(|m| m == first) => first.clone(),
+        _ => None,
+    }
+}
+
+/// Find the extras a package is installed with: those any of its parents, or `pyproject.toml`,
+/// require it with. Eg `socks`, for `requests[socks]`.
+fn find_extras(all_reqs: &[Req], all_deps: &[Dependency], name: &str) -> Vec<String> {
+    let mut result = vec![];
+    for dep in all_deps
+        .iter()
+        .filter(|d| util::compare_names(&d.name, name))
+    {
+        let parent_reqs = match all_deps.iter().find(|d| d.id == dep.parent) {
+            Some(p) => &p.reqs,
+            None => all_reqs, // ie top-level
+        };
+        for req in parent_reqs
+            .iter()
+            .filter(|r| util::compare_names(&r.name, name))
+        {
+            result.extend(
+                req.install_with_extras
+                    .iter()
+                    .flatten()
+                    .map(|e| e.to_lowercase()),
+            );
+        }
+    }
+    result.sort();
+    result.dedup();
+    result
+}
+
+/// We've determined we need to add all the included packages, and renamed all but one.
+fn make_renamed_packs(
+    index: &Index,
+    _vers_cache: &HashMap<String, (String, Version, Vec<Version>)>,
+    deps: &[Dependency],
+    //    all_deps: &[Dependency],
     name: &str,
 ) -> Vec<Package> {
-    util::print_color(
+    warnings::warn(
+        &index.warnings,
+        WarningCode::MultipleVersions,
+        Some(name),
         &format!(
             "Installing multiple versions for {}. If this package uses \
              compiled code or importlib, this may fail when importing. Note that \
              your package may not be published unless this is resolved...",
             name
         ),
-        Color::DarkYellow,
     );
 
     let dep_display: Vec<String> = deps
@@ -509,6 +1394,8 @@ fn make_renamed_packs(
             version: dep.version,
             deps: vec![], // to be filled in after resolution
             rename,
+            markers: None,
+            extras: vec![],
         });
     }
     result
@@ -540,21 +1427,467 @@ fn assign_subdeps(packages: &mut Vec<Package>, updated_ids: &HashMap<u32, u32>)
     }
 }
 
-/// Determine which dependencies we need to install, using the newest ones which meet
-/// all constraints. Gets data from a cached repo, and Pypi. Returns name, version, and name/version of its deps.
+/// Split a solver package into its name and extra, eg `requests[socks]`.
+fn split_extra(package: &str) -> (&str, Option<&str>) {
+    match package.strip_suffix(']').and_then(|p| p.split_once('[')) {
+        Some((name, extra)) => (name, Some(extra)),
+        None => (package, None),
+    }
+}
+
+/// Packages' dependencies for the solver, from the warehouse and pydeps, or the lock. Packages
+/// are identified by their standardized names. An extra, eg `requests[socks]`, is a package of
+/// its own, which depends on the same version of `requests`, and the extra's dependencies.
+struct IndexProvider<'a> {
+    index: &'a Index,
+    reqs: &'a [Req],
+    locked: &'a [Package],
+    /// Use locked versions where they're still compatible, with their locked dependencies, so
+    /// we don't query for them.
+    pin_locked: bool,
+    os: util::Os,
+    py_vers: Version,
+    vers_cache: HashMap<String, (String, Version, Vec<Version>)>,
+    /// Versions' published requirements, or `None` if pydeps doesn't have them.
+    metadata: HashMap<(String, Version), Option<ReqCache>>,
+    /// The requirements of versions we've given dependencies for, and the packages they depend on.
+    used: HashMap<(String, Version), (Vec<Req>, Vec<String>)>,
+    /// Names as the warehouse formats them, eg `IPython`.
+    names: HashMap<String, String>,
+}
+
+impl<'a> IndexProvider<'a> {
+    fn new(
+        index: &'a Index,
+        reqs: &'a [Req],
+        locked: &'a [Package],
+        pin_locked: bool,
+        os: util::Os,
+        py_vers: &Version,
+    ) -> Self {
+        Self {
+            index,
+            reqs,
+            locked,
+            pin_locked,
+            os,
+            py_vers: *py_vers,
+            vers_cache: HashMap::new(),
+            metadata: HashMap::new(),
+            used: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    fn locked_package(&self, name: &str) -> Option<&'a Package> {
+        self.locked
+            .iter()
+            .find(|p| util::compare_names(&p.name, name) && matches!(p.rename, Rename::No))
+    }
+
+    /// A locked version we can use for a requirement without querying, when pinning them.
+    fn pinned(&self, req: &Req) -> Option<&'a Package> {
+        self.locked_package(&req.name)
+            .filter(|p| self.pin_locked && is_compat(&req.constraints, &p.version))
+    }
+
+    fn version_info(&mut self, name: &str) -> (String, Version, Vec<Version>) {
+        if let Some(info) = self.vers_cache.get(name) {
+            return info.clone();
+        }
+        match get_version_info(self.index, name) {
+            Ok(info) => {
+                self.vers_cache.insert(name.to_owned(), info.clone());
+                info
+            }
+            Err(e) => {
+                util::abort_with(
+                    util::ExitCode::Network,
+                    &format!(
+                        "Can't get version info for the dependency `{}`. \
+                         Is it spelled correctly?\n{}",
+                        name, e.details
+                    ),
+                );
+                unreachable!()
+            }
+        }
+    }
+
+    /// The versions that meet a requirement.
+    fn allowed(&mut self, req: &Req) -> BTreeSet<Version> {
+        let std_name = util::standardize_name(&req.name);
+        if let Some(package) = self.pinned(req) {
+            self.names.insert(std_name, package.name.clone());
+            return vec![package.version].into_iter().collect();
+        }
+        let (name, _, all_versions) = self.version_info(&req.name);
+        self.names.insert(std_name, name);
+        let versions = allowed_versions(all_versions, self.index.prereleases.allows(req));
+        skip_yanked(versions, req, &yanked_versions(self.index, &req.name))
+            .into_iter()
+            .filter(|v| is_compat(&req.constraints, v))
+            .collect()
+    }
+
+    /// Get published requirements from pydeps, in one query.
+    fn fetch_metadata(&mut self, wanted: &[(String, Version)]) {
+        let mut query: HashMap<String, Vec<Version>> = HashMap::new();
+        for (package, version) in wanted {
+            let name = self.names.get(package).unwrap_or(package).clone();
+            query.entry(name).or_default().push(*version);
+        }
+        let found = match get_req_cache_multiple(self.index, &query) {
+            Ok(f) => f,
+            Err(e) => {
+                util::abort_with(
+                    util::ExitCode::Network,
+                    &format!(
+                        "Problem getting dependency data: {}\n\
+                         It's taking a long time to get dependency data - this usually suggests \
+                         that the dependency tree is being newly built. Please try again in a few \
+                         minutes, and if the error still occurs, consider opening an issue on github.",
+                        e
+                    ),
+                );
+                unreachable!()
+            }
+        };
+        for key in wanted {
+            let data = found
+                .iter()
+                .find(|r| {
+                    r.name
+                        .as_ref()
+                        .map_or(false, |n| util::compare_names(n, &key.0))
+                        && Version::from_str(&r.version).ok() == Some(key.1)
+                })
+                .cloned();
+            self.metadata.insert(key.clone(), data);
+        }
+    }
+
+    /// A version's requirements: its locked dependencies, pinned, or the ones it publishes.
+    fn published_reqs(
+        &mut self,
+        package: &str,
+        version: &Version,
+        extra: Option<&str>,
+    ) -> Result<Vec<Req>, String> {
+        if let Some(locked) = self.locked_package(package) {
+            let extras_locked = extra.map_or(true, |e| locked.extras.iter().any(|l| l == e));
+            if self.pin_locked && locked.version == *version && extras_locked {
+                // An extra's dependencies are locked with the package's.
+                if extra.is_some() {
+                    return Ok(vec![]);
+                }
+                return Ok(locked
+                    .deps
+                    .iter()
+                    .filter_map(|(_, name, vers)| {
+                        Req::from_str(&format!("{} (=={})", name, vers.to_string2()), true).ok()
+                    })
+                    .collect());
+            }
+        }
+
+        let key = (package.to_owned(), *version);
+        if !self.metadata.contains_key(&key) {
+            self.fetch_metadata(std::slice::from_ref(&key));
+        }
+        match &self.metadata[&key] {
+            None => Err("pydeps has no dependency data for it".into()),
+            Some(data) if !supports_python(data.requires_python.as_deref(), &self.py_vers) => {
+                Err(format!(
+                    "it requires Python {}",
+                    data.requires_python.as_deref().unwrap_or_default()
+                ))
+            }
+            Some(data) => Ok(data.reqs()),
+        }
+    }
+
+    /// Get the published requirements of the versions we're likely to pick next, in one query,
+    /// rather than one for each as the solver asks.
+    fn prefetch_likely(&mut self, deps: &[solver::Dep]) {
+        let mut wanted = vec![];
+        for dep in deps.iter().filter(|d| split_extra(&d.package).1.is_none()) {
+            let likely = match self.preferred(&dep.package) {
+                Some(v) if dep.versions.contains(&v) => v,
+                _ => match dep.versions.iter().next_back() {
+                    Some(v) => *v,
+                    None => continue,
+                },
+            };
+            let locked = self
+                .locked_package(&dep.package)
+                .map_or(false, |p| self.pin_locked && p.version == likely);
+            let key = (dep.package.clone(), likely);
+            if !locked && !self.metadata.contains_key(&key) && !wanted.contains(&key) {
+                wanted.push(key);
+            }
+        }
+        if wanted.len() > 1 {
+            self.fetch_metadata(&wanted);
+        }
+    }
+
+    /// Packages to install, from the solver's solution, and an edge from each parent to each
+    /// of its dependencies, for finding markers and extras.
+    fn packages(&self, solution: &[(String, Version)]) -> (Vec<Package>, Vec<Dependency>) {
+        let chosen: Vec<&(String, Version)> = solution
+            .iter()
+            .filter(|(p, _)| split_extra(p).1.is_none())
+            .collect();
+        let id_of = |package: &str| {
+            chosen
+                .iter()
+                .position(|(p, _)| p == split_extra(package).0)
+                .map(|i| i as u32 + 1)
+        };
+        let mut packages: Vec<Package> = chosen
+            .iter()
+            .enumerate()
+            .map(|(i, (p, v))| Package {
+                id: i as u32 + 1,
+                parent: 0,
+                name: self.display(p),
+                version: *v,
+                deps: vec![],
+                rename: Rename::No,
+                markers: None,
+                extras: vec![],
+            })
+            .collect();
+
+        let root = (solver::ROOT.to_owned(), Version::new(0, 0, 0));
+        let mut edges = vec![];
+        for (package, version) in std::iter::once(&root).chain(solution.iter()) {
+            let parent = id_of(package).unwrap_or(0);
+            let children = match self.used.get(&(package.clone(), *version)) {
+                Some((_, c)) => c,
+                None => continue,
+            };
+            for child in children.iter().filter_map(|c| id_of(c)) {
+                if child != parent && !edges.contains(&(parent, child)) {
+                    edges.push((parent, child));
+                }
+            }
+        }
+
+        let mut all_deps = vec![];
+        for (parent, child) in edges {
+            let (name, version) = {
+                let p = &packages[child as usize - 1];
+                (p.name.clone(), p.version)
+            };
+            if packages[child as usize - 1].parent == 0 && parent != 0 {
+                packages[child as usize - 1].parent = parent;
+            }
+            if parent != 0 {
+                packages[parent as usize - 1]
+                    .deps
+                    .push((child, name.clone(), version));
+            }
+            let package = &chosen[child as usize - 1].0;
+            all_deps.push(Dependency {
+                id: child,
+                name,
+                version,
+                reqs: self
+                    .used
+                    .get(&(package.clone(), version))
+                    .map(|(r, _)| r.clone())
+                    .unwrap_or_default(),
+                parent,
+            });
+        }
+
+        for package in &mut packages {
+            package.markers = find_markers(self.reqs, &all_deps, &package.name);
+            package.extras = find_extras(self.reqs, &all_deps, &package.name);
+        }
+        (packages, all_deps)
+    }
+}
+
+impl solver::Provider for IndexProvider<'_> {
+    fn dependencies(
+        &mut self,
+        package: &str,
+        version: &Version,
+    ) -> Result<Vec<solver::Dep>, String> {
+        let (name, extra) = split_extra(package);
+        let published = if package == solver::ROOT {
+            self.reqs.to_vec()
+        } else {
+            self.published_reqs(name, version, extra)?
+        };
+
+        let mut marker_env = MarkerEnv::new(self.os, &self.py_vers, self.index.implementation);
+        marker_env.extras = extra.map(|e| vec![e.to_owned()]).unwrap_or_default();
+        let constrained = apply_constraints(
+            self.index,
+            &apply_overrides(&published, &self.index.overrides),
+            self.os,
+            &self.py_vers,
+        );
+        let applicable: Vec<Req> = constrained
+            .into_iter()
+            .filter(|r| {
+                req_applies(
+                    r,
+                    self.os,
+                    &self.py_vers,
+                    self.index.implementation,
+                    &marker_env,
+                )
+            })
+            // An extra's package only adds the extra's dependencies to the package's.
+            .filter(|r| extra.is_none() || r.extra.is_some())
+            .collect();
+        let reqs = merge_reqs(&applicable);
+
+        let to_query: Vec<Req> = reqs
+            .iter()
+            .filter(|r| self.pinned(r).is_none())
+            .cloned()
+            .collect();
+        prefetch_version_info(self.index, &to_query, &mut self.vers_cache);
+
+        let mut result = vec![];
+        if extra.is_some() {
+            result.push(solver::Dep {
+                package: name.to_owned(),
+                versions: vec![*version].into_iter().collect(),
+                constraints: format!("=={}", version.to_string2()),
+            });
+        }
+        for req in &reqs {
+            let versions = self.allowed(req);
+            let constraints = if req.constraints.is_empty() {
+                "(any version)".to_owned()
+            } else {
+                req.constraints
+                    .iter()
+                    .map(|c| c.to_string2(false, false))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            let std_name = util::standardize_name(&req.name);
+            for extra in req.install_with_extras.iter().flatten() {
+                result.push(solver::Dep {
+                    package: format!("{}[{}]", std_name, extra.to_lowercase()),
+                    versions: versions.clone(),
+                    constraints: constraints.clone(),
+                });
+            }
+            result.push(solver::Dep {
+                package: std_name,
+                versions,
+                constraints,
+            });
+        }
+
+        self.prefetch_likely(&result);
+        self.used.insert(
+            (package.to_owned(), *version),
+            (
+                published,
+                result.iter().map(|d| d.package.clone()).collect(),
+            ),
+        );
+        Ok(result)
+    }
+
+    fn preferred(&self, package: &str) -> Option<Version> {
+        self.locked_package(split_extra(package).0)
+            .map(|p| p.version)
+    }
+
+    fn display(&self, package: &str) -> String {
+        let (name, extra) = split_extra(package);
+        let name = self.names.get(name).map_or(name, String::as_str);
+        match extra {
+            Some(e) => format!("{}[{}]", name, e),
+            None => name.to_owned(),
+        }
+    }
+}
+
+fn warn_override_violations(
+    index: &Index,
+    reqs: &[Req],
+    deps: &[Dependency],
+    resolved: &[Package],
+) {
+    for (name, message) in override_violations(&index.overrides, reqs, deps, resolved) {
+        warnings::warn(
+            &index.warnings,
+            WarningCode::OverrideConflict,
+            Some(&name),
+            &message,
+        );
+    }
+}
+
+/// Determine which dependencies we need to install, using the newest ones which meet all
+/// constraints, with a backtracking solver. Locked versions are kept where they're still
+/// compatible. If there's no solution, we explain why, and install multiple versions of packages
+/// whose requirements conflict. Returns packages, with their dependencies.
 pub fn resolve(
+    index: &Index,
     reqs: &[Req],
     locked: &[crate::Package],
     os: util::Os,
     py_vers: &Version,
-    //) -> Result<Vec<(String, Version, Vec<Req>)>, reqwest::Error> {
-) -> Result<Vec<crate::Package>, reqwest::Error> {
+) -> Result<Vec<crate::Package>, NetError> {
+    let mut report = String::new();
+    // First try keeping every locked version we can, without querying for them.
+    for pin_locked in [true, false] {
+        if pin_locked && locked.is_empty() {
+            continue;
+        }
+        let mut provider = IndexProvider::new(index, reqs, locked, pin_locked, os, py_vers);
+        match solver::solve(&mut provider) {
+            Ok(solution) => {
+                let (packages, deps) = provider.packages(&solution);
+                warn_override_violations(index, reqs, &deps, &packages);
+                warn_yanked_resolved(index, &packages);
+                return Ok(packages);
+            }
+            Err(r) => report = r,
+        }
+    }
+
+    util::print_color(
+        &format!(
+            "No set of versions meets every requirement:\n{}\n\
+             Installing multiple versions of conflicting packages instead.",
+            report
+        ),
+        Color::DarkYellow,
+    );
+    resolve_with_renames(index, reqs, locked, os, py_vers)
+}
+
+/// Resolve by picking the newest compatible version for each requirement, then installing
+/// multiple versions of packages that are required with conflicting constraints, renamed.
+/// Gets data from a cached repo, and Pypi.
+fn resolve_with_renames(
+    index: &Index,
+    reqs: &[Req],
+    locked: &[crate::Package],
+    os: util::Os,
+    py_vers: &Version,
+) -> Result<Vec<crate::Package>, NetError> {
     let mut result = Vec::new();
     let mut cache = HashMap::new();
     let mut reqs_searched = Vec::new();
 
     let mut version_cache = HashMap::new();
     if guess_graph(
+        index,
         0,
         reqs,
         locked,
@@ -568,7 +1901,7 @@ pub fn resolve(
     )
     .is_err()
     {
-        util::abort("Problem resolving dependencies");
+        util::abort_with(util::ExitCode::Resolution, "Problem resolving dependencies");
     }
 
     let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
@@ -606,17 +1939,20 @@ pub fn resolve(
                     version: dep.version,
                     deps: vec![], // to be filled in after resolution
                     rename: Rename::No,
+                    markers: None,
+                    extras: vec![],
                 });
             }
             x if x > 1 => {
                 // Find what constraints are driving each dep that shares a name.
-                let constraints = find_constraints(reqs, &result, deps);
+                let constraints = find_constraints(&index.overrides, reqs, &result, deps);
 
                 let _names: Vec<String> = deps.iter().map(|d| d.version.to_string()).collect();
                 let inter = dep_types::intersection_many(&constraints);
 
                 if inter.is_empty() {
                     result_cleaned.append(&mut make_renamed_packs(
+                        index,
                         &version_cache,
                         deps,
                         &fmtd_name,
@@ -653,6 +1989,8 @@ pub fn resolve(
                         version: best.version,
                         deps: vec![], // to be filled in after resolution
                         rename: Rename::No,
+                        markers: None,
+                        extras: vec![],
                     });
 
                     // Indicate we need to update the parent. We can't do it here, since
@@ -674,6 +2012,7 @@ pub fn resolve(
 
                     if versions.is_empty() {
                         result_cleaned.append(&mut make_renamed_packs(
+                            index,
                             &version_cache,
                             deps,
                             //                            &result,
@@ -714,6 +2053,8 @@ pub fn resolve(
                         version: newest_unresolved.version,
                         deps: vec![], // to be filled in after resolution
                         rename: Rename::No,
+                        markers: None,
+                        extras: vec![],
                     });
 
                     // todo: Do a check on newest_unresolved! If fails, execute renamed plan
@@ -731,6 +2072,16 @@ pub fn resolve(
     // Now, assign subdeps, so we can store them in the lock.
     assign_subdeps(&mut result_cleaned, &updated_ids);
 
+    // Record which environment markers each package was required with, so exports for other
+    // platforms can leave out ones they don't need.
+    for package in &mut result_cleaned {
+        package.markers = find_markers(reqs, &result, &package.name);
+        package.extras = find_extras(reqs, &result, &package.name);
+    }
+
+    warn_override_violations(index, reqs, &result, &result_cleaned);
+    warn_yanked_resolved(index, &result_cleaned);
+
     let mut a = result;
     for b in &mut a {
         b.reqs = vec![];
@@ -739,16 +2090,595 @@ pub fn resolve(
     Ok(result_cleaned)
 }
 
+/// How long to use the cached list of package names before fetching a new one.
+const PACKAGE_NAMES_MAX_AGE: time::Duration = time::Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Deserialize)]
+struct SimpleProject {
+    name: String,
+}
+
+/// The package list from the [Simple API](https://warehouse.pypa.io/api-reference/legacy.html),
+/// in its [JSON form](https://www.python.org/dev/peps/pep-0691/).
+#[derive(Debug, Deserialize)]
+struct SimpleIndex {
+    projects: Vec<SimpleProject>,
+}
+
+/// Fetch the names of all packages on `PyPi`.
+fn fetch_package_names(settings: &net::Settings) -> Result<Vec<String>, NetError> {
+    let url = "https://pypi.org/simple/";
+    let index: SimpleIndex = net::send(settings, url, |client| {
+        client.get(url).header(
+            reqwest::header::ACCEPT,
+            "application/vnd.pypi.simple.v1+json",
+        )
+    })?
+    .json()
+    .map_err(|e| NetError::new(url, &e))?;
+
+    Ok(index.projects.into_iter().map(|p| p.name).collect())
+}
+
+/// Load the names of all packages on `PyPi` from a cache file, refreshing it if it's more than
+/// a day old. If we can't refresh it, eg when offline, use the stale list.
+pub fn cached_package_names(settings: &net::Settings, cache_path: &Path) -> Vec<String> {
+    let is_fresh = fs::metadata(cache_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|age| age < PACKAGE_NAMES_MAX_AGE)
+        .unwrap_or(false);
+
+    if !is_fresh {
+        if let Ok(names) = fetch_package_names(settings) {
+            // Completion still works if we can't write the cache; it's just slower next time.
+            fs::write(cache_path, names.join("\n")).ok();
+            return names;
+        }
+    }
+
+    match fs::read_to_string(cache_path) {
+        Ok(data) => data.lines().map(ToOwned::to_owned).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Find package names starting with `prefix`, ignoring differences in case, `-`, `_`, and `.`.
+pub fn complete_package_name<'a>(names: &'a [String], prefix: &str) -> Vec<&'a String> {
+    let prefix = util::standardize_name(prefix);
+
+    let mut result: Vec<&String> = names
+        .iter()
+        .filter(|n| util::standardize_name(n).starts_with(&prefix))
+        .collect();
+    result.sort_by_key(|n| (n.len(), n.to_lowercase()));
+    result
+}
+
+/// Widely-used packages, which are common targets for typosquatting.
+const POPULAR_PACKAGES: &[&str] = &[
+    "aiohttp",
+    "attrs",
+    "beautifulsoup4",
+    "boto3",
+    "botocore",
+    "certifi",
+    "cffi",
+    "chardet",
+    "charset-normalizer",
+    "click",
+    "colorama",
+    "cryptography",
+    "cython",
+    "dataclasses",
+    "decorator",
+    "django",
+    "docutils",
+    "filelock",
+    "flask",
+    "fsspec",
+    "google-auth",
+    "grpcio",
+    "idna",
+    "importlib-metadata",
+    "jinja2",
+    "jmespath",
+    "jsonschema",
+    "lxml",
+    "markupsafe",
+    "matplotlib",
+    "mock",
+    "numpy",
+    "openpyxl",
+    "packaging",
+    "pandas",
+    "paramiko",
+    "pillow",
+    "pip",
+    "platformdirs",
+    "pluggy",
+    "protobuf",
+    "psutil",
+    "psycopg2",
+    "pyasn1",
+    "pycparser",
+    "pydantic",
+    "pygments",
+    "pyjwt",
+    "pyparsing",
+    "pytest",
+    "python-dateutil",
+    "pytz",
+    "pyyaml",
+    "redis",
+    "requests",
+    "rsa",
+    "s3transfer",
+    "scikit-learn",
+    "scipy",
+    "setuptools",
+    "simplejson",
+    "six",
+    "sqlalchemy",
+    "tensorflow",
+    "toml",
+    "tomli",
+    "torch",
+    "tqdm",
+    "typing-extensions",
+    "tzdata",
+    "urllib3",
+    "virtualenv",
+    "websocket-client",
+    "werkzeug",
+    "wheel",
+    "wrapt",
+    "zipp",
+];
+
+/// Edit distance between two names, counting a swap of adjacent letters as one edit, since
+/// that's a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for j in 0..=b.len() {
+        dist[0][j] = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            dist[i][j] = min(
+                min(dist[i - 1][j] + 1, dist[i][j - 1] + 1),
+                dist[i - 1][j - 1] + cost,
+            );
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = min(dist[i][j], dist[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
+/// Find a well-known package, or one of `existing`, that `name` is one typo away from. Returns
+/// `None` if `name` is itself one of them. Short names are skipped, since many legitimate
+/// packages are a letter apart, eg `six` and `sip`.
+pub fn find_typosquat_target<'a>(name: &str, existing: &'a [String]) -> Option<&'a str> {
+    let std_name = util::standardize_name(name);
+    if std_name.len() < 4 {
+        return None;
+    }
+
+    let known: Vec<&str> = POPULAR_PACKAGES
+        .iter()
+        .copied()
+        .chain(existing.iter().map(String::as_str))
+        .collect();
+    if known.iter().any(|k| util::standardize_name(k) == std_name) {
+        return None;
+    }
+
+    known
+        .into_iter()
+        .find(|k| edit_distance(&util::standardize_name(k), &std_name) == 1)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn extras_from_parents() {
+        let top = |name: &str, extras: Option<Vec<&str>>| Req {
+            install_with_extras: extras.map(|e| e.into_iter().map(str::to_owned).collect()),
+            ..Req::new(name.into(), vec![])
+        };
+        let dep = |id, name: &str, parent, reqs| Dependency {
+            id,
+            name: name.into(),
+            version: Version::new(1, 0, 0),
+            reqs,
+            parent,
+        };
+        let all_reqs = vec![top("requests", Some(vec!["socks"])), top("uvicorn", None)];
+        let all_deps = vec![
+            dep(1, "requests", 0, vec![]),
+            dep(
+                2,
+                "uvicorn",
+                0,
+                vec![top("Requests", Some(vec!["Security"]))],
+            ),
+            dep(3, "requests", 2, vec![]),
+        ];
+
+        assert_eq!(
+            find_extras(&all_reqs, &all_deps, "requests"),
+            vec!["security", "socks"]
+        );
+        assert!(find_extras(&all_reqs, &all_deps, "uvicorn").is_empty());
+    }
+
+    #[test]
+    fn typosquats() {
+        let existing: Vec<String> = vec!["acme-utils".into()];
+        assert_eq!(
+            find_typosquat_target("reqeusts", &existing),
+            Some("requests")
+        );
+        assert_eq!(find_typosquat_target("nunpy", &existing), Some("numpy"));
+        assert_eq!(
+            find_typosquat_target("acme-utlis", &existing),
+            Some("acme-utils")
+        );
+        assert_eq!(find_typosquat_target("Requests", &existing), None);
+        assert_eq!(find_typosquat_target("python_dateutil", &existing), None);
+        assert_eq!(find_typosquat_target("sip", &existing), None);
+        assert_eq!(find_typosquat_target("httpx", &existing), None);
+    }
+
+    #[test]
+    fn python_support() {
+        let py38 = Version::new(3, 8, 0);
+        assert!(supports_python(None, &py38));
+        assert!(supports_python(Some(">=3.6, <3.13"), &py38));
+        assert!(!supports_python(Some(">=3.9"), &py38));
+        assert!(!supports_python(Some("<3.8"), &py38));
+        // Wildcards aren't supported; don't reject on them.
+        assert!(supports_python(Some(">=2.7, !=3.0.*"), &py38));
+    }
+
+    #[test]
+    fn prerelease_policy() {
+        let vers = |s: &str| Version::from_str(s).unwrap();
+        let versions = vec![vers("1.9.0"), vers("2.0.0rc1")];
+        assert_eq!(
+            allowed_versions(versions.clone(), false),
+            vec![vers("1.9.0")]
+        );
+        assert_eq!(allowed_versions(versions.clone(), true), versions);
+        assert_eq!(
+            allowed_versions(vec![vers("0.1.0b2")], false),
+            vec![vers("0.1.0b2")]
+        );
+
+        let policy = PrereleasePolicy {
+            allow: true,
+            overrides: vec![("NumPy".to_owned(), false), ("black".to_owned(), true)]
+                .into_iter()
+                .collect(),
+        };
+        assert!(policy.allows(&Req::new("requests".into(), vec![])));
+        assert!(!policy.allows(&Req::new("numpy".into(), vec![])));
+        assert!(policy.allows(&Req::new(
+            "numpy".into(),
+            vec![Constraint::new(ReqType::Gte, vers("2.0.0b1"))]
+        )));
+        assert!(!PrereleasePolicy::default().allows(&Req::new("six".into(), vec![])));
+        assert!(PrereleasePolicy {
+            allow: false,
+            ..policy
+        }
+        .allows(&Req::new("black".into(), vec![])));
+    }
+
+    #[test]
+    fn yanked_versions_skipped_unless_pinned() {
+        let file = |yanked: bool, reason: Option<&str>| -> WarehouseRelease {
+            serde_json::from_value(serde_json::json!({
+                "filename": "six-1.16.0-py2.py3-none-any.whl",
+                "has_sig": false,
+                "digests": { "md5": "", "sha256": "" },
+                "packagetype": "bdist_wheel",
+                "python_version": "py2.py3",
+                "url": "",
+                "yanked": yanked,
+                "yanked_reason": reason,
+            }))
+            .unwrap()
+        };
+        assert_eq!(yank_reason(&[file(false, None)]), None);
+        assert_eq!(yank_reason(&[file(true, None), file(false, None)]), None);
+        assert_eq!(
+            yank_reason(&[file(true, None), file(true, Some("Broken wheels"))]),
+            Some("Broken wheels".into())
+        );
+        assert_eq!(
+            yank_reason(&[file(true, Some(""))]),
+            Some("no reason given".into())
+        );
+
+        let vers = |s: &str| Version::from_str(s).unwrap();
+        let versions = vec![vers("1.0.0"), vers("1.1.0"), vers("1.2.0")];
+        let yanked: HashMap<Version, String> = vec![
+            (vers("1.2.0"), "Broken".to_owned()),
+            (vers("1.1.0"), "Broken".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        let any = Req::from_str("six >=1.0", true).unwrap();
+        assert_eq!(
+            skip_yanked(versions.clone(), &any, &yanked),
+            vec![vers("1.0.0")]
+        );
+        let pinned = Req::from_str("six ==1.2.0", true).unwrap();
+        assert_eq!(skip_yanked(versions.clone(), &pinned, &yanked), versions);
+        let pinned = Req::from_str("six ==1.0.0", true).unwrap();
+        assert_eq!(skip_yanked(versions, &pinned, &yanked), vec![vers("1.0.0")]);
+    }
+
+    #[test]
+    fn yanked_kept_apart_by_source() {
+        let yanked_file: WarehouseRelease = serde_json::from_value(serde_json::json!({
+            "filename": "widget-1.0.0-py3-none-any.whl",
+            "has_sig": false,
+            "digests": { "md5": "", "sha256": "" },
+            "packagetype": "bdist_wheel",
+            "python_version": "py3",
+            "url": "",
+            "yanked": true,
+            "yanked_reason": "Broken",
+        }))
+        .unwrap();
+        let releases: HashMap<String, Vec<WarehouseRelease>> =
+            vec![("1.0.0".to_owned(), vec![yanked_file])]
+                .into_iter()
+                .collect();
+
+        let pypi = Index::default();
+        let internal = Index {
+            sources: vec![Source {
+                name: "internal".into(),
+                url: "https://pypi.acme.example/".into(),
+                packages: vec!["widget".into()],
+            }],
+            ..pypi.clone()
+        };
+        record_yanked(&internal, "widget", &releases);
+        assert_eq!(
+            yanked_versions(&internal, "Widget").get(&Version::new(1, 0, 0)),
+            Some(&"Broken".to_owned())
+        );
+        // Clones share what's been recorded, but PyPI's `widget` isn't the source's.
+        assert!(yanked_versions(&pypi, "widget").is_empty());
+    }
+
+    #[test]
+    fn constraints_limit_required_packages() {
+        let vers = |s: &str| Version::from_str(s).unwrap();
+        let constraints = vec![
+            Req::from_str("urllib3 <2", true).unwrap(),
+            Req::from_str("numpy ==1.26.4", true).unwrap(),
+            Req::from_str("idna ==3.6 ; sys_platform == 'win32'", true).unwrap(),
+        ];
+        let reqs = vec![
+            Req::new(
+                "URLLib3".into(),
+                vec![Constraint::new(ReqType::Gte, vers("1.21.1"))],
+            ),
+            Req::new("idna".into(), vec![]),
+        ];
+        let index = Index {
+            constraints,
+            ..Index::default()
+        };
+        let merged = merge_reqs(&apply_constraints(
+            &index,
+            &reqs,
+            util::Os::Linux,
+            &vers("3.12"),
+        ));
+        // numpy isn't required, so it isn't added, and the `idna` constraint is for Windows.
+        assert_eq!(merged.len(), 2);
+        assert_eq!(
+            merged[0].constraints,
+            vec![
+                Constraint::new(ReqType::Gte, vers("1.21.1")),
+                Constraint::new(ReqType::Lt, vers("2"))
+            ]
+        );
+        assert_eq!(merged[1], reqs[1]);
+    }
+
+    #[test]
+    fn overrides_replace_constraints() {
+        let vers = |s: &str| Version::from_str(s).unwrap();
+        let pin = vec![Req::new(
+            "urllib3".into(),
+            vec![Constraint::new(ReqType::Exact, vers("1.26.18"))],
+        )];
+        let requests_reqs = vec![
+            Req::new(
+                "URLLib3".into(),
+                vec![Constraint::new(ReqType::Gte, vers("2.0.0"))],
+            ),
+            Req::new("idna".into(), vec![]),
+        ];
+        let applied = apply_overrides(&requests_reqs, &pin);
+        assert_eq!(applied[0].name, "URLLib3");
+        assert_eq!(applied[0].constraints, pin[0].constraints);
+        assert_eq!(applied[1], requests_reqs[1]);
+
+        let package = |id, name: &str, version| Package {
+            id,
+            parent: 0,
+            name: name.into(),
+            version: vers(version),
+            deps: vec![],
+            rename: Rename::No,
+            markers: None,
+            extras: vec![],
+        };
+        let deps = vec![Dependency {
+            id: 1,
+            name: "requests".into(),
+            version: vers("2.31.0"),
+            reqs: requests_reqs,
+            parent: 0,
+        }];
+        let resolved = vec![
+            package(1, "requests", "2.31.0"),
+            package(2, "urllib3", "1.26.18"),
+        ];
+        let violations = override_violations(&pin, &[], &deps, &resolved);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].0, "urllib3");
+        assert!(violations[0].1.contains("requests 2.31.0 requires >=2.0.0"));
+
+        assert!(override_violations(&pin, &[], &deps, &resolved[..1]).is_empty());
+    }
+
+    #[test]
+    fn internal_prefixes() {
+        let prefixes: Vec<String> = vec!["acme-*".into(), "widgetcorp".into()];
+        assert_eq!(internal_prefix("acme-utils", &prefixes), Some("acme-*"));
+        assert_eq!(internal_prefix("Acme_Auth", &prefixes), Some("acme-*"));
+        assert_eq!(internal_prefix("WidgetCorp", &prefixes), Some("widgetcorp"));
+        assert_eq!(internal_prefix("widgetcorp-extra", &prefixes), None);
+        assert_eq!(internal_prefix("acmetools", &prefixes), None);
+        assert_eq!(internal_prefix("requests", &prefixes), None);
+    }
+
+    #[test]
+    fn sources_serve_their_packages() {
+        let index = Index {
+            sources: vec![Source {
+                name: "internal".into(),
+                url: "https://pypi.acme.example/".into(),
+                packages: vec!["acme-*".into(), "widget".into()],
+            }],
+            ..Index::default()
+        };
+        assert_eq!(
+            index.warehouse_url("acme_utils", None),
+            "https://pypi.acme.example/pypi/acme_utils/json"
+        );
+        assert_eq!(
+            index.warehouse_url("Widget", Some(&Version::new(1, 2, 0))),
+            "https://pypi.acme.example/pypi/Widget/1.2.0/json"
+        );
+        assert_eq!(
+            index.warehouse_url("requests", None),
+            "https://pypi.org/pypi/requests/json"
+        );
+        assert_eq!(
+            index.simple_url("acme-auth"),
+            "https://pypi.acme.example/simple"
+        );
+        assert_eq!(index.simple_url("requests"), "https://pypi.org/simple");
+        assert_eq!(index.cache_key("Acme_Utils", None), "internal--acme_utils");
+        assert_eq!(
+            index.cache_key("requests", Some(&Version::new(2, 31, 0))),
+            "requests-2.31.0"
+        );
+    }
+
+    #[test]
+    fn package_name_completion() {
+        let names: Vec<String> = vec![
+            "requests-oauthlib".into(),
+            "Requests".into(),
+            "requests_toolbelt".into(),
+            "numpy".into(),
+            "zope.interface".into(),
+        ];
+
+        assert_eq!(
+            complete_package_name(&names, "req"),
+            vec!["Requests", "requests-oauthlib", "requests_toolbelt"]
+        );
+        assert_eq!(
+            complete_package_name(&names, "requests_t"),
+            vec!["requests_toolbelt"]
+        );
+        assert_eq!(
+            complete_package_name(&names, "zope-"),
+            vec!["zope.interface"]
+        );
+        assert!(complete_package_name(&names, "scipy").is_empty());
+    }
+
+    #[test]
+    fn health() {
+        assert_eq!(days_from_date("1970-01-01"), Some(0));
+        assert_eq!(days_from_date("2019-05-16T14:12:07"), Some(18_032));
+        assert_eq!(days_from_date("2020-03-01"), Some(18_322));
+        assert_eq!(days_from_date("2019-13-01"), None);
+        assert_eq!(days_from_date("soon"), None);
+
+        let pack = |name: &str, locked, latest, uploaded: &str, vulns: usize| Health {
+            name: name.into(),
+            locked,
+            latest,
+            locked_uploaded: Some(uploaded.into()),
+            latest_uploaded: Some("2019-05-16".into()),
+            vulnerabilities: vec!["PYSEC-2019-1".into(); vulns],
+        };
+        let current = pack(
+            "six",
+            Version::new(1, 12, 0),
+            Version::new(1, 12, 0),
+            "2019-05-16",
+            0,
+        );
+        let behind = pack(
+            "numpy",
+            Version::new(1, 14, 0),
+            Version::new(1, 16, 3),
+            "2019-01-06",
+            0,
+        );
+        let vulnerable = pack(
+            "urllib3",
+            Version::new(1, 25, 0),
+            Version::new(1, 25, 2),
+            "2019-04-22",
+            1,
+        );
+        assert_eq!(current.days_behind(), Some(0));
+        assert_eq!(behind.days_behind(), Some(130));
+        assert_eq!(behind.latest_age(18_042), Some(10));
+
+        let mut all = vec![current.clone(), behind.clone(), vulnerable.clone()];
+        sort_by_priority(&mut all);
+        assert_eq!(all, vec![vulnerable, behind, current]);
+    }
+
     #[test]
     fn warehouse_versions() {
         // Makes API call
         // Assume no new releases since writing this test.
         assert_eq!(
-            get_version_info("scinot").unwrap().2.sort(),
+            get_version_info(&Index::default(), "scinot")
+                .unwrap()
+                .2
+                .sort(),
             vec![
                 Version::new(0, 0, 1),
                 Version::new(0, 0, 2),
@@ -0,0 +1,699 @@
+//! A [PubGrub](https://github.com/dart-lang/pub/blob/master/doc/solver.md) version solver. It
+//! picks a version of each package, one at a time, and when it finds a conflict, works out which
+//! earlier choices caused it, records that as a new incompatibility so the same mistake isn't
+//! made again, and backtracks to before them. If there's no solution, the incompatibilities it
+//! derived explain why, step by step.
+//!
+//! Version sets here are the versions known to match, rather than ranges, since we get every
+//! version of a package from the warehouse before using its constraints.
+
+use crate::dep_types::Version;
+use std::collections::{BTreeSet, HashMap};
+
+/// The package standing in for the project, whose dependencies are its requirements.
+pub const ROOT: &str = "";
+
+/// A dependency of a package version: the versions of it that'd do.
+#[derive(Clone, Debug)]
+pub struct Dep {
+    pub package: String,
+    pub versions: BTreeSet<Version>,
+    /// How it was specified, eg `>=1.21.1, <3`, for explaining conflicts.
+    pub constraints: String,
+}
+
+/// Where the solver gets packages' dependencies from.
+pub trait Provider {
+    /// A version's dependencies, or why it can't be used, eg that it doesn't support our
+    /// Python version.
+    fn dependencies(&mut self, package: &str, version: &Version) -> Result<Vec<Dep>, String>;
+    /// The version to try first, if it's allowed, eg the locked one. Otherwise we try the newest.
+    fn preferred(&self, package: &str) -> Option<Version>;
+    /// A package's name, as shown in explanations.
+    fn display(&self, package: &str) -> String;
+}
+
+/// That a package is selected with one of `versions`, or if not `positive`, that it isn't.
+#[derive(Clone, Debug, PartialEq)]
+struct Term {
+    package: String,
+    positive: bool,
+    versions: BTreeSet<Version>,
+}
+
+impl Term {
+    fn new(package: &str, positive: bool, versions: BTreeSet<Version>) -> Self {
+        Self {
+            package: package.to_owned(),
+            positive,
+            versions,
+        }
+    }
+
+    fn negate(&self) -> Self {
+        Self::new(&self.package, !self.positive, self.versions.clone())
+    }
+
+    /// Both terms at once. They must be for the same package.
+    fn intersect(&self, other: &Self) -> Self {
+        let (versions, positive) = match (self.positive, other.positive) {
+            (true, true) => (&self.versions & &other.versions, true),
+            (true, false) => (&self.versions - &other.versions, true),
+            (false, true) => (&other.versions - &self.versions, true),
+            (false, false) => (&self.versions | &other.versions, false),
+        };
+        Self::new(&self.package, positive, versions)
+    }
+
+    /// Whether `other` being true means this is.
+    fn satisfied_by(&self, other: &Self) -> bool {
+        match (other.positive, self.positive) {
+            (true, true) => other.versions.is_subset(&self.versions),
+            (true, false) => other.versions.is_disjoint(&self.versions),
+            (false, true) => false,
+            (false, false) => self.versions.is_subset(&other.versions),
+        }
+    }
+
+    /// Whether `other` being true means this can't be.
+    fn contradicted_by(&self, other: &Self) -> bool {
+        let both = self.intersect(other);
+        both.positive && both.versions.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Cause {
+    /// The project must be selected.
+    Root,
+    /// Facts from the provider, described.
+    External(String),
+    /// Derived from two other incompatibilities, by index.
+    Derived(usize, usize),
+}
+
+/// Terms that can't all be true.
+#[derive(Clone, Debug)]
+struct Incompatibility {
+    terms: Vec<Term>,
+    cause: Cause,
+}
+
+#[derive(Clone, Debug)]
+struct Assignment {
+    term: Term,
+    level: usize,
+    /// The incompatibility it was derived from. `None` for decisions.
+    cause: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Relation {
+    Satisfied,
+    Contradicted,
+    /// All but this term are satisfied.
+    AlmostSatisfied(usize),
+    Inconclusive,
+}
+
+struct Solver<'a, P: Provider> {
+    provider: &'a mut P,
+    incompatibilities: Vec<Incompatibility>,
+    by_package: HashMap<String, Vec<usize>>,
+    assignments: Vec<Assignment>,
+    level: usize,
+}
+
+impl<'a, P: Provider> Solver<'a, P> {
+    fn add(&mut self, terms: Vec<Term>, cause: Cause) -> usize {
+        // Terms for the same package are merged, since they must all be true at once.
+        let mut merged: Vec<Term> = vec![];
+        for term in terms {
+            match merged.iter_mut().find(|t| t.package == term.package) {
+                Some(t) => *t = t.intersect(&term),
+                None => merged.push(term),
+            }
+        }
+        let i = self.incompatibilities.len();
+        for term in &merged {
+            self.by_package
+                .entry(term.package.clone())
+                .or_default()
+                .push(i);
+        }
+        self.incompatibilities.push(Incompatibility {
+            terms: merged,
+            cause,
+        });
+        i
+    }
+
+    /// What the assignments so far say about a package, if anything.
+    fn current(assignments: &[Assignment], package: &str) -> Option<Term> {
+        assignments
+            .iter()
+            .filter(|a| a.term.package == package)
+            .fold(None, |acc: Option<Term>, a| match acc {
+                Some(t) => Some(t.intersect(&a.term)),
+                None => Some(a.term.clone()),
+            })
+    }
+
+    fn relation(&self, i: usize) -> Relation {
+        let mut inconclusive = None;
+        for (j, term) in self.incompatibilities[i].terms.iter().enumerate() {
+            match Self::current(&self.assignments, &term.package) {
+                Some(s) if term.satisfied_by(&s) => (),
+                Some(s) if term.contradicted_by(&s) => return Relation::Contradicted,
+                _ => {
+                    if inconclusive.is_some() {
+                        return Relation::Inconclusive;
+                    }
+                    inconclusive = Some(j);
+                }
+            }
+        }
+        match inconclusive {
+            Some(j) => Relation::AlmostSatisfied(j),
+            None => Relation::Satisfied,
+        }
+    }
+
+    fn derive(&mut self, term: Term, cause: usize) {
+        self.assignments.push(Assignment {
+            term,
+            level: self.level,
+            cause: Some(cause),
+        });
+    }
+
+    /// Derive what we can from the incompatibilities, starting with those about `package`.
+    /// Returns the incompatibility that shows there's no solution, if we find one.
+    fn propagate(&mut self, package: &str) -> Result<(), usize> {
+        let mut changed = vec![package.to_owned()];
+        while let Some(package) = changed.pop() {
+            let ids: Vec<usize> = self
+                .by_package
+                .get(&package)
+                .map(|ids| ids.iter().rev().copied().collect())
+                .unwrap_or_default();
+            for i in ids {
+                match self.relation(i) {
+                    Relation::Satisfied => {
+                        let learned = self.resolve_conflict(i)?;
+                        if let Relation::AlmostSatisfied(j) = self.relation(learned) {
+                            let term = self.incompatibilities[learned].terms[j].negate();
+                            changed = vec![term.package.clone()];
+                            self.derive(term, learned);
+                        }
+                        break;
+                    }
+                    Relation::AlmostSatisfied(j) => {
+                        let term = self.incompatibilities[i].terms[j].negate();
+                        if !changed.contains(&term.package) {
+                            changed.push(term.package.clone());
+                        }
+                        self.derive(term, i);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of the assignments before `until` that, with `with` assigned too, makes all of
+    /// an incompatibility's terms satisfied. `None` if `with` does on its own, or nothing does.
+    /// Walks the assignments once, keeping what they say about each of the terms' packages.
+    fn satisfier(&self, terms: &[Term], until: usize, with: Option<&Term>) -> Option<usize> {
+        let mut known: HashMap<&str, Term> = HashMap::new();
+        if let Some(t) = with {
+            known.insert(&t.package, t.clone());
+        }
+        let all_satisfied = |known: &HashMap<&str, Term>| {
+            terms.iter().all(|t| {
+                known
+                    .get(t.package.as_str())
+                    .map_or(false, |s| t.satisfied_by(s))
+            })
+        };
+        if all_satisfied(&known) {
+            return None;
+        }
+
+        for (k, a) in self.assignments[..until].iter().enumerate() {
+            if !terms.iter().any(|t| t.package == a.term.package) {
+                continue;
+            }
+            let term = match known.get(a.term.package.as_str()) {
+                Some(t) => t.intersect(&a.term),
+                None => a.term.clone(),
+            };
+            known.insert(&a.term.package, term);
+            if all_satisfied(&known) {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    fn is_failure(&self, i: usize) -> bool {
+        match self.incompatibilities[i].terms.as_slice() {
+            [] => true,
+            [t] => t.positive && t.package == ROOT,
+            _ => false,
+        }
+    }
+
+    /// Find the cause of a conflict, and backtrack to before it. Returns an incompatibility that
+    /// holds at the level we backtracked to, or if there's no solution, the one that shows that.
+    fn resolve_conflict(&mut self, mut i: usize) -> Result<usize, usize> {
+        loop {
+            if self.is_failure(i) {
+                return Err(i);
+            }
+            let terms = self.incompatibilities[i].terms.clone();
+
+            // The assignment that made the incompatibility satisfied.
+            let satisfier_index = self
+                .satisfier(&terms, self.assignments.len(), None)
+                .expect("A conflict must be satisfied");
+            let satisfier = self.assignments[satisfier_index].clone();
+            let term = terms
+                .iter()
+                .find(|t| t.package == satisfier.term.package)
+                .unwrap()
+                .clone();
+
+            // The latest decision level the other terms were already satisfied at.
+            let previous_level = self
+                .satisfier(&terms, satisfier_index, Some(&satisfier.term))
+                .map_or(1, |k| self.assignments[k].level.max(1));
+
+            let cause = match satisfier.cause {
+                Some(c) if previous_level == satisfier.level => c,
+                _ => {
+                    self.assignments.retain(|a| a.level <= previous_level);
+                    self.level = previous_level;
+                    return Ok(i);
+                }
+            };
+
+            let mut prior: Vec<Term> = terms
+                .iter()
+                .chain(self.incompatibilities[cause].terms.iter())
+                .filter(|t| t.package != satisfier.term.package)
+                .cloned()
+                .collect();
+            if !term.satisfied_by(&satisfier.term) {
+                prior.push(satisfier.term.intersect(&term.negate()).negate());
+            }
+            i = self.add(prior, Cause::Derived(i, cause));
+        }
+    }
+
+    /// Pick a version of a package we know we need, but haven't chosen yet. Returns the package,
+    /// or `None` if there aren't any left, ie we've found a solution.
+    fn decide(&mut self) -> Option<String> {
+        let decided: Vec<&str> = self
+            .assignments
+            .iter()
+            .filter(|a| a.cause.is_none())
+            .map(|a| a.term.package.as_str())
+            .collect();
+        let mut packages: Vec<&str> = self
+            .assignments
+            .iter()
+            .map(|a| a.term.package.as_str())
+            .filter(|p| !decided.contains(p))
+            .collect();
+        packages.dedup();
+
+        // Packages with the fewest options first, since they're the most likely to conflict.
+        let (package, versions) = packages
+            .into_iter()
+            .filter_map(|p| {
+                Self::current(&self.assignments, p)
+                    .filter(|t| t.positive)
+                    .map(|t| (p.to_owned(), t.versions))
+            })
+            .min_by_key(|(_, versions)| versions.len())?;
+
+        let version = match self.provider.preferred(&package) {
+            Some(v) if versions.contains(&v) => v,
+            _ => match versions.iter().next_back() {
+                Some(v) => *v,
+                None => {
+                    let text = format!("no versions of {} match", self.provider.display(&package));
+                    self.add(
+                        vec![Term::new(&package, true, versions)],
+                        Cause::External(text),
+                    );
+                    return Some(package);
+                }
+            },
+        };
+        let this = Term::new(&package, true, vec![version].into_iter().collect());
+        let name = if package == ROOT {
+            "the project".to_owned()
+        } else {
+            format!(
+                "{} {}",
+                self.provider.display(&package),
+                version.to_string2()
+            )
+        };
+
+        match self.provider.dependencies(&package, &version) {
+            Err(reason) => {
+                self.add(
+                    vec![this],
+                    Cause::External(format!("{} can't be used: {}", name, reason)),
+                );
+            }
+            Ok(deps) => {
+                for dep in deps {
+                    let mut text = format!(
+                        "{} depends on {} {}",
+                        name,
+                        self.provider.display(&dep.package),
+                        dep.constraints
+                    );
+                    let mut terms = vec![this.clone()];
+                    if dep.versions.is_empty() {
+                        text.push_str(", which no version matches");
+                    } else {
+                        terms.push(Term::new(&dep.package, false, dep.versions));
+                    }
+                    self.add(terms, Cause::External(text));
+                }
+                self.level += 1;
+                self.assignments.push(Assignment {
+                    term: this,
+                    level: self.level,
+                    cause: None,
+                });
+            }
+        }
+        Some(package)
+    }
+
+    fn describe_versions(versions: &BTreeSet<Version>) -> String {
+        let all: Vec<String> = versions.iter().map(Version::to_string2).collect();
+        match all.len() {
+            0 => "(no versions)".into(),
+            1..=3 => all.join(" or "),
+            _ => format!("{} to {}", all[0], all[all.len() - 1]),
+        }
+    }
+
+    fn describe_term(&self, term: &Term) -> String {
+        if term.package == ROOT {
+            return "the project".into();
+        }
+        format!(
+            "{} {}",
+            self.provider.display(&term.package),
+            Self::describe_versions(&term.versions)
+        )
+    }
+
+    fn describe(&self, i: usize) -> String {
+        let inc = &self.incompatibilities[i];
+        match &inc.cause {
+            Cause::External(text) => return text.clone(),
+            Cause::Root => return "the project is required".into(),
+            Cause::Derived(..) => (),
+        }
+        // The project's always used, so it's implied.
+        let for_project = inc.terms.iter().any(|t| t.package == ROOT && t.positive);
+        let terms: Vec<&Term> = inc.terms.iter().filter(|t| t.package != ROOT).collect();
+        match (for_project, terms.as_slice()) {
+            (_, []) => "the project's requirements can't all be met".into(),
+            (true, [t]) if t.positive => format!("the project can't use {}", self.describe_term(t)),
+            (true, [t]) => format!("the project requires {}", self.describe_term(t)),
+            (false, [t]) if t.positive => format!("{} can't be used", self.describe_term(t)),
+            (false, [t]) => format!("{} is required", self.describe_term(t)),
+            (false, [a, b]) if a.positive != b.positive => {
+                let (p, n) = if a.positive { (a, b) } else { (b, a) };
+                format!(
+                    "{} requires {}",
+                    self.describe_term(p),
+                    self.describe_term(n)
+                )
+            }
+            (false, [a, b]) => format!(
+                "{} and {} can't both be used",
+                self.describe_term(a),
+                self.describe_term(b)
+            ),
+            _ => {
+                let parts: Vec<String> = terms
+                    .iter()
+                    .map(|t| {
+                        if t.positive {
+                            self.describe_term(t)
+                        } else {
+                            format!("not {}", self.describe_term(t))
+                        }
+                    })
+                    .collect();
+                let all = format!("{} can't all be used", parts.join(", "));
+                if for_project {
+                    format!("for the project, {}", all)
+                } else {
+                    all
+                }
+            }
+        }
+    }
+
+    /// Lines explaining how an incompatibility was derived, numbered so later lines can refer
+    /// to earlier ones.
+    fn explain(&self, i: usize, lines: &mut Vec<String>, numbers: &mut HashMap<usize, usize>) {
+        let (a, b) = match self.incompatibilities[i].cause {
+            Cause::Derived(a, b) => (a, b),
+            _ => return,
+        };
+        let mut because = vec![];
+        for c in [a, b] {
+            if let Cause::Derived(..) = self.incompatibilities[c].cause {
+                if !numbers.contains_key(&c) {
+                    self.explain(c, lines, numbers);
+                }
+                because.push(format!("{} ({})", self.describe(c), numbers[&c]));
+            } else {
+                because.push(self.describe(c));
+            }
+        }
+        lines.push(format!(
+            "Because {} and {}, {}.",
+            because[0],
+            because[1],
+            self.describe(i)
+        ));
+        numbers.insert(i, lines.len());
+    }
+
+    fn report(&self, failure: usize) -> String {
+        let mut lines = vec![];
+        self.explain(failure, &mut lines, &mut HashMap::new());
+        if lines.is_empty() {
+            return format!("{}.", self.describe(failure));
+        }
+        lines
+            .iter()
+            .enumerate()
+            .map(|(n, line)| format!("({}) {}", n + 1, line))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Choose a version of each package the project needs, so every dependency's met. Returns them,
+/// or an explanation of why that's impossible.
+pub fn solve(provider: &mut impl Provider) -> Result<Vec<(String, Version)>, String> {
+    let mut solver = Solver {
+        provider,
+        incompatibilities: vec![],
+        by_package: HashMap::new(),
+        assignments: vec![],
+        level: 0,
+    };
+    let root_version: BTreeSet<Version> = vec![Version::new(0, 0, 0)].into_iter().collect();
+    solver.add(vec![Term::new(ROOT, false, root_version)], Cause::Root);
+
+    let mut next = Some(ROOT.to_owned());
+    while let Some(package) = next {
+        if let Err(failure) = solver.propagate(&package) {
+            return Err(solver.report(failure));
+        }
+        next = solver.decide();
+    }
+
+    Ok(solver
+        .assignments
+        .iter()
+        .filter(|a| a.cause.is_none() && a.term.package != ROOT)
+        .map(|a| {
+            (
+                a.term.package.clone(),
+                *a.term.versions.iter().next().unwrap(),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// Packages' versions and dependencies, eg `("a", "1.0.0", &[("b", ">=1.0.0")])`.
+    struct Index {
+        versions: HashMap<String, Vec<Version>>,
+        deps: HashMap<(String, Version), Vec<(String, String)>>,
+        root: Vec<(String, String)>,
+    }
+
+    /// A package version, and its dependencies' names and constraints.
+    type Listing<'a> = (&'a str, &'a str, &'a [(&'a str, &'a str)]);
+
+    impl Index {
+        fn new(root: &[(&str, &str)], packages: &[Listing]) -> Self {
+            let mut versions: HashMap<String, Vec<Version>> = HashMap::new();
+            let mut deps = HashMap::new();
+            for (name, version, requires) in packages {
+                let version = Version::from_str(version).unwrap();
+                versions.entry((*name).into()).or_default().push(version);
+                deps.insert(
+                    ((*name).into(), version),
+                    requires
+                        .iter()
+                        .map(|(n, c)| ((*n).into(), (*c).into()))
+                        .collect(),
+                );
+            }
+            Self {
+                versions,
+                deps,
+                root: root
+                    .iter()
+                    .map(|(n, c)| ((*n).into(), (*c).into()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl Provider for Index {
+        fn dependencies(&mut self, package: &str, version: &Version) -> Result<Vec<Dep>, String> {
+            let requires = if package == ROOT {
+                self.root.clone()
+            } else {
+                self.deps[&(package.to_owned(), *version)].clone()
+            };
+            if requires.iter().any(|(n, _)| n == "unavailable") {
+                return Err("it's broken".into());
+            }
+            Ok(requires
+                .into_iter()
+                .map(|(name, constraints)| {
+                    let constrs =
+                        crate::dep_types::Constraint::from_str_multiple(&constraints).unwrap();
+                    Dep {
+                        versions: self
+                            .versions
+                            .get(&name)
+                            .into_iter()
+                            .flatten()
+                            .filter(|v| constrs.iter().all(|c| c.is_compatible(v)))
+                            .copied()
+                            .collect(),
+                        package: name,
+                        constraints,
+                    }
+                })
+                .collect())
+        }
+
+        fn preferred(&self, _package: &str) -> Option<Version> {
+            None
+        }
+
+        fn display(&self, package: &str) -> String {
+            package.to_owned()
+        }
+    }
+
+    fn solution(index: &mut Index) -> Vec<String> {
+        let mut result: Vec<String> = solve(index)
+            .unwrap()
+            .into_iter()
+            .map(|(p, v)| format!("{} {}", p, v.to_string2()))
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn newest_versions() {
+        let mut index = Index::new(
+            &[("a", ">=1.0.0")],
+            &[
+                ("a", "1.0.0", &[]),
+                ("a", "2.0.0", &[("b", "^1.0.0")]),
+                ("b", "1.0.0", &[]),
+                ("b", "1.1.0", &[]),
+                ("b", "2.0.0", &[]),
+            ],
+        );
+        assert_eq!(solution(&mut index), vec!["a 2.0.0", "b 1.1.0"]);
+    }
+
+    #[test]
+    fn backtracks_past_conflicts() {
+        // The newest `foo` needs a `bar` the project rules out, so we use the older one.
+        let mut index = Index::new(
+            &[("foo", ">=1.0.0"), ("bar", "<2.0.0")],
+            &[
+                ("foo", "1.0.0", &[("bar", ">=1.0.0")]),
+                ("foo", "1.1.0", &[("bar", ">=2.0.0")]),
+                ("bar", "1.0.0", &[]),
+                ("bar", "2.0.0", &[]),
+            ],
+        );
+        assert_eq!(solution(&mut index), vec!["bar 1.0.0", "foo 1.0.0"]);
+
+        // And past versions that can't be used.
+        let mut index = Index::new(
+            &[("foo", ">=1.0.0")],
+            &[
+                ("foo", "1.0.0", &[]),
+                ("foo", "1.1.0", &[("unavailable", "")]),
+            ],
+        );
+        assert_eq!(solution(&mut index), vec!["foo 1.0.0"]);
+    }
+
+    #[test]
+    fn explains_conflicts() {
+        let mut index = Index::new(
+            &[("foo", ">=1.0.0"), ("bar", "^1.0.0")],
+            &[
+                ("foo", "1.0.0", &[("bar", ">=2.0.0")]),
+                ("bar", "1.0.0", &[]),
+                ("bar", "2.0.0", &[]),
+            ],
+        );
+        let report = solve(&mut index).unwrap_err();
+        assert!(report.contains("foo 1.0.0 depends on bar >=2.0.0"));
+        assert!(report.contains("the project depends on bar ^1.0.0"));
+        assert!(report.ends_with("the project's requirements can't all be met."));
+
+        let mut index = Index::new(&[("foo", ">=3.0.0")], &[("foo", "1.0.0", &[])]);
+        assert_eq!(
+            solve(&mut index).unwrap_err(),
+            "the project depends on foo >=3.0.0, which no version matches."
+        );
+    }
+}
@@ -1,13 +1,14 @@
 use crate::dep_parser::{
-    parse_constraint, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version, parse_wh_py_vers,
+    parse_constraint, parse_marker, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version,
+    parse_wh_py_vers,
 };
 use crate::{dep_resolution, util};
-use crossterm::{Color, Colored};
+use crossterm::Color;
 use nom::combinator::all_consuming;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
-use std::{cmp, fmt, num, str::FromStr};
+use std::{cmp, fmt, num, path::Path, process::Command, str::FromStr};
 
 pub const MAX_VER: u32 = 999_999; // Represents the highest major version we can have
 
@@ -56,6 +57,14 @@ impl From<num::ParseIntError> for DependencyError {
     }
 }
 
+impl From<crate::net::NetError> for DependencyError {
+    fn from(e: crate::net::NetError) -> Self {
+        Self {
+            details: e.to_string(),
+        }
+    }
+}
+
 impl From<reqwest::Error> for DependencyError {
     fn from(_: reqwest::Error) -> Self {
         Self {
@@ -162,6 +171,14 @@ impl Version {
         Self::new(MAX_VER, 0, 0)
     }
 
+    /// An alpha, beta, release candidate, or dev release, eg `2.0.0rc1`.
+    pub fn is_prerelease(&self) -> bool {
+        match self.modifier {
+            Some((VersionModifier::Null, _)) | None => false,
+            Some(_) => true,
+        }
+    }
+
     /// Prevents repetition.
     fn add_str_mod(&self, s: &mut String) {
         if let Some(extra_num) = self.extra_num {
@@ -246,9 +263,9 @@ impl PartialOrd for Version {
 
 impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let num_c = Colored::Fg(Color::Blue);
-        let dot_c = Colored::Fg(Color::DarkYellow);
-        let r = Colored::Fg(Color::Reset);
+        let num_c = util::fg(Color::Blue);
+        let dot_c = util::fg(Color::DarkYellow);
+        let r = util::fg(Color::Reset);
 
         let mut suffix = "".to_string();
         if let Some(num) = self.extra_num {
@@ -583,6 +600,416 @@ pub struct Extras {
     pub extra: Option<String>,
     pub sys_platform: Option<(ReqType, util::Os)>,
     pub python_version: Option<Constraint>,
+    pub implementation_name: Option<(ReqType, String)>,
+}
+
+impl Extras {
+    /// The `extra`, `sys_platform`, `python_version`, and `implementation_name` conditions a
+    /// marker requires, if it's made of comparisons joined by `and`. We can't represent `or`
+    /// this way; `Marker::evaluate` handles those.
+    pub fn from_marker(marker: &Marker) -> Self {
+        let mut result = Self {
+            extra: None,
+            sys_platform: None,
+            python_version: None,
+            implementation_name: None,
+        };
+        let parts = match marker {
+            Marker::And(ms) => ms.iter().collect(),
+            Marker::Compare(..) => vec![marker],
+            Marker::Or(_) => vec![],
+        };
+
+        for part in parts {
+            let (var, op, lit) = match part {
+                Marker::Compare(MarkerValue::Variable(v), op, MarkerValue::Literal(l)) => {
+                    (v, op, l)
+                }
+                _ => continue,
+            };
+            let rt = ReqType::from_str(op);
+            match (var.as_ref(), rt) {
+                ("extra", Ok(ReqType::Exact)) => result.extra = Some(lit.clone()),
+                ("sys_platform", Ok(rt)) if rt == ReqType::Exact || rt == ReqType::Ne => {
+                    if let Ok(os) = util::Os::from_str(lit) {
+                        result.sys_platform = Some((rt, os))
+                    }
+                }
+                ("python_version", Ok(rt)) => {
+                    if let Ok(v) = Version::from_str(lit) {
+                        result.python_version = Some(Constraint::new(rt, v))
+                    }
+                }
+                ("implementation_name", Ok(rt)) | ("platform_python_implementation", Ok(rt))
+                    if rt == ReqType::Exact || rt == ReqType::Ne =>
+                {
+                    result.implementation_name = Some((rt, lit.to_lowercase()))
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+}
+
+/// One side of a marker comparison: a variable, eg `python_version`, or a quoted string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkerValue {
+    Variable(String),
+    Literal(String),
+}
+
+impl fmt::Display for MarkerValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Variable(v) => write!(f, "{}", v),
+            Self::Literal(l) if l.contains('"') => write!(f, "'{}'", l),
+            Self::Literal(l) => write!(f, "\"{}\"", l),
+        }
+    }
+}
+
+/// A [PEP 508](https://peps.python.org/pep-0508/#environment-markers) environment marker, eg
+/// `python_version < "3.8" or sys_platform == "win32"`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Marker {
+    /// Eg `python_version`, `<`, `"3.8"`.
+    Compare(MarkerValue, String, MarkerValue),
+    And(Vec<Marker>),
+    Or(Vec<Marker>),
+}
+
+impl Marker {
+    /// Whether the marker holds in an environment.
+    pub fn evaluate(&self, env: &MarkerEnv) -> bool {
+        match self {
+            Self::And(ms) => ms.iter().all(|m| m.evaluate(env)),
+            Self::Or(ms) => ms.iter().any(|m| m.evaluate(env)),
+            Self::Compare(lhs, op, rhs) => {
+                // `extra == "test"` holds if `test` is one of the extras we're installing.
+                let extra = match (lhs, rhs) {
+                    (MarkerValue::Variable(v), MarkerValue::Literal(name))
+                    | (MarkerValue::Literal(name), MarkerValue::Variable(v))
+                        if v == "extra" =>
+                    {
+                        Some(name)
+                    }
+                    _ => None,
+                };
+                match extra {
+                    Some(name) => {
+                        let found = env.extras.iter().any(|e| util::compare_names(e, name));
+                        match op.as_ref() {
+                            "==" | "===" => found,
+                            "!=" => !found,
+                            _ => false,
+                        }
+                    }
+                    None => compare_marker_values(&env.value(lhs), op, &env.value(rhs)),
+                }
+            }
+        }
+    }
+
+    /// The marker with `extra` conditions removed, or `None` if what's left always holds. We use
+    /// this once we've picked the extras to install, eg for the lock file.
+    pub fn without_extras(&self) -> Option<Self> {
+        match self {
+            Self::Compare(lhs, _, rhs) => {
+                let extra = MarkerValue::Variable("extra".into());
+                if lhs == &extra || rhs == &extra {
+                    None
+                } else {
+                    Some(self.clone())
+                }
+            }
+            Self::And(ms) => {
+                let mut rest: Vec<Self> = ms.iter().filter_map(Self::without_extras).collect();
+                match rest.len() {
+                    0 => None,
+                    1 => Some(rest.remove(0)),
+                    _ => Some(Self::And(rest)),
+                }
+            }
+            Self::Or(ms) => {
+                let rest: Option<Vec<Self>> = ms.iter().map(Self::without_extras).collect();
+                rest.map(|mut r| {
+                    if r.len() == 1 {
+                        r.remove(0)
+                    } else {
+                        Self::Or(r)
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for Marker {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all_consuming(parse_marker)(s.trim())
+            .map_err(|_| DependencyError::new(&format!("Problem parsing markers: {}", s)))
+            .map(|(_, m)| m)
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compare(lhs, op, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+            Self::And(ms) => {
+                let parts: Vec<String> = ms
+                    .iter()
+                    .map(|m| match m {
+                        Self::Or(_) => format!("({})", m),
+                        _ => m.to_string(),
+                    })
+                    .collect();
+                write!(f, "{}", parts.join(" and "))
+            }
+            Self::Or(ms) => {
+                let parts: Vec<String> = ms.iter().map(|m| m.to_string()).collect();
+                write!(f, "{}", parts.join(" or "))
+            }
+        }
+    }
+}
+
+/// Compare two marker values. Versions compare as versions, eg `"3.10" > "3.9"`; anything else
+/// as strings.
+fn compare_marker_values(lhs: &str, op: &str, rhs: &str) -> bool {
+    match op {
+        "in" => return rhs.contains(lhs),
+        "not in" => return !rhs.contains(lhs),
+        "===" => return lhs == rhs,
+        _ => (),
+    }
+    // Eg `python_version == "3.*"`.
+    if rhs.ends_with(".*") && (op == "==" || op == "!=") {
+        let prefix = &rhs[..rhs.len() - 1];
+        let matches = lhs.starts_with(prefix) || lhs == &prefix[..prefix.len() - 1];
+        return matches == (op == "==");
+    }
+
+    match (
+        Version::from_str(lhs),
+        ReqType::from_str(op),
+        Version::from_str(rhs),
+    ) {
+        (Ok(l), Ok(rt), Ok(r)) => Constraint::new(rt, r).is_compatible(&l),
+        _ => match op {
+            "==" => lhs == rhs,
+            "!=" => lhs != rhs,
+            _ => false,
+        },
+    }
+}
+
+/// Asks an interpreter for the values of marker variables.
+const MARKER_ENV_SCRIPT: &str = r#"
+import json, os, platform, sys
+v = sys.implementation.version
+iv = "{0.major}.{0.minor}.{0.micro}".format(v)
+if v.releaselevel != "final":
+    iv += v.releaselevel[0] + str(v.serial)
+print(json.dumps({
+    "os_name": os.name,
+    "sys_platform": sys.platform,
+    "platform_machine": platform.machine(),
+    "platform_python_implementation": platform.python_implementation(),
+    "platform_release": platform.release(),
+    "platform_system": platform.system(),
+    "platform_version": platform.version(),
+    "python_version": ".".join(platform.python_version_tuple()[:2]),
+    "python_full_version": platform.python_version(),
+    "implementation_name": sys.implementation.name,
+    "implementation_version": iv,
+}))
+"#;
+
+/// The values of marker variables in an environment, and the extras we're installing.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MarkerEnv {
+    pub os_name: String,
+    pub sys_platform: String,
+    pub platform_machine: String,
+    pub platform_python_implementation: String,
+    pub platform_release: String,
+    pub platform_system: String,
+    pub platform_version: String,
+    pub python_version: String,
+    pub python_full_version: String,
+    pub implementation_name: String,
+    pub implementation_version: String,
+    #[serde(default)]
+    pub extras: Vec<String>,
+}
+
+impl MarkerEnv {
+    /// The environment of a Python version on an OS, for when we don't have an interpreter to
+    /// ask, eg when resolving. We don't know `platform_release` or `platform_version`.
+    pub fn new(os: util::Os, py_vers: &Version, implementation: util::Implementation) -> Self {
+        let (os_name, sys_platform, platform_system) = match os {
+            util::Os::Windows | util::Os::Windows32 => ("nt", "win32", "Windows"),
+            util::Os::Linux | util::Os::Linux32 => ("posix", "linux", "Linux"),
+            util::Os::Mac => ("posix", "darwin", "Darwin"),
+            util::Os::Any => ("", "", ""),
+        };
+        let platform_machine = match (os, std::env::consts::ARCH) {
+            (util::Os::Windows32, _) => "x86",
+            (util::Os::Linux32, _) => "i686",
+            (util::Os::Windows, "aarch64") => "ARM64",
+            (util::Os::Windows, _) => "AMD64",
+            (util::Os::Mac, "aarch64") => "arm64",
+            (_, arch) => arch,
+        };
+        let full_version = py_vers.to_string2();
+        Self {
+            os_name: os_name.into(),
+            sys_platform: sys_platform.into(),
+            platform_machine: platform_machine.into(),
+            platform_python_implementation: match implementation {
+                util::Implementation::CPython => "CPython".into(),
+                util::Implementation::PyPy => "PyPy".into(),
+            },
+            platform_release: String::new(),
+            platform_system: platform_system.into(),
+            platform_version: String::new(),
+            python_version: format!("{}.{}", py_vers.major, py_vers.minor),
+            python_full_version: full_version.clone(),
+            implementation_name: implementation.name().into(),
+            implementation_version: full_version,
+            extras: vec![],
+        }
+    }
+
+    /// Ask an interpreter, eg the environment's, for its marker values.
+    pub fn from_interpreter(python: &Path) -> Option<Self> {
+        let output = Command::new(python)
+            .args(&["-c", MARKER_ENV_SCRIPT])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        serde_json::from_slice(&output.stdout).ok()
+    }
+
+    fn value(&self, val: &MarkerValue) -> String {
+        let name = match val {
+            MarkerValue::Literal(l) => return l.clone(),
+            MarkerValue::Variable(v) => v,
+        };
+        match name.as_ref() {
+            "os_name" => &self.os_name,
+            "sys_platform" => &self.sys_platform,
+            "platform_machine" => &self.platform_machine,
+            "platform_python_implementation" => &self.platform_python_implementation,
+            "platform_release" => &self.platform_release,
+            "platform_system" => &self.platform_system,
+            "platform_version" => &self.platform_version,
+            "python_version" => &self.python_version,
+            "python_full_version" => &self.python_full_version,
+            "implementation_name" => &self.implementation_name,
+            "implementation_version" => &self.implementation_version,
+            _ => "",
+        }
+        .to_owned()
+    }
+}
+
+/// Check if an `implementation_name` or `platform_python_implementation` marker applies to a
+/// Python implementation.
+pub fn implementation_matches(
+    marker: &Option<(ReqType, String)>,
+    implementation: util::Implementation,
+) -> bool {
+    match marker {
+        Some((ReqType::Ne, name)) => name != implementation.name(),
+        Some((_, name)) => name == implementation.name(),
+        None => true,
+    }
+}
+
+/// Check if a `sys_platform` marker applies to an operating system.
+pub fn platform_matches(sys_platform: Option<(ReqType, util::Os)>, os: util::Os) -> bool {
+    match sys_platform {
+        Some((rt, os_)) => match rt {
+            // A specified win32 req could apply to 64-bit windows too.
+            ReqType::Exact => os_ == os || (os_ == util::Os::Windows32 && os == util::Os::Windows),
+            ReqType::Ne => os_ != os,
+            _ => {
+                util::abort("Reqtypes for Os must be == or !=");
+                unreachable!()
+            }
+        },
+        None => true,
+    }
+}
+
+/// What to check out of a git dependency's repo: its `branch`, `tag` or `rev` in
+/// `pyproject.toml`. Without one, we use the repo's default branch.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl GitRef {
+    /// What to pass `git checkout`, once the repo's fetched. Branches are checked out from the
+    /// remote, so we get its latest commit.
+    pub fn checkout_target(&self) -> String {
+        match self {
+            Self::Branch(b) => format!("origin/{}", b),
+            Self::Tag(t) => format!("tags/{}", t),
+            Self::Rev(r) => r.clone(),
+        }
+    }
+}
+
+/// A git dependency's source in the lock, in the same format as `Cargo.lock`'s: eg
+/// `git+https://github.com/org/repo?tag=v1.2.0#{commit}`.
+pub fn git_source(url: &str, git_ref: Option<&GitRef>, commit: &str) -> String {
+    let query = match git_ref {
+        Some(GitRef::Branch(b)) => format!("?branch={}", b),
+        Some(GitRef::Tag(t)) => format!("?tag={}", t),
+        Some(GitRef::Rev(r)) => format!("?rev={}", r),
+        None => String::new(),
+    };
+    format!("git+{}{}#{}", url, query, commit)
+}
+
+/// The repo's URL, the ref, and the commit from a source made by `git_source`.
+pub fn parse_git_source(source: &str) -> Option<(String, Option<GitRef>, String)> {
+    let (repo, commit) = source.strip_prefix("git+")?.rsplit_once('#')?;
+    let (url, git_ref) = match repo.rsplit_once('?') {
+        Some((url, query)) => {
+            let git_ref = match query.split_once('=')? {
+                ("branch", b) => GitRef::Branch(b.to_owned()),
+                ("tag", t) => GitRef::Tag(t.to_owned()),
+                ("rev", r) => GitRef::Rev(r.to_owned()),
+                _ => return None,
+            };
+            (url, Some(git_ref))
+        }
+        None => (repo, None),
+    };
+    Some((url.to_owned(), git_ref, commit.to_owned()))
+}
+
+/// A direct URL dependency's source in the lock, with its archive's hash: eg
+/// `url+https://example.com/saturn-0.3.4-py3-none-any.whl#sha256={hash}`.
+pub fn url_source(url: &str, sha256: &str) -> String {
+    format!("url+{}#sha256={}", url, sha256)
+}
+
+/// The URL and hash from a source made by `url_source`.
+pub fn parse_url_source(source: &str) -> Option<(String, String)> {
+    let (url, sha256) = source.strip_prefix("url+")?.rsplit_once("#sha256=")?;
+    Some((url.to_owned(), sha256.to_owned()))
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -592,9 +1019,19 @@ pub struct Req {
     pub extra: Option<String>,
     pub sys_platform: Option<(ReqType, util::Os)>,
     pub python_version: Option<Constraint>,
+    pub implementation_name: Option<(ReqType, String)>,
     pub install_with_extras: Option<Vec<String>>,
     pub path: Option<String>,
-    pub git: Option<String>, // String is the git repo. // todo: Branch
+    pub git: Option<String>, // String is the git repo.
+    pub git_ref: Option<GitRef>,
+    /// For `path` reqs: install it so changes to its source apply without reinstalling.
+    pub editable: bool,
+    /// A wheel or source archive to install directly, instead of resolving it from PyPI, eg from
+    /// `name @ https://..`.
+    pub url: Option<String>,
+    /// The full environment marker, if it's from package metadata.
+    #[serde(skip)]
+    pub marker: Option<Marker>,
 }
 
 impl Req {
@@ -605,22 +1042,33 @@ impl Req {
             extra: None,
             sys_platform: None,
             python_version: None,
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: None,
         }
     }
 
-    pub fn new_with_extras(name: String, constraints: Vec<Constraint>, extras: Extras) -> Self {
+    pub fn new_with_marker(name: String, constraints: Vec<Constraint>, marker: Marker) -> Self {
+        let extras = Extras::from_marker(&marker);
         Self {
             name,
             constraints,
             extra: extras.extra,
             sys_platform: extras.sys_platform,
             python_version: extras.python_version,
+            implementation_name: extras.implementation_name,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: Some(marker),
         }
     }
 
@@ -634,39 +1082,104 @@ impl Req {
         .map(|x| x.1)
     }
 
+    /// Environment markers for this req, without `extra` conditions, in a format `Marker` can
+    /// read back. Eg `sys_platform == "win32" and python_version < "3.6"`.
+    pub fn markers(&self) -> Option<String> {
+        if let Some(m) = &self.marker {
+            return m.without_extras().map(|m| m.to_string());
+        }
+        let mut parts = vec![];
+        if let Some((rt, os)) = self.sys_platform {
+            let platform = match os {
+                util::Os::Windows | util::Os::Windows32 => "win32",
+                util::Os::Linux | util::Os::Linux32 => "linux",
+                util::Os::Mac => "darwin",
+                util::Os::Any => return None,
+            };
+            // Platforms only compare with `==` and `!=`.
+            if rt != ReqType::Exact && rt != ReqType::Ne {
+                return None;
+            }
+            parts.push(format!("sys_platform {} \"{}\"", rt.to_string(), platform));
+        }
+        if let Some(c) = &self.python_version {
+            parts.push(format!(
+                "python_version {} \"{}\"",
+                c.type_.to_string(),
+                c.version.to_string2()
+            ));
+        }
+        if let Some((rt, name)) = &self.implementation_name {
+            parts.push(format!(
+                "implementation_name {} \"{}\"",
+                rt.to_string(),
+                name
+            ));
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" and "))
+        }
+    }
+
     /// We use this for parsing requirements.txt.
     pub fn from_pip_str(s: &str) -> Option<Self> {
         // todo multiple ie single quotes support?
         all_consuming(parse_pip_str)(s).ok().map(|x| x.1)
     }
 
-    /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`
+    /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`, or
+    /// `requests = { version = "^2.31.0", extras = ["socks"] }` with extras.
     pub fn to_cfg_string(&self) -> String {
-        match self.constraints.len() {
+        if let Some(url) = &self.url {
+            let extras = match &self.install_with_extras {
+                Some(extras) if !extras.is_empty() => {
+                    let extras: Vec<String> = extras.iter().map(|e| format!("\"{}\"", e)).collect();
+                    format!(", extras = [{}]", extras.join(", "))
+                }
+                _ => String::new(),
+            };
+            return format!(r#"{} = {{ url = "{}"{} }}"#, self.name, url, extras);
+        }
+        let (name, constraints) = match self.constraints.len() {
             0 => {
+                // Only new configs are written without constraints, so there are no other
+                // sources to look in yet.
                 let (name, latest_version) = if let Ok((fmtd_name, version, _)) =
-                    dep_resolution::get_version_info(&self.name)
+                    dep_resolution::get_version_info(&Default::default(), &self.name)
                 {
                     (fmtd_name, version)
                 } else {
                     util::abort(&format!("Unable to find version info for {:?}", &self.name));
                     unreachable!()
                 };
-                format!(
-                    r#"{} = "{}""#,
+                (
                     name,
-                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false)
+                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false),
                 )
             }
-            _ => format!(
-                r#"{} = "{}""#,
-                self.name,
+            _ => (
+                self.name.clone(),
                 self.constraints
                     .iter()
                     .map(|r| r.to_string2(true, false))
                     .collect::<Vec<String>>()
-                    .join(", ")
+                    .join(", "),
             ),
+        };
+        match &self.install_with_extras {
+            Some(extras) if !extras.is_empty() => {
+                let extras: Vec<String> = extras.iter().map(|e| format!("\"{}\"", e)).collect();
+                format!(
+                    r#"{} = {{ version = "{}", extras = [{}] }}"#,
+                    name,
+                    constraints,
+                    extras.join(", ")
+                )
+            }
+            _ => format!(r#"{} = "{}""#, name, constraints),
         }
     }
 
@@ -695,10 +1208,10 @@ impl fmt::Display for Req {
         write!(
             f,
             "{}{} {}{}",
-            Colored::Fg(Color::DarkCyan),
+            util::fg(Color::DarkCyan),
             self.name,
             constraints,
-            Colored::Fg(Color::Reset)
+            util::fg(Color::Reset)
         )
     }
 }
@@ -718,6 +1231,8 @@ pub struct Package {
     pub version: Version,
     pub deps: Vec<(u32, String, Version)>,
     pub rename: Rename,
+    pub markers: Option<String>, // Environment markers this package was required with, if any.
+    pub extras: Vec<String>,     // Extras it's installed with, eg `socks` for `requests[socks]`.
 }
 
 /// Similar to that used by Cargo.lock. Represents an exact package to download. // todo(Although
@@ -733,6 +1248,8 @@ pub struct LockPackage {
     pub source: Option<String>,
     pub dependencies: Option<Vec<String>>,
     pub rename: Option<String>,
+    pub markers: Option<String>,
+    pub extras: Option<Vec<String>>,
 }
 
 /// Modelled after [Cargo.lock](https://doc.rust-lang.org/cargo/guide/cargo-toml-vs-cargo-lock.html)
@@ -957,9 +1474,14 @@ pub mod tests {
             extra: Some("security".into()),
             sys_platform: None,
             python_version: None,
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: Some(Marker::from_str("extra == 'security'").unwrap()),
         };
 
         let actual2 = Req::from_str(
@@ -974,9 +1496,16 @@ pub mod tests {
             extra: Some("test".into()),
             sys_platform: None,
             python_version: Some(Constraint::new(Exact, Version::new(2, 7, 0))),
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: Some(
+                Marker::from_str("extra == \"test\" and ( python_version == \"2.7\")").unwrap(),
+            ),
         };
 
         let actual3 = Req::from_str(
@@ -991,9 +1520,16 @@ pub mod tests {
             extra: None,
             sys_platform: Some((Exact, crate::Os::Windows32)),
             python_version: Some(Constraint::new(Lt, Version::new(3, 6, 0))),
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: Some(
+                Marker::from_str("sys_platform == \"win32\" and python_version < \"3.6\"").unwrap(),
+            ),
         };
 
         let actual4 = Req::from_str("envisage ; extra == 'app'", true).unwrap();
@@ -1005,9 +1541,14 @@ pub mod tests {
             extra: Some("app".into()),
             sys_platform: None,
             python_version: None,
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: Some(Marker::from_str("extra == 'app'").unwrap()),
         };
 
         assert_eq!(actual, expected);
@@ -1031,9 +1572,14 @@ pub mod tests {
             extra: None,
             sys_platform: None,
             python_version: None,
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: None,
         };
 
         let expected2 = Req {
@@ -1045,9 +1591,14 @@ pub mod tests {
             extra: None,
             sys_platform: None,
             python_version: None,
+            implementation_name: None,
             install_with_extras: None,
             path: None,
             git: None,
+            git_ref: None,
+            editable: false,
+            url: None,
+            marker: None,
         };
 
         assert_eq!(actual1, expected1);
@@ -1184,6 +1735,23 @@ pub mod tests {
 
         //        assert_eq!(a._to_pip_string(), "package==3.3.6".to_string());
         assert_eq!(a.to_cfg_string(), r#"package = "3.3.6""#.to_string());
+
+        let mut b = Req::new(
+            "requests".to_string(),
+            vec![Constraint::new(Caret, Version::new(2, 31, 0))],
+        );
+        b.install_with_extras = Some(vec!["socks".into(), "security".into()]);
+        assert_eq!(
+            b.to_cfg_string(),
+            r#"requests = { version = "^2.31.0", extras = ["socks", "security"] }"#
+        );
+
+        let mut c = Req::new("saturn".to_string(), vec![]);
+        c.url = Some("https://example.com/saturn-0.3.4.tar.gz".into());
+        assert_eq!(
+            c.to_cfg_string(),
+            r#"saturn = { url = "https://example.com/saturn-0.3.4.tar.gz" }"#
+        );
     }
 
     #[test]
@@ -1408,4 +1976,151 @@ pub mod tests {
         assert_eq!(a6, vec![Constraint::new(Gte, Version::new(2, 0, 0))]);
         assert_eq!(a7, vec![Constraint::new(Caret, Version::new(2, 7, 0))]);
     }
+
+    #[test]
+    fn markers_roundtrip() {
+        let mut req = Req::new("win-unicode-console".into(), vec![]);
+        req.sys_platform = Some((Exact, crate::Os::Windows32));
+        req.python_version = Some(Constraint::new(Lt, Version::new(3, 6, 0)));
+
+        let markers = req.markers().unwrap();
+        assert_eq!(
+            markers,
+            "sys_platform == \"win32\" and python_version < \"3.6.0\""
+        );
+
+        let marker = Marker::from_str(&markers).unwrap();
+        let applies =
+            |os, v| marker.evaluate(&MarkerEnv::new(os, &v, util::Implementation::CPython));
+        assert!(applies(crate::Os::Windows, Version::new(3, 5, 2)));
+        assert!(!applies(crate::Os::Windows, Version::new(3, 7, 0)));
+        assert!(!applies(crate::Os::Linux, Version::new(3, 5, 2)));
+        assert_eq!(Req::new("numpy".into(), vec![]).markers(), None);
+
+        let mut req = Req::new("uvloop".into(), vec![]);
+        req.sys_platform = Some((Ne, crate::Os::Windows32));
+        let markers = req.markers().unwrap();
+        assert_eq!(markers, "sys_platform != \"win32\"");
+        let marker = Marker::from_str(&markers).unwrap();
+        let applies = |os| {
+            marker.evaluate(&MarkerEnv::new(
+                os,
+                &Version::new(3, 10, 0),
+                util::Implementation::CPython,
+            ))
+        };
+        assert!(applies(crate::Os::Linux));
+        assert!(!applies(crate::Os::Windows));
+
+        // Markers from metadata are kept as they are, apart from `extra`.
+        let req = Req::from_str(
+            "colorama ; extra == 'cli' and (sys_platform == 'win32' or os_name == 'nt')",
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            req.markers().unwrap(),
+            "sys_platform == \"win32\" or os_name == \"nt\""
+        );
+        assert_eq!(
+            Req::from_str("six ; extra == 'a' or python_version < '3'", true)
+                .unwrap()
+                .markers(),
+            None
+        );
+    }
+
+    #[test]
+    fn evaluate_markers() {
+        let mut env = MarkerEnv::new(
+            crate::Os::Linux,
+            &Version::new(3, 10, 4),
+            util::Implementation::CPython,
+        );
+        env.platform_machine = "x86_64".into();
+        env.extras = vec!["Socks".into()];
+        let holds = |m: &str| Marker::from_str(m).unwrap().evaluate(&env);
+
+        assert!(holds(
+            "python_version >= \"3.8\" and sys_platform != 'win32'"
+        ));
+        assert!(holds(
+            "python_version < '3.8' or platform_system == 'Linux'"
+        ));
+        assert!(!holds(
+            "python_version < '3.8' or (os_name == 'nt' and extra == 'socks')"
+        ));
+        // Versions compare as versions, not strings.
+        assert!(holds("python_version > '3.9'"));
+        assert!(holds("python_full_version == '3.10.*'"));
+        assert!(!holds("python_version == '3.1.*'"));
+        assert!(holds("'3.11' > python_version"));
+        assert!(holds("platform_machine in 'x86_64 aarch64'"));
+        assert!(holds("platform_machine not in \"arm64\""));
+        assert!(holds("implementation_name == 'cpython'"));
+        assert!(holds("extra == 'socks'"));
+        assert!(!holds("extra == 'test'"));
+
+        assert!(Marker::from_str("python_version < \"3.8").is_err());
+        assert!(Marker::from_str("platform == 'linux'").is_err());
+    }
+
+    #[test]
+    fn implementation_markers() {
+        let extras = |m| Extras::from_marker(&Marker::from_str(m).unwrap());
+        let not_pypy = extras("implementation_name != \"pypy\"");
+        let pypy = extras("platform_python_implementation == 'PyPy'");
+
+        assert!(implementation_matches(
+            &not_pypy.implementation_name,
+            util::Implementation::CPython
+        ));
+        assert!(!implementation_matches(
+            &not_pypy.implementation_name,
+            util::Implementation::PyPy
+        ));
+        assert!(implementation_matches(
+            &pypy.implementation_name,
+            util::Implementation::PyPy
+        ));
+        assert!(implementation_matches(&None, util::Implementation::PyPy));
+    }
+
+    #[test]
+    fn git_sources_roundtrip() {
+        let commit = "3f1c2a9e8b7d6c5f4e3d2c1b0a9f8e7d6c5b4a39";
+        let url = "https://github.com/org/repo";
+        for git_ref in &[
+            None,
+            Some(GitRef::Branch("main".into())),
+            Some(GitRef::Tag("v1.2.0".into())),
+            Some(GitRef::Rev("3f1c2a9".into())),
+        ] {
+            let source = git_source(url, git_ref.as_ref(), commit);
+            assert_eq!(
+                parse_git_source(&source),
+                Some((url.to_owned(), git_ref.clone(), commit.to_owned()))
+            );
+        }
+        assert_eq!(
+            git_source(url, Some(&GitRef::Tag("v1.2.0".into())), commit),
+            format!("git+{}?tag=v1.2.0#{}", url, commit)
+        );
+        assert_eq!(
+            parse_git_source("pypi+https://pypi.org/pypi/six/1.12.0/json"),
+            None
+        );
+    }
+
+    #[test]
+    fn url_sources_roundtrip() {
+        let url = "https://example.com/saturn-0.3.4-py3-none-any.whl";
+        let source = url_source(url, "ab12");
+        assert_eq!(source, format!("url+{}#sha256=ab12", url));
+        assert_eq!(
+            parse_url_source(&source),
+            Some((url.to_owned(), "ab12".to_owned()))
+        );
+        assert_eq!(parse_url_source(&format!("url+{}", url)), None);
+    }
 }
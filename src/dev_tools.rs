@@ -0,0 +1,144 @@
+//! The test runner, linter, and formatter run by `pyflow test`, `pyflow lint`, and
+//! `pyflow fmt-py`. We use the ones the project's configured; if they're missing, we install
+//! them into a separate tools environment, so they don't change the project's dependencies.
+
+use std::{fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tool {
+    Pytest,
+    Ruff,
+    Flake8,
+    Black,
+    RuffFormat,
+}
+
+impl Tool {
+    /// The package that provides this tool.
+    pub fn package(self) -> &'static str {
+        match self {
+            Self::Pytest => "pytest",
+            Self::Ruff | Self::RuffFormat => "ruff",
+            Self::Flake8 => "flake8",
+            Self::Black => "black",
+        }
+    }
+
+    /// Arguments to pass to Python to run this tool. `args` are from the user; without them,
+    /// formatters run on the whole project.
+    pub fn python_args(self, mut args: Vec<String>) -> Vec<String> {
+        let (module, subcmd, default_path) = match self {
+            Self::Pytest => ("pytest", None, false),
+            Self::Ruff => ("ruff", Some("check"), true),
+            Self::Flake8 => ("flake8", None, false),
+            Self::Black => ("black", None, true),
+            Self::RuffFormat => ("ruff", Some("format"), true),
+        };
+
+        let mut result = vec!["-m".to_owned(), module.to_owned()];
+        if let Some(s) = subcmd {
+            result.push(s.to_owned());
+        }
+        if args.is_empty() && default_path {
+            result.push(".".to_owned());
+        }
+        result.append(&mut args);
+        result
+    }
+}
+
+/// Which tools a project uses, from its config files and dependencies.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Signals {
+    pub ruff: bool,
+    pub flake8: bool,
+    pub black: bool,
+}
+
+fn file_contains(path: &Path, text: &str) -> bool {
+    fs::read_to_string(path)
+        .map(|data| data.contains(text))
+        .unwrap_or(false)
+}
+
+/// Look for tool config in the project directory, and for the tools among its dependencies.
+pub fn find_signals(proj_path: &Path, dep_names: &[String]) -> Signals {
+    let has_dep = |name: &str| {
+        dep_names
+            .iter()
+            .any(|d| crate::util::compare_names(d, name))
+    };
+    let pyproject = proj_path.join("pyproject.toml");
+
+    Signals {
+        ruff: has_dep("ruff")
+            || file_contains(&pyproject, "[tool.ruff")
+            || proj_path.join("ruff.toml").exists()
+            || proj_path.join(".ruff.toml").exists(),
+        flake8: has_dep("flake8")
+            || proj_path.join(".flake8").exists()
+            || file_contains(&proj_path.join("setup.cfg"), "[flake8]")
+            || file_contains(&proj_path.join("tox.ini"), "[flake8]"),
+        black: has_dep("black") || file_contains(&pyproject, "[tool.black"),
+    }
+}
+
+/// Ruff, unless the project only uses flake8.
+pub fn choose_linter(signals: Signals) -> Tool {
+    if signals.flake8 && !signals.ruff {
+        Tool::Flake8
+    } else {
+        Tool::Ruff
+    }
+}
+
+/// Black, unless the project only uses Ruff.
+pub fn choose_formatter(signals: Signals) -> Tool {
+    if signals.ruff && !signals.black {
+        Tool::RuffFormat
+    } else {
+        Tool::Black
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_choice() {
+        let none = Signals::default();
+        let ruff = Signals {
+            ruff: true,
+            ..Default::default()
+        };
+        let flake8_black = Signals {
+            flake8: true,
+            black: true,
+            ..Default::default()
+        };
+
+        assert_eq!(choose_linter(none), Tool::Ruff);
+        assert_eq!(choose_linter(ruff), Tool::Ruff);
+        assert_eq!(choose_linter(flake8_black), Tool::Flake8);
+        assert_eq!(choose_formatter(none), Tool::Black);
+        assert_eq!(choose_formatter(ruff), Tool::RuffFormat);
+        assert_eq!(choose_formatter(flake8_black), Tool::Black);
+    }
+
+    #[test]
+    fn tool_args() {
+        assert_eq!(
+            Tool::Pytest.python_args(vec!["-x".into()]),
+            vec!["-m", "pytest", "-x"]
+        );
+        assert_eq!(
+            Tool::RuffFormat.python_args(vec![]),
+            vec!["-m", "ruff", "format", "."]
+        );
+        assert_eq!(
+            Tool::Black.python_args(vec!["src".into()]),
+            vec!["-m", "black", "src"]
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! `pyflow devcontainer init`: a `.devcontainer/devcontainer.json` that sets up the project's
+//! environment when the container's created, eg in VS Code or GitHub Codespaces. Pyflow's
+//! directory is a named volume, so Python installs and downloaded packages survive rebuilds.
+
+use crate::{dep_types::Version, paths, util};
+use crossterm::Color;
+use serde_json::{json, Value};
+use std::{fs, path::Path};
+
+/// Where pyflow's directory is mounted in the container.
+const HOME_MOUNT: &str = "/pyflow";
+
+/// The `devcontainer.json` for a project. It uses the devcontainer Python image for the
+/// project's version, if it has one, so pyflow can use the image's Python instead of
+/// downloading one.
+fn config(name: &str, py_version: Option<&Version>) -> Value {
+    let tag = match py_version {
+        Some(v) => format!("{}.{}", v.major, v.minor),
+        None => "3".into(),
+    };
+    json!({
+        "name": name,
+        "image": format!("mcr.microsoft.com/devcontainers/python:{}", tag),
+        "containerEnv": { paths::HOME_VAR: HOME_MOUNT },
+        "mounts": [format!("source=pyflow-home,target={},type=volume", HOME_MOUNT)],
+        // Volumes are created owned by root.
+        "onCreateCommand": format!(
+            "sudo chown -R \"$(id -u)\" {} && (command -v pyflow >/dev/null || pip install --user pyflow)",
+            HOME_MOUNT
+        ),
+        "postCreateCommand": "pyflow sync",
+    })
+}
+
+/// Write `.devcontainer/devcontainer.json` in the project.
+pub fn init(proj_path: &Path, name: &str, py_version: Option<&Version>) {
+    let path = proj_path.join(".devcontainer").join("devcontainer.json");
+    if path.exists() {
+        util::abort(&format!(
+            "{:?} already exists. Remove it to make a new one.",
+            path
+        ));
+    }
+    let text = serde_json::to_string_pretty(&config(name, py_version)).unwrap();
+    if fs::create_dir_all(path.parent().unwrap()).is_err()
+        || fs::write(&path, format!("{}\n", text)).is_err()
+    {
+        util::abort(&format!("Problem writing {:?}", path));
+    }
+    util::print_color(&format!("Created {:?}", path), Color::Green);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn config_for_version() {
+        let cfg = config("saturn", Some(&Version::new_short(3, 12)));
+        assert_eq!(cfg["image"], "mcr.microsoft.com/devcontainers/python:3.12");
+        assert_eq!(cfg["containerEnv"]["PYFLOW_HOME"], HOME_MOUNT);
+        assert_eq!(cfg["postCreateCommand"], "pyflow sync");
+
+        assert_eq!(
+            config("saturn", None)["image"],
+            "mcr.microsoft.com/devcontainers/python:3"
+        );
+    }
+}
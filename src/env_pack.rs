@@ -0,0 +1,427 @@
+//! `pyflow env pack` and `pyflow env unpack`: ship a synced environment to a machine without
+//! index access, with the same OS and architecture. The archive has the environment, eg
+//! `__pypackages__/3.12`, and the Python install it uses, if pyflow installed it. Environments
+//! store absolute paths, eg in script shebangs and `pyvenv.cfg`, so they're rewritten on unpack.
+
+use crate::{py_versions, util};
+use crossterm::Color;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+const MANIFEST: &str = "pyflow-env.toml";
+const ENV_DIR: &str = "env";
+const PYTHON_DIR: &str = "python";
+
+/// Left out of archives, since they're only relevant to where the environment was built.
+const SKIPPED: &[&str] = &["build-logs"];
+
+/// Where `pyflow-env.toml` says the archive came from.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Manifest {
+    /// Eg `3.12`, the environment's folder in `__pypackages__`.
+    python_version: String,
+    os: String,
+    arch: String,
+    /// Where the environment was.
+    env_path: String,
+    /// Where the Python install it uses was, if it's included.
+    python_root: Option<String>,
+}
+
+/// The Python install an environment uses, if pyflow manages it. Others, eg the system's, are
+/// expected to be on the target machine already.
+fn managed_python_root(vers_path: &Path, pyflow_path: &Path) -> Option<PathBuf> {
+    let home = py_versions::env_home(vers_path)?;
+    // Eg `~/.local/share/pyflow/python-3.12.7/bin`, or on Windows, the install itself.
+    let root = if home.ends_with("bin") {
+        home.parent()?.to_owned()
+    } else {
+        home
+    };
+    let pyflow_path = fs::canonicalize(pyflow_path).ok()?;
+    if root.starts_with(&pyflow_path) && root != pyflow_path {
+        Some(root)
+    } else {
+        None
+    }
+}
+
+fn add_dir<W: io::Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &Path,
+    name: &Path,
+    top: bool,
+) -> io::Result<()> {
+    builder.append_dir(name, dir)?;
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let filename = path.file_name().unwrap();
+        if top && SKIPPED.iter().any(|s| filename == *s) {
+            continue;
+        }
+        if fs::symlink_metadata(&path)?.is_dir() {
+            add_dir(builder, &path, &name.join(filename), false)?;
+        } else {
+            builder.append_path_with_name(&path, name.join(filename))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_archive(
+    vers_path: &Path,
+    python_root: Option<&Path>,
+    manifest: &Manifest,
+    output: &Path,
+) -> io::Result<()> {
+    let encoder = GzEncoder::new(fs::File::create(output)?, Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    // Keep symlinks, eg the environment's `python`, so they can be pointed at the new install.
+    builder.follow_symlinks(false);
+
+    let text = toml::to_string(manifest).expect("Problem serializing the manifest");
+    let mut header = tar::Header::new_gnu();
+    header.set_size(text.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, text.as_bytes())?;
+
+    add_dir(&mut builder, vers_path, Path::new(ENV_DIR), true)?;
+    if let Some(root) = python_root {
+        let name = Path::new(PYTHON_DIR).join(root.file_name().unwrap());
+        add_dir(&mut builder, root, &name, false)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// The default archive name, eg `saturn-env-3.12-linux-x86_64.tar.gz`.
+pub fn default_path(proj_path: &Path, name: &str, python_version: &str) -> PathBuf {
+    proj_path.join(format!(
+        "{}-env-{}-{}-{}.tar.gz",
+        name,
+        python_version,
+        env::consts::OS,
+        env::consts::ARCH
+    ))
+}
+
+/// `pyflow env pack`.
+pub fn pack(vers_path: &Path, pyflow_path: &Path, output: &Path) {
+    if !vers_path.join(".venv").exists() {
+        util::abort(&format!(
+            "There's no environment at {:?} yet. Run `pyflow sync` first.",
+            vers_path
+        ));
+    }
+    let env_path = fs::canonicalize(vers_path).unwrap_or_else(|_| vers_path.to_owned());
+    let python_root = managed_python_root(vers_path, pyflow_path);
+    let manifest = Manifest {
+        python_version: vers_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned(),
+        os: env::consts::OS.into(),
+        arch: env::consts::ARCH.into(),
+        env_path: env_path.to_string_lossy().into_owned(),
+        python_root: python_root
+            .as_ref()
+            .map(|r| r.to_string_lossy().into_owned()),
+    };
+
+    if let Err(e) = write_archive(vers_path, python_root.as_deref(), &manifest, output) {
+        util::abort(&format!("Problem writing {:?}: {}", output, e));
+    }
+    if python_root.is_none() {
+        util::print_color(
+            "The environment's Python wasn't installed by pyflow, so it isn't included. \
+             Install the same version at the same path where it's unpacked.",
+            Color::DarkYellow,
+        );
+    }
+    util::print_color(
+        &format!("Packed the environment into {:?}", output),
+        Color::Green,
+    );
+}
+
+/// Where an archive entry goes, eg `env/lib/six.py` to `lib/six.py` in `dest`. `None` if it's
+/// not under `prefix`, or tries to escape it.
+fn entry_target(path: &Path, prefix: &Path, dest: &Path) -> Option<PathBuf> {
+    let rel = path.strip_prefix(prefix).ok()?;
+    if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(dest.join(rel))
+    } else {
+        None
+    }
+}
+
+/// Replace paths from where the archive was packed with where it's unpacked.
+fn rewrite(text: &str, moves: &[(String, String)]) -> String {
+    let mut result = text.to_owned();
+    for (old, new) in moves {
+        result = result.replace(old.as_str(), new);
+    }
+    result
+}
+
+/// Point files that refer to the old locations at the new ones: `pyvenv.cfg`, and scripts in
+/// the environment's `bin` folders, eg their shebangs, and `activate`. Symlinks to the old
+/// Python install are recreated.
+fn relocate(dest: &Path, moves: &[(String, String)]) -> io::Result<()> {
+    let cfg_path = dest.join(".venv").join("pyvenv.cfg");
+    if let Ok(text) = fs::read_to_string(&cfg_path) {
+        fs::write(&cfg_path, rewrite(&text, moves))?;
+    }
+
+    for dir in &[
+        dest.join(".venv").join("bin"),
+        dest.join(".venv").join("Scripts"),
+        dest.join("bin"),
+    ] {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+            if let Ok(target) = fs::read_link(&path) {
+                let target = target.to_string_lossy();
+                let new_target = rewrite(&target, moves);
+                if new_target != target {
+                    fs::remove_file(&path)?;
+                    #[cfg(unix)]
+                    std::os::unix::fs::symlink(&new_target, &path)?;
+                    #[cfg(windows)]
+                    std::os::windows::fs::symlink_file(&new_target, &path)?;
+                }
+                continue;
+            }
+            // Binaries, eg on Windows, aren't valid UTF-8, and are left alone.
+            if let Ok(text) = fs::read_to_string(&path) {
+                let new_text = rewrite(&text, moves);
+                if new_text != text {
+                    fs::write(&path, new_text)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unpack an archive's environment into `pypackages_path`, and its Python install, if any, into
+/// `python_parent`, unless there's one there already. Returns its manifest, and where the
+/// environment went.
+fn unpack_archive(
+    archive: &Path,
+    pypackages_path: &Path,
+    python_parent: &Path,
+) -> Result<(Manifest, PathBuf), String> {
+    let file = fs::File::open(archive).map_err(|e| format!("Can't open {:?}: {}", archive, e))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let mut entries = tar.entries().map_err(|e| e.to_string())?;
+
+    let not_packed = || format!("{:?} isn't an environment from `pyflow env pack`", archive);
+    let mut first = entries.next().and_then(Result::ok).ok_or_else(not_packed)?;
+    if first.path().map_err(|e| e.to_string())? != Path::new(MANIFEST) {
+        return Err(not_packed());
+    }
+    let mut text = String::new();
+    io::Read::read_to_string(&mut first, &mut text).map_err(|e| e.to_string())?;
+    let manifest: Manifest = toml::from_str(&text).map_err(|_| not_packed())?;
+
+    if manifest.os != env::consts::OS || manifest.arch != env::consts::ARCH {
+        return Err(format!(
+            "This environment was packed on {} {}, so it can't be used on {} {}",
+            manifest.os,
+            manifest.arch,
+            env::consts::OS,
+            env::consts::ARCH
+        ));
+    }
+    let dest = pypackages_path.join(&manifest.python_version);
+    if dest.exists() {
+        return Err(format!(
+            "{:?} already exists. Remove it first, eg with `pyflow reset`.",
+            dest
+        ));
+    }
+
+    let python_prefix = manifest
+        .python_root
+        .as_ref()
+        .map(|r| Path::new(PYTHON_DIR).join(Path::new(r).file_name().unwrap_or_default()));
+    let python_dest = python_prefix
+        .as_ref()
+        .map(|p| python_parent.join(p.file_name().unwrap_or_default()));
+    let install_python = python_dest.as_ref().is_some_and(|p| !p.exists());
+
+    let extracted = entries.try_for_each(|entry| {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let target = match (&python_prefix, &python_dest) {
+            (Some(prefix), Some(python_dest)) if path.starts_with(prefix) => {
+                if !install_python {
+                    return Ok(());
+                }
+                entry_target(&path, prefix, python_dest)
+            }
+            _ => entry_target(&path, Path::new(ENV_DIR), &dest),
+        };
+        let target = match target {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        entry
+            .unpack(&target)
+            .map(|_| ())
+            .map_err(|e| format!("Problem unpacking {:?}: {}", target, e))
+    });
+    if let Err(e) = extracted {
+        // Don't leave a partial environment behind, which would look synced.
+        fs::remove_dir_all(&dest).ok();
+        return Err(e);
+    }
+
+    let new_env = fs::canonicalize(&dest).unwrap_or_else(|_| dest.clone());
+    let mut moves = vec![(
+        manifest.env_path.clone(),
+        new_env.to_string_lossy().into_owned(),
+    )];
+    if let (Some(old), Some(new)) = (&manifest.python_root, &python_dest) {
+        let new = fs::canonicalize(new).unwrap_or_else(|_| new.to_owned());
+        moves.push((old.clone(), new.to_string_lossy().into_owned()));
+    }
+    relocate(&dest, &moves).map_err(|e| format!("Problem updating paths in {:?}: {}", dest, e))?;
+    Ok((manifest, dest))
+}
+
+/// `pyflow env unpack`: unpack into the project's `__pypackages__`.
+pub fn unpack(archive: &Path, pypackages_path: &Path, pyflow_path: &Path) {
+    match unpack_archive(archive, pypackages_path, pyflow_path) {
+        Ok((manifest, dest)) => {
+            py_versions::register_env(pyflow_path, &dest);
+            if manifest.python_root.is_none() {
+                util::print_color(
+                    "The archive doesn't include a Python install; the environment uses the one \
+                     it was packed with, at the same path.",
+                    Color::DarkYellow,
+                );
+            }
+            util::print_color(
+                &format!(
+                    "Unpacked the Python {} environment into {:?}",
+                    manifest.python_version, dest
+                ),
+                Color::Green,
+            );
+        }
+        Err(e) => util::abort(&e),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_entries_stay_in_place() {
+        let dest = Path::new("/proj/__pypackages__/3.12");
+        assert_eq!(
+            entry_target(Path::new("env/lib/six.py"), Path::new(ENV_DIR), dest),
+            Some(dest.join("lib/six.py"))
+        );
+        assert_eq!(
+            entry_target(Path::new("env/../../etc/passwd"), Path::new(ENV_DIR), dest),
+            None
+        );
+        assert_eq!(
+            entry_target(Path::new("python/x"), Path::new(ENV_DIR), dest),
+            None
+        );
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let dir = util::TempDir::new("env-pack");
+        let root = dir.path();
+        let old_pyflow = root.join("old-pyflow");
+        let python = old_pyflow.join("python-3.12.7");
+        fs::create_dir_all(python.join("bin")).unwrap();
+        fs::write(python.join("bin").join("python3.12"), "binary").unwrap();
+        let python = fs::canonicalize(&python).unwrap();
+
+        let vers_path = root.join("old").join("__pypackages__").join("3.12");
+        fs::create_dir_all(vers_path.join(".venv").join("bin")).unwrap();
+        fs::create_dir_all(vers_path.join("lib")).unwrap();
+        fs::create_dir_all(vers_path.join("bin")).unwrap();
+        fs::create_dir_all(vers_path.join("build-logs")).unwrap();
+        fs::write(vers_path.join("lib").join("six.py"), "import sys").unwrap();
+        fs::write(vers_path.join("build-logs").join("numpy.log"), "").unwrap();
+        fs::write(
+            vers_path.join(".venv").join("pyvenv.cfg"),
+            format!("home = {}\n", python.join("bin").display()),
+        )
+        .unwrap();
+        let old_env = fs::canonicalize(&vers_path).unwrap();
+        fs::write(
+            vers_path.join("bin").join("black"),
+            format!("#!{}/.venv/bin/python\nimport black\n", old_env.display()),
+        )
+        .unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            python.join("bin").join("python3.12"),
+            vers_path.join(".venv").join("bin").join("python"),
+        )
+        .unwrap();
+
+        let archive = root.join("env.tar.gz");
+        pack(&vers_path, &old_pyflow, &archive);
+
+        let new_pyflow = root.join("new-pyflow");
+        fs::create_dir_all(&new_pyflow).unwrap();
+        let pypackages = root.join("new").join("__pypackages__");
+        let (manifest, dest) = unpack_archive(&archive, &pypackages, &new_pyflow).unwrap();
+        assert_eq!(manifest.python_version, "3.12");
+        assert_eq!(dest, pypackages.join("3.12"));
+
+        let new_env = fs::canonicalize(&dest).unwrap();
+        let new_python = fs::canonicalize(new_pyflow.join("python-3.12.7")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("lib").join("six.py")).unwrap(),
+            "import sys"
+        );
+        assert!(!dest.join("build-logs").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("bin").join("black")).unwrap(),
+            format!("#!{}/.venv/bin/python\nimport black\n", new_env.display())
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join(".venv").join("pyvenv.cfg")).unwrap(),
+            format!("home = {}\n", new_python.join("bin").display())
+        );
+        assert!(new_python.join("bin").join("python3.12").exists());
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(dest.join(".venv").join("bin").join("python")).unwrap(),
+            new_python.join("bin").join("python3.12")
+        );
+
+        // It won't overwrite an environment.
+        assert!(unpack_archive(&archive, &pypackages, &new_pyflow)
+            .unwrap_err()
+            .contains("already exists"));
+    }
+}
@@ -0,0 +1,419 @@
+//! Environment variables required by a project's scripts, from `[tool.pyflow.env-vars]`.
+
+use crate::util::{self, abort};
+use crossterm::Color;
+use serde::Deserialize;
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// An environment variable a project's scripts need. If it has no default or source, it must be
+/// set before running them. Secrets aren't displayed. If `tasks` is empty, it's for all scripts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct EnvVar {
+    pub name: String,
+    pub default: Option<String>,
+    pub secret: bool,
+    pub from: Option<Source>,
+    pub tasks: Vec<String>,
+}
+
+/// Where to read a variable's value from when it isn't set, eg a secret kept out of
+/// `pyproject.toml`. Paths are relative to the project.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub enum Source {
+    /// `file:path`: The whole file, eg a mounted Docker or Kubernetes secret.
+    File(PathBuf),
+    /// `env-file:path`: The variable's entry in a dotenv-style file.
+    EnvFile(PathBuf),
+    /// `cmd:command`: The output of a shell command, eg `cmd:pass show api-token`, for a
+    /// secret store.
+    Command(String),
+}
+
+impl Source {
+    pub fn from_str(s: &str) -> Option<Self> {
+        s.strip_prefix("file:")
+            .map(|path| Self::File(path.into()))
+            .or_else(|| s.strip_prefix("env-file:").map(|p| Self::EnvFile(p.into())))
+            .or_else(|| s.strip_prefix("cmd:").map(|c| Self::Command(c.to_owned())))
+    }
+
+    /// Read the value for the variable `name`. Returns `None` if the file, entry, or command
+    /// output isn't available.
+    fn read(&self, name: &str, proj_path: &Path) -> Option<String> {
+        match self {
+            Self::File(path) => fs::read_to_string(proj_path.join(path))
+                .ok()
+                .map(|d| d.trim_end_matches(&['\r', '\n'][..]).to_owned()),
+            Self::EnvFile(path) => fs::read_to_string(proj_path.join(path))
+                .ok()?
+                .lines()
+                .filter_map(parse_env_line)
+                .find(|(key, _)| key == name)
+                .map(|(_, val)| val),
+            Self::Command(cmd) => {
+                let (shell, flag) = if cfg!(windows) {
+                    ("cmd", "/C")
+                } else {
+                    ("sh", "-c")
+                };
+                let output = Command::new(shell)
+                    .arg(flag)
+                    .arg(cmd)
+                    .current_dir(proj_path)
+                    .output()
+                    .ok()?;
+                if !output.status.success() {
+                    return None;
+                }
+                Some(
+                    String::from_utf8_lossy(&output.stdout)
+                        .trim_end_matches(&['\r', '\n'][..])
+                        .to_owned(),
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::EnvFile(path) => write!(f, "env-file:{}", path.display()),
+            Self::Command(cmd) => write!(f, "cmd:{}", cmd),
+        }
+    }
+}
+
+impl EnvVar {
+    /// Whether the script or task `task` uses this variable.
+    fn applies_to(&self, task: &str) -> bool {
+        self.tasks.is_empty() || self.tasks.iter().any(|t| t == task)
+    }
+}
+
+/// Parse a line of a dotenv-style file, eg `KEY=value`, `export KEY="value"`. Returns `None` for
+/// blank lines and comments.
+fn parse_env_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let line = line.trim_start_matches("export ").trim_start();
+
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next()?.trim();
+    let val = parts.next()?.trim();
+    if key.is_empty() {
+        return None;
+    }
+
+    let val = if val.len() >= 2
+        && ((val.starts_with('"') && val.ends_with('"'))
+            || (val.starts_with('\'') && val.ends_with('\'')))
+    {
+        &val[1..val.len() - 1]
+    } else {
+        val
+    };
+
+    Some((key.to_owned(), val.to_owned()))
+}
+
+/// Set variables from a dotenv-style file, as passed to `--with-env`. Variables already set
+/// in the environment take precedence.
+pub fn load_env_file(path: &Path) {
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(_) => {
+            abort(&format!("Problem reading the environment file {:?}", path));
+            unreachable!()
+        }
+    };
+
+    for (key, val) in data.lines().filter_map(parse_env_line) {
+        if env::var_os(&key).is_none() {
+            env::set_var(key, val);
+        }
+    }
+}
+
+/// Find values for variables that aren't set, from their source or default. Returns the
+/// values found, and the required variables that have neither.
+fn resolve<'a>(
+    vars: &[&'a EnvVar],
+    is_set: impl Fn(&str) -> bool,
+    read: impl Fn(&Source, &str) -> Option<String>,
+) -> (Vec<(&'a str, String)>, Vec<&'a EnvVar>) {
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for var in vars.iter().filter(|v| !is_set(&v.name)) {
+        let val = var
+            .from
+            .as_ref()
+            .and_then(|src| read(src, &var.name))
+            .or_else(|| var.default.clone());
+        match val {
+            Some(v) => found.push((var.name.as_str(), v)),
+            None => missing.push(*var),
+        }
+    }
+    (found, missing)
+}
+
+/// The variables that aren't set, and would be read from a command's output.
+fn from_commands<'a>(vars: &[&'a EnvVar], is_set: impl Fn(&str) -> bool) -> Vec<&'a EnvVar> {
+    vars.iter()
+        .filter(|v| !is_set(&v.name) && matches!(v.from, Some(Source::Command(_))))
+        .copied()
+        .collect()
+}
+
+fn is_set(name: &str) -> bool {
+    env::var_os(name).is_some()
+}
+
+fn missing_msg(missing: &[&EnvVar]) -> String {
+    let names: Vec<String> = missing
+        .iter()
+        .map(|v| match &v.from {
+            Some(src) => format!("{} (from {})", v.name, src),
+            None => v.name.clone(),
+        })
+        .collect();
+    names.join(", ")
+}
+
+/// Check that the variables the script or task `task` uses are present before running it,
+/// and set ones that aren't from their source or default. `cmd:` sources come from the project,
+/// so they're only run with `allow_commands`, from `allow-env-commands` in the global config.
+pub fn apply(vars: &[EnvVar], task: &str, proj_path: &Path, allow_commands: bool) {
+    let scoped: Vec<&EnvVar> = vars.iter().filter(|v| v.applies_to(task)).collect();
+    let commands = from_commands(&scoped, is_set);
+    if !allow_commands && !commands.is_empty() {
+        abort(&format!(
+            "`{}` needs environment variables read from commands in `pyproject.toml`: {}. Set \
+             them, or to let pyflow run these commands, set `allow-env-commands = true` in \
+             pyflow's global `config.toml`.",
+            task,
+            missing_msg(&commands)
+        ));
+    }
+    let (found, missing) = resolve(&scoped, is_set, |src, name| src.read(name, proj_path));
+    if !missing.is_empty() {
+        abort(&format!(
+            "Missing required environment variables for `{}`: {}. Set them, or pass a file \
+             with `--with-env`.",
+            task,
+            missing_msg(&missing)
+        ));
+    }
+
+    for (name, val) in found {
+        env::set_var(name, val);
+    }
+}
+
+/// Display the variables this project's scripts use, and where their values come from. With
+/// `check`, exit with an error if any required ones are missing; for use in CI. Commands aren't
+/// run, so variables read from them are reported as unchecked.
+pub fn show(vars: &[EnvVar], check: bool, proj_path: &Path) {
+    if vars.is_empty() {
+        util::print_color(
+            "No environment variables are specified in `pyproject.toml`",
+            Color::DarkBlue,
+        );
+        return;
+    }
+
+    for var in vars {
+        let value = match env::var(&var.name) {
+            Ok(_) if var.secret => "(set, secret)".to_owned(),
+            Ok(v) => format!("\"{}\"", v),
+            Err(_) => match (&var.from, &var.default) {
+                (Some(src), _) if var.secret => format!("(from {}, secret)", src),
+                (Some(src), _) => format!("(from {})", src),
+                (None, Some(_)) if var.secret => "(default, secret)".to_owned(),
+                (None, Some(d)) => format!("\"{}\" (default)", d),
+                (None, None) => {
+                    format!("{}missing{}", util::fg(Color::Red), util::fg(Color::Reset))
+                }
+            },
+        };
+        let scope = if var.tasks.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", var.tasks.join(", "))
+        };
+        println!(
+            "{}{}{} = {}{}",
+            util::fg(Color::Cyan),
+            var.name,
+            util::fg(Color::Reset),
+            value,
+            scope
+        );
+    }
+
+    if check {
+        // Read sources too, so CI catches a secret that isn't available.
+        let all: Vec<&EnvVar> = vars.iter().collect();
+        let unchecked = from_commands(&all, is_set);
+        let rest: Vec<&EnvVar> = all.into_iter().filter(|v| !unchecked.contains(v)).collect();
+        let (_, missing) = resolve(&rest, is_set, |src, name| src.read(name, proj_path));
+        if !unchecked.is_empty() {
+            util::print_color(
+                &format!(
+                    "Not checked, since that means running commands: {}",
+                    missing_msg(&unchecked)
+                ),
+                Color::DarkYellow,
+            );
+        }
+        if missing.is_empty() {
+            util::print_color("All required environment variables are set", Color::Green);
+        } else {
+            abort(&format!(
+                "Required environment variables are missing: {}",
+                missing_msg(&missing)
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn env_lines() {
+        assert_eq!(
+            parse_env_line("DATABASE_URL=postgres://localhost/db"),
+            Some(("DATABASE_URL".into(), "postgres://localhost/db".into()))
+        );
+        assert_eq!(
+            parse_env_line("export LOG_LEVEL = \"debug\""),
+            Some(("LOG_LEVEL".into(), "debug".into()))
+        );
+        assert_eq!(
+            parse_env_line("TOKEN='a=b'"),
+            Some(("TOKEN".into(), "a=b".into()))
+        );
+        assert_eq!(parse_env_line("# A comment"), None);
+        assert_eq!(parse_env_line("   "), None);
+        assert_eq!(parse_env_line("NO_EQUALS"), None);
+    }
+
+    #[test]
+    fn missing_vars() {
+        let vars = [
+            EnvVar {
+                name: "DATABASE_URL".into(),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "LOG_LEVEL".into(),
+                default: Some("info".into()),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "API_TOKEN".into(),
+                secret: true,
+                ..Default::default()
+            },
+        ];
+
+        let all: Vec<&EnvVar> = vars.iter().collect();
+        let (found, missing) = resolve(&all, |name| name == "API_TOKEN", |_, _| None);
+        assert_eq!(found, vec![("LOG_LEVEL", "info".to_owned())]);
+        assert_eq!(missing, vec![&vars[0]]);
+    }
+
+    #[test]
+    fn sources_and_scopes() {
+        let vars = [
+            EnvVar {
+                name: "API_TOKEN".into(),
+                secret: true,
+                from: Source::from_str("env-file:.env.secrets"),
+                ..Default::default()
+            },
+            EnvVar {
+                name: "DEPLOY_KEY".into(),
+                secret: true,
+                from: Source::from_str("cmd:pass show deploy-key"),
+                tasks: vec!["deploy".into()],
+                ..Default::default()
+            },
+            EnvVar {
+                name: "SIGNING_KEY".into(),
+                from: Source::from_str("file:secrets/signing-key"),
+                default: Some("unsigned".into()),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            vars[0].from,
+            Some(Source::EnvFile(PathBuf::from(".env.secrets")))
+        );
+        assert_eq!(Source::from_str("vault:token"), None);
+
+        // Only `deploy` needs the deploy key.
+        let scoped: Vec<&EnvVar> = vars.iter().filter(|v| v.applies_to("test")).collect();
+        assert_eq!(scoped, vec![&vars[0], &vars[2]]);
+
+        // A source that can't be read falls back to the default.
+        let (found, missing) = resolve(
+            &scoped,
+            |_| false,
+            |src, _| match src {
+                Source::EnvFile(_) => Some("abc123".into()),
+                _ => None,
+            },
+        );
+        assert_eq!(
+            found,
+            vec![
+                ("API_TOKEN", "abc123".to_owned()),
+                ("SIGNING_KEY", "unsigned".to_owned())
+            ]
+        );
+        assert!(missing.is_empty());
+
+        let all: Vec<&EnvVar> = vars.iter().collect();
+        let (_, missing) = resolve(&all, |_| false, |_, _| None);
+        assert_eq!(missing, vec![&vars[0], &vars[1]]);
+
+        // Only variables that aren't set would run their command.
+        assert_eq!(from_commands(&all, |_| false), vec![&vars[1]]);
+        assert!(from_commands(&all, |name| name == "DEPLOY_KEY").is_empty());
+    }
+
+    #[test]
+    fn read_sources() {
+        let dir = util::TempDir::new("env-vars");
+        fs::write(dir.path().join("token"), "abc123\n").unwrap();
+        fs::write(dir.path().join(".env.secrets"), "API_TOKEN=\"xyz\"\n").unwrap();
+
+        assert_eq!(
+            Source::File("token".into()).read("TOKEN", dir.path()),
+            Some("abc123".into())
+        );
+        assert_eq!(
+            Source::EnvFile(".env.secrets".into()).read("API_TOKEN", dir.path()),
+            Some("xyz".into())
+        );
+        assert_eq!(
+            Source::EnvFile(".env.secrets".into()).read("OTHER", dir.path()),
+            None
+        );
+        assert_eq!(
+            Source::File("absent".into()).read("TOKEN", dir.path()),
+            None
+        );
+    }
+}
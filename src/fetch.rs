@@ -0,0 +1,138 @@
+//! `pyflow fetch`: download the files the lock needs for a set of platforms and Python versions
+//! into the cache, without installing them, eg so a nightly job can warm a CI cache for each
+//! combination in a build matrix. `pyflow install` then finds them there.
+
+use crate::{install, net, util};
+use std::{fs, path::Path};
+
+/// A package file to download.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Artifact {
+    pub filename: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+/// Add an artifact, unless one with the same filename is there already: targets often share
+/// files, eg pure-Python wheels, and source archives.
+pub fn add(artifacts: &mut Vec<Artifact>, artifact: Artifact) {
+    if !artifacts.iter().any(|a| a.filename == artifact.filename) {
+        artifacts.push(artifact);
+    }
+}
+
+/// Whether the cache has the file already, with the published hash.
+fn is_cached(path: &Path, sha256: &str) -> bool {
+    path.exists() && install::file_sha256(path).is_ok_and(|h| h.eq_ignore_ascii_case(sha256))
+}
+
+/// Download an artifact into the cache. It's written to a temporary file first, so an interrupted
+/// download isn't mistaken for a cached one.
+fn download(net: &net::Settings, artifact: &Artifact, dest: &Path) -> Result<(), String> {
+    let resp = net::get(net, &artifact.url).map_err(|e| e.to_string())?;
+    let len = resp.content_length();
+    let partial = dest.with_file_name(format!("{}.part", artifact.filename));
+    let file = fs::File::create(&partial).map_err(|e| e.to_string())?;
+
+    let hash = install::HashingReader::new(util::Progress::new(resp, 0, len), Some(file)).finish();
+    let result = match hash {
+        Ok(h) if h.eq_ignore_ascii_case(&artifact.sha256) => {
+            fs::rename(&partial, dest).map_err(|e| e.to_string())
+        }
+        Ok(h) => Err(format!(
+            "its hash, {}, doesn't match the published one, {}",
+            h, artifact.sha256
+        )),
+        Err(e) => Err(e.to_string()),
+    };
+    if result.is_err() {
+        fs::remove_file(&partial).ok();
+    }
+    result
+}
+
+/// What `fetch` did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub downloaded: usize,
+    pub cached: usize,
+    /// Filenames, and what went wrong.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Download the artifacts the cache doesn't have yet. Cached files whose hashes don't match are
+/// downloaded again.
+pub fn fetch(net: &net::Settings, artifacts: &[Artifact], cache: &Path) -> Summary {
+    let mut summary = Summary::default();
+    if let Err(e) = fs::create_dir_all(cache) {
+        util::abort(&format!("Problem creating the cache directory: {}", e));
+    }
+    for artifact in artifacts {
+        let dest = cache.join(&artifact.filename);
+        if is_cached(&dest, &artifact.sha256) {
+            summary.cached += 1;
+            continue;
+        }
+        println!("⬇ {}", artifact.filename);
+        match download(net, artifact, &dest) {
+            Ok(()) => summary.downloaded += 1,
+            Err(e) => summary.failed.push((artifact.filename.clone(), e)),
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn artifact(filename: &str, sha256: &str) -> Artifact {
+        Artifact {
+            filename: filename.into(),
+            url: format!("https://files.pythonhosted.org/packages/{}", filename),
+            sha256: sha256.into(),
+        }
+    }
+
+    #[test]
+    fn targets_share_artifacts() {
+        let mut artifacts = vec![];
+        add(
+            &mut artifacts,
+            artifact("six-1.16.0-py2.py3-none-any.whl", "a"),
+        );
+        add(
+            &mut artifacts,
+            artifact("six-1.16.0-py2.py3-none-any.whl", "a"),
+        );
+        add(&mut artifacts, artifact("six-1.16.0.tar.gz", "b"));
+        assert_eq!(artifacts.len(), 2);
+    }
+
+    #[test]
+    fn skips_cached_files() {
+        let dir = util::TempDir::new("fetch");
+        let cache = dir.path();
+        let filename = "six-1.16.0-py2.py3-none-any.whl";
+        fs::write(cache.join(filename), b"wheel").unwrap();
+        let sha256 = install::file_sha256(&cache.join(filename)).unwrap();
+
+        let summary = fetch(
+            &net::Settings::default(),
+            &[artifact(filename, &sha256.to_uppercase())],
+            cache,
+        );
+        assert_eq!(
+            summary,
+            Summary {
+                downloaded: 0,
+                cached: 1,
+                failed: vec![]
+            }
+        );
+
+        // One that doesn't match is downloaded again.
+        assert!(!is_cached(&cache.join(filename), "0000"));
+        assert!(!is_cached(&cache.join("six-1.16.0.tar.gz"), &sha256));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     dep_types::{Req, Version},
-    util, Config,
+    net, policy, util, Config,
 };
 use crossterm::Color;
 use regex::Regex;
@@ -8,7 +8,54 @@ use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// Settings for every project, from `config.toml` in pyflow's directory, eg
+/// `~/.local/share/pyflow/config.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GlobalConfig {
+    pub proxy: Option<ProxyConfig>,
+    /// Where to download Python builds from, instead of GitHub and python.org.
+    #[serde(alias = "python_mirror")]
+    pub python_mirror: Option<String>,
+    /// Added to our user agent, eg an email address, for proxies that require identification.
+    pub user_agent_contact: Option<String>,
+    /// Minisign public keys to check Python archives' signatures with.
+    pub python_signing_keys: Option<Vec<String>>,
+    /// Refuse Python archives that aren't signed with a trusted key.
+    pub require_python_signatures: Option<bool>,
+    /// How long to wait for each host, in seconds, eg `"pypi.internal.example.com" = 5`.
+    pub timeouts: Option<HashMap<String, u64>>,
+    /// Run the commands `cmd:` sources in projects' `[tool.pyflow.env-vars]` specify.
+    pub allow_env_commands: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyConfig {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    /// Hosts to connect to directly, eg `["localhost", ".internal.example.com"]`.
+    pub no_proxy: Option<Vec<String>>,
+}
+
+impl GlobalConfig {
+    pub fn load(path: &Path) -> Self {
+        let data = match fs::read_to_string(path) {
+            Ok(d) => d,
+            Err(_) => return Self::default(),
+        };
+        match toml::from_str(&data) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                util::abort(&format!("Problem parsing {:?}: {}", path, e));
+                unreachable!()
+            }
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Pipfile {
@@ -52,10 +99,83 @@ pub struct DepComponent {
     pub constrs: Option<String>,
     pub extras: Option<Vec<String>>,
     pub path: Option<String>,
+    pub editable: Option<bool>,
     pub git: Option<String>,
     pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    pub url: Option<String>,
     pub service: Option<String>,
     pub python: Option<String>,
+    pub source: Option<String>, // A name in `[tool.pyflow.sources]`.
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows both an index's URL, ie `internal = "https://pypi.internal.example.com"`, and maps that
+/// list its packages too: `internal = { url = "https://...", packages = ["acme-*"] }`
+pub enum SourceWrapper {
+    A(String),
+    B(SourceComponent),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SourceComponent {
+    pub url: String,
+    pub packages: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows both internal names that must be `path` or `git` dependencies, ie
+/// `internal_prefixes = ["acme-*"]`, and maps from them to the source they come from:
+/// `internal_prefixes = { "acme-*" = "internal" }`
+pub enum InternalPrefixesWrapper {
+    A(Vec<String>),
+    B(HashMap<String, String>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows both a default value, ie `LOG_LEVEL = "info"`, and maps: `API_TOKEN = { secret = true }`
+pub enum EnvVarWrapper {
+    A(String),
+    B(EnvVarComponent),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows both a plain entry, ie `lint = "tools:lint"`, and tasks with options:
+/// `build = { call = "tools:build", depends-on = ["proto"] }`
+pub enum ScriptWrapper {
+    A(String),
+    B(ScriptComponent),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScriptComponent {
+    pub call: String,
+    pub depends_on: Option<Vec<String>>,
+    pub run_if: Option<String>,
+    pub platforms: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+/// Allows silencing a warning for all packages, ie `multiple-versions = true`, or some:
+/// `typosquat = ["reqeusts"]`
+pub enum WarningWrapper {
+    A(bool),
+    B(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnvVarComponent {
+    pub default: Option<String>,
+    pub secret: Option<bool>,
+    pub from: Option<String>,
+    pub tasks: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -88,12 +208,43 @@ pub struct Pyflow {
     pub readme: Option<String>,
     pub build: Option<String>,
     //    pub entry_points: Option<HashMap<String, Vec<String>>>,
-    pub scripts: Option<HashMap<String, String>>,
+    pub scripts: Option<HashMap<String, ScriptWrapper>>,
     pub python_requires: Option<String>,
     pub dependencies: Option<HashMap<String, DepComponentWrapper>>,
     #[serde(rename = "dev-dependencies")]
     pub dev_dependencies: Option<HashMap<String, DepComponentWrapper>>,
     pub extras: Option<HashMap<String, String>>,
+    pub active_env: Option<String>,
+    pub members: Option<Vec<String>>,
+    pub internal_prefixes: Option<InternalPrefixesWrapper>,
+    pub require_approval: Option<bool>,
+    pub prereleases: Option<HashMap<String, bool>>,
+    pub overrides: Option<HashMap<String, String>>,
+    pub constraints: Option<Vec<String>>,
+    pub policy: Option<policy::Policy>,
+    pub sources: Option<HashMap<String, SourceWrapper>>,
+    pub temp_dir: Option<String>,
+    pub prompt_timeout: Option<u64>,
+    #[serde(rename = "python-update-policy")]
+    pub python_update_policy: Option<String>,
+    #[serde(rename = "python-archive")]
+    pub python_archive: Option<String>,
+    pub py_variant: Option<String>,
+    #[serde(rename = "env-vars")]
+    pub env_vars: Option<HashMap<String, EnvVarWrapper>>,
+    pub warnings: Option<HashMap<String, WarningWrapper>>,
+    #[serde(rename = "sync-exports")]
+    pub sync_exports: Option<HashMap<String, SyncExportComponent>>,
+}
+
+/// A file to regenerate from the lock, eg `"requirements-dev.txt" = { dev = true }`. The
+/// fields are as in `pyflow export`.
+#[derive(Debug, Deserialize)]
+pub struct SyncExportComponent {
+    pub format: Option<String>,
+    pub dev: Option<bool>,
+    pub platform: Option<String>,
+    pub python: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -248,6 +399,13 @@ pub fn add_reqs_to_cfg(cfg_path: &Path, added: &[Req], added_dev: &[Req]) {
 }
 
 /// Remove dependencies from pyproject.toml.
+/// The package a dependency line is for, from its key, eg `requests` for
+/// `requests = { version = "^2.31.0", extras = ["socks"] }` or `"requests[socks]" = "^2.31"`.
+fn req_line_name(line: &str) -> Option<Req> {
+    let key = &line[..line.find('=')?];
+    Req::from_str(key.trim().trim_matches('"'), false).ok()
+}
+
 pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
     // todo: Handle removing dev deps.
     // todo: DRY from parsing the config.
@@ -290,6 +448,8 @@ pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
             // todo: handle comments
             let req_line = if let Ok(r) = Req::from_str(line, false) {
                 r
+            } else if let Some(r) = req_line_name(line) {
+                r
             } else {
                 result.push_str(line);
                 result.push('\n');
@@ -338,6 +498,266 @@ pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
     }
 }
 
+/// Read a constraints file, in pip's format: a requirement on each line, eg `urllib3<2`, or
+/// `numpy==1.26.4; python_version < "3.13"`. Files it includes with `-c`, relative to it, are read
+/// too.
+pub fn read_constraints(path: &Path) -> Result<Vec<Req>, String> {
+    let mut read = vec![];
+    read_constraints_from(path, &mut read)
+}
+
+fn read_constraints_from(path: &Path, read: &mut Vec<PathBuf>) -> Result<Vec<Req>, String> {
+    // Files may include each other.
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    if read.contains(&canonical) {
+        return Ok(vec![]);
+    }
+    read.push(canonical);
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Problem reading constraints from {}: {}", path.display(), e))?;
+
+    let mut result = vec![];
+    for line in text.replace("\\\r\n", " ").replace("\\\n", " ").lines() {
+        let line = match line.find(" #") {
+            Some(i) => &line[..i],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let included = line
+            .strip_prefix("--constraint")
+            .or_else(|| line.strip_prefix("-c"));
+        if let Some(included) = included {
+            let included = included.trim_start_matches([' ', '=']).trim();
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            result.extend(read_constraints_from(&dir.join(included), read)?);
+            continue;
+        }
+        // Other options, eg `--index-url`, don't limit versions.
+        if line.starts_with('-') {
+            continue;
+        }
+        match parse_constraint(line) {
+            Some(r) => result.push(r),
+            None => return Err(format!("Problem parsing `{}` in {}", line, path.display())),
+        }
+    }
+    Ok(result)
+}
+
+/// Eg `urllib3>=1.26,<2 --hash=sha256:..`, from `pip-compile`; we don't need the hashes.
+fn parse_constraint(line: &str) -> Option<Req> {
+    let spec: Vec<&str> = line
+        .split_whitespace()
+        .filter(|p| !p.starts_with("--hash"))
+        .collect();
+    let spec = spec.join(" ");
+    // We parse it as in package metadata, which has a space before the constraints, eg
+    // `urllib3 <2`.
+    let end = spec.find(';').unwrap_or(spec.len());
+    let spec = match spec[..end].find(|c| "<>=!~".contains(c)) {
+        Some(i) => format!("{} {}", spec[..i].trim_end(), &spec[i..]),
+        None => spec,
+    };
+    Req::from_str(&spec, true).ok()
+}
+
+/// Parse `setup.cfg`-style data into (section, key): value. Indented lines continue the
+/// previous value, as used for lists like `install_requires`.
+fn parse_cfg_sections(data: &str) -> HashMap<(String, String), String> {
+    let mut result: HashMap<(String, String), String> = HashMap::new();
+    let mut section = String::new();
+    let mut key: Option<String> = None;
+
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let Some(k) = &key {
+                let val = result.entry((section.clone(), k.clone())).or_default();
+                if !val.is_empty() {
+                    val.push('\n');
+                }
+                val.push_str(trimmed);
+            }
+        } else if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            section = trimmed[1..trimmed.len() - 1].trim().to_owned();
+            key = None;
+        } else {
+            let mut parts = trimmed.splitn(2, |c| c == '=' || c == ':');
+            let k = parts.next().unwrap_or("").trim().to_owned();
+            let v = parts.next().unwrap_or("").trim().to_owned();
+            result.insert((section.clone(), k.clone()), v);
+            key = Some(k);
+        }
+    }
+    result
+}
+
+/// Parse the headers of a `PKG-INFO` or `METADATA` file. Repeated headers, like `Classifier`,
+/// collect multiple values.
+fn parse_pkg_info(data: &str) -> HashMap<String, Vec<String>> {
+    let mut result: HashMap<String, Vec<String>> = HashMap::new();
+    for line in data.lines() {
+        // Headers end at the first blank line; the long description follows.
+        if line.trim().is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+            if !k.starts_with(char::is_whitespace) {
+                result
+                    .entry(k.trim().to_owned())
+                    .or_default()
+                    .push(v.trim().to_owned());
+            }
+        }
+    }
+    result
+}
+
+/// Find the Python version to use from a `python_requires` lower bound, eg `>=3.7`.
+fn py_vers_from_requires(requires: &str) -> Option<Version> {
+    let re = Regex::new(r">=\s*(\d+)\.(\d+)").unwrap();
+    re.captures(requires).map(|caps| {
+        Version::new_short(
+            caps.get(1).unwrap().as_str().parse().unwrap(),
+            caps.get(2).unwrap().as_str().parse().unwrap(),
+        )
+    })
+}
+
+/// Set a config field from legacy metadata. Keys are lowercase, with `_` instead of `-`.
+/// Adds a note to `skipped` for anything we can't translate.
+fn migrate_field(cfg: &mut Config, key: &str, val: &str, skipped: &mut Vec<String>) {
+    if val.is_empty() || val == "UNKNOWN" {
+        return;
+    }
+    match key {
+        "name" => cfg.name = Some(val.to_owned()),
+        "version" => match Version::from_str(val) {
+            Ok(v) => cfg.version = Some(v),
+            Err(_) => skipped.push(format!("version: {}", val)),
+        },
+        "description" | "summary" => cfg.description = Some(val.to_owned()),
+        "author" => cfg.authors.insert(0, val.to_owned()),
+        "author_email" => match cfg.authors.first_mut() {
+            Some(author) => *author = format!("{} <{}>", author, val),
+            None => cfg.authors.push(val.to_owned()),
+        },
+        "license" => cfg.license = Some(val.to_owned()),
+        "url" | "home_page" => cfg.homepage = Some(val.to_owned()),
+        "keywords" => {
+            cfg.keywords = val
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|k| !k.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        }
+        "classifier" | "classifiers" => {
+            for c in val.lines() {
+                cfg.classifiers.push(c.to_owned());
+            }
+        }
+        "python_requires" | "requires_python" => {
+            cfg.python_requires = Some(val.to_owned());
+            cfg.py_version = py_vers_from_requires(val);
+        }
+        "install_requires" => {
+            for line in val.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                match Req::from_pip_str(line) {
+                    Some(r) => cfg.reqs.push(r),
+                    None => skipped.push(format!("dependency: {}", line)),
+                }
+            }
+        }
+        // We read requirements from `requires.txt` instead, which is always generated.
+        "requires_dist" => (),
+        "metadata_version"
+        | "platform"
+        | "long_description"
+        | "long_description_content_type"
+        | "description_content_type" => (),
+        _ => skipped.push(format!("{}: {}", key, val)),
+    }
+}
+
+/// Populate a config from a `setup.cfg` file's `[metadata]` and `[options]` sections. Returns
+/// notes on anything we couldn't translate.
+pub fn migrate_setup_cfg(cfg: &mut Config, path: &Path) -> Vec<String> {
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(_) => {
+            util::abort(&format!("Problem reading {:?}", path));
+            unreachable!()
+        }
+    };
+
+    let mut skipped = vec![];
+    let mut fields: Vec<((String, String), String)> =
+        parse_cfg_sections(&data).into_iter().collect();
+    // `author` must come before `author_email`.
+    fields.sort();
+
+    for ((section, key), val) in fields {
+        let key = key.to_lowercase().replace("-", "_");
+        match section.as_str() {
+            "metadata" => migrate_field(cfg, &key, &val, &mut skipped),
+            "options" => match key.as_str() {
+                "install_requires" | "python_requires" => {
+                    migrate_field(cfg, &key, &val, &mut skipped)
+                }
+                // Build settings; pyflow finds packages itself.
+                "packages" | "package_dir" | "include_package_data" | "zip_safe" => (),
+                _ => skipped.push(format!("[options] {}", key)),
+            },
+            s if s.starts_with("options.") || s == "bdist_wheel" || s == "tool:pytest" => {
+                skipped.push(format!("[{}] {}", section, key))
+            }
+            _ => (),
+        }
+    }
+    skipped
+}
+
+/// Populate a config from an `egg-info` directory generated by `setup.py egg_info`. Returns notes
+/// on anything we couldn't translate.
+pub fn migrate_egg_info(cfg: &mut Config, egg_info_path: &Path) -> Vec<String> {
+    let mut skipped = vec![];
+
+    let pkg_info = fs::read_to_string(egg_info_path.join("PKG-INFO"))
+        .expect("Problem reading generated `PKG-INFO`");
+    let mut headers: Vec<(String, Vec<String>)> = parse_pkg_info(&pkg_info).into_iter().collect();
+    headers.sort();
+    for (key, vals) in headers {
+        let key = key.to_lowercase().replace("-", "_");
+        migrate_field(cfg, &key, &vals.join("\n"), &mut skipped);
+    }
+
+    // `requires.txt` lists requirements, then sections for extras and markers, eg `[test]`.
+    if let Ok(requires) = fs::read_to_string(egg_info_path.join("requires.txt")) {
+        let mut section: Option<String> = None;
+        for line in requires.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if line.starts_with('[') {
+                section = Some(line.to_owned());
+            } else if let Some(s) = &section {
+                skipped.push(format!("dependency in {}: {}", s, line));
+            } else {
+                migrate_field(cfg, "install_requires", line, &mut skipped);
+            }
+        }
+    }
+
+    if egg_info_path.join("entry_points.txt").exists() {
+        skipped.push("entry points; add them to `[tool.pyflow.scripts]`".into());
+    }
+    skipped
+}
+
 /// Update the config file with a new version.
 pub fn change_py_vers(cfg_path: &Path, specified: &Version) {
     let f = fs::File::open(&cfg_path)
@@ -361,11 +781,256 @@ pub fn change_py_vers(cfg_path: &Path, specified: &Version) {
         .expect("Unable to write pyproject.toml while adding Python version");
 }
 
+/// Find a file `extends` refers to, relative to the file it's in, which may be a URL.
+fn join_location(base: &str, location: &str) -> String {
+    let is_url = |l: &str| l.starts_with("http://") || l.starts_with("https://");
+    if is_url(location) || Path::new(location).is_absolute() {
+        location.to_owned()
+    } else if is_url(base) {
+        format!(
+            "{}{}",
+            &base[..base.rfind('/').map_or(base.len(), |i| i + 1)],
+            location
+        )
+    } else {
+        // Resolve `..`s, so a file extended through different paths is seen as the same one.
+        let joined = Path::new(base)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(location);
+        let mut result = std::path::PathBuf::new();
+        for component in joined.components() {
+            match component {
+                Component::ParentDir if result.file_name().is_some() => {
+                    result.pop();
+                }
+                Component::CurDir => (),
+                c => result.push(c),
+            }
+        }
+        result.to_string_lossy().into_owned()
+    }
+}
+
+fn load_location(net: &net::Settings, location: &str) -> Result<String, String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let problem =
+            |e: &dyn std::fmt::Display| format!("Problem downloading {}: {}", location, e);
+        net::get(net, location)
+            .map_err(|e| problem(&e))?
+            .text()
+            .map_err(|e| problem(&e))
+    } else {
+        fs::read_to_string(location).map_err(|e| format!("Problem reading {}: {}", location, e))
+    }
+}
+
+/// Merge `overrides` into `base`. Tables, eg `dependencies`, are merged key by key; other values,
+/// including arrays, replace the base's.
+fn merge_tables(base: &mut toml::value::Table, overrides: toml::value::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge_tables(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Replace a `[tool.pyflow]` table with the one it `extends`, merged with its own values. The
+/// file extended can be a `pyproject.toml`, or hold the table's keys at its top level, and can
+/// extend another in turn. `seen` holds the files extended so far, to catch cycles.
+fn resolve_extends(
+    table: &mut toml::value::Table,
+    location: &str,
+    load: &dyn Fn(&str) -> Result<String, String>,
+    seen: &mut Vec<String>,
+) -> Result<(), String> {
+    let parent_location = match table.remove("extends") {
+        None => return Ok(()),
+        Some(toml::Value::String(l)) => join_location(location, &l),
+        Some(_) => return Err(format!("`extends` in {} must be a path or URL", location)),
+    };
+    if seen.contains(&parent_location) {
+        return Err(format!(
+            "{} is extended more than once, through {}",
+            parent_location,
+            seen.join(", ")
+        ));
+    }
+    seen.push(parent_location.clone());
+
+    let mut parent: toml::value::Table = toml::from_str(&load(&parent_location)?)
+        .map_err(|e| format!("Problem parsing {}: {}", parent_location, e))?;
+    if let Some(toml::Value::Table(pyflow)) = parent
+        .get_mut("tool")
+        .and_then(|t| t.as_table_mut())
+        .and_then(|t| t.remove("pyflow"))
+    {
+        parent = pyflow;
+    }
+    resolve_extends(&mut parent, &parent_location, load, seen)?;
+
+    merge_tables(&mut parent, std::mem::take(table));
+    *table = parent;
+    Ok(())
+}
+
+/// Read `pyproject.toml`, with its `[tool.pyflow]` table merged with the ones it `extends`, eg
+/// `extends = "../pyflow-defaults.toml"`, so an organization can share settings across projects.
+/// Values set here override inherited ones.
+pub fn read_pyproject(path: &Path, net: &net::Settings) -> Result<toml::Value, String> {
+    let location = path.to_string_lossy();
+    let load = |l: &str| load_location(net, l);
+    let mut value: toml::Value = toml::from_str(&load(&location)?)
+        .map_err(|e| format!("Problem parsing {:?}: {}", path, e))?;
+    if let Some(pyflow) = value
+        .get_mut("tool")
+        .and_then(|t| t.get_mut("pyflow"))
+        .and_then(|p| p.as_table_mut())
+    {
+        resolve_extends(pyflow, &location, &load, &mut vec![])?;
+    }
+    Ok(value)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use crate::dep_types::{Constraint, ReqType::Caret};
 
+    #[test]
+    fn reads_constraints_files() {
+        let tmp = util::TempDir::new("constraints");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("org")).unwrap();
+        fs::write(
+            dir.join("constraints.txt"),
+            "# Managed centrally\n-c org/base.txt\nurllib3>=1.26,<2  # Until we move\n\
+             numpy==1.26.4 ; python_version < \"3.13\"\n--index-url https://example.com\n\
+             idna==3.6 \\\n    --hash=sha256:c05567e9c24a6b9faaa835c4821bad0590fbb9d5779e7caa6e1cc4978e7eb24f\n",
+        )
+        .unwrap();
+        fs::write(dir.join("org/base.txt"), "-c ../constraints.txt\nsix<2\n").unwrap();
+
+        let reqs = read_constraints(&dir.join("constraints.txt")).unwrap();
+        let names: Vec<&str> = reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["six", "urllib3", "numpy", "idna"]);
+        assert_eq!(reqs[1].constraints.len(), 2);
+        assert!(reqs[2].python_version.is_some());
+
+        fs::write(
+            dir.join("org/base.txt"),
+            "six @ https://example.com/six.whl\n",
+        )
+        .unwrap();
+        assert!(read_constraints(&dir.join("constraints.txt")).is_err());
+        assert!(read_constraints(&dir.join("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn extends_merges_tables() {
+        let files: HashMap<&str, &str> = [
+            (
+                "/org/pyflow-defaults.toml",
+                "extends = \"base.toml\"\nrequire_approval = true\n\
+                 internal_prefixes = [\"acme-*\"]\n[dependencies]\nrequests = \"^2.22\"\n",
+            ),
+            (
+                "/org/base.toml",
+                "[tool.pyflow]\ntemp_dir = \"build\"\nrequire_approval = false\n",
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let load = |l: &str| {
+            files
+                .get(l)
+                .map(|d| (*d).to_owned())
+                .ok_or_else(|| format!("Can't find {}", l))
+        };
+
+        let mut table: toml::value::Table = toml::from_str(
+            "extends = \"../org/pyflow-defaults.toml\"\ninternal_prefixes = []\n\
+             [dependencies]\nnumpy = \"^1.17\"\n",
+        )
+        .unwrap();
+        resolve_extends(&mut table, "/proj/pyproject.toml", &load, &mut vec![]).unwrap();
+
+        let expected: toml::value::Table = toml::from_str(
+            "temp_dir = \"build\"\nrequire_approval = true\ninternal_prefixes = []\n\
+             [dependencies]\nrequests = \"^2.22\"\nnumpy = \"^1.17\"\n",
+        )
+        .unwrap();
+        assert_eq!(table, expected);
+
+        let mut cycle: toml::value::Table = toml::from_str("extends = \"a.toml\"").unwrap();
+        let load_cycle = |_: &str| Ok("extends = \"a.toml\"".to_owned());
+        assert!(
+            resolve_extends(&mut cycle, "/proj/pyproject.toml", &load_cycle, &mut vec![]).is_err()
+        );
+
+        assert_eq!(
+            join_location("https://example.com/org/defaults.toml", "base.toml"),
+            "https://example.com/org/base.toml"
+        );
+    }
+
+    #[test]
+    fn cfg_sections() {
+        let data = "[metadata]\nname = saturn\nclassifiers =\n    License :: OSI Approved\n    \
+            Topic :: Science\n\n[options]\ninstall_requires =\n    numpy>=1.16\n    scipy\n";
+        let sections = parse_cfg_sections(data);
+
+        let get = |s: &str, k: &str| sections.get(&(s.to_owned(), k.to_owned())).cloned();
+        assert_eq!(get("metadata", "name"), Some("saturn".into()));
+        assert_eq!(
+            get("metadata", "classifiers"),
+            Some("License :: OSI Approved\nTopic :: Science".into())
+        );
+        assert_eq!(
+            get("options", "install_requires"),
+            Some("numpy>=1.16\nscipy".into())
+        );
+    }
+
+    #[test]
+    fn pkg_info_headers() {
+        let data = "Metadata-Version: 1.2\nName: saturn\nClassifier: A\nClassifier: B\n\n\
+            Description: not a header\n";
+        let headers = parse_pkg_info(data);
+
+        assert_eq!(headers["Name"], vec!["saturn".to_owned()]);
+        assert_eq!(headers["Classifier"], vec!["A".to_owned(), "B".to_owned()]);
+        assert!(!headers.contains_key("Description"));
+    }
+
+    #[test]
+    fn migrate_fields() {
+        let mut cfg = Config::default();
+        let mut skipped = vec![];
+        migrate_field(&mut cfg, "author", "Fraa Jad", &mut skipped);
+        migrate_field(&mut cfg, "author_email", "jad@edhar.math", &mut skipped);
+        migrate_field(&mut cfg, "python_requires", ">=3.7, <4", &mut skipped);
+        migrate_field(
+            &mut cfg,
+            "version",
+            "attr: saturn.__version__",
+            &mut skipped,
+        );
+        migrate_field(&mut cfg, "license", "UNKNOWN", &mut skipped);
+
+        assert_eq!(cfg.authors, vec!["Fraa Jad <jad@edhar.math>".to_owned()]);
+        assert_eq!(cfg.py_version, Some(Version::new_short(3, 7)));
+        assert_eq!(cfg.license, None);
+        assert_eq!(
+            skipped,
+            vec!["version: attr: saturn.__version__".to_owned()]
+        );
+    }
+
     // We're not concerned with testing formatting in this func.
     fn base_constrs() -> Vec<Constraint> {
         vec![Constraint::new(Caret, Version::new(0, 0, 1))]
@@ -447,6 +1112,27 @@ dev_b = "^0.0.1"
         assert_eq!(expected, &actual);
     }
 
+    #[test]
+    fn remove_deps_with_extras() {
+        let dir = util::TempDir::new("remove-deps");
+        let path = dir.path().join("pyproject.toml");
+        fs::write(
+            &path,
+            r#"[tool.pyflow.dependencies]
+a = "^0.3.5"
+requests = { version = "^2.31.0", extras = ["socks"] }
+"uvicorn[standard]" = "^0.23"
+"#,
+        )
+        .unwrap();
+
+        remove_reqs_from_cfg(&path, &["requests".into(), "uvicorn".into()]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[tool.pyflow.dependencies]\na = \"^0.3.5\"\n"
+        );
+    }
+
     #[test]
     fn add_deps_no_dev_deps_sect() {
         let actual = update_cfg(
@@ -1,13 +1,46 @@
 use crate::util::print_color;
-use crate::{commands, dep_types::Version, util};
-use crossterm::{Color, Colored};
+use crate::{build_logs, commands, dep_types::Version, net, util};
+use crossterm::Color;
+use data_encoding::HEXLOWER;
 use flate2::read::GzDecoder;
 use regex::Regex;
 use ring::digest;
-use std::{fs, io, io::BufRead, path::Path, process::Command};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::{
+    fs, io,
+    io::BufRead,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::Mutex,
+    thread, time,
+};
 use tar::Archive;
 
-#[derive(Copy, Clone, Debug)]
+/// Lines of a failed build's output to show.
+const BUILD_LOG_TAIL: usize = 30;
+
+/// How to build source packages, from `pyflow sync --build-jobs` and `--keep-going`.
+#[derive(Clone, Copy, Debug)]
+pub struct BuildOptions {
+    /// Build up to this many at once.
+    pub jobs: usize,
+    /// Keep building the rest after one fails, instead of stopping.
+    pub keep_going: bool,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            keep_going: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PackageType {
     Wheel,
     Source,
@@ -29,6 +62,161 @@ fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::
     Ok(context.finish())
 }
 
+/// Hashes what's read through it, and optionally copies it to a file, eg to cache a download
+/// while it's being unpacked.
+pub struct HashingReader<R: io::Read> {
+    inner: R,
+    context: digest::Context,
+    copy: Option<fs::File>,
+}
+
+impl<R: io::Read> HashingReader<R> {
+    pub fn new(inner: R, copy: Option<fs::File>) -> Self {
+        Self {
+            inner,
+            context: digest::Context::new(&digest::SHA256),
+            copy,
+        }
+    }
+
+    /// Read what's left, and return the SHA256 of everything read, in lowercase hex.
+    pub fn finish(mut self) -> Result<String, io::Error> {
+        io::copy(&mut self, &mut io::sink())?;
+        if let Some(f) = &mut self.copy {
+            f.flush()?;
+        }
+        Ok(HEXLOWER.encode(self.context.finish().as_ref()))
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.context.update(&buf[..count]);
+        if let Some(f) = &mut self.copy {
+            f.write_all(&buf[..count])?;
+        }
+        Ok(count)
+    }
+}
+
+/// The SHA256 of a file, in lowercase hex.
+pub fn file_sha256(path: &Path) -> Result<String, io::Error> {
+    HashingReader::new(fs::File::open(path)?, None).finish()
+}
+
+/// Unpack a `.tar.gz` source package as it's read. Returns `false` if it isn't a tar file; some
+/// are zips.
+fn unpack_source(reader: &mut impl io::Read, dest: &Path) -> bool {
+    let mut archive = Archive::new(GzDecoder::new(reader));
+
+    // We iterate over and copy entries instead of running `Archive.unpack`, since
+    // symlinks in the archive may cause the unpack to break. If this happens, we want
+    // to continue unpacking the other files.
+    let entries = match archive.entries() {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+    for file in entries {
+        let mut f = match file {
+            Ok(f) => f,
+            Err(_) => return false,
+        };
+        if let Err(e) = f.unpack_in(dest) {
+            print_color(
+                &format!("Problem unpacking file {:?}: {:?}", f.path(), e),
+                Color::DarkYellow,
+            );
+            let f_path = f.path().expect("Problem getting path from archive");
+            let filename = f_path.file_name().expect("Problem getting file name");
+
+            // In the `pandocfilters` Python package, the readme file specified in
+            // `setup.py` is a symlink, which we can't unwrap, and is requried to exist,
+            // or the wheel build fails. Workaround here; may apply to other packages as well.
+            if filename.to_str().unwrap().to_lowercase().contains("readme")
+                && fs::File::create(dest.join(f.path().unwrap())).is_err()
+            {
+                print_color("Problem creating dummy readme", Color::DarkYellow);
+            }
+        }
+    }
+    true
+}
+
+/// Records what's installed in an environment, so we can detect if it's changed outside of pyflow.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct EnvManifest {
+    /// Package name: version
+    pub packages: BTreeMap<String, String>,
+    /// Top-level entry in `lib`: sha256 of its files
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Add a file or directory's contents to a hash, including relative paths, so renames and
+/// additions are caught. Skip bytecode caches, since Python rewrites them.
+fn hash_entry(context: &mut digest::Context, path: &Path, rel_path: &str) -> io::Result<()> {
+    if path.is_dir() {
+        let mut names: Vec<String> = fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n != "__pycache__")
+            .collect();
+        names.sort();
+        for name in names {
+            hash_entry(
+                context,
+                &path.join(&name),
+                &format!("{}/{}", rel_path, name),
+            )?;
+        }
+    } else if !rel_path.ends_with(".pyc") {
+        context.update(rel_path.as_bytes());
+        context.update(sha256_digest(fs::File::open(path)?)?.as_ref());
+    }
+    Ok(())
+}
+
+/// Build a manifest of the packages installed in a `lib` directory, and hashes of their files.
+pub fn build_env_manifest(lib_path: &Path) -> io::Result<EnvManifest> {
+    let mut result = EnvManifest::default();
+    for (name, version, _) in util::find_installed(lib_path) {
+        result.packages.insert(name, version.to_string2());
+    }
+
+    if lib_path.exists() {
+        for entry in fs::read_dir(lib_path)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name == "__pycache__" {
+                continue;
+            }
+            let mut context = digest::Context::new(&digest::SHA256);
+            hash_entry(&mut context, &lib_path.join(&name), &name)?;
+            result.hashes.insert(
+                name,
+                data_encoding::HEXLOWER.encode(context.finish().as_ref()),
+            );
+        }
+    }
+    Ok(result)
+}
+
+/// List the entries that differ between two manifests: changed, added, or removed.
+pub fn manifest_differences(expected: &EnvManifest, found: &EnvManifest) -> Vec<String> {
+    let mut result: Vec<String> = expected
+        .hashes
+        .iter()
+        .filter(|(name, hash)| found.hashes.get(*name) != Some(hash))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in found.hashes.keys() {
+        if !expected.hashes.contains_key(name) {
+            result.push(name.clone());
+        }
+    }
+    result.sort();
+    result
+}
+
 /// If the setup.py file uses `distutils.core`, replace with `setuptools`. This is required to build
 /// a wheel. Eg, replace `from distutils.core import setup` with `from setuptools import setup`.
 fn replace_distutils(setup_path: &Path) {
@@ -160,9 +348,10 @@ pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_pa
     //    fs::write(scripts_file, existing_scripts).expect("Unable to write to the console_scripts file");
 }
 
-/// Download and install a package. For wheels, we can just extract the contents into
-/// the lib folder.  For source dists, make a wheel first.
-pub fn download_and_install_package(
+/// Download a package, and check its hash. Wheels are installed, by extracting them into the lib
+/// folder. Source packages are unpacked, and returned to be built.
+#[allow(clippy::too_many_arguments)]
+pub fn fetch_package(
     name: &str,
     version: &Version,
     url: &str,
@@ -171,7 +360,9 @@ pub fn download_and_install_package(
     paths: &util::Paths,
     package_type: PackageType,
     rename: &Option<(u32, String)>,
-) -> Result<(), reqwest::Error> {
+    net: &net::Settings,
+    prompt_timeout: Option<time::Duration>,
+) -> Result<Option<SourceBuild>, net::NetError> {
     if !paths.lib.exists() {
         fs::create_dir(&paths.lib).expect("Problem creating lib directory");
     }
@@ -180,63 +371,48 @@ pub fn download_and_install_package(
     }
     let archive_path = paths.cache.join(filename);
 
-    // If the archive is already in the lib folder, don't re-download it. Note that this
-    // isn't the usual flow, but may have some uses.
-    if !archive_path.exists() {
-        // Save the file
-        let mut resp = reqwest::get(url)?; // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded package file");
-        // todo: DRY between here and py_versions.
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the package archive: {:?}", e));
-        }
-    }
-
-    let file = util::open_archive(&archive_path);
-
-    // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
-    let reader = io::BufReader::new(&file);
-    let file_digest = sha256_digest(reader).unwrap_or_else(|_| {
-        util::abort(&format!("Problem reading hash for {}", filename));
-        unreachable!()
-    });
-
-    let file_digest_str = data_encoding::HEXUPPER.encode(file_digest.as_ref());
-    if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
-        util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str.to_lowercase()), Color::Red);
-
-        let mut input = String::new();
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Unable to read user input Hash fail decision");
-
-        let input = input
-            .chars()
-            .next()
-            .expect("Problem reading input")
-            .to_string();
-
-        if input.to_lowercase().contains('y') {
-        } else {
-            util::abort("Exiting due to failed hash");
-        }
-    }
-
-    // We must re-open the file after computing the hash.
-    let archive_file = util::open_archive(&archive_path);
+    // If the archive is already in the cache, don't re-download it. Otherwise, it's saved to the
+    // cache, hashed, and unpacked, as it downloads.
+    let source: Box<dyn io::Read> = if archive_path.exists() {
+        Box::new(util::open_archive(&archive_path))
+    } else {
+        let resp = net::get(net, url)?;
+        let len = resp.content_length();
+        Box::new(util::Progress::new(resp, 0, len))
+    };
+    let copy = if archive_path.exists() {
+        None
+    } else {
+        Some(fs::File::create(&archive_path).expect("Failed to save downloaded package file"))
+    };
+    let mut reader = HashingReader::new(source, copy);
+
+    let download_problem = |e: io::Error| {
+        // Clean up the downloaded file, or we'll get an error next time.
+        fs::remove_file(&archive_path).ok();
+        util::abort_with(
+            util::ExitCode::Network,
+            &format!("Problem downloading the package archive: {:?}", e),
+        );
+    };
 
     let rename = match rename.as_ref() {
         Some((_, new)) => Some((name.to_owned(), new.to_owned())),
         None => None,
     };
 
-    match package_type {
+    // Archives are unpacked into a temporary directory as they download, and only used once
+    // their hash is checked. Source packages are built there, so a failed build doesn't leave
+    // them in the lib folder. Wheels are moved into the lib folder from next to it.
+    let staging = match package_type {
+        PackageType::Source => util::TempDir::new_in_root(&paths.temp, "build"),
         PackageType::Wheel => {
-            util::extract_zip(&archive_file, &paths.lib, &rename);
+            util::TempDir::new_in(paths.lib.parent().unwrap_or(&paths.lib), "wheel")
         }
+    };
+    let (mut not_tar, mut streamed) = (false, false);
+
+    match package_type {
         PackageType::Source => {
             // todo: Support .tar.bz2
             if archive_path.extension().unwrap() == "bz2" {
@@ -246,244 +422,276 @@ pub fn download_and_install_package(
                     &archive_path
                 ));
             }
-
-            // Extract the tar.gz source code.
-            let tar = GzDecoder::new(&archive_file);
-            let mut archive = Archive::new(tar);
-
-            // We iterate over and copy entries instead of running `Archive.unpack`, since
-            // symlinks in the archive may cause the unpack to break. If this happens, we want
-            // to continue unpacking the other files.
-            // Overall, this is a pretty verbose workaround!
-            match archive.entries() {
-                Ok(entries) => {
-                    for file in entries {
-                        match file {
-                            Ok(mut f) => {
-                                match f.unpack_in(&paths.lib) {
-                                    Ok(_) => (),
-                                    Err(e) => {
-                                        print_color(
-                                            &format!(
-                                                "Problem unpacking file {:?}: {:?}",
-                                                f.path(),
-                                                e
-                                            ),
-                                            Color::DarkYellow,
-                                        );
-                                        let f_path =
-                                            f.path().expect("Problem getting path from archive");
-
-                                        let filename =
-                                            f_path.file_name().expect("Problem getting file name");
-
-                                        // In the `pandocfilters` Python package, the readme file specified in
-                                        // `setup.py` is a symlink, which we can't unwrap, and is requried to exist,
-                                        // or the wheel build fails. Workaround here; may apply to other packages as well.
-                                        if filename
-                                            .to_str()
-                                            .unwrap()
-                                            .to_lowercase()
-                                            .contains("readme")
-                                            && fs::File::create(&paths.lib.join(f.path().unwrap()))
-                                                .is_err()
-                                        {
-                                            print_color(
-                                                "Problem creating dummy readme",
-                                                Color::DarkYellow,
-                                            );
-                                        }
-                                    }
-                                };
-                            }
-                            Err(e) => {
-                                // todo: dRY while troubleshooting
-                                println!(
-                                    "Problem opening the tar.gz archive: {:?}: {:?},  checking if it's a zip...",
-                                    &archive_file, e
-                                );
-                                // The extract_wheel function just extracts a zip file, so it's appropriate here.
-                                // We'll then continue with this leg, and build/move/cleanup.
-
-                                // Check if we have a zip file instead.
-                                util::extract_zip(&archive_file, &paths.lib, &None);
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!(
-                        "Problem opening the tar.gz archive: {:?}: {:?},  checking if it's a zip...",
-                        &archive_file, e
-                    );
-                    // The extract_wheel function just extracts a zip file, so it's appropriate here.
-                    // We'll then continue with this leg, and build/move/cleanup.
-
-                    // Check if we have a zip file instead.
-                    util::extract_zip(&archive_file, &paths.lib, &None);
-                }
+            not_tar = !unpack_source(&mut reader, staging.path());
+        }
+        PackageType::Wheel => {
+            match util::extract_zip_stream(&mut reader, staging.path(), &rename) {
+                Ok(s) => streamed = s,
+                Err(e) => download_problem(e),
             }
+        }
+    }
 
-            // The archive is now unpacked into a parent folder from the `tar.gz`. Place
-            // its sub-folders directly in the lib folder, and deleten the parent.
-            let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
-            let folder_name = re
-                .captures(filename)
-                .expect("Problem matching extracted folder name")
-                .get(1)
-                .unwrap_or_else(|| {
-                    util::abort(&format!(
-                        "Unable to find extracted folder name: {}",
-                        filename
-                    ));
-                    unreachable!()
-                })
-                .as_str();
-
-            // todo: This fs_extras move does a full copy. Normal fs lib doesn't include
-            // todo moves, only copies. Figure out how to do a normal move,
-            // todo, to speed this up.
-
-            let extracted_parent = paths.lib.join(folder_name);
-
-            replace_distutils(&extracted_parent.join("setup.py"));
-
-            #[cfg(target_os = "windows")]
-            {
-                let output = Command::new(paths.bin.join("python"))
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-            // The Linux and Mac builds appear to be unable to build wheels due to
-            // missing the ctypes library; revert to system python.
-            #[cfg(target_os = "linux")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-            #[cfg(target_os = "macos")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
+    let file_digest_str = match reader.finish() {
+        Ok(d) => d,
+        Err(e) => {
+            download_problem(e);
+            unreachable!()
+        }
+    };
+    let hash_matches = file_digest_str == expected_digest.to_lowercase();
+    if !hash_matches {
+        util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str), Color::Red);
+
+        let input = util::require_input(prompt_timeout);
+
+        if !input.to_lowercase().contains('y') {
+            // Don't leave what was unpacked, or the archive in the cache to be used next time.
+            drop(staging);
+            fs::remove_file(&archive_path).ok();
+            util::abort_with(util::ExitCode::HashMismatch, "Exiting due to failed hash");
+            unreachable!()
+        }
+    }
 
-            let dist_path = &extracted_parent.join("dist");
-            if !dist_path.exists() {
-                #[cfg(target_os = "windows")]
-                let error = &format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for Windows, and the system is missing dependencies required to compile it, \
-                 or if on WSL and installing to a mounted directory.",
-                    name
-                );
-
-                #[cfg(target_os = "linux")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.\
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-                #[cfg(target_os = "macos")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-
-                util::abort(&error);
-            }
+    // Wheels that couldn't be extracted as they downloaded, and zipped source packages, are
+    // extracted now they're complete; zip files' index is at the end.
+    let archive_file = util::open_archive(&archive_path);
+    match package_type {
+        PackageType::Wheel if streamed => {
+            util::set_zip_permissions(&archive_file, staging.path(), &rename)
+        }
+        PackageType::Wheel => {
+            fs::remove_dir_all(staging.path()).ok();
+            util::extract_zip(&archive_file, staging.path(), &rename);
+        }
+        PackageType::Source if not_tar => {
+            println!(
+                "Problem opening the tar.gz archive: {:?}, checking if it's a zip...",
+                &archive_path
+            );
+            util::extract_zip(&archive_file, staging.path(), &None);
+        }
+        PackageType::Source => (),
+    }
+    drop(archive_file);
+    // It was only installed since we were asked to; don't install it again without asking.
+    if !hash_matches {
+        fs::remove_file(&archive_path).ok();
+    }
 
-            let built_wheel_filename = util::find_first_file(dist_path)
-                .file_name()
-                .expect("Unable to find built wheel filename")
-                .to_str()
-                .unwrap()
-                .to_owned();
+    if let PackageType::Wheel = package_type {
+        if let Err(e) = util::move_contents(staging.path(), &paths.lib) {
+            util::abort(&format!(
+                "Problem moving {} into {:?}: {}",
+                filename, paths.lib, e
+            ));
+        }
+        setup_scripts(name, version, &paths.lib, &paths.entry_pt);
+        return Ok(None);
+    }
 
-            let moved_path = paths.lib.join(&built_wheel_filename);
+    // The archive is now unpacked into a parent folder from the `tar.gz`; build from it.
+    let re = Regex::new(r"^(.*?)(?:\.tar\.gz|\.zip)$").unwrap();
+    let folder_name = re
+        .captures(filename)
+        .expect("Problem matching extracted folder name")
+        .get(1)
+        .unwrap_or_else(|| {
+            util::abort(&format!(
+                "Unable to find extracted folder name: {}",
+                filename
+            ));
+            unreachable!()
+        })
+        .as_str();
+
+    Ok(Some(SourceBuild {
+        name: name.to_owned(),
+        version: *version,
+        src_path: staging.path().join(folder_name),
+        dir: staging,
+        rename,
+    }))
+}
 
-            // todo: Again, try to move vice copy.
-            let options = fs_extra::file::CopyOptions::new();
-            fs_extra::file::move_file(dist_path.join(&built_wheel_filename), &moved_path, &options)
-                .expect("Problem copying wheel built from source");
+/// Download and install a package. For wheels, we can just extract the contents into
+/// the lib folder.  For source dists, make a wheel first.
+#[allow(clippy::too_many_arguments)]
+pub fn download_and_install_package(
+    name: &str,
+    version: &Version,
+    url: &str,
+    filename: &str,
+    expected_digest: &str,
+    paths: &util::Paths,
+    package_type: PackageType,
+    rename: &Option<(u32, String)>,
+    net: &net::Settings,
+    prompt_timeout: Option<time::Duration>,
+) -> Result<(), net::NetError> {
+    let fetched = fetch_package(
+        name,
+        version,
+        url,
+        filename,
+        expected_digest,
+        paths,
+        package_type,
+        rename,
+        net,
+        prompt_timeout,
+    )?;
+    if let Some(build) = fetched {
+        match build.build(paths) {
+            Ok(wheel) => build.install(&wheel, paths),
+            Err(log) => util::abort(&build.failure(&log)),
+        }
+    }
+    Ok(())
+}
 
-            let file_created = fs::File::open(&moved_path).expect("Can't find created wheel.");
-            util::extract_zip(&file_created, &paths.lib, &rename);
+/// Why building `name` from source may have failed.
+fn build_failure_hint(name: &str) -> String {
+    #[cfg(target_os = "windows")]
+    let error = format!(
+        "Problem building {} from source. \
+     This may occur if a package that requires compiling has no wheels available \
+     for Windows, and the system is missing dependencies required to compile it, \
+     or if on WSL and installing to a mounted directory.",
+        name
+    );
 
-            // Remove the created and moved wheel
-            if fs::remove_file(moved_path).is_err() {
-                util::abort(&format!(
-                    "Problem removing this downloaded package: {:?}",
-                    &built_wheel_filename
-                ));
-            }
-            // Remove the source directeory extracted from the tar.gz file.
-            if fs::remove_dir_all(&extracted_parent).is_err() {
-                util::abort(&format!(
-                    "Problem removing parent folder of this downloaded package: {:?}",
-                    &extracted_parent
-                ));
-            }
+    #[cfg(target_os = "linux")]
+    let error = format!(
+        "Problem building {} from source. \
+     This may occur if a package that requires compiling has no wheels available \
+     for this OS and this system is missing dependencies required to compile it.\
+     Try running `pip install --upgrade wheel`, then try again",
+        name
+    );
+    #[cfg(target_os = "macos")]
+    let error = format!(
+        "Problem building {} from source. \
+     This may occur if a package that requires compiling has no wheels available \
+     for this OS and this system is missing dependencies required to compile it.
+     Try running `pip install --upgrade wheel`, then try again",
+        name
+    );
+    error
+}
+
+/// A source package that's been downloaded and unpacked, ready to build into a wheel.
+pub struct SourceBuild {
+    pub name: String,
+    pub version: Version,
+    /// Where it's unpacked and built; removed when dropped.
+    dir: util::TempDir,
+    src_path: PathBuf,
+    rename: Option<(String, String)>,
+}
+
+impl SourceBuild {
+    /// Build a wheel with `setup.py bdist_wheel`. Each build has its own temporary directory,
+    /// which is also its `TMPDIR`, so builds that run at once don't share files. Returns the
+    /// wheel, or the build's output if it failed. The output's saved to the environment's build
+    /// logs either way.
+    pub fn build(&self, paths: &util::Paths) -> Result<PathBuf, String> {
+        replace_distutils(&self.src_path.join("setup.py"));
+
+        // The Linux and Mac builds appear to be unable to build wheels due to
+        // missing the ctypes library; revert to system python.
+        let python = if cfg!(target_os = "windows") {
+            paths.bin.join("python")
+        } else {
+            PathBuf::from("python3")
+        };
+        let tmp = self.dir.path().join("tmp");
+        fs::create_dir_all(&tmp).ok();
+        let output = Command::new(&python)
+            .current_dir(&self.src_path)
+            .args(["setup.py", "bdist_wheel"])
+            .env("TMPDIR", &tmp)
+            .env("TEMP", &tmp)
+            .env("TMP", &tmp)
+            .output();
+        let log = match &output {
+            Ok(o) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&o.stdout),
+                String::from_utf8_lossy(&o.stderr)
+            ),
+            Err(e) => format!("Problem running {:?}: {}", python, e),
+        };
+
+        let dist_path = self.src_path.join("dist");
+        let succeeded = matches!(&output, Ok(o) if o.status.success()) && dist_path.exists();
+        build_logs::save(
+            paths.lib.parent().unwrap_or(&paths.lib),
+            &self.name,
+            &self.version,
+            &log,
+            succeeded,
+        );
+        if succeeded {
+            Ok(util::find_first_file(&dist_path))
+        } else {
+            Err(log)
         }
     }
-    setup_scripts(name, version, &paths.lib, &paths.entry_pt);
 
-    Ok(())
+    /// Install the wheel `build` made.
+    pub fn install(&self, wheel: &Path, paths: &util::Paths) {
+        let file = fs::File::open(wheel).expect("Can't find created wheel.");
+        util::extract_zip(&file, &paths.lib, &self.rename);
+        setup_scripts(&self.name, &self.version, &paths.lib, &paths.entry_pt);
+    }
+
+    /// Why the build failed: a hint, and the end of its output.
+    pub fn failure(&self, log: &str) -> String {
+        let lines: Vec<&str> = log.lines().collect();
+        let start = lines.len().saturating_sub(BUILD_LOG_TAIL);
+        format!(
+            "{}\n{}\nRun `pyflow logs {}` to see all of its output.",
+            build_failure_hint(&self.name),
+            lines[start..].join("\n"),
+            self.name
+        )
+    }
+}
+
+/// Build source packages, up to `options.jobs` at once. Returns each one's wheel, or its output
+/// if it failed, in the same order. Once one fails, ones that haven't started aren't built, and
+/// are `None`, unless `options.keep_going` is set.
+pub fn build_in_parallel(
+    builds: &[SourceBuild],
+    paths: &util::Paths,
+    options: BuildOptions,
+) -> Vec<Option<Result<PathBuf, String>>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<PathBuf, String>>>> = Mutex::new(vec![None; builds.len()]);
+    thread::scope(|s| {
+        for _ in 0..options.jobs.clamp(1, builds.len().max(1)) {
+            s.spawn(|| loop {
+                if failed.load(Ordering::Relaxed) && !options.keep_going {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let build = match builds.get(i) {
+                    Some(b) => b,
+                    None => break,
+                };
+                println!("Building {} {}...", build.name, build.version.to_string2());
+                let result = build.build(paths);
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results.into_inner().unwrap()
 }
 
 pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
@@ -515,6 +723,11 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
         }
     }
 
+    let editable_path = lib_path.join(format!("{}-{}.dist-info", name_ins, vers_ins.to_string2()));
+    if uninstall_editable(&editable_path, lib_path) {
+        return;
+    }
+
     let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string()));
 
     // todo: could top_level.txt be in egg-info too?
@@ -539,7 +752,7 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
             if fs::remove_file(lib_path.join(&format!("{}.py", folder_name))).is_err() {
                 println!(
                     "{}Problem uninstalling {} {}",
-                    Colored::Fg(Color::DarkRed),
+                    util::fg(Color::DarkRed),
                     name_ins,
                     vers_ins.to_string(),
                 )
@@ -558,7 +771,7 @@ pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
     if !meta_folder_removed {
         println!(
             "{}Problem uninstalling metadata for {}: {}",
-            Colored::Fg(Color::DarkRed),
+            util::fg(Color::DarkRed),
             name_ins,
             vers_ins.to_string(),
         )
@@ -619,11 +832,14 @@ pub fn rename_metadata(path: &Path, _old: &str, new: &str) {
     // todo: Modify other files like entry_points.txt, perhaps.
 }
 
-/// Clone a git repo of a Python package, and build/install a wheel from it.
+/// Clone a git repo of a Python package, check out `target`, eg a commit or `origin/main`, and
+/// build/install a wheel from it. `fetch` updates an existing clone first, eg for a branch.
 /// Or do the same, but with a path instead of git.
 pub fn download_and_install_git(
     name: &str,
     url: &str,
+    target: &str,
+    fetch: bool,
     git_path: &Path,
     paths: &util::Paths,
 ) -> util::Metadata {
@@ -635,35 +851,148 @@ pub fn download_and_install_git(
                                                     //    match url {
                                                     //        GitPath::Git(url) => {
                                                     // Download the repo into the pyflow folder.
-                                                    // todo: Handle checking if it's current and correct; not just a matching folder
-                                                    // todo name.
-    if !&git_path.join(&folder_name).exists() && commands::download_git_repo(url, git_path).is_err()
+    let repo_path = git_path.join(&folder_name);
+    let cloned = !repo_path.exists();
+    if cloned && commands::download_git_repo(url, &repo_path).is_err() {
+        util::abort_with(
+            util::ExitCode::Network,
+            &format!("Problem cloning this repo: {}", url),
+        );
+    }
+    if commands::checkout_git_repo(&repo_path, target, fetch && !cloned).is_err() {
+        util::abort_with(
+            util::ExitCode::Network,
+            &format!("Problem checking out {} from this repo: {}", target, url),
+        );
+    }
+    //        }
+    //        GitPath::Path(path) => {
+    //            let f = &git_path.join(&folder_name);
+    //            if !&f.exists() {
+    //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
+    //                let options = fs_extra::dir::CopyOptions::new();
+    //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
+    //                    .expect("Problem copying path requirement to lib folder");
+    //            }
+    //        }
+    //}
+
+    build_and_install_wheel(name, &repo_path, paths)
+}
+
+/// Install a package so it's imported from its source, and changes to it apply without
+/// reinstalling: a `.pth` file pointing at `import_root`, and `dist-info` metadata with a PEP 610
+/// `direct_url.json`, so tools can tell it's editable, and where it's from.
+pub fn install_editable(
+    name: &str,
+    version: &Version,
+    source: &Path,
+    import_root: &Path,
+    lib: &Path,
+) {
+    let dist_name = util::standardize_name(name).replace('-', "_");
+    let dist_info = format!("{}-{}.dist-info", dist_name, version.to_string2());
+    let pth = format!("__editable__.{}-{}.pth", dist_name, version.to_string2());
+
+    let mut url_path = source.to_string_lossy().replace('\\', "/");
+    if !url_path.starts_with('/') {
+        url_path.insert(0, '/');
+    }
+    let direct_url = serde_json::json!({
+        "url": format!("file://{}", url_path),
+        "dir_info": { "editable": true },
+    });
+    let files = [
+        (
+            format!("{}/METADATA", dist_info),
+            format!(
+                "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+                name,
+                version.to_string2()
+            ),
+        ),
+        (format!("{}/INSTALLER", dist_info), "pyflow\n".to_owned()),
+        (
+            format!("{}/direct_url.json", dist_info),
+            direct_url.to_string(),
+        ),
+        (pth, format!("{}\n", import_root.to_string_lossy())),
+    ];
+    let mut record: String = files
+        .iter()
+        .map(|(path, _)| format!("{},,\n", path))
+        .collect();
+    record.push_str(&format!("{}/RECORD,,\n", dist_info));
+
+    let written = fs::create_dir_all(lib.join(&dist_info)).is_ok()
+        && files
+            .iter()
+            .chain(&[(format!("{}/RECORD", dist_info), record)])
+            .all(|(path, text)| fs::write(lib.join(path), text).is_ok());
+    if !written {
+        util::abort(&format!("Problem installing {} as editable", name));
+    }
+}
+
+/// Whether the package at `dist_info_path` was installed as editable, per its `direct_url.json`.
+pub fn is_editable(dist_info_path: &Path) -> bool {
+    fs::read_to_string(dist_info_path.join("direct_url.json"))
+        .ok()
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+        .is_some_and(|v| v["dir_info"]["editable"] == true)
+}
+
+/// Remove what `install_editable` installed, if the package at `dist_info_path` is editable.
+/// Returns whether it was.
+fn uninstall_editable(dist_info_path: &Path, lib_path: &Path) -> bool {
+    if !is_editable(dist_info_path) {
+        return false;
+    }
+    for line in fs::read_to_string(dist_info_path.join("RECORD"))
+        .unwrap_or_default()
+        .lines()
     {
-        util::abort(&format!("Problem cloning this repo: {}", url));
-    } // todo to keep dl small while troubleshooting.
-      //        }
-      //        GitPath::Path(path) => {
-      //            let f = &git_path.join(&folder_name);
-      //            if !&f.exists() {
-      //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
-      //                let options = fs_extra::dir::CopyOptions::new();
-      //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
-      //                    .expect("Problem copying path requirement to lib folder");
-      //            }
-      //        }
-      //}
-
-    // Build a wheel from the repo
+        if let Some(path) = line.split(',').next().filter(|p| p.ends_with(".pth")) {
+            fs::remove_file(lib_path.join(path)).ok();
+        }
+    }
+    fs::remove_dir_all(dist_info_path).is_ok()
+}
+
+/// Build a wheel from a package's source, eg a git repo or a path dependency, and install it.
+/// Projects with a `pyproject.toml` are built with the backend it names, per PEP 517; pip does
+/// that for us, in an isolated build environment. The wheel's built outside the source, so we
+/// don't touch its `dist` folder.
+pub fn build_and_install_wheel(name: &str, src_path: &Path, paths: &util::Paths) -> util::Metadata {
+    let dist = util::TempDir::new_in_root(&paths.temp, "wheel");
+    let dist_path = dist.path().to_str().unwrap();
+    let (args, task): (Vec<&str>, _) = if src_path.join("pyproject.toml").exists() {
+        (
+            vec![
+                "-m",
+                "pip",
+                "wheel",
+                "--no-deps",
+                "--wheel-dir",
+                dist_path,
+                ".",
+            ],
+            "building a wheel",
+        )
+    } else {
+        (
+            vec!["setup.py", "bdist_wheel", "--dist-dir", dist_path],
+            "running setup.py bdist_wheel",
+        )
+    };
     let output = Command::new(paths.bin.join("python"))
-        // We assume that the module code is in the repo's immediate subfolder that has
-        // the package's name.
-        .current_dir(&git_path.join(&folder_name))
-        .args(&["setup.py", "bdist_wheel"])
+        .current_dir(src_path)
+        .args(&args)
         .output()
-        .expect("Problem running setup.py bdist_wheel");
-    util::check_command_output(&output, "running setup.py bdist_wheel");
+        .unwrap_or_else(|_| panic!("Problem {}", task));
+    util::check_command_output(&output, task);
 
-    let archive_path = util::find_first_file(&git_path.join(folder_name).join("dist"));
+    let archive_path = util::find_first_file(dist.path());
     let filename = archive_path
         .file_name()
         .expect("Problem pulling filename from archive path");
@@ -700,9 +1029,57 @@ pub fn download_and_install_git(
     // Remove the created and moved wheel
     if fs::remove_file(&archive_path).is_err() {
         util::abort(&format!(
-            "Problem removing this wheel built from a git repo: {:?}",
+            "Problem removing this wheel we built: {:?}",
             archive_path
         ));
     }
     metadata
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A source package whose `setup.py` fails.
+    fn failing_build(name: &str) -> SourceBuild {
+        let dir = util::TempDir::new("build");
+        let src_path = dir.path().join(name);
+        fs::create_dir_all(&src_path).unwrap();
+        fs::write(src_path.join("setup.py"), "raise SystemExit('stub')\n").unwrap();
+        SourceBuild {
+            name: name.to_owned(),
+            version: Version::new(1, 0, 0),
+            dir,
+            src_path,
+            rename: None,
+        }
+    }
+
+    #[test]
+    fn failed_builds() {
+        let env = util::TempDir::new("env");
+        let paths = util::Paths {
+            bin: env.path().join("bin"),
+            lib: env.path().join("lib"),
+            entry_pt: env.path().join("bin"),
+            cache: env.path().join("cache"),
+            temp: env.path().join("tmp"),
+        };
+        let builds: Vec<SourceBuild> = ["a", "b", "c"].iter().map(|n| failing_build(n)).collect();
+
+        let options = BuildOptions {
+            jobs: 1,
+            keep_going: false,
+        };
+        let results = build_in_parallel(&builds, &paths, options);
+        assert!(matches!(results[0], Some(Err(_))));
+        assert!(results[1].is_none() && results[2].is_none());
+
+        let options = BuildOptions {
+            keep_going: true,
+            ..options
+        };
+        let results = build_in_parallel(&builds, &paths, options);
+        assert!(results.iter().all(|r| matches!(r, Some(Err(_)))));
+    }
+}
@@ -0,0 +1,228 @@
+//! An append-only journal of the installs and uninstalls a sync makes. If a sync's interrupted,
+//! the next one finds the packages it left half-installed, and redoes them; packages it finished
+//! were verified, and are skipped. `pyflow doctor` reports or rolls back unfinished operations.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Install,
+    Uninstall,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Install => "install",
+            Self::Uninstall => "uninstall",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "install" => Some(Self::Install),
+            "uninstall" => Some(Self::Uninstall),
+            _ => None,
+        }
+    }
+}
+
+/// An install or uninstall of one package version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Op {
+    pub action: Action,
+    pub name: String,
+    pub version: String,
+}
+
+/// Find operations a sync started, but didn't finish. Returns `None` if the sync completed.
+/// Lines are eg `start install numpy 1.17.0`, followed by `done install numpy 1.17.0` once it's
+/// finished, and `end` once the whole sync has.
+fn parse(text: &str) -> Option<Vec<Op>> {
+    let mut unfinished: Vec<Op> = vec![];
+    let mut complete = true;
+    for line in text.lines() {
+        let parts: Vec<&str> = line.split('\t').collect();
+        match parts.as_slice() {
+            ["begin", ..] => {
+                complete = false;
+                unfinished.clear();
+            }
+            ["end"] => complete = true,
+            [state, action, name, version] => {
+                let op = match Action::from_name(action) {
+                    Some(action) => Op {
+                        action,
+                        name: (*name).to_owned(),
+                        version: (*version).to_owned(),
+                    },
+                    None => continue,
+                };
+                match *state {
+                    "start" => unfinished.push(op),
+                    "done" => unfinished.retain(|o| *o != op),
+                    _ => (),
+                }
+            }
+            // Eg the last line, if we were interrupted while writing it.
+            _ => (),
+        }
+    }
+
+    if complete {
+        None
+    } else {
+        Some(unfinished)
+    }
+}
+
+/// Find operations an interrupted sync didn't finish, from its journal. Returns `None` if there's
+/// no journal, or the last sync completed.
+pub fn find_unfinished(path: &Path) -> Option<Vec<Op>> {
+    parse(&fs::read_to_string(path).ok()?)
+}
+
+pub struct Journal {
+    file: fs::File,
+}
+
+impl Journal {
+    /// Start the journal for a new sync, replacing the last one's.
+    pub fn begin(path: &Path) -> io::Result<Self> {
+        let mut journal = Self {
+            file: fs::File::create(path)?,
+        };
+        journal.write(&format!("begin\t{}", process::id()))?;
+        Ok(journal)
+    }
+
+    /// Write a line, and make sure it's on disk before we act on it.
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()
+    }
+
+    pub fn start(&mut self, action: Action, name: &str, version: &str) {
+        self.write(&format!("start\t{}\t{}\t{}", action.name(), name, version))
+            .ok();
+    }
+
+    pub fn done(&mut self, action: Action, name: &str, version: &str) {
+        self.write(&format!("done\t{}\t{}\t{}", action.name(), name, version))
+            .ok();
+    }
+
+    /// Record that the sync's complete.
+    pub fn end(mut self) {
+        self.write("end").ok();
+    }
+}
+
+/// Stops two syncs from changing an environment at once. Released when dropped.
+pub struct SyncLock {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    // Signal 0 checks the process exists, without affecting it.
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_running(_pid: u32) -> bool {
+    true
+}
+
+impl SyncLock {
+    /// Take the lock, unless another running process has it; then, return its process id. A lock
+    /// left by a process that's no longer running, eg one that was killed, is taken over.
+    pub fn acquire(path: &Path) -> Result<Self, u32> {
+        for _ in 0..2 {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(mut f) => {
+                    write!(f, "{}", process::id()).ok();
+                    return Ok(Self {
+                        path: path.to_owned(),
+                    });
+                }
+                Err(_) => {
+                    let holder = fs::read_to_string(path)
+                        .ok()
+                        .and_then(|s| s.trim().parse::<u32>().ok());
+                    match holder {
+                        Some(pid) if is_running(pid) => return Err(pid),
+                        _ => {
+                            fs::remove_file(path).ok();
+                        }
+                    }
+                }
+            }
+        }
+        Err(0)
+    }
+}
+
+impl Drop for SyncLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn op(action: Action, name: &str, version: &str) -> Op {
+        Op {
+            action,
+            name: name.into(),
+            version: version.into(),
+        }
+    }
+
+    #[test]
+    fn unfinished_ops() {
+        let complete = "begin\t12\nstart\tinstall\tsix\t1.12.0\ndone\tinstall\tsix\t1.12.0\nend\n";
+        assert_eq!(parse(complete), None);
+
+        let interrupted = "begin\t12\n\
+                           start\tuninstall\tsix\t1.11.0\n\
+                           done\tuninstall\tsix\t1.11.0\n\
+                           start\tinstall\tsix\t1.12.0\n\
+                           done\tinstall\tsix\t1.12.0\n\
+                           start\tinstall\tnumpy\t1.17.0\n\
+                           done\tinst";
+        assert_eq!(
+            parse(interrupted),
+            Some(vec![op(Action::Install, "numpy", "1.17.0")])
+        );
+
+        assert_eq!(parse("begin\t12\n"), Some(vec![]));
+    }
+
+    #[test]
+    fn sync_lock() {
+        let dir = crate::util::TempDir::new("sync-lock");
+        let path = dir.path().join("sync.lock");
+        let lock = SyncLock::acquire(&path).unwrap();
+        assert_eq!(SyncLock::acquire(&path).err(), Some(process::id()));
+        drop(lock);
+        assert!(!path.exists());
+
+        // A lock from a process that's exited is taken over.
+        if cfg!(unix) {
+            fs::write(&path, "4294967").unwrap();
+            assert!(SyncLock::acquire(&path).is_ok());
+        }
+    }
+}
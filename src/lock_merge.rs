@@ -0,0 +1,258 @@
+//! A three-way merge of `pyflow.lock` files, for use as a git merge driver. Packages changed on
+//! only one side are taken from it; packages changed differently on both are left out, so the
+//! next sync re-resolves just those, and keeps the rest of the pins.
+
+use crate::{
+    dep_types::{Lock, LockPackage},
+    util,
+};
+use std::collections::HashMap;
+
+/// Identifies a package across locks: its name, and the name it's renamed to, if any. Ids aren't
+/// used, since each side assigns its own.
+type Key = (String, Option<String>);
+
+fn rename_parts(rename: &str) -> Option<(u32, &str)> {
+    let mut parts = rename.splitn(2, ' ');
+    let id = parts.next()?.parse().ok()?;
+    Some((id, parts.next()?))
+}
+
+fn key(pack: &LockPackage) -> Key {
+    (
+        util::standardize_name(&pack.name),
+        pack.rename
+            .as_ref()
+            .and_then(|r| rename_parts(r))
+            .map(|(_, name)| util::standardize_name(name)),
+    )
+}
+
+/// Compare packages, ignoring ids.
+fn same(a: &LockPackage, b: &LockPackage) -> bool {
+    a.version == b.version
+        && a.source == b.source
+        && a.dependencies == b.dependencies
+        && a.markers == b.markers
+}
+
+/// Merge one value that may be missing from any side. `Err` means both sides changed it, in
+/// different ways.
+fn merge3<T: Clone>(
+    base: Option<&T>,
+    ours: Option<&T>,
+    theirs: Option<&T>,
+    eq: impl Fn(&T, &T) -> bool,
+) -> Result<Option<T>, ()> {
+    let opt_eq = |a: Option<&T>, b: Option<&T>| match (a, b) {
+        (Some(a), Some(b)) => eq(a, b),
+        (None, None) => true,
+        _ => false,
+    };
+
+    if opt_eq(ours, theirs) || opt_eq(base, theirs) {
+        Ok(ours.cloned())
+    } else if opt_eq(base, ours) {
+        Ok(theirs.cloned())
+    } else {
+        Err(())
+    }
+}
+
+/// The result of merging locks: the merged lock, and names of packages that conflicted, and
+/// need to be re-resolved.
+#[derive(Debug)]
+pub struct Merged {
+    pub lock: Lock,
+    pub conflicts: Vec<String>,
+}
+
+pub fn merge(base: &Lock, ours: &Lock, theirs: &Lock) -> Merged {
+    let empty = vec![];
+    let packs =
+        |lock: &'_ Lock| -> Vec<LockPackage> { lock.package.as_ref().unwrap_or(&empty).to_vec() };
+    let (base_packs, our_packs, their_packs) = (packs(base), packs(ours), packs(theirs));
+    let by_key = |packs: &[LockPackage]| -> HashMap<Key, LockPackage> {
+        packs.iter().map(|p| (key(p), p.clone())).collect()
+    };
+    let (base_map, our_map, their_map) = (
+        by_key(&base_packs),
+        by_key(&our_packs),
+        by_key(&their_packs),
+    );
+
+    // Keep our order, followed by packages only they have.
+    let mut keys: Vec<Key> = our_packs.iter().map(key).collect();
+    for pack in base_packs.iter().chain(their_packs.iter()) {
+        if !keys.contains(&key(pack)) {
+            keys.push(key(pack));
+        }
+    }
+
+    let mut conflicts = vec![];
+    // The merged package, and the lock it came from, to look up rename parents in.
+    let mut merged: Vec<(LockPackage, &[LockPackage])> = vec![];
+    for k in &keys {
+        let (b, o, t) = (base_map.get(k), our_map.get(k), their_map.get(k));
+        match merge3(b, o, t, same) {
+            Ok(Some(pack)) => {
+                let source: &[LockPackage] = if o.map_or(false, |o| same(o, &pack)) {
+                    &our_packs
+                } else {
+                    &their_packs
+                };
+                merged.push((pack, source));
+            }
+            Ok(None) => (),
+            Err(_) => conflicts.push(o.or(t).map(|p| p.name.clone()).unwrap_or_default()),
+        }
+    }
+
+    // Each side numbers packages independently, so renumber them, and point renames at the
+    // new ids. A renamed package whose parent didn't make it is re-resolved too.
+    let merged_keys: Vec<Key> = merged.iter().map(|(p, _)| key(p)).collect();
+    let mut result = vec![];
+    for (pack, source) in &merged {
+        let mut pack = pack.clone();
+        pack.id = merged_keys.iter().position(|k| k == &key(&pack)).unwrap() as u32;
+
+        if let Some((parent_id, new_name)) = pack.rename.as_ref().and_then(|r| rename_parts(r)) {
+            let new_parent = source
+                .iter()
+                .find(|p| p.id == parent_id)
+                .and_then(|parent| merged_keys.iter().position(|k| k == &key(parent)));
+            match new_parent {
+                Some(id) => pack.rename = Some(format!("{} {}", id, new_name)),
+                None => {
+                    conflicts.push(pack.name.clone());
+                    continue;
+                }
+            }
+        }
+        result.push(pack);
+    }
+
+    let mut metadata = HashMap::new();
+    let mut meta_keys: Vec<&String> = ours.metadata.keys().collect();
+    meta_keys.extend(base.metadata.keys().chain(theirs.metadata.keys()));
+    for k in meta_keys {
+        let (b, o, t) = (
+            base.metadata.get(k),
+            ours.metadata.get(k),
+            theirs.metadata.get(k),
+        );
+        // Metadata follows the packages; if it conflicts, ours is as good as theirs.
+        match merge3(b, o, t, |a, b| a == b) {
+            Ok(Some(v)) => {
+                metadata.insert(k.clone(), v);
+            }
+            Ok(None) => (),
+            Err(_) => {
+                metadata.insert(k.clone(), o.cloned().unwrap_or_default());
+            }
+        }
+    }
+
+    conflicts.sort();
+    conflicts.dedup();
+
+    Merged {
+        lock: Lock {
+            package: Some(result),
+            metadata,
+        },
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn pack(id: u32, name: &str, version: &str) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.into(),
+            version: version.into(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            markers: None,
+            extras: None,
+        }
+    }
+
+    fn lock(packs: Vec<LockPackage>) -> Lock {
+        Lock {
+            package: Some(packs),
+            metadata: HashMap::new(),
+        }
+    }
+
+    fn versions(lock: &Lock) -> Vec<(String, String)> {
+        lock.package
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|p| (p.name.clone(), p.version.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn merge_locks() {
+        let base = lock(vec![
+            pack(0, "requests", "2.22.0"),
+            pack(1, "urllib3", "1.25.3"),
+            pack(2, "six", "1.12.0"),
+            pack(3, "numpy", "1.17.0"),
+        ]);
+        // We upgrade urllib3 and numpy, and remove six.
+        let ours = lock(vec![
+            pack(0, "requests", "2.22.0"),
+            pack(1, "urllib3", "1.25.6"),
+            pack(2, "numpy", "1.17.2"),
+        ]);
+        // They add click, keep six, and upgrade numpy differently.
+        let theirs = lock(vec![
+            pack(0, "click", "7.0.0"),
+            pack(1, "requests", "2.22.0"),
+            pack(2, "urllib3", "1.25.3"),
+            pack(3, "six", "1.12.0"),
+            pack(4, "numpy", "1.17.3"),
+        ]);
+
+        let merged = merge(&base, &ours, &theirs);
+        assert_eq!(
+            versions(&merged.lock),
+            vec![
+                ("requests".to_owned(), "2.22.0".to_owned()),
+                ("urllib3".to_owned(), "1.25.6".to_owned()),
+                ("click".to_owned(), "7.0.0".to_owned()),
+            ]
+        );
+        assert_eq!(merged.conflicts, vec!["numpy".to_owned()]);
+    }
+
+    #[test]
+    fn merge_renames() {
+        let base = lock(vec![]);
+        let ours = lock(vec![pack(0, "requests", "2.22.0")]);
+        let mut renamed = pack(5, "urllib3", "1.24.0");
+        renamed.rename = Some("3 urllib3_1_24".into());
+        let theirs = lock(vec![
+            pack(2, "urllib3", "1.25.3"),
+            pack(3, "botocore", "1.13.0"),
+            renamed,
+        ]);
+
+        let merged = merge(&base, &ours, &theirs);
+        let packs = merged.lock.package.unwrap();
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(packs.len(), 4);
+        assert_eq!(packs[2].name, "botocore");
+        assert_eq!(
+            packs[3].rename,
+            Some(format!("{} urllib3_1_24", packs[2].id))
+        );
+    }
+}
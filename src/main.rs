@@ -1,25 +1,53 @@
 #![allow(clippy::non_ascii_literal)]
+#![recursion_limit = "256"] // For the `json!` schemas.
 
-use crate::dep_types::{Constraint, Lock, LockPackage, Package, Rename, Req, ReqType, Version};
+use crate::dep_types::{
+    Constraint, DependencyError, GitRef, Lock, LockPackage, Marker, MarkerEnv, Package, Rename,
+    Req, ReqType, Version,
+};
 use crate::util::{abort, Os};
-use crossterm::{Color, Colored};
+use crossterm::Color;
 use regex::Regex;
 use serde::Deserialize;
-use std::{collections::HashMap, env, error::Error, fs, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap, env, error::Error, fs, path::PathBuf, process, str::FromStr, time,
+};
 
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use structopt::StructOpt;
 
+mod bootstrap;
 mod build;
+mod build_logs;
+mod bundle;
 mod commands;
 mod dep_parser;
 mod dep_resolution;
 mod dep_types;
+mod dev_tools;
+mod devcontainer;
+mod env_pack;
+mod env_vars;
+mod fetch;
 mod files;
 mod install;
+mod journal;
+mod lock_merge;
+mod minisign;
+mod net;
+mod paths;
+mod policy;
 mod py_versions;
+mod pylock;
+mod rename;
+mod schema;
+mod tasks;
+mod tree;
+mod tui;
 mod util;
+mod warnings;
+mod workspace;
 
 // todo:
 // Custom build system
@@ -31,6 +59,8 @@ mod util;
 // clear download git source as an option. In general, git install is a mess
 
 type PackToInstall = ((String, Version), Option<(u32, String)>); // ((Name, Version), (parent id, rename name))
+/// A release's files, or why it can't be found.
+type FoundReleases = Result<Vec<dep_resolution::WarehouseRelease>, String>;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "pyflow", about = "Python packaging and publishing")]
@@ -42,6 +72,154 @@ struct Opt {
 
     #[structopt(short = "ms", long)]
     ms: Vec<String>,
+
+    /// When to color output: `auto`, `always`, or `never`. `auto` respects `NO_COLOR`, and
+    /// disables color when not writing to a terminal.
+    #[structopt(long, default_value = "auto", global = true)]
+    color: util::ColorChoice,
+
+    /// Don't warn when run inside an activated virtualenv or conda env. Pyflow always uses its own
+    /// environment; this overrides `active_env` in `pyproject.toml`.
+    #[structopt(long, global = true)]
+    ignore_active_env: bool,
+
+    /// Run in the project at this path, instead of searching for one from the current directory.
+    /// Eg `pyflow --project ~/code/myproj install`.
+    #[structopt(long, global = true, parse(from_os_str))]
+    project: Option<PathBuf>,
+
+    /// Exit with an error if `pyflow.lock` needs to be updated, instead of re-locking. Eg for CI.
+    #[structopt(long, global = true)]
+    locked: bool,
+
+    /// Install exactly what's in `pyflow.lock`, without resolving dependencies or updating it.
+    /// Like `--locked`, exits with an error if it doesn't satisfy `pyproject.toml`.
+    #[structopt(long, global = true)]
+    frozen: bool,
+
+    /// Create temporary build and extraction directories here, instead of the system's temp
+    /// directory. Eg if `/tmp` is small or `noexec`. Overrides `temp_dir` in `pyproject.toml`.
+    #[structopt(long, global = true, parse(from_os_str))]
+    temp_dir: Option<PathBuf>,
+
+    /// The architecture to install Python for, eg `x86` for 32-bit Python on 64-bit Windows.
+    /// Defaults to the one pyflow's built for.
+    #[structopt(long, global = true)]
+    arch: Option<String>,
+
+    /// The Python interpreter to create environments with, eg `python3.11` or a full path,
+    /// instead of prompting when several match the project's version.
+    #[structopt(long, global = true, env = "PYFLOW_PYTHON")]
+    py_alias: Option<String>,
+
+    /// Rebuild the environment if it was made with a different Python version than the
+    /// project's, and remove ones for versions it no longer uses. Eg after changing `py_version`.
+    #[structopt(long, global = true)]
+    migrate: bool,
+
+    /// Allow dependencies to resolve to pre-releases, eg `2.0.0rc1`. Without it, they only do
+    /// if their constraints name one. Overridden per package by `[tool.pyflow.prereleases]`.
+    #[structopt(long, global = true)]
+    pre: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum EnvCommand {
+    /// Display each variable, and whether it's set
+    #[structopt(name = "vars")]
+    Vars {
+        /// Exit with an error if any required variables are missing. Eg for CI.
+        #[structopt(long)]
+        check: bool,
+        /// Load environment variables from a file, eg `.env`, before checking
+        #[structopt(long, parse(from_os_str))]
+        with_env: Option<PathBuf>,
+    },
+    /// Pack the synced environment, and its Python, into an archive to unpack on machines
+    /// without index access. They need the same OS and architecture.
+    #[structopt(name = "pack")]
+    Pack {
+        /// Where to write it. Defaults to eg `myproject-env-3.12-linux-x86_64.tar.gz`.
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Unpack an archive from `pyflow env pack` into `__pypackages__`, updating the paths its
+    /// scripts and Python use
+    #[structopt(name = "unpack")]
+    Unpack {
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum DebugCommand {
+    /// Show the metadata pyflow uses for a release, eg `pyflow debug metadata requests==2.22.0`.
+    /// Useful when reporting unexpected resolution results.
+    #[structopt(name = "metadata")]
+    Metadata {
+        /// The package, optionally with a version. Defaults to the latest.
+        #[structopt(name = "package")]
+        package: String,
+    },
+    /// Collect diagnostics into a `.tar.gz` to attach to a bug report: pyflow's version, the
+    /// platform, settings with secrets redacted, `pyproject.toml`, the lock, and recent syncs
+    #[structopt(name = "bundle")]
+    Bundle {
+        /// Where to write it. Defaults to `pyflow-bundle-{timestamp}.tar.gz` here
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum WorkspaceCommand {
+    /// Show which members depend on which, through path dependencies, in build order
+    #[structopt(name = "graph")]
+    Graph,
+    /// List the members affected by changes since a git revision: those with changed files, and
+    /// those that depend on them. Eg `pyflow workspace changed --since origin/main`
+    #[structopt(name = "changed")]
+    Changed {
+        #[structopt(long)]
+        since: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum DevcontainerCommand {
+    /// Write a `.devcontainer/devcontainer.json` that runs `pyflow sync` when the container's
+    /// created, with pyflow's directory kept in a volume
+    #[structopt(name = "init")]
+    Init,
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Show `[tool.pyflow]` merged with the files it `extends`, as pyflow uses it
+    #[structopt(name = "resolve")]
+    Resolve,
+}
+
+#[derive(StructOpt, Debug)]
+enum LockCommand {
+    /// Merge lock files changed on two branches, for use as a git merge driver. Packages that
+    /// conflict are left out, and re-resolved by the next `pyflow install`. Eg
+    /// `pyflow lock merge --ours a.lock --theirs b.lock --base base.lock`
+    #[structopt(name = "merge")]
+    Merge {
+        /// Our version of the lock file. The result is written here, unless `--output` is set.
+        #[structopt(long, parse(from_os_str))]
+        ours: PathBuf,
+        /// Their version of the lock file
+        #[structopt(long, parse(from_os_str))]
+        theirs: PathBuf,
+        /// The lock file both branches started from
+        #[structopt(long, parse(from_os_str))]
+        base: PathBuf,
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -64,6 +242,31 @@ enum SubCommand {
         /// Save package to your dev-dependencies section
         #[structopt(short, long)]
         dev: bool,
+        /// Limit versions with a constraints file, in pip's format, eg `urllib3<2`. Packages in
+        /// it aren't installed unless something requires them
+        #[structopt(short = "c", long = "constraint", parse(from_os_str))]
+        constraints: Vec<PathBuf>,
+    },
+    /// Install the locked packages, and remove others. With `--from`, install exactly the
+    /// packages in a `pylock.toml`, or a `requirements.txt` pinned with hashes, without using
+    /// `pyproject.toml` or `pyflow.lock`. Eg `pyflow sync --from pylock.toml`
+    #[structopt(name = "sync")]
+    Sync {
+        #[structopt(long, parse(from_os_str))]
+        from: Option<PathBuf>,
+        /// The Python version to install them for, if `pyproject.toml` doesn't specify one
+        #[structopt(long)]
+        python: Option<String>,
+        /// List the packages installed or skipped because of their environment markers
+        #[structopt(long)]
+        explain_markers: bool,
+        /// Build up to this many source-only packages at once, each in its own temporary
+        /// directory. Eg `--build-jobs 4`
+        #[structopt(long, default_value = "1")]
+        build_jobs: usize,
+        /// Keep building source packages after one fails, to see every failure at once
+        #[structopt(long)]
+        keep_going: bool,
     },
     /// Uninstall all packages, or ones specified
     #[structopt(name = "uninstall")]
@@ -71,11 +274,22 @@ enum SubCommand {
         #[structopt(name = "packages")]
         packages: Vec<String>,
     },
-    /// Run python
+    /// Run python. Or manage the Python versions pyflow installs: `pyflow python list`,
+    /// `pyflow python install 3.11`, `pyflow python uninstall 3.9`, and `pyflow python pin 3.11`
+    /// to write a `.python-version`. `pyflow python shims` makes
+    /// version-named commands for them, eg `python3.11`, and `pyflow python exec 3.10 -- app.py`
+    /// runs one outside a project. `pyflow python prune` removes those no environment uses
     #[structopt(name = "python")]
     Python {
         #[structopt(name = "args")]
         args: Vec<String>,
+        /// With `install`, install from this archive instead of downloading Python, eg
+        /// `pyflow python install --from-file python-3.10.tar.xz`
+        #[structopt(long, parse(from_os_str))]
+        from_file: Option<PathBuf>,
+        /// With `prune`, show what would be removed, without removing it
+        #[structopt(long)]
+        dry_run: bool,
     },
     /// Display all installed packages and console scripts
     #[structopt(name = "list")]
@@ -85,6 +299,10 @@ enum SubCommand {
     Package {
         #[structopt(name = "extras")]
         extras: Vec<String>,
+        /// In a workspace root, build every member, each after the members it depends on. Path
+        /// dependencies between members are pinned to their versions.
+        #[structopt(long)]
+        all: bool,
     },
     /// Publish to `pypi`
     #[structopt(name = "publish")]
@@ -92,6 +310,22 @@ enum SubCommand {
     /// Create a `pyproject.toml` from requirements.txt, pipfile etc, setup.py etc
     #[structopt(name = "init")]
     Init,
+    /// Create a `pyproject.toml` from a legacy `setup.py` or `setup.cfg`. Eg `pyflow migrate setup.py`
+    #[structopt(name = "migrate")]
+    Migrate {
+        #[structopt(name = "file", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Rename the project: in `pyproject.toml`, its entry points, editable installs of it, and
+    /// `pyflow.lock`. Eg `pyflow rename rocket`
+    #[structopt(name = "rename")]
+    Rename {
+        #[structopt(name = "name")]
+        name: String,
+        /// Rename its package folder too, eg `my_app` or `src/my_app`
+        #[structopt(long)]
+        move_package: bool,
+    },
     /// Remove the environment, and uninstall all packages
     #[structopt(name = "reset")]
     Reset,
@@ -102,6 +336,9 @@ enum SubCommand {
     /// as a shortcut.
     #[structopt(name = "run")] // We don't need to invoke this directly, but the option exists
     Run {
+        /// Load environment variables from a file, eg `.env`, before running
+        #[structopt(long, parse(from_os_str))]
+        with_env: Option<PathBuf>,
         #[structopt(name = "args")]
         args: Vec<String>,
     },
@@ -132,6 +369,200 @@ enum SubCommand {
         #[structopt(name = "version")]
         version: String,
     },
+    /// Work with the environment variables scripts need, specified in `[tool.pyflow.env-vars]`
+    #[structopt(name = "env")]
+    Env {
+        #[structopt(subcommand)]
+        cmd: EnvCommand,
+    },
+    /// List package names on `PyPi` starting with a prefix, one per line. Used for shell and
+    /// editor completion of `pyflow install`.
+    #[structopt(name = "complete-package")]
+    CompletePackage {
+        #[structopt(name = "prefix")]
+        prefix: String,
+    },
+    /// Show a JSON Schema for a file pyflow uses: `lock`, `pyproject`, or `config`. Eg
+    /// `pyflow schema pyproject > pyflow.schema.json`, for editors to validate it with
+    #[structopt(name = "schema")]
+    Schema {
+        #[structopt(name = "name")]
+        name: Option<String>,
+    },
+    /// Export locked dependencies in `requirements.txt` format, for a target platform and Python
+    /// version. Eg `pyflow export --platform linux --python 3.8 -o requirements.txt`
+    #[structopt(name = "export")]
+    Export {
+        /// `requirements`, or `pylock` for a PEP 751 `pylock.toml`, with each package's files
+        /// and hashes
+        #[structopt(long)]
+        format: Option<String>,
+        /// The target operating system: `linux`, `windows`, or `mac`. Defaults to this one.
+        #[structopt(long)]
+        platform: Option<String>,
+        /// The target Python version. Defaults to `py_version` in `pyproject.toml`.
+        #[structopt(long)]
+        python: Option<String>,
+        /// Include dev dependencies
+        #[structopt(short, long)]
+        dev: bool,
+        /// Write to this file, instead of the terminal
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Download the files the lock needs for platforms and Python versions into the cache,
+    /// without installing them, eg `pyflow fetch --platform linux,windows --python 3.11,3.12`
+    #[structopt(name = "fetch")]
+    Fetch {
+        /// Target operating systems: `linux`, `windows`, or `mac`. Defaults to this one.
+        #[structopt(long, use_delimiter = true)]
+        platform: Vec<String>,
+        /// Target Python versions. Defaults to `py_version` in `pyproject.toml`.
+        #[structopt(long, use_delimiter = true)]
+        python: Vec<String>,
+        /// Include dev dependencies
+        #[structopt(short, long)]
+        dev: bool,
+    },
+    /// Run the project's tests with pytest. Pass arguments after `--`, eg `pyflow test -- -x`
+    #[structopt(name = "test")]
+    Test {
+        /// Collect coverage with coverage.py, and show a report combining all environments
+        #[structopt(long)]
+        coverage: bool,
+        /// With `--coverage`, exit with an error if total coverage is below this percentage
+        #[structopt(long)]
+        fail_under: Option<f32>,
+        /// Run in environments for these Python versions instead, eg `--python 3.7,3.8`
+        #[structopt(long, use_delimiter = true)]
+        python: Vec<String>,
+        #[structopt(name = "args")]
+        args: Vec<String>,
+    },
+    /// Lint the project with Ruff, or flake8 if that's what it's configured for
+    #[structopt(name = "lint")]
+    Lint {
+        #[structopt(name = "args")]
+        args: Vec<String>,
+    },
+    /// Format the project's Python code with Black, or Ruff if that's what it's configured for
+    #[structopt(name = "fmt-py")]
+    FmtPy {
+        #[structopt(name = "args")]
+        args: Vec<String>,
+    },
+    /// Tools for diagnosing problems
+    #[structopt(name = "debug")]
+    Debug {
+        #[structopt(subcommand)]
+        cmd: DebugCommand,
+    },
+    /// Set up a devcontainer, eg for VS Code or Codespaces. Eg `pyflow devcontainer init`
+    #[structopt(name = "devcontainer")]
+    Devcontainer {
+        #[structopt(subcommand)]
+        cmd: DevcontainerCommand,
+    },
+    /// Work with `pyproject.toml`'s settings. Eg `pyflow config resolve`
+    #[structopt(name = "config")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Work with `pyflow.lock`. Eg `pyflow lock merge`
+    #[structopt(name = "lock")]
+    Lock {
+        #[structopt(subcommand)]
+        cmd: LockCommand,
+    },
+    /// Work with a workspace's members. Eg `pyflow workspace graph`
+    #[structopt(name = "workspace")]
+    Workspace {
+        #[structopt(subcommand)]
+        cmd: WorkspaceCommand,
+    },
+    /// Show a summary of this project: its Python version, environment, and locked dependencies
+    #[structopt(name = "status")]
+    Status,
+    /// Show the output of source package builds, from recent syncs. Eg `pyflow logs numpy`, or
+    /// `pyflow logs --last-failure`. Without either, lists the logs kept
+    #[structopt(name = "logs")]
+    Logs {
+        #[structopt(name = "package")]
+        package: Option<String>,
+        /// Show the most recent failed build's log
+        #[structopt(long)]
+        last_failure: bool,
+    },
+    /// Show the locked dependencies as a tree, starting from the project's requirements, or
+    /// from a package, eg `pyflow tree requests`. Packages shown earlier are marked `(*)`
+    #[structopt(name = "tree")]
+    Tree {
+        #[structopt(name = "package")]
+        package: Option<String>,
+        /// How many levels of dependencies to show
+        #[structopt(long)]
+        depth: Option<usize>,
+        /// Show what depends on each package instead, eg `pyflow tree --invert urllib3`
+        #[structopt(short, long)]
+        invert: bool,
+    },
+    /// Explore the project in a terminal dashboard: its dependency tree, outdated and vulnerable
+    /// packages, and environment, with keys to show, update and remove packages
+    #[structopt(name = "ui")]
+    Ui {},
+    /// Show each chain of dependencies that pulls a package in, from the project's requirements
+    /// down to it, eg `pyflow why urllib3`
+    #[structopt(name = "why")]
+    Why {
+        #[structopt(name = "package")]
+        package: String,
+    },
+    /// Check the environment for operations an interrupted `pyflow install` didn't finish.
+    /// Running `pyflow install` again finishes them
+    #[structopt(name = "doctor")]
+    Doctor {
+        /// Remove packages that were left partly installed, instead of finishing them
+        #[structopt(long)]
+        rollback: bool,
+    },
+    /// Show how far behind the latest releases locked dependencies are, and which have known
+    /// vulnerabilities, most urgent first. Eg `pyflow outdated`, or `pyflow outdated numpy`
+    #[structopt(name = "outdated")]
+    Outdated {
+        #[structopt(name = "packages")]
+        packages: Vec<String>,
+    },
+    /// Show a locked dependency's release dates, how far behind the latest it is, and its known
+    /// vulnerabilities. Eg `pyflow info requests`
+    #[structopt(name = "info")]
+    Info {
+        #[structopt(name = "package")]
+        package: String,
+    },
+    /// Allow packages to be installed when `require_approval` is set in `pyproject.toml`, by
+    /// adding them to `pyflow.approved`. Eg `pyflow approve numpy scipy`
+    #[structopt(name = "approve")]
+    Approve {
+        #[structopt(name = "packages")]
+        packages: Vec<String>,
+    },
+    /// Check the project: compare `pyflow.lock` against a blessed lock file, exiting with an
+    /// error if it has packages or versions the baseline doesn't, eg
+    /// `pyflow check --baseline https://example.com/pyflow.lock`. Or check type stubs.
+    #[structopt(name = "check")]
+    Check {
+        /// A URL or path to the baseline lock file
+        #[structopt(long)]
+        baseline: Option<String>,
+        /// Find `types-*` stub packages for dependencies, and stubs for removed ones. Eg
+        /// `pyflow check --stubs`, for projects using mypy
+        #[structopt(long)]
+        stubs: bool,
+        /// With `--stubs`, add missing stubs to `dev-dependencies`, and remove unneeded ones
+        #[structopt(long)]
+        fix: bool,
+    },
 }
 
 /// A config, parsed from pyproject.toml
@@ -140,6 +571,9 @@ enum SubCommand {
 pub struct Config {
     name: Option<String>,
     py_version: Option<Version>,
+    #[serde(skip)]
+    py_implementation: util::Implementation, // From `py_version`, eg `pypy3.10`.
+    py_constraints: Vec<Constraint>, // From `py_version`, if it's eg `>=3.7.4`.
     reqs: Vec<Req>,
     dev_reqs: Vec<Req>,
     version: Option<Version>,
@@ -158,11 +592,31 @@ pub struct Config {
     //    entry_points: HashMap<String, Vec<String>>, // todo option?
     scripts: HashMap<String, String>, //todo: put under [tool.pyflow.scripts] ?
     //    console_scripts: Vec<String>, // We don't parse these; pass them to `setup.py` as-entered.
+    #[serde(skip)]
+    tasks: HashMap<String, tasks::TaskOpts>, // Options for scripts specified as tables.
     python_requires: Option<String>,
+    active_env: Option<util::ActiveEnvPolicy>,
+    members: Vec<String>, // Workspace member directories, relative to this project.
+    internal_prefixes: Vec<String>, // Names that must never come from PyPI, eg `acme-*`.
+    require_approval: bool, // New packages must be listed in `pyflow.approved` to install.
+    prereleases: HashMap<String, bool>, // Packages that may, or may not, resolve to pre-releases.
+    overrides: Vec<Req>,  // Constraints that replace any others on these packages.
+    constraints: Vec<PathBuf>, // Constraints files, relative to the project.
+    policy: policy::Policy,
+    sources: Vec<dep_resolution::Source>, // From `[tool.pyflow.sources]`, and `source` keys.
+    temp_dir: Option<PathBuf>,            // Relative to the project.
+    prompt_timeout: Option<u64>,          // Seconds. `PYFLOW_PROMPT_TIMEOUT` overrides it.
+    python_update_policy: py_versions::UpdatePolicy,
+    python_archive: Option<PathBuf>, // Relative to the project.
+    py_variant: py_versions::Variant,
+    warnings: Vec<warnings::Suppression>,
+    env_vars: Vec<env_vars::EnvVar>,
+    #[serde(skip)]
+    sync_exports: Vec<SyncExport>,
 }
 
 /// Reduce repetition between reqs and dev reqs when populating reqs of path reqs.
-fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
+fn pop_reqs_helper(reqs: &[Req], dev: bool, net: &net::Settings) -> Vec<Req> {
     let mut result = vec![];
     for req in reqs.iter().filter(|r| r.path.is_some()) {
         let req_path = PathBuf::from(req.path.clone().unwrap());
@@ -189,7 +643,7 @@ fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
         // We don't parse `setup.py`, since it involves running arbitrary Python code.
 
         if pyproj.exists() {
-            let mut req_cfg = Config::from_file(&PathBuf::from(&pyproj))
+            let mut req_cfg = Config::from_file(&PathBuf::from(&pyproj), net)
                 .unwrap_or_else(|| panic!("Problem parsing`pyproject.toml`: {:?}", &pyproj));
             result.append(&mut req_cfg.reqs)
         }
@@ -213,11 +667,18 @@ impl Config {
     /// Helper fn to prevent repetition
     fn parse_deps(deps: HashMap<String, files::DepComponentWrapper>) -> Vec<Req> {
         let mut result = Vec::new();
-        for (name, data) in deps {
+        for (key, data) in deps {
+            // Eg `"requests[socks]" = "^2.31"`.
+            let (name, mut extras) = match Req::from_str(&key, false) {
+                Ok(r) => (r.name, r.install_with_extras),
+                Err(_) => (key, None),
+            };
             let constraints;
-            let mut extras = None;
             let mut git = None;
+            let mut git_ref = None;
             let mut path = None;
+            let mut editable = false;
+            let mut url = None;
             let mut python_version = None;
             match data {
                 files::DepComponentWrapper::A(constrs) => {
@@ -248,14 +709,37 @@ impl Config {
                     };
 
                     if let Some(ex) = subdata.extras {
-                        extras = Some(ex);
+                        let mut all = extras.unwrap_or_default();
+                        all.extend(
+                            ex.into_iter()
+                                .filter(|e| !all.contains(e))
+                                .collect::<Vec<_>>(),
+                        );
+                        extras = Some(all);
                     }
                     if let Some(p) = subdata.path {
                         path = Some(p);
                     }
+                    editable = subdata.editable.unwrap_or(false);
                     if let Some(repo) = subdata.git {
                         git = Some(repo);
                     }
+                    let mut refs: Vec<GitRef> = vec![
+                        subdata.branch.map(GitRef::Branch),
+                        subdata.tag.map(GitRef::Tag),
+                        subdata.rev.map(GitRef::Rev),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                    if refs.len() > 1 {
+                        abort(&format!(
+                            "{} can only have one of `branch`, `tag` and `rev`",
+                            name
+                        ));
+                    }
+                    git_ref = refs.pop();
+                    url = subdata.url;
                     if let Some(v) = subdata.python {
                         python_version = Some(
                             Constraint::from_str(&v)
@@ -271,14 +755,40 @@ impl Config {
                 extra: None,
                 sys_platform: None,
                 python_version,
+                implementation_name: None,
                 install_with_extras: extras,
                 path,
                 git,
+                git_ref,
+                editable,
+                url,
+                marker: None,
             });
         }
         result
     }
 
+    /// Add dependencies with a `source` key, eg `acme-utils = { version = "^1.2", source =
+    /// "internal" }`, to that source's packages.
+    fn add_dep_sources(&mut self, deps: &HashMap<String, files::DepComponentWrapper>) {
+        for (key, data) in deps {
+            let source_name = match data {
+                files::DepComponentWrapper::B(files::DepComponent {
+                    source: Some(s), ..
+                }) => s,
+                _ => continue,
+            };
+            let name = Req::from_str(key, false).map_or_else(|_| key.clone(), |r| r.name);
+            match self.sources.iter_mut().find(|s| &s.name == source_name) {
+                Some(source) => source.packages.push(name),
+                None => abort(&format!(
+                    "{} is from the source `{}`, which isn't in `[tool.pyflow.sources]`",
+                    name, source_name
+                )),
+            }
+        }
+    }
+
     // todo: DRY at the top from `from_file`.
     fn from_pipfile(path: &Path) -> Option<Self> {
         // todo: Lots of tweaks and QC could be done re what fields to parse, and how best to
@@ -308,19 +818,25 @@ impl Config {
 
     /// Pull config data from `pyproject.toml`. We use this to deserialize things like Versions
     /// and requirements.
-    fn from_file(path: &Path) -> Option<Self> {
+    fn from_file(path: &Path, net: &net::Settings) -> Option<Self> {
         // todo: Lots of tweaks and QC could be done re what fields to parse, and how best to
         // todo parse and store them.
-        let toml_str = match fs::read_to_string(path) {
-            Ok(d) => d,
-            Err(_) => return None,
-        };
+        if !path.exists() {
+            return None;
+        }
 
-        let decoded: files::Pyproject = if let Ok(d) = toml::from_str(&toml_str) {
-            d
-        } else {
-            abort("Problem parsing `pyproject.toml`");
-            unreachable!()
+        let decoded: files::Pyproject = match files::read_pyproject(path, net) {
+            Ok(value) => match value.try_into() {
+                Ok(d) => d,
+                Err(_) => {
+                    abort("Problem parsing `pyproject.toml`");
+                    unreachable!()
+                }
+            },
+            Err(e) => {
+                abort(&e);
+                unreachable!()
+            }
         };
         let mut result = Self::default();
 
@@ -414,9 +930,14 @@ impl Config {
                             extra: None,
                             sys_platform: None,
                             python_version,
+                            implementation_name: None,
                             install_with_extras: extras,
                             path: None,
                             git: None,
+                            git_ref: None,
+                            editable: false,
+                            url: None,
+                            marker: None,
                         });
                     }
                 }
@@ -465,10 +986,50 @@ impl Config {
             //                result.entry_points = v;
             //            } // todo
             if let Some(v) = pf.scripts {
-                result.scripts = v;
+                for (name, script) in v {
+                    match script {
+                        files::ScriptWrapper::A(call) => {
+                            result.scripts.insert(name, call);
+                        }
+                        files::ScriptWrapper::B(subdata) => {
+                            let platforms = subdata
+                                .platforms
+                                .unwrap_or_default()
+                                .iter()
+                                .map(|p| {
+                                    Os::from_str(p).unwrap_or_else(|e| {
+                                        abort(&e.details);
+                                        unreachable!()
+                                    })
+                                })
+                                .collect();
+                            let run_if = subdata.run_if.map(|c| {
+                                tasks::Condition::from_str(&c).unwrap_or_else(|e| {
+                                    abort(&e.details);
+                                    unreachable!()
+                                })
+                            });
+                            result.tasks.insert(
+                                name.clone(),
+                                tasks::TaskOpts {
+                                    depends_on: subdata.depends_on.unwrap_or_default(),
+                                    run_if,
+                                    platforms,
+                                },
+                            );
+                            result.scripts.insert(name, subdata.call);
+                        }
+                    }
+                }
             }
 
             if let Some(v) = pf.python_requires {
+                if Constraint::from_str_multiple(&v).is_err() {
+                    abort(&format!(
+                        "Problem parsing `python_requires` in `pyproject.toml`: {}",
+                        v
+                    ));
+                }
                 result.python_requires = Some(v);
             }
 
@@ -476,6 +1037,156 @@ impl Config {
                 result.package_url = Some(v);
             }
 
+            if let Some(v) = pf.members {
+                result.members = v;
+            }
+
+            // Prefixes mapped to a source are added to it, once sources are parsed.
+            let mut internal_sources = vec![];
+            match pf.internal_prefixes {
+                Some(files::InternalPrefixesWrapper::A(v)) => result.internal_prefixes = v,
+                Some(files::InternalPrefixesWrapper::B(v)) => {
+                    internal_sources = v.into_iter().collect();
+                    internal_sources.sort();
+                    result.internal_prefixes =
+                        internal_sources.iter().map(|(p, _)| p.clone()).collect();
+                }
+                None => (),
+            }
+
+            if let Some(v) = pf.temp_dir {
+                result.temp_dir = Some(PathBuf::from(v));
+            }
+
+            if let Some(v) = pf.prompt_timeout {
+                result.prompt_timeout = Some(v);
+            }
+
+            if let Some(v) = pf.require_approval {
+                result.require_approval = v;
+            }
+
+            if let Some(v) = pf.prereleases {
+                result.prereleases = v;
+            }
+
+            if let Some(overrides) = pf.overrides {
+                for (name, constraints) in overrides {
+                    match Constraint::from_str_multiple(&constraints) {
+                        Ok(c) => result.overrides.push(Req::new(name, c)),
+                        Err(_) => abort(&format!(
+                            "Problem parsing `{} = \"{}\"` in `[tool.pyflow.overrides]`",
+                            name, constraints
+                        )),
+                    }
+                }
+            }
+
+            if let Some(v) = pf.constraints {
+                result.constraints = v.into_iter().map(PathBuf::from).collect();
+            }
+
+            if let Some(v) = pf.policy {
+                result.policy = v;
+            }
+
+            if let Some(sources) = pf.sources {
+                result.sources = sources
+                    .into_iter()
+                    .map(|(name, source)| match source {
+                        files::SourceWrapper::A(url) => dep_resolution::Source {
+                            name,
+                            url,
+                            packages: vec![],
+                        },
+                        files::SourceWrapper::B(subdata) => dep_resolution::Source {
+                            name,
+                            url: subdata.url,
+                            packages: subdata.packages.unwrap_or_default(),
+                        },
+                    })
+                    .collect();
+                result.sources.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+            for (prefix, source_name) in internal_sources {
+                match result.sources.iter_mut().find(|s| s.name == source_name) {
+                    Some(source) => source.packages.push(prefix),
+                    None => abort(&format!(
+                        "`{}` in `internal_prefixes` is from the source `{}`, which isn't in \
+                         `[tool.pyflow.sources]`",
+                        prefix, source_name
+                    )),
+                }
+            }
+
+            if let Some(v) = pf.warnings {
+                result.warnings = warnings::parse_suppressions(v);
+            }
+
+            if let Some(vars) = pf.env_vars {
+                result.env_vars = vars
+                    .into_iter()
+                    .map(|(name, var)| match var {
+                        files::EnvVarWrapper::A(default) => env_vars::EnvVar {
+                            name,
+                            default: Some(default),
+                            ..Default::default()
+                        },
+                        files::EnvVarWrapper::B(subdata) => env_vars::EnvVar {
+                            from: subdata.from.map(|f| {
+                                env_vars::Source::from_str(&f).unwrap_or_else(|| {
+                                    abort(&format!(
+                                        "Problem parsing the source of the environment variable \
+                                         {}: {}. It must start with `file:`, `env-file:`, or \
+                                         `cmd:`.",
+                                        name, f
+                                    ));
+                                    unreachable!()
+                                })
+                            }),
+                            name,
+                            default: subdata.default,
+                            secret: subdata.secret.unwrap_or(false),
+                            tasks: subdata.tasks.unwrap_or_default(),
+                        },
+                    })
+                    .collect();
+                result.env_vars.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+
+            if let Some(exports) = pf.sync_exports {
+                result.sync_exports = exports
+                    .into_iter()
+                    .map(|(path, spec)| SyncExport::from_spec(path, spec))
+                    .collect();
+                result.sync_exports.sort_by(|a, b| a.path.cmp(&b.path));
+            }
+
+            if let Some(v) = pf.active_env {
+                match util::ActiveEnvPolicy::from_str(&v) {
+                    Ok(policy) => result.active_env = Some(policy),
+                    Err(e) => abort(&e.details),
+                }
+            }
+
+            if let Some(v) = pf.python_update_policy {
+                match py_versions::UpdatePolicy::from_str(&v) {
+                    Ok(policy) => result.python_update_policy = policy,
+                    Err(e) => abort(&e.details),
+                }
+            }
+
+            if let Some(v) = pf.python_archive {
+                result.python_archive = Some(PathBuf::from(v));
+            }
+
+            if let Some(v) = pf.py_variant {
+                match py_versions::Variant::from_str(&v) {
+                    Ok(variant) => result.py_variant = variant,
+                    Err(e) => abort(&e.details),
+                }
+            }
+
             if let Some(v) = pf.version {
                 result.version = Some(
                     Version::from_str(&v).expect("Problem parsing version in `pyproject.toml`"),
@@ -483,12 +1194,31 @@ impl Config {
             }
 
             if let Some(v) = pf.py_version {
-                result.py_version = Some(
-                    Version::from_str(&v)
-                        .expect("Problem parsing python version in `pyproject.toml`"),
-                );
+                let (implementation, v) = util::Implementation::split_version(&v);
+                result.py_implementation = implementation;
+                // Eg `>=3.7.4`: the environment uses 3.7, with an interpreter at least 3.7.4.
+                if v.starts_with(|c: char| !c.is_ascii_digit()) {
+                    result.py_constraints = Constraint::from_str_multiple(v).unwrap_or_else(|_| {
+                        abort(&format!(
+                            "Problem parsing `py_version`: {}. It should look like `3.11`, or \
+                             `>=3.11.4`",
+                            v
+                        ));
+                        unreachable!()
+                    });
+                    // Which version satisfies them depends on what's installed; it's picked
+                    // with `py_versions::pick_version` once we know.
+                } else {
+                    result.py_version = Some(
+                        Version::from_str(v)
+                            .expect("Problem parsing python version in `pyproject.toml`"),
+                    );
+                }
             }
 
+            for deps in pf.dependencies.iter().chain(pf.dev_dependencies.iter()) {
+                result.add_dep_sources(deps);
+            }
             if let Some(deps) = pf.dependencies {
                 result.reqs = Self::parse_deps(deps);
             }
@@ -500,11 +1230,28 @@ impl Config {
         Some(result)
     }
 
+    /// Where to look for packages: PyPI, and `[tool.pyflow.sources]`, with the prerelease and
+    /// override settings that limit which versions are used.
+    fn index(&self) -> dep_resolution::Index {
+        dep_resolution::Index {
+            sources: self.sources.clone(),
+            prereleases: dep_resolution::PrereleasePolicy {
+                allow: false,
+                overrides: self.prereleases.clone(),
+            },
+            overrides: self.overrides.clone(),
+            implementation: self.py_implementation,
+            warnings: self.warnings.clone(),
+            ..Default::default()
+        }
+    }
+
     /// For reqs of `path` type, add their sub-reqs by parsing `setup.py` or `pyproject.toml`.
-    fn populate_path_subreqs(&mut self) {
-        self.reqs.append(&mut pop_reqs_helper(&self.reqs, false));
+    fn populate_path_subreqs(&mut self, net: &net::Settings) {
+        self.reqs
+            .append(&mut pop_reqs_helper(&self.reqs, false, net));
         self.dev_reqs
-            .append(&mut pop_reqs_helper(&self.dev_reqs, true));
+            .append(&mut pop_reqs_helper(&self.dev_reqs, true, net));
     }
 
     /// Create a new `pyproject.toml` file.
@@ -524,7 +1271,13 @@ impl Config {
             result.push_str(&("name = \"\"".to_owned() + "\n"));
         }
         if let Some(py_v) = &self.py_version {
-            result.push_str(&("py_version = \"".to_owned() + &py_v.to_string_no_patch() + "\"\n"));
+            let prefix = match self.py_implementation {
+                util::Implementation::PyPy => "pypy",
+                util::Implementation::CPython => "",
+            };
+            result.push_str(
+                &("py_version = \"".to_owned() + prefix + &py_v.to_string_no_patch() + "\"\n"),
+            );
         } else {
             result.push_str(&("py_version = \"3.8\"".to_owned() + "\n"));
         }
@@ -551,6 +1304,18 @@ impl Config {
         if let Some(v) = &self.homepage {
             result.push_str(&(format!("homepage = \"{}\"", v) + "\n"));
         }
+        if let Some(v) = &self.license {
+            result.push_str(&(format!("license = \"{}\"", v) + "\n"));
+        }
+        if let Some(v) = &self.python_requires {
+            result.push_str(&(format!("python_requires = \"{}\"", v) + "\n"));
+        }
+        if !self.keywords.is_empty() {
+            result.push_str(&format!("keywords = {:?}\n", self.keywords));
+        }
+        if !self.classifiers.is_empty() {
+            result.push_str(&format!("classifiers = {:?}\n", self.classifiers));
+        }
 
         // todo: More fields
 
@@ -581,7 +1346,7 @@ impl Config {
 }
 
 /// Create a template directory for a python project.
-pub fn new(name: &str) -> Result<(), Box<dyn Error>> {
+pub fn new(name: &str, prompt_timeout: Option<time::Duration>) -> Result<(), Box<dyn Error>> {
     if !PathBuf::from(name).exists() {
         fs::create_dir_all(&format!("{}/{}", name, name.replace("-", "_")))?;
         fs::File::create(&format!("{}/{}/__init__.py", name, name.replace("-", "_")))?;
@@ -594,6 +1359,7 @@ build/
 dist/
 __pycache__/
 __pypackages__/
+.pyflow
 .ipynb_checkpoints/
 *.pyc
 *~
@@ -611,7 +1377,7 @@ __pypackages__/
     let cfg = Config {
         name: Some(name.to_string()),
         authors: util::get_git_author(),
-        py_version: Some(util::prompt_py_vers()),
+        py_version: Some(util::prompt_py_vers(prompt_timeout)),
         ..Default::default()
     };
 
@@ -640,6 +1406,40 @@ fn write_lock(path: &Path, data: &Lock) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Merge lock files, as `pyflow lock merge`. Git passes an empty base if the file was added on
+/// both branches.
+fn merge_lock_files(ours: &Path, theirs: &Path, base: &Path, output: &Path) {
+    let read = |path: &Path| match read_lock(path) {
+        Ok(l) => l,
+        Err(_) => {
+            abort(&format!("Problem reading the lock file {:?}", path));
+            unreachable!()
+        }
+    };
+    let base_lock = match fs::read_to_string(base) {
+        Ok(data) if data.trim().is_empty() => Lock::default(),
+        _ => read(base),
+    };
+
+    let merged = lock_merge::merge(&base_lock, &read(ours), &read(theirs));
+    if write_lock(output, &merged.lock).is_err() {
+        abort(&format!("Problem writing the lock file {:?}", output));
+    }
+
+    if merged.conflicts.is_empty() {
+        util::print_color("Merged the lock files", Color::Green);
+    } else {
+        util::print_color(
+            &format!(
+                "Merged the lock files. These packages changed on both branches, and will be \
+                 re-resolved by `pyflow install`: {}",
+                merged.conflicts.join(", ")
+            ),
+            Color::DarkYellow,
+        );
+    }
+}
+
 fn parse_lockpack_rename(rename: &str) -> (u32, String) {
     let re = Regex::new(r"^(\d+)\s(.*)$").unwrap();
     let caps = re
@@ -653,58 +1453,363 @@ fn parse_lockpack_rename(rename: &str) -> (u32, String) {
 }
 
 /// Install/uninstall deps as required from the passed list, and re-write the lock file.
-fn sync_deps(
+/// The changes a sync makes to the environment.
+#[derive(Debug, Default, PartialEq)]
+struct SyncDelta {
+    added: Vec<(String, Version)>,
+    upgraded: Vec<(String, Version, Version)>, // (name, installed, new). Includes downgrades.
+    removed: Vec<(String, Version)>,
+}
+
+/// Group the packages a sync installs and uninstalls into additions, upgrades, and removals.
+fn find_sync_delta(
+    to_install: &[&(String, Version)],
+    to_uninstall: &[&(String, Version)],
+) -> SyncDelta {
+    let mut result = SyncDelta::default();
+    for (name, version) in to_install {
+        match to_uninstall
+            .iter()
+            .find(|(n, _)| util::compare_names(n, name))
+        {
+            Some((_, old)) => result.upgraded.push((name.clone(), *old, *version)),
+            None => result.added.push((name.clone(), *version)),
+        }
+    }
+    for (name, version) in to_uninstall {
+        if !to_install.iter().any(|(n, _)| util::compare_names(n, name)) {
+            result.removed.push((name.clone(), *version));
+        }
+    }
+    result
+}
+
+/// Roughly how many times bigger packages are once installed than their archives. Source
+/// distributions are built into wheels first, so need more room.
+const WHEEL_UNPACK_FACTOR: u64 = 3;
+const SDIST_BUILD_FACTOR: u64 = 10;
+
+/// Check there's room to download and install packages, from the archive sizes on PyPi.
+fn check_install_space(
     paths: &util::Paths,
-    lock_packs: &[LockPackage],
-    dont_uninstall: &[String],
-    installed: &[(String, Version, Vec<String>)],
-    os: util::Os,
-    python_vers: &Version,
+    releases: &[(dep_resolution::WarehouseRelease, install::PackageType)],
 ) {
-    let packages: Vec<PackToInstall> = lock_packs
+    let needed: u64 = releases
         .iter()
-        .map(|lp| {
-            (
-                (
-                    util::standardize_name(&lp.name),
-                    Version::from_str(&lp.version).expect("Problem parsing lock version"),
-                ),
-                match &lp.rename {
-                    // todo back to our custom type?
-                    Some(rn) => Some(parse_lockpack_rename(rn)),
-                    None => None,
-                },
-            )
+        .map(|(release, package_type)| {
+            let download = if paths.cache.join(&release.filename).exists() {
+                0
+            } else {
+                release.size
+            };
+            let factor = match package_type {
+                install::PackageType::Wheel => WHEEL_UNPACK_FACTOR,
+                install::PackageType::Source => SDIST_BUILD_FACTOR,
+            };
+            download + release.size * factor
         })
-        .collect();
+        .sum();
 
-    // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
-    let installed: Vec<(String, Version)> = installed
-        .iter()
-        // Don't standardize name here; see note below in to_uninstall.
-        .map(|t| (t.0.clone(), t.1))
-        .collect();
+    // The cache and environment may be on different filesystems, so check both.
+    util::check_disk_space(&paths.lib, needed, "Installing these packages");
+    util::check_disk_space(&paths.cache, needed, "Installing these packages");
+}
 
-    // Filter by not-already-installed.
-    let to_install: Vec<&PackToInstall> = packages
-        .iter()
-        .filter(|(pack, _)| {
-            let mut contains = false;
-            for inst in &installed {
-                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
-                    contains = true;
-                    break;
-                }
-            }
+/// Show what a sync will change before doing it. Only these packages are downloaded.
+fn print_sync_delta(delta: &SyncDelta) {
+    if delta == &SyncDelta::default() {
+        return;
+    }
+    println!(
+        "Syncing: {} to add, {} to upgrade, {} to remove",
+        delta.added.len(),
+        delta.upgraded.len(),
+        delta.removed.len()
+    );
+    for (name, version) in &delta.added {
+        util::print_color(
+            &format!("  + {} {}", name, version.to_string2()),
+            Color::Green,
+        );
+    }
+    for (name, old, new) in &delta.upgraded {
+        util::print_color(
+            &format!("  ~ {} {} → {}", name, old.to_string2(), new.to_string2()),
+            Color::Cyan,
+        );
+    }
+    for (name, version) in &delta.removed {
+        util::print_color(
+            &format!("  - {} {}", name, version.to_string2()),
+            Color::DarkYellow,
+        );
+    }
+}
 
-            // The typing module is sometimes downloaded, causing a conflict/improper
-            // behavior compared to the built in module.
-            !contains && pack.0 != "typing"
-        })
-        .collect();
+/// Records what a sync's doing in an environment, in its version directory.
+const JOURNAL_FILENAME: &str = "sync-journal";
 
-    // todo: Once you include rename info in installed, you won't need to use the map logic here.
-    let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
+/// What the last resolution in an environment was asked for, and what it found, for
+/// `pyflow debug bundle`.
+const RESOLUTION_TRACE_FILENAME: &str = "last-resolution.txt";
+
+/// What the last sync in an environment did, for `pyflow status`.
+const SYNC_REPORT_FILENAME: &str = "last-sync.txt";
+
+/// Vulnerabilities `pyflow outdated` found in locked packages, in `__pypackages__`, for
+/// `pyflow status`.
+const AUDIT_FILENAME: &str = "last-audit.txt";
+
+/// What a sync found: how many archives were installed from the download cache, and locked
+/// versions that have been yanked, which stay until they're no longer installed.
+#[derive(Debug, Default, PartialEq)]
+struct SyncReport {
+    cache_hits: usize,
+    cache_misses: usize,
+    yanked: Vec<(String, Version, String)>, // name, version, reason
+}
+
+impl SyncReport {
+    /// Read a report saved by `to_text`. Lines we can't read are skipped.
+    fn from_text(text: &str) -> Self {
+        let mut result = Self::default();
+        for line in text.lines() {
+            let (key, value) = match line.split_once(" = ") {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                "cache-hits" => result.cache_hits = value.parse().unwrap_or(0),
+                "cache-misses" => result.cache_misses = value.parse().unwrap_or(0),
+                "yanked" => {
+                    let mut parts = value.splitn(3, ' ');
+                    if let (Some(name), Some(Ok(version)), Some(reason)) = (
+                        parts.next(),
+                        parts.next().map(Version::from_str),
+                        parts.next(),
+                    ) {
+                        result.yanked.push((name.into(), version, reason.into()));
+                    }
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+
+    fn to_text(&self) -> String {
+        let mut result = format!(
+            "cache-hits = {}\ncache-misses = {}\n",
+            self.cache_hits, self.cache_misses
+        );
+        for (name, version, reason) in &self.yanked {
+            result.push_str(&format!(
+                "yanked = {} {} {}\n",
+                name,
+                version.to_string2(),
+                reason
+            ));
+        }
+        result
+    }
+}
+
+/// Stop other pyflow processes from changing an environment until this is dropped.
+fn lock_env(vers_path: &Path) -> journal::SyncLock {
+    match journal::SyncLock::acquire(&vers_path.join("sync.lock")) {
+        Ok(l) => l,
+        Err(pid) => {
+            abort(&format!(
+                "Another pyflow process ({}) is changing this environment. Wait for it to \
+                 finish, or if it's not running, remove {:?}",
+                pid,
+                vers_path.join("sync.lock")
+            ));
+            unreachable!()
+        }
+    }
+}
+
+/// A package version's files on PyPI.
+/// The files PyPI has for a release. If it's been removed, says what's gone.
+fn warehouse_releases(
+    index: &dep_resolution::Index,
+    name: &str,
+    version: &Version,
+) -> FoundReleases {
+    match dep_resolution::get_warehouse_release(index, name, version) {
+        Ok(d) if d.is_empty() => Err(format!(
+            "PyPI no longer has {} {}: the release, or its files, were deleted",
+            name,
+            version.to_string2()
+        )),
+        Ok(d) => {
+            if let Some(reason) = dep_resolution::yank_reason(&d) {
+                dep_resolution::warn_yanked(index, name, version, &reason);
+            }
+            Ok(d)
+        }
+        Err(e) if e.status == Some(reqwest::StatusCode::NOT_FOUND) => Err(format!(
+            "PyPI no longer has a project named {}: it was deleted",
+            name
+        )),
+        Err(e) => {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem getting release data for {}: {}", name, e),
+            );
+            unreachable!()
+        }
+    }
+}
+
+/// Files for a release in the download cache, eg if it's been removed from PyPI since we
+/// downloaded it.
+/// The name and version in a package archive's filename, eg `six-1.12.0-py2.py3-none-any.whl`,
+/// or `six-1.12.0.tar.gz`.
+fn archive_name_version(filename: &str) -> Option<(&str, &str)> {
+    if filename.ends_with(".whl") {
+        let mut parts = filename.splitn(3, '-');
+        parts.next().zip(parts.next())
+    } else {
+        [".tar.gz", ".zip"]
+            .iter()
+            .find_map(|ext| filename.strip_suffix(ext))
+            .and_then(|stem| stem.rsplit_once('-'))
+    }
+}
+
+fn cached_releases(
+    cache_path: &Path,
+    name: &str,
+    version: &Version,
+) -> Vec<dep_resolution::WarehouseRelease> {
+    let filenames: Vec<String> = fs::read_dir(cache_path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut result = vec![];
+    for filename in filenames {
+        let matches = match archive_name_version(&filename) {
+            Some((n, v)) => {
+                util::compare_names(n, name) && Version::from_str(v).ok() == Some(*version)
+            }
+            None => false,
+        };
+        if !matches {
+            continue;
+        }
+        let path = cache_path.join(&filename);
+        if let Ok(sha256) = install::file_sha256(&path) {
+            let file = pylock::File {
+                size: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+                name: filename,
+                url: String::new(),
+                sha256,
+            };
+            result.push(release_from_file(&file, None));
+        }
+    }
+    result
+}
+
+/// `find_releases` finds the files we can install each package version from; usually
+/// `warehouse_releases`. If some locked packages can't be found, eg since they've been removed
+/// from PyPI, and aren't in the download cache, we report them, and return their names without
+/// changing the environment.
+#[allow(clippy::too_many_arguments)]
+fn sync_deps(
+    paths: &util::Paths,
+    lock_packs: &[LockPackage],
+    dont_uninstall: &[String],
+    installed: &[(String, Version, Vec<String>)],
+    os: util::Os,
+    python_vers: &Version,
+    py: &py_versions::Settings,
+    build_options: install::BuildOptions,
+    find_releases: &dyn Fn(&str, &Version) -> FoundReleases,
+) -> Result<(), Vec<String>> {
+    // A lock made on another platform can include packages this one doesn't need.
+    let env = venv_marker_env(paths, os, python_vers, py.implementation);
+    let packages: Vec<PackToInstall> = lock_packs
+        .iter()
+        .filter(|lp| markers_apply(&lp.markers, &env))
+        .map(|lp| {
+            (
+                (
+                    util::standardize_name(&lp.name),
+                    Version::from_str(&lp.version).expect("Problem parsing lock version"),
+                ),
+                match &lp.rename {
+                    // todo back to our custom type?
+                    Some(rn) => Some(parse_lockpack_rename(rn)),
+                    None => None,
+                },
+            )
+        })
+        .collect();
+
+    let vers_path = paths.lib.parent().unwrap_or(&paths.lib);
+    let _lock = lock_env(vers_path);
+
+    // Packages an interrupted sync was installing may be half-installed; remove them, so they're
+    // installed again. Those it finished were verified, so are kept.
+    let journal_path = vers_path.join(JOURNAL_FILENAME);
+    let mut repaired = vec![];
+    for op in journal::find_unfinished(&journal_path).unwrap_or_default() {
+        if op.action != journal::Action::Install {
+            continue;
+        }
+        if let Ok(version) = Version::from_str(&op.version) {
+            util::print_color(
+                &format!(
+                    "Reinstalling {} {}, which an interrupted sync didn't finish",
+                    op.name, op.version
+                ),
+                Color::DarkYellow,
+            );
+            install::uninstall(&op.name, &version, &paths.lib);
+            repaired.push((op.name, version));
+        }
+    }
+
+    // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
+    let installed: Vec<(String, Version)> = installed
+        .iter()
+        // Don't standardize name here; see note below in to_uninstall.
+        .map(|t| (t.0.clone(), t.1))
+        .filter(|(name, version)| {
+            !repaired
+                .iter()
+                .any(|(n, v)| util::compare_names(n, name) && v == version)
+        })
+        .collect();
+
+    // Filter by not-already-installed.
+    let to_install: Vec<&PackToInstall> = packages
+        .iter()
+        .filter(|(pack, _)| {
+            let mut contains = false;
+            for inst in &installed {
+                if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
+                    contains = true;
+                    break;
+                }
+            }
+
+            // The typing module is sometimes downloaded, causing a conflict/improper
+            // behavior compared to the built in module.
+            !contains && pack.0 != "typing"
+        })
+        .collect();
+
+    // todo: Once you include rename info in installed, you won't need to use the map logic here.
+    let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
     let to_uninstall: Vec<&(String, Version)> = installed
         .iter()
         .filter(|inst| {
@@ -731,58 +1836,226 @@ fn sync_deps(
         })
         .collect();
 
-    for (name, version) in &to_uninstall {
-        // todo: Deal with renamed. Currently won't work correctly with them.
-        install::uninstall(name, version, &paths.lib)
+    // Yanked versions found before stay in the report while they're installed.
+    let report_path = vers_path.join(SYNC_REPORT_FILENAME);
+    let mut report = SyncReport::default();
+    if let Ok(previous) = fs::read_to_string(&report_path) {
+        report.yanked = SyncReport::from_text(&previous)
+            .yanked
+            .into_iter()
+            .filter(|(name, version, _)| {
+                installed
+                    .iter()
+                    .any(|(n, v)| util::compare_names(n, name) && v == version)
+                    && !to_uninstall
+                        .iter()
+                        .any(|(n, v)| util::compare_names(n, name) && v == version)
+            })
+            .collect();
     }
 
-    for ((name, version), rename) in &to_install {
-        let data = dep_resolution::get_warehouse_release(name, version)
-            .expect("Problem getting warehouse data");
+    let mut removed = vec![];
+    let found: Vec<Vec<dep_resolution::WarehouseRelease>> = to_install
+        .iter()
+        .map(|((name, version), _)| match find_releases(name, version) {
+            Ok(r) => {
+                if let Some(reason) = dep_resolution::yank_reason(&r) {
+                    report.yanked.push((name.clone(), *version, reason));
+                }
+                r
+            }
+            Err(reason) => {
+                let cached = cached_releases(&paths.cache, name, version);
+                if cached.is_empty() {
+                    util::print_color(
+                        &format!("{}, and it isn't in the download cache", reason),
+                        Color::Red,
+                    );
+                    removed.push(name.clone());
+                } else {
+                    util::print_color(
+                        &format!("{}; installing it from the download cache", reason),
+                        Color::DarkYellow,
+                    );
+                }
+                cached
+            }
+        })
+        .collect();
+    if !removed.is_empty() {
+        return Err(removed);
+    }
+
+    let install_packs: Vec<&(String, Version)> = to_install.iter().map(|(p, _)| p).collect();
+    let delta = find_sync_delta(&install_packs, &to_uninstall);
+    print_sync_delta(&delta);
+
+    let mut journal = match journal::Journal::begin(&journal_path) {
+        Ok(j) => j,
+        Err(e) => {
+            abort(&format!("Problem writing the sync journal: {}", e));
+            unreachable!()
+        }
+    };
 
-        let (best_release, package_type) =
-            util::find_best_release(&data, name, version, os, python_vers);
+    for (name, version) in &to_uninstall {
+        // todo: Deal with renamed. Currently won't work correctly with them.
+        let vers_str = version.to_string2();
+        journal.start(journal::Action::Uninstall, name, &vers_str);
+        install::uninstall(name, version, &paths.lib);
+        journal.done(journal::Action::Uninstall, name, &vers_str);
+    }
 
+    // Found when we install the first wheel. `Some(None)` if the interpreter isn't CPython.
+    let releases: Vec<(dep_resolution::WarehouseRelease, install::PackageType)> = to_install
+        .iter()
+        .zip(found.iter())
+        .map(|(((name, version), _), found)| {
+            util::find_best_release(found, name, version, os, python_vers, py)
+        })
+        .collect();
+    check_install_space(paths, &releases);
+
+    let mut interp_abi: Option<Option<String>> = None;
+    // With `--build-jobs`, source packages are downloaded here, and built together after.
+    let build_jobs = build_options.jobs;
+    let mut builds = vec![];
+    for (((name, version), rename), (best_release, package_type)) in
+        to_install.iter().zip(releases.into_iter())
+    {
         // Powershell  doesn't like emojis
         // todo format literal issues, so repeating this whole statement.
         #[cfg(target_os = "windows")]
         println!(
             "Installing {}{}{} {} ...",
-            Colored::Fg(Color::Cyan),
+            util::fg(Color::Cyan),
             &name,
-            Colored::Fg(Color::Reset),
+            util::fg(Color::Reset),
             &version
         );
         #[cfg(target_os = "linux")]
         println!(
             "⬇ Installing {}{}{} {} ...",
-            Colored::Fg(Color::Cyan),
+            util::fg(Color::Cyan),
             &name,
-            Colored::Fg(Color::Reset),
+            util::fg(Color::Reset),
             &version
         );
         #[cfg(target_os = "macos")]
         println!(
             "⬇ Installing {}{}{} {} ...",
-            Colored::Fg(Color::Cyan),
+            util::fg(Color::Cyan),
             &name,
-            Colored::Fg(Color::Reset),
+            util::fg(Color::Reset),
             &version
         );
 
-        if install::download_and_install_package(
-            name,
-            version,
-            &best_release.url,
-            &best_release.filename,
-            &best_release.digests.sha256,
-            paths,
-            package_type,
-            rename,
-        )
-        .is_err()
+        if let install::PackageType::Wheel = package_type {
+            if interp_abi.is_none() {
+                interp_abi = Some(commands::find_py_abi(&paths.bin));
+            }
+            if let Some(Some(abi)) = &interp_abi {
+                if let Err(wheel_abi) = util::check_wheel_abi(&best_release.filename, abi) {
+                    abort(&format!(
+                        "{} is built for the `{}` ABI, but this environment's Python uses `{}`, \
+                         so it wouldn't import. Check whether this is a debug or free-threaded \
+                         build of Python, or use a version of {} with a wheel for `{}`.",
+                        best_release.filename, wheel_abi, abi, name, abi
+                    ));
+                }
+            }
+        }
+
+        if paths.cache.join(&best_release.filename).exists() {
+            report.cache_hits += 1;
+        } else {
+            report.cache_misses += 1;
+        }
+
+        let vers_str = version.to_string2();
+        journal.start(journal::Action::Install, name, &vers_str);
+        let queued = builds.len();
+        let result = if build_jobs > 1 {
+            install::fetch_package(
+                name,
+                version,
+                &best_release.url,
+                &best_release.filename,
+                &best_release.digests.sha256,
+                paths,
+                package_type,
+                rename,
+                &py.net,
+                py.prompt_timeout,
+            )
+            .map(|build| builds.extend(build))
+        } else {
+            install::download_and_install_package(
+                name,
+                version,
+                &best_release.url,
+                &best_release.filename,
+                &best_release.digests.sha256,
+                paths,
+                package_type,
+                rename,
+                &py.net,
+                py.prompt_timeout,
+            )
+        };
+        if let Err(e) = result {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem downloading {}: {}", name, e),
+            );
+        }
+        // Source packages are done once they're built.
+        if builds.len() == queued {
+            journal.done(journal::Action::Install, name, &vers_str);
+        }
+    }
+
+    if !builds.is_empty() {
+        println!(
+            "Building {} source packages, up to {} at once",
+            builds.len(),
+            build_jobs
+        );
+        let mut failed = 0;
+        let mut not_built = 0;
+        for (build, result) in
+            builds
+                .iter()
+                .zip(install::build_in_parallel(&builds, paths, build_options))
         {
-            abort("Problem downloading packages");
+            match result {
+                Some(Ok(wheel)) => {
+                    build.install(&wheel, paths);
+                    let vers_str = build.version.to_string2();
+                    journal.done(journal::Action::Install, &build.name, &vers_str);
+                }
+                // Each failure's shown on its own, so their output isn't interleaved.
+                Some(Err(log)) => {
+                    failed += 1;
+                    util::print_color(&build.failure(&log), Color::Red);
+                }
+                None => not_built += 1,
+            }
+        }
+        if not_built > 0 {
+            abort(&format!(
+                "{} of {} source packages failed to build, so {} others weren't built. Pass \
+                 `--keep-going` to build them anyway.",
+                failed,
+                builds.len(),
+                not_built
+            ));
+        } else if failed > 0 {
+            abort(&format!(
+                "{} of {} source packages failed to build",
+                failed,
+                builds.len()
+            ));
         }
     }
     // Perform renames after all packages are installed, or we may attempt to rename a package
@@ -817,6 +2090,9 @@ fn sync_deps(
             );
         }
     }
+    journal.end();
+    fs::write(&report_path, report.to_text()).ok();
+    Ok(())
 }
 
 fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) -> bool {
@@ -833,13 +2109,29 @@ fn already_locked(locked: &[Package], name: &str, constraints: &[Constraint]) ->
     result
 }
 
+/// Find requirements the lock doesn't satisfy, eg ones added to `pyproject.toml` since it was
+/// written.
+fn find_unlocked(reqs: &[Req], locked: &[Package]) -> Vec<String> {
+    reqs.iter()
+        .filter(|r| {
+            !locked.iter().any(|p| util::compare_names(&p.name, &r.name))
+                || !already_locked(locked, &r.name, &r.constraints)
+        })
+        .map(|r| r.name.clone())
+        .collect()
+}
+
 /// Execute a python CLI tool, either specified in `pyproject.toml`, or in a dependency.
+#[allow(clippy::too_many_arguments)]
 fn run_cli_tool(
     lib_path: &Path,
     bin_path: &Path,
     vers_path: &Path,
+    proj_path: &Path,
     cfg: &Config,
+    os: Os,
     args: Vec<String>,
+    allow_env_commands: bool,
 ) {
     // Allow both `pyflow run ipython` (args), and `pyflow ipython` (opt.script)
     if args.is_empty() {
@@ -862,31 +2154,44 @@ fn run_cli_tool(
 
     // If a script name is specified by by this project and a dependency, favor
     // this project.
-    if let Some(s) = cfg.scripts.get(&name) {
-        let abort_msg = format!(
-            "Problem running the function {}, specified in `pyproject.toml`",
-            name,
-        );
-
-        if let Some(caps) = re.captures(s) {
-            let module = caps.get(1).unwrap().as_str();
-            let function = caps.get(2).unwrap().as_str();
-            let mut args_to_pass = vec![
-                "-c".to_owned(),
-                format!(r#"import {}; {}.{}()"#, module, module, function),
-            ];
+    if cfg.scripts.contains_key(&name) {
+        let run_call = |task: &str, s: &str, mut task_args: Vec<String>| {
+            env_vars::apply(&cfg.env_vars, task, proj_path, allow_env_commands);
+            let abort_msg = format!(
+                "Problem running the function {}, specified in `pyproject.toml`",
+                task,
+            );
 
-            args_to_pass.append(&mut specified_args);
-            if commands::run_python(bin_path, &[lib_path.to_owned()], &args_to_pass).is_err() {
-                abort(&abort_msg);
+            if let Some(caps) = re.captures(s) {
+                let module = caps.get(1).unwrap().as_str();
+                let function = caps.get(2).unwrap().as_str();
+                let mut args_to_pass = vec![
+                    "-c".to_owned(),
+                    format!(r#"import {}; {}.{}()"#, module, module, function),
+                ];
+
+                args_to_pass.append(&mut task_args);
+                if commands::run_python(bin_path, &[lib_path.to_owned()], &args_to_pass).is_err() {
+                    abort(&abort_msg);
+                }
+            } else {
+                abort(&format!("Problem parsing the following script: {:#?}. Must be in the format module:function_name", s));
+                unreachable!()
             }
-        } else {
-            abort(&format!("Problem parsing the following script: {:#?}. Must be in the format module:function_name", s));
-            unreachable!()
-        }
+        };
+
+        let ctx = tasks::TaskContext {
+            scripts: &cfg.scripts,
+            tasks: &cfg.tasks,
+            os,
+            proj_path,
+            state_path: vers_path.join("task-state.toml"),
+        };
+        tasks::run(&name, &ctx, specified_args, run_call);
         return;
     }
     //            None => {
+    env_vars::apply(&cfg.env_vars, &name, proj_path, allow_env_commands);
     let abort_msg = format!(
         "Problem running the CLI tool {}. Is it installed? \
          Try running `pyflow install {}`",
@@ -908,57 +2213,258 @@ fn run_cli_tool(
     }
 }
 
-/// Find a script's dependencies from a variable: `__requires__ = [dep1, dep2]`
-fn find_deps_from_script(file_path: &Path) -> Vec<String> {
-    // todo: Helper for this type of logic? We use it several times in the program.
-    let f = fs::File::open(file_path).expect("Problem opening the Python script file.");
+/// Names of the project's dependencies, including dev ones.
+fn dep_names(cfg: &Config) -> Vec<String> {
+    cfg.reqs
+        .iter()
+        .chain(cfg.dev_reqs.iter())
+        .map(|r| r.name.clone())
+        .collect()
+}
 
-    let re = Regex::new(r"^__requires__\s*=\s*\[(.*?)\]$").unwrap();
+/// Make sure tool packages for `pyflow test`, `lint`, or `fmt-py` are available. Ones that
+/// aren't among the project's dependencies are installed in a tools environment alongside the
+/// project's. Returns the lib paths to run them with.
+fn find_dev_tools(
+    packages: &[&str],
+    paths: &util::Paths,
+    vers_path: &Path,
+    os: Os,
+    py_vers: &Version,
+) -> Vec<PathBuf> {
+    let mut lib_paths = vec![paths.lib.clone()];
 
-    let mut result = vec![];
-    for line in BufReader::new(f).lines() {
-        if let Ok(l) = line {
-            if let Some(c) = re.captures(&l) {
-                let deps_list = c.get(1).unwrap().as_str().to_owned();
-                let deps: Vec<&str> = deps_list.split(',').collect();
-                result = deps
-                    .into_iter()
-                    .map(|d| {
-                        d.to_owned()
-                            .replace(" ", "")
-                            .replace("\"", "")
-                            .replace("'", "")
-                    })
-                    .filter(|d| !d.is_empty())
-                    .collect();
-            }
-        }
+    let installed = util::find_installed(&paths.lib);
+    let missing: Vec<&str> = packages
+        .iter()
+        .copied()
+        .filter(|p| !installed.iter().any(|(n, _, _)| util::compare_names(n, p)))
+        .collect();
+    if missing.is_empty() {
+        return lib_paths;
     }
 
-    result
-}
+    let tools_path = vers_path.join("tools");
+    let tool_paths = util::Paths {
+        bin: paths.bin.clone(),
+        lib: tools_path.join("lib"),
+        entry_pt: tools_path.join("bin"),
+        cache: paths.cache.clone(),
+        temp: paths.temp.clone(),
+    };
+    if !tool_paths.lib.exists() && fs::create_dir_all(&tool_paths.lib).is_err() {
+        abort("Problem creating the tools environment");
+    }
 
-/// Run a standalone script file, with package management
-/// // todo: Perhaps move this logic to its own file, if it becomes long.
-/// todo: We're using script name as unique identifier; address this in the future,
-/// todo perhaps with an id in a comment at the top of a file
-fn run_script(
-    script_env_path: &Path,
-    dep_cache_path: &Path,
-    os: util::Os,
-    args: &mut Vec<String>,
-    pyflow_dir: &Path,
-) {
-    // todo: DRY with run_cli_tool and subcommand::Install
-    let filename = if let Some(a) = args.get(0) {
-        a.clone()
-    } else {
-        abort("`run` must be followed by the script to run, eg `pyflow script myscript.py`");
-        unreachable!()
+    let tools_installed = util::find_installed(&tool_paths.lib);
+    let is_installed = |package: &str| {
+        tools_installed
+            .iter()
+            .any(|(n, _, _)| util::compare_names(n, package))
     };
+    if !missing.iter().all(|p| is_installed(p)) {
+        util::print_color(
+            &format!(
+                "Installing {} in the tools environment; add to `dev-dependencies` to choose \
+                 versions",
+                missing.join(", ")
+            ),
+            Color::DarkBlue,
+        );
+        // Keep the other tools installed here.
+        let mut reqs: Vec<Req> = missing
+            .iter()
+            .map(|p| Req::new((*p).to_owned(), vec![]))
+            .collect();
+        for other in DEV_TOOL_PACKAGES {
+            if !missing.contains(other) && is_installed(other) {
+                reqs.push(Req::new((*other).to_owned(), vec![]));
+            }
+        }
 
-    // todo: Consider a metadata file, but for now, we'll use folders
-    //    let scripts_data_path = script_env_path.join("scripts.toml");
+        let lock_path = tools_path.join("pyflow-tools.lock");
+        let lockpacks = read_lock(&lock_path)
+            .ok()
+            .and_then(|l| l.package)
+            .unwrap_or_else(Vec::new);
+        sync(
+            &tool_paths,
+            &lockpacks,
+            &reqs,
+            &[],
+            &[],
+            os,
+            py_vers,
+            &lock_path,
+            &tools_path.join("git"),
+            &SyncPolicy::default(),
+        );
+    }
+    lib_paths.push(tool_paths.lib);
+    lib_paths
+}
+
+/// Packages that may be installed in the tools environment.
+const DEV_TOOL_PACKAGES: &[&str] = &["pytest", "coverage", "ruff", "flake8", "black"];
+
+/// Run a tool for `pyflow lint` or `fmt-py`, exiting with `ToolFailed` if it fails.
+fn run_dev_tool(
+    tool: dev_tools::Tool,
+    args: Vec<String>,
+    paths: &util::Paths,
+    vers_path: &Path,
+    os: Os,
+    py_vers: &Version,
+) {
+    let lib_paths = find_dev_tools(&[tool.package()], paths, vers_path, os, py_vers);
+    match commands::run_python_status(&paths.bin, &lib_paths, &tool.python_args(args)) {
+        Ok(true) => (),
+        Ok(false) => util::abort_with(
+            util::ExitCode::ToolFailed,
+            &format!("{} failed", tool.package()),
+        ),
+        Err(_) => abort(&format!("Problem running {}", tool.package())),
+    }
+}
+
+/// An environment to run tests in, for `pyflow test --python`.
+struct TestEnv {
+    paths: util::Paths,
+    vers_path: PathBuf,
+    py_vers: Version,
+}
+
+/// Run pytest in each environment, optionally collecting coverage. Coverage data from all
+/// environments is combined into one report. Exits with an error if tests fail in any
+/// environment, or if coverage is below `fail_under`.
+fn run_tests(envs: &[TestEnv], args: &[String], coverage: bool, fail_under: Option<f32>, os: Os) {
+    let pytest = dev_tools::Tool::Pytest;
+    let packages: &[&str] = if coverage {
+        &[pytest.package(), "coverage"]
+    } else {
+        &[pytest.package()]
+    };
+    let env_libs: Vec<(&TestEnv, Vec<PathBuf>)> = envs
+        .iter()
+        .map(|env| {
+            let libs = find_dev_tools(packages, &env.paths, &env.vers_path, os, &env.py_vers);
+            (env, libs)
+        })
+        .collect();
+
+    let run = |env: &TestEnv, libs: &[PathBuf], args: Vec<String>| {
+        commands::run_python_status(&env.paths.bin, libs, &args).unwrap_or_else(|_| {
+            abort("Problem running the tests");
+            unreachable!()
+        })
+    };
+    let coverage_cmd = |cmd: &[&str]| -> Vec<String> {
+        ["-m", "coverage"]
+            .iter()
+            .chain(cmd.iter())
+            .map(|a| (*a).to_owned())
+            .collect()
+    };
+
+    if coverage {
+        let (env, libs) = &env_libs[0];
+        run(env, libs, coverage_cmd(&["erase"]));
+    }
+
+    let mut failed = vec![];
+    for (env, libs) in &env_libs {
+        if envs.len() > 1 {
+            util::print_color(
+                &format!("Testing with Python {}", env.py_vers.to_string_no_patch()),
+                Color::Cyan,
+            );
+        }
+        let mut test_args = if coverage {
+            coverage_cmd(&["run", "--parallel-mode"])
+        } else {
+            vec![]
+        };
+        test_args.extend(pytest.python_args(args.to_vec()));
+        if !run(env, libs, test_args) {
+            failed.push(env.py_vers.to_string_no_patch());
+        }
+    }
+
+    let mut coverage_ok = true;
+    if coverage {
+        let (env, libs) = &env_libs[0];
+        run(env, libs, coverage_cmd(&["combine"]));
+        let mut report = coverage_cmd(&["report"]);
+        if let Some(f) = fail_under {
+            report.push(format!("--fail-under={}", f));
+        }
+        coverage_ok = run(env, libs, report);
+    }
+
+    if !failed.is_empty() {
+        util::abort_with(
+            util::ExitCode::ToolFailed,
+            &format!("Tests failed with Python {}", failed.join(", ")),
+        );
+    }
+    if !coverage_ok {
+        util::abort_with(util::ExitCode::ToolFailed, "The coverage report failed");
+    }
+}
+
+/// Find a script's dependencies from a variable: `__requires__ = [dep1, dep2]`
+fn find_deps_from_script(file_path: &Path) -> Vec<String> {
+    // todo: Helper for this type of logic? We use it several times in the program.
+    let f = fs::File::open(file_path).expect("Problem opening the Python script file.");
+
+    let re = Regex::new(r"^__requires__\s*=\s*\[(.*?)\]$").unwrap();
+
+    let mut result = vec![];
+    for line in BufReader::new(f).lines() {
+        if let Ok(l) = line {
+            if let Some(c) = re.captures(&l) {
+                let deps_list = c.get(1).unwrap().as_str().to_owned();
+                let deps: Vec<&str> = deps_list.split(',').collect();
+                result = deps
+                    .into_iter()
+                    .map(|d| {
+                        d.to_owned()
+                            .replace(" ", "")
+                            .replace("\"", "")
+                            .replace("'", "")
+                    })
+                    .filter(|d| !d.is_empty())
+                    .collect();
+            }
+        }
+    }
+
+    result
+}
+
+/// Run a standalone script file, with package management
+/// // todo: Perhaps move this logic to its own file, if it becomes long.
+/// todo: We're using script name as unique identifier; address this in the future,
+/// todo perhaps with an id in a comment at the top of a file
+fn run_script(
+    script_env_path: &Path,
+    dep_cache_path: &Path,
+    os: util::Os,
+    args: &mut Vec<String>,
+    pyflow_dir: &Path,
+    py: &mut py_versions::Settings,
+    temp_root: &Path,
+) {
+    // todo: DRY with run_cli_tool and subcommand::Install
+    let filename = if let Some(a) = args.get(0) {
+        a.clone()
+    } else {
+        abort("`run` must be followed by the script to run, eg `pyflow script myscript.py`");
+        unreachable!()
+    };
+
+    // todo: Consider a metadata file, but for now, we'll use folders
+    //    let scripts_data_path = script_env_path.join("scripts.toml");
 
     let env_path = script_env_path.join(&filename);
     if !env_path.exists() {
@@ -977,7 +2483,7 @@ fn run_script(
         )
         .expect("Problem parsing version from file");
     } else {
-        cfg_vers = util::prompt_py_vers();
+        cfg_vers = util::prompt_py_vers(py.prompt_timeout);
 
         fs::File::create(&py_vers_path)
             .expect("Problem creating a file to store the Python version for this script");
@@ -987,22 +2493,60 @@ fn run_script(
 
     // todo DRY
     let pypackages_dir = env_path.join("__pypackages__");
-    let (vers_path, py_vers) =
-        util::find_or_create_venv(&cfg_vers, &pypackages_dir, pyflow_dir, dep_cache_path);
+    let lock_path = env_path.join("pyproject.lock");
+    let manifest_path = env_path.join("manifest.toml");
+    let (mut vers_path, mut py_vers) = util::find_or_create_venv(
+        py,
+        &cfg_vers,
+        &[],
+        &pypackages_dir,
+        pyflow_dir,
+        dep_cache_path,
+        temp_root,
+    );
+
+    // If the environment's changed since we last set it up, eg from manual edits, or something
+    // tampering with the cache, rebuild it instead of running the script in it.
+    if !verify_script_env(&manifest_path, &vers_path.join("lib")) {
+        if fs::remove_dir_all(&pypackages_dir).is_err()
+            || (lock_path.exists() && fs::remove_file(&lock_path).is_err())
+        {
+            abort("Problem removing the script's environment");
+        }
+        let (vp, pv) = util::find_or_create_venv(
+            py,
+            &cfg_vers,
+            &[],
+            &pypackages_dir,
+            pyflow_dir,
+            dep_cache_path,
+            temp_root,
+        );
+        vers_path = vp;
+        py_vers = pv;
+    }
 
     let bin_path = util::find_bin_path(&vers_path);
     let lib_path = vers_path.join("lib");
     let script_path = vers_path.join("bin");
-    let lock_path = env_path.join("pyproject.lock");
 
     let paths = util::Paths {
         bin: bin_path,
         lib: lib_path,
         entry_pt: script_path,
         cache: dep_cache_path.to_owned(),
+        temp: temp_root.to_owned(),
     };
 
     let deps = find_deps_from_script(&PathBuf::from(&filename));
+    let policy = SyncPolicy {
+        index: dep_resolution::Index {
+            net: py.net.clone(),
+            ..Default::default()
+        },
+        python: py.clone(),
+        ..Default::default()
+    };
 
     let lock = match read_lock(&lock_path) {
         Ok(l) => l,
@@ -1023,7 +2567,7 @@ fn run_script(
                     Version::from_str(&lp.version).expect("Problem getting version"),
                 )
             } else {
-                let vinfo = dep_resolution::get_version_info(name)
+                let vinfo = dep_resolution::get_version_info(&policy.index, name)
                     .unwrap_or_else(|_| panic!("Problem getting version info for {}", &name));
                 (vinfo.0, vinfo.1)
             };
@@ -1041,15 +2585,493 @@ fn run_script(
         os,
         &py_vers,
         &lock_path,
+        &pyflow_dir.join("git"),
+        &policy,
     );
 
+    match install::build_env_manifest(&paths.lib)
+        .ok()
+        .and_then(|m| toml::to_string(&m).ok())
+    {
+        Some(data) => {
+            if fs::write(&manifest_path, data).is_err() {
+                abort("Problem writing the script environment's manifest");
+            }
+        }
+        None => abort("Problem reading the script's environment"),
+    }
+
     if commands::run_python(&paths.bin, &[paths.lib], args).is_err() {
         abort("Problem running this script")
     };
 }
 
+/// Check a script environment against the manifest we wrote when we last synced it. Returns
+/// `false` if installed files have changed. Environments without a manifest pass; we write one
+/// after syncing.
+fn verify_script_env(manifest_path: &Path, lib_path: &Path) -> bool {
+    let expected: install::EnvManifest = match fs::read_to_string(manifest_path) {
+        Ok(data) => match toml::from_str(&data) {
+            Ok(m) => m,
+            Err(_) => {
+                util::print_color(
+                    "The script environment's manifest is unreadable; rebuilding the environment",
+                    Color::DarkYellow,
+                );
+                return false;
+            }
+        },
+        Err(_) => return true,
+    };
+
+    let found = match install::build_env_manifest(lib_path) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+
+    let changed = install::manifest_differences(&expected, &found);
+    if changed.is_empty() && expected.packages == found.packages {
+        return true;
+    }
+    util::print_color(
+        &format!(
+            "The script environment has changed since it was set up ({}); rebuilding it",
+            changed.join(", ")
+        ),
+        Color::DarkYellow,
+    );
+    false
+}
+
+/// Whether `sync` may update `pyflow.lock` when it doesn't match `pyproject.toml`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LockMode {
+    /// Re-lock as needed. The default.
+    Update,
+    /// Exit with an error if the lock would change. (`--locked`)
+    Locked,
+    /// Install from the lock as-is, without resolving. (`--frozen`)
+    Frozen,
+}
+
+impl Default for LockMode {
+    fn default() -> Self {
+        Self::Update
+    }
+}
+
+/// Project settings that limit what `sync` may lock and install.
+#[derive(Clone, Debug, Default)]
+struct SyncPolicy {
+    internal_prefixes: Vec<String>,
+    approved: Option<Vec<String>>, // `None` unless `require_approval` is set.
+    lock_mode: LockMode,
+    rules: policy::Policy, // From `[tool.pyflow.policy]`, with its file loaded.
+    index: dep_resolution::Index,
+    python: py_versions::Settings, // Picks wheels for the environment's Python.
+    builds: install::BuildOptions,
+}
+
+/// A summary of a resolution: the requirements, what was locked, and what it found, if it
+/// finished.
+fn resolution_trace(reqs: &[Req], locked: &[Package], resolved: Option<&[Package]>) -> String {
+    let package = |p: &Package| {
+        let deps: Vec<String> = p
+            .deps
+            .iter()
+            .map(|(_, name, version)| format!("{} {}", name, version.to_string2()))
+            .collect();
+        if deps.is_empty() {
+            format!("    {} {}\n", p.name, p.version.to_string2())
+        } else {
+            format!(
+                "    {} {}, requiring {}\n",
+                p.name,
+                p.version.to_string2(),
+                deps.join(", ")
+            )
+        }
+    };
+
+    let mut result = "Requirements:\n".to_owned();
+    for req in reqs {
+        let constraints: Vec<String> = req
+            .constraints
+            .iter()
+            .map(|c| c.to_string2(false, false))
+            .collect();
+        result.push_str(&format!("    {} {}", req.name, constraints.join(", ")));
+        if let Some(marker) = &req.marker {
+            result.push_str(&format!("; {}", marker));
+        }
+        result.push('\n');
+    }
+    result.push_str("Locked:\n");
+    for p in locked {
+        result.push_str(&package(p));
+    }
+    match resolved {
+        Some(resolved) => {
+            result.push_str("Resolved:\n");
+            for p in resolved {
+                result.push_str(&package(p));
+            }
+        }
+        None => result.push_str("Resolution didn't finish\n"),
+    }
+    result
+}
+
+/// Whether a lock entry is for a git or direct URL dependency, which we install directly
+/// instead of resolving.
+fn is_direct(lp: &LockPackage) -> bool {
+    lp.source
+        .as_ref()
+        .is_some_and(|s| s.starts_with("git+") || s.starts_with("url+"))
+}
+
+/// An installed package's `dist-info` folder, and its version.
+fn installed_dist_info(lib_path: &Path, name: &str) -> Option<(PathBuf, Version)> {
+    if !lib_path.exists() {
+        return None;
+    }
+    util::find_folders(lib_path).into_iter().find_map(|folder| {
+        let (n, v) = folder.strip_suffix(".dist-info")?.rsplit_once('-')?;
+        if util::compare_names(n, name) {
+            Some((lib_path.join(&folder), Version::from_str(v).ok()?))
+        } else {
+            None
+        }
+    })
+}
+
+/// The metadata of a package installed at `version`, if it is.
+fn installed_metadata(lib_path: &Path, name: &str, version: &Version) -> Option<util::Metadata> {
+    installed_dist_info(lib_path, name)
+        .filter(|(_, v)| v == version)
+        .map(|(path, _)| util::parse_metadata(&path.join("METADATA")))
+}
+
+/// The folder a path dependency's modules are imported from: its `src` folder, if it's laid
+/// out that way.
+fn import_root(path: &Path) -> PathBuf {
+    let src = path.join("src");
+    if src.is_dir() {
+        src
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Whether we can build a wheel from a path dependency: it has a `setup.py`, or a
+/// `[build-system]` in its `pyproject.toml`. Those that can't be built, like pyflow projects
+/// without one, are put on the `PYTHONPATH` when we run Python instead.
+fn is_buildable(path: &Path, net: &net::Settings) -> bool {
+    path.join("setup.py").exists()
+        || files::read_pyproject(&path.join("pyproject.toml"), net)
+            .map(|p| p.get("build-system").is_some())
+            .unwrap_or(false)
+}
+
+/// Path dependencies to put on the `PYTHONPATH`: editable ones, and those we can't build.
+fn path_reqs_to_import(reqs: &[Req], proj_path: &Path, net: &net::Settings) -> Vec<PathBuf> {
+    reqs.iter()
+        .filter_map(|r| r.path.as_ref().map(|p| (r, proj_path.join(p))))
+        .filter(|(r, path)| r.editable || !is_buildable(path, net))
+        .map(|(_, path)| import_root(&path))
+        .collect()
+}
+
+/// Install path dependencies. Editable ones are installed by pointing the environment at their
+/// source. Others are built into wheels, and rebuilt when the version in their `pyproject.toml`
+/// changes.
+fn sync_path_reqs(
+    reqs: &[Req],
+    installed: &[(String, Version, Vec<String>)],
+    proj_path: &Path,
+    paths: &util::Paths,
+    net: &net::Settings,
+) {
+    for req in reqs {
+        let path = match &req.path {
+            Some(p) => proj_path.join(p),
+            None => continue,
+        };
+        let declared = Config::from_file(&path.join("pyproject.toml"), net).and_then(|c| c.version);
+        let current = installed_dist_info(&paths.lib, &req.name)
+            .map(|(dist_info, version)| (version, install::is_editable(&dist_info)));
+        let buildable = is_buildable(&path, net);
+
+        let up_to_date = match current {
+            Some((version, editable)) if editable == req.editable => {
+                declared.is_none_or(|d| d == version)
+            }
+            Some(_) => false,
+            None => !req.editable && !buildable,
+        };
+        if up_to_date {
+            continue;
+        }
+        for (name, version, _) in installed {
+            if util::compare_names(name, &req.name) {
+                install::uninstall(name, version, &paths.lib);
+            }
+        }
+
+        if req.editable {
+            println!("Installing {} from {:?}, as editable", req.name, path);
+            let source = path.canonicalize().unwrap_or_else(|_| path.clone());
+            install::install_editable(
+                &req.name,
+                &declared.unwrap_or_default(),
+                &source,
+                &import_root(&source),
+                &paths.lib,
+            );
+        } else if buildable {
+            println!("Installing {} from {:?}", req.name, path);
+            install::build_and_install_wheel(&req.name, &path, paths);
+        }
+    }
+}
+
+/// Install git dependencies: at the commits they're locked to, if the lock still matches the
+/// repo and ref `pyproject.toml` asks for, or else, if `update`, at the ref's latest commit.
+/// Returns their lock entries, and their own requirements.
+fn sync_git_reqs(
+    reqs: &[Req],
+    lockpacks: &[LockPackage],
+    installed: &[(String, Version, Vec<String>)],
+    update: bool,
+    git_path: &Path,
+    paths: &util::Paths,
+) -> Vec<(LockPackage, Vec<Req>)> {
+    let mut result = vec![];
+    for req in reqs {
+        let url = match &req.git {
+            Some(u) => u,
+            None => continue,
+        };
+        let locked = lockpacks
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &req.name))
+            .find_map(|lp| {
+                let (locked_url, locked_ref, commit) =
+                    dep_types::parse_git_source(lp.source.as_ref()?)?;
+                if &locked_url == url && locked_ref == req.git_ref {
+                    Some((lp, commit))
+                } else {
+                    None
+                }
+            });
+        if locked.is_none() && !update {
+            abort(&format!(
+                "`pyflow.lock` is out of date: it doesn't have the commit to use for {}. Run \
+                 `pyflow install` without `--locked` or `--frozen` to update it.",
+                req.name
+            ));
+        }
+
+        let repo_path = git_path.join(util::standardize_name(&req.name));
+        if let Some((lp, commit)) = &locked {
+            let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+            if commands::git_head(&repo_path).as_ref() == Some(commit) {
+                if let Some(metadata) = installed_metadata(&paths.lib, &req.name, &version) {
+                    result.push(((*lp).clone(), metadata.requires_dist));
+                    continue;
+                }
+            }
+        }
+
+        for (name, version, _) in installed {
+            if util::compare_names(name, &req.name) {
+                install::uninstall(name, version, &paths.lib);
+            }
+        }
+        // A locked commit never moves, but a branch, or the default one, might have.
+        let (target, fetch) = match (&locked, &req.git_ref) {
+            (Some((_, commit)), _) => (commit.clone(), false),
+            (None, Some(git_ref @ GitRef::Branch(_))) => (git_ref.checkout_target(), true),
+            (None, Some(git_ref)) => (git_ref.checkout_target(), false),
+            (None, None) => ("origin/HEAD".to_owned(), true),
+        };
+        let metadata =
+            install::download_and_install_git(&req.name, url, &target, fetch, git_path, paths);
+        let commit = match commands::git_head(&repo_path) {
+            Some(c) => c,
+            None => {
+                abort(&format!(
+                    "Problem finding the commit of {} we installed",
+                    req.name
+                ));
+                unreachable!()
+            }
+        };
+
+        result.push((
+            LockPackage {
+                id: 0, // Set once the rest are resolved.
+                name: req.name.clone(),
+                version: metadata.version.to_string2(),
+                source: Some(dep_types::git_source(url, req.git_ref.as_ref(), &commit)),
+                dependencies: None,
+                rename: None,
+                markers: None,
+                extras: None,
+            },
+            metadata.requires_dist,
+        ));
+    }
+    result
+}
+
+/// Install direct URL dependencies. If the URL's locked, its archive is checked against the
+/// locked hash; otherwise, if `update`, it's downloaded and hashed into the lock. Returns their
+/// lock entries, and their own requirements.
+fn sync_url_reqs(
+    reqs: &[Req],
+    lockpacks: &[LockPackage],
+    installed: &[(String, Version, Vec<String>)],
+    update: bool,
+    paths: &util::Paths,
+    net: &net::Settings,
+    prompt_timeout: Option<time::Duration>,
+) -> Vec<(LockPackage, Vec<Req>)> {
+    let mut result = vec![];
+    for req in reqs {
+        let url = match &req.url {
+            Some(u) => u,
+            None => continue,
+        };
+        let locked = lockpacks
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &req.name))
+            .find_map(|lp| {
+                let (locked_url, sha256) = dep_types::parse_url_source(lp.source.as_ref()?)?;
+                if &locked_url == url {
+                    Some((lp, sha256))
+                } else {
+                    None
+                }
+            });
+        if locked.is_none() && !update {
+            abort(&format!(
+                "`pyflow.lock` is out of date: it doesn't have the hash of {}'s archive. Run \
+                 `pyflow install` without `--locked` or `--frozen` to update it.",
+                req.name
+            ));
+        }
+
+        // Eg `saturn-0.3.4-py3-none-any.whl` from `https://example.com/saturn-0.3.4-py3-none-any.whl?download=1`.
+        let filename = url
+            .split(['?', '#'])
+            .next()
+            .and_then(|u| u.rsplit('/').next())
+            .unwrap_or_default()
+            .to_owned();
+        let version = match archive_name_version(&filename).map(|(_, v)| Version::from_str(v)) {
+            Some(Ok(v)) => v,
+            _ => {
+                abort(&format!(
+                    "Can't find {}'s version in {:?}. Its URL should be for a wheel, or a \
+                     source archive like `{}-1.0.0.tar.gz`.",
+                    req.name, filename, req.name
+                ));
+                unreachable!()
+            }
+        };
+
+        if let Some((lp, _)) = &locked {
+            if let Some(metadata) = installed_metadata(&paths.lib, &req.name, &version) {
+                result.push(((*lp).clone(), metadata.requires_dist));
+                continue;
+            }
+        }
+
+        for (name, version, _) in installed {
+            if util::compare_names(name, &req.name) {
+                install::uninstall(name, version, &paths.lib);
+            }
+        }
+        // To lock a new URL, download it fresh and hash it. Installing then uses the copy in
+        // the cache.
+        let archive_path = paths.cache.join(&filename);
+        let sha256 = match &locked {
+            Some((_, sha256)) => sha256.clone(),
+            None => {
+                fs::create_dir_all(&paths.cache).ok();
+                fs::remove_file(&archive_path).ok();
+                let downloaded =
+                    net::get(net, url)
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut resp| {
+                            let mut file =
+                                fs::File::create(&archive_path).map_err(|e| e.to_string())?;
+                            std::io::copy(&mut resp, &mut file).map_err(|e| e.to_string())
+                        });
+                if let Err(e) = downloaded {
+                    fs::remove_file(&archive_path).ok();
+                    util::abort_with(
+                        util::ExitCode::Network,
+                        &format!("Problem downloading {}: {}", req.name, e),
+                    );
+                }
+                match install::file_sha256(&archive_path) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        abort(&format!("Problem hashing {:?}: {}", archive_path, e));
+                        unreachable!()
+                    }
+                }
+            }
+        };
+
+        println!("Installing {} from {}", req.name, url);
+        let package_type = if filename.ends_with(".whl") {
+            install::PackageType::Wheel
+        } else {
+            install::PackageType::Source
+        };
+        if let Err(e) = install::download_and_install_package(
+            &req.name,
+            &version,
+            url,
+            &filename,
+            &sha256,
+            paths,
+            package_type,
+            &None,
+            net,
+            prompt_timeout,
+        ) {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem downloading {}: {}", req.name, e),
+            );
+        }
+        let requires = installed_metadata(&paths.lib, &req.name, &version)
+            .map(|m| m.requires_dist)
+            .unwrap_or_default();
+
+        result.push((
+            LockPackage {
+                id: 0, // Set once the rest are resolved.
+                name: req.name.clone(),
+                version: version.to_string2(),
+                source: Some(dep_types::url_source(url, &sha256)),
+                dependencies: None,
+                rename: None,
+                markers: None,
+                extras: None,
+            },
+            requires,
+        ));
+    }
+    result
+}
+
 /// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
-/// the config and lock files.
+/// the config and lock files. Returns whether we wrote the lock.
 fn sync(
     paths: &util::Paths,
     lockpacks: &[LockPackage],
@@ -1059,14 +3081,17 @@ fn sync(
     os: util::Os,
     py_vers: &Version,
     lock_path: &Path,
-) {
+    git_path: &Path,
+    policy: &SyncPolicy,
+) -> bool {
     let installed = util::find_installed(&paths.lib);
     // We control the lock format, so this regex will always match
     let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
 
-    // We don't need to resolve reqs that are already locked.
+    // We don't need to resolve reqs that are already locked. Git and URL dependencies aren't resolved.
     let locked: Vec<Package> = lockpacks
         .iter()
+        .filter(|lp| !is_direct(lp))
         .map(|lp| {
             let mut deps = vec![];
             for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
@@ -1086,6 +3111,8 @@ fn sync(
                 version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
                 deps,
                 rename: Rename::No, // todo
+                markers: lp.markers.clone(),
+                extras: lp.extras.clone().unwrap_or_default(),
             }
         })
         .collect();
@@ -1107,12 +3134,116 @@ fn sync(
         combined_reqs.push(dev_req);
     }
 
-    let resolved = if let Ok(r) = dep_resolution::resolve(&combined_reqs, &locked, os, py_vers) {
-        r
-    } else {
-        abort("Problem resolving dependencies");
-        unreachable!()
+    // We don't resolve git, path and URL requirements, since we install them directly, but we
+    // do resolve their requirements.
+    let mut direct_packs = sync_git_reqs(
+        &combined_reqs,
+        lockpacks,
+        &installed,
+        policy.lock_mode == LockMode::Update,
+        git_path,
+        paths,
+    );
+    direct_packs.extend(sync_url_reqs(
+        &combined_reqs,
+        lockpacks,
+        &installed,
+        policy.lock_mode == LockMode::Update,
+        paths,
+        &policy.index.net,
+        policy.python.prompt_timeout,
+    ));
+    sync_path_reqs(
+        &combined_reqs,
+        &installed,
+        lock_path.parent().unwrap_or_else(|| Path::new(".")),
+        paths,
+        &policy.index.net,
+    );
+    combined_reqs.retain(|r| r.git.is_none() && r.path.is_none() && r.url.is_none());
+    for (_, requires) in &direct_packs {
+        combined_reqs.extend(requires.iter().cloned());
+    }
+
+    if policy.lock_mode != LockMode::Update {
+        let unlocked = find_unlocked(&combined_reqs, &locked);
+        if !unlocked.is_empty() {
+            abort(&format!(
+                "`pyflow.lock` is out of date: it doesn't satisfy {} from `pyproject.toml`. Run \
+                 `pyflow install` without `--locked` or `--frozen` to update it.",
+                unlocked.join(", ")
+            ));
+        }
+    }
+
+    if policy.lock_mode == LockMode::Frozen {
+        let lockpacks: Vec<LockPackage> = lockpacks
+            .iter()
+            .filter(|lp| !is_direct(lp))
+            .cloned()
+            .collect();
+        // The lock may have been edited by hand, so it can't vouch for what it lists.
+        let names: Vec<(String, String)> = lockpacks
+            .iter()
+            .map(|lp| (lp.name.clone(), lp.version.clone()))
+            .collect();
+        check_internal_names(&names, policy);
+        if let Some(approved) = &policy.approved {
+            let names: Vec<String> = names.into_iter().map(|(n, _)| n).collect();
+            check_approved(&find_unapproved(&names, &[], approved));
+        }
+        check_policy(&policy.rules, &policy.index, &lockpacks);
+        if let Err(removed) = sync_deps(
+            paths,
+            &lockpacks,
+            dont_uninstall,
+            &installed,
+            os,
+            py_vers,
+            &policy.python,
+            policy.builds,
+            &|name, version| warehouse_releases(&policy.index, name, version),
+        ) {
+            abort(&format!(
+                "Can't install {} from `pyflow.lock`. Run `pyflow install` without `--frozen` \
+                 to re-resolve {}.",
+                removed.join(", "),
+                if removed.len() == 1 { "it" } else { "them" }
+            ));
+        }
+        return false;
+    }
+
+    // Written before resolving too, so it's there if resolution fails.
+    let trace_path = paths
+        .lib
+        .parent()
+        .unwrap_or(&paths.lib)
+        .join(RESOLUTION_TRACE_FILENAME);
+    fs::write(&trace_path, resolution_trace(&combined_reqs, &locked, None)).ok();
+    let resolved = if let Ok(r) =
+        dep_resolution::resolve(&policy.index, &combined_reqs, &locked, os, py_vers)
+    {
+        r
+    } else {
+        util::abort_with(util::ExitCode::Resolution, "Problem resolving dependencies");
+        unreachable!()
     };
+    fs::write(
+        &trace_path,
+        resolution_trace(&combined_reqs, &locked, Some(&resolved)),
+    )
+    .ok();
+
+    let names: Vec<(String, String)> = resolved
+        .iter()
+        .map(|p| (p.name.clone(), p.version.to_string2()))
+        .collect();
+    check_internal_names(&names, policy);
+    if let Some(approved) = &policy.approved {
+        let names: Vec<String> = names.into_iter().map(|(n, _)| n).collect();
+        check_approved(&find_unapproved(&names, lockpacks, approved));
+    }
 
     // Now merge the existing lock packages with new ones from resolved packages.
     // We have a collection of requirements; attempt to merge them with the already-locked ones.
@@ -1127,8 +3258,12 @@ fn sync(
                 .collect();
             let existing2 = existing[0];
 
-            updated_lock_packs.push(existing2.clone());
-            continue;
+            // If it's now installed with more extras, lock their dependencies too.
+            let locked_extras = existing2.extras.clone().unwrap_or_default();
+            if package.extras.iter().all(|e| locked_extras.contains(e)) {
+                updated_lock_packs.push(existing2.clone());
+                continue;
+            }
         }
 
         let deps = package
@@ -1136,9 +3271,10 @@ fn sync(
             .iter()
             .map(|(_, name, version)| {
                 format!(
-                    "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
+                    "{} {} pypi+{}/pypi/{}/{}/json",
                     name,
                     version.to_string2(),
+                    policy.index.url(name),
                     name,
                     version.to_string2(),
                 )
@@ -1150,7 +3286,8 @@ fn sync(
             name: package.name.clone(),
             version: package.version.to_string(),
             source: Some(format!(
-                "pypi+https://pypi.org/pypi/{}/{}/json",
+                "pypi+{}/pypi/{}/{}/json",
+                policy.index.url(&package.name),
                 package.name,
                 package.version.to_string()
             )),
@@ -1159,8 +3296,30 @@ fn sync(
                 Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
                 Rename::No => None,
             },
+            markers: package.markers.clone(),
+            extras: if package.extras.is_empty() {
+                None
+            } else {
+                Some(package.extras.clone())
+            },
         });
     }
+    let first_id = resolved.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+    for (i, (mut lp, _)) in direct_packs.into_iter().enumerate() {
+        lp.id = first_id + i as u32;
+        updated_lock_packs.push(lp);
+    }
+    check_policy(&policy.rules, &policy.index, &updated_lock_packs);
+
+    if policy.lock_mode == LockMode::Locked {
+        let diff = diff_locks(&updated_lock_packs, lockpacks);
+        if diff != LockDiff::default() {
+            abort(
+                "`pyflow.lock` is out of date: resolving `pyproject.toml` would change it. Run \
+                 `pyflow install` without `--locked` to update it.",
+            );
+        }
+    }
 
     let updated_lock = Lock {
         //        metadata: Some(lock_metadata),
@@ -1173,14 +3332,231 @@ fn sync(
 
     // Now that we've confirmed or modified the lock file, we're ready to sync installed
     // depenencies with it.
-    sync_deps(
+    let pypi_packs: Vec<LockPackage> = updated_lock_packs
+        .iter()
+        .filter(|lp| !is_direct(lp))
+        .cloned()
+        .collect();
+    if let Err(removed) = sync_deps(
         paths,
-        &updated_lock_packs,
+        &pypi_packs,
         dont_uninstall,
         &installed,
         os,
         py_vers,
-    );
+        &policy.python,
+        policy.builds,
+        &|name, version| warehouse_releases(&policy.index, name, version),
+    ) {
+        // Resolve just those again; the rest of the lock stays as it is.
+        if policy.lock_mode == LockMode::Update
+            && util::confirm(
+                &format!(
+                    "Find other versions of {}, keeping the rest of `pyflow.lock`?",
+                    removed.join(", ")
+                ),
+                policy.python.prompt_timeout,
+            )
+        {
+            let kept: Vec<LockPackage> = updated_lock_packs
+                .into_iter()
+                .filter(|lp| !removed.iter().any(|n| util::compare_names(n, &lp.name)))
+                .collect();
+            return sync(
+                paths,
+                &kept,
+                reqs,
+                dev_reqs,
+                dont_uninstall,
+                os,
+                py_vers,
+                lock_path,
+                git_path,
+                policy,
+            );
+        }
+        abort(&format!(
+            "Can't install {} from `pyflow.lock`. Run `pyflow install` without `--locked`, and \
+             choose to find other versions.",
+            removed.join(", ")
+        ));
+    }
+    true
+}
+
+/// Describe a `pylock.toml` file the way PyPI does, for `find_best_release`.
+fn release_from_file(
+    file: &pylock::File,
+    requires_python: Option<&str>,
+) -> dep_resolution::WarehouseRelease {
+    let is_wheel = file.name.ends_with(".whl");
+    // Wheel names end with `-{python tag}-{abi tag}-{platform tag}.whl`.
+    let python_version = if is_wheel {
+        file.name.rsplit('-').nth(2).unwrap_or("any").to_owned()
+    } else {
+        "source".to_owned()
+    };
+    dep_resolution::WarehouseRelease {
+        filename: file.name.clone(),
+        has_sig: false,
+        digests: dep_resolution::WarehouseDigests {
+            md5: String::new(),
+            sha256: file.sha256.clone(),
+        },
+        packagetype: if is_wheel { "bdist_wheel" } else { "sdist" }.to_owned(),
+        python_version,
+        requires_python: requires_python.map(ToOwned::to_owned),
+        url: file.url.clone(),
+        dependencies: None,
+        yanked: false,
+        yanked_reason: None,
+        size: file.size,
+        upload_time: None,
+    }
+}
+
+/// Install exactly the packages in a `pylock.toml`, or a `requirements.txt` pinned with hashes,
+/// eg one another tool wrote, and remove any others. Files are checked against the hashes it
+/// lists, instead of PyPI's.
+#[allow(clippy::too_many_arguments)]
+fn sync_from(
+    paths: &util::Paths,
+    index: &dep_resolution::Index,
+    py: &py_versions::Settings,
+    path: &Path,
+    os: Os,
+    py_vers: &Version,
+    explain: bool,
+    build_options: install::BuildOptions,
+) {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            abort(&format!("Problem reading {:?}: {}", path, e));
+            unreachable!()
+        }
+    };
+    let env = venv_marker_env(paths, os, py_vers, py.implementation);
+    let applies = |marker: &Option<String>| markers_apply(marker, &env);
+    let parse_problem = |e: DependencyError| {
+        abort(&format!("Problem reading {:?}: {}", path, e.details));
+    };
+
+    // Keyed by standardized name.
+    let mut releases: HashMap<String, Vec<dep_resolution::WarehouseRelease>> = HashMap::new();
+    let mut lockpacks = vec![];
+    let mut listed = vec![];
+    if path.extension().and_then(|e| e.to_str()) == Some("toml") {
+        for package in pylock::read(&text).unwrap_or_else(|e| {
+            parse_problem(e);
+            unreachable!()
+        }) {
+            listed.push((
+                package.name.clone(),
+                package.version.clone().unwrap_or_default(),
+                package.marker.clone(),
+            ));
+            if !applies(&package.marker) {
+                continue;
+            }
+            let (sdist, wheels) = match &package.source {
+                pylock::Source::Index { sdist, wheels, .. } => (sdist, wheels),
+                _ => {
+                    abort(&format!(
+                        "{} is a directory, VCS or archive package, which `pyflow sync --from` \
+                         can't install yet",
+                        package.name
+                    ));
+                    unreachable!()
+                }
+            };
+            let version = match &package.version {
+                Some(v) => v.clone(),
+                None => {
+                    abort(&format!("{} doesn't specify its version", package.name));
+                    unreachable!()
+                }
+            };
+            releases.insert(
+                util::standardize_name(&package.name),
+                sdist
+                    .iter()
+                    .chain(wheels.iter())
+                    .map(|f| release_from_file(f, package.requires_python.as_deref()))
+                    .collect(),
+            );
+            lockpacks.push((package.name, version));
+        }
+    } else {
+        for req in pylock::read_hashed_requirements(&text).unwrap_or_else(|e| {
+            parse_problem(e);
+            unreachable!()
+        }) {
+            listed.push((req.name.clone(), req.version.clone(), req.marker.clone()));
+            if !applies(&req.marker) {
+                continue;
+            }
+            let version = util::fallible_v_parse(&req.version);
+            // Only install files that match the hashes.
+            // PyPI's, or those in the download cache, if it's been removed from PyPI.
+            let found = warehouse_releases(index, &req.name, &version)
+                .unwrap_or_else(|_| cached_releases(&paths.cache, &req.name, &version));
+            let matching: Vec<dep_resolution::WarehouseRelease> = found
+                .into_iter()
+                .filter(|r| req.hashes.contains(&r.digests.sha256.to_lowercase()))
+                .collect();
+            if matching.is_empty() {
+                util::abort_with(
+                    util::ExitCode::HashMismatch,
+                    &format!(
+                        "None of {} {}'s files on PyPI match the hashes in {:?}",
+                        req.name, req.version, path
+                    ),
+                );
+            }
+            releases.insert(util::standardize_name(&req.name), matching);
+            lockpacks.push((req.name, req.version));
+        }
+    }
+
+    let lockpacks: Vec<LockPackage> = lockpacks
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, version))| LockPackage {
+            id: i as u32,
+            name,
+            version,
+            source: None,
+            dependencies: None,
+            rename: None,
+            markers: None,
+            extras: None,
+        })
+        .collect();
+
+    sync_deps(
+        paths,
+        &lockpacks,
+        &[],
+        &util::find_installed(&paths.lib),
+        os,
+        py_vers,
+        py,
+        build_options,
+        &|name, _| {
+            Ok(releases
+                .get(&util::standardize_name(name))
+                .cloned()
+                .unwrap_or_default())
+        },
+    )
+    .unwrap_or_else(|removed| {
+        abort(&format!("Can't find {} in {:?}", removed.join(", "), path));
+        unreachable!()
+    });
+    if explain {
+        print!("{}", explain_markers(&listed, &env));
+    }
 }
 
 #[derive(Clone)]
@@ -1199,7 +3575,12 @@ impl ToString for ClearChoice {
 }
 
 /// Clear `Pyflow`'s cache. Allow the user to select which parts to clear based on a prompt.
-fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
+fn clear(
+    pyflow_path: &Path,
+    cache_path: &Path,
+    script_env_path: &Path,
+    prompt_timeout: Option<time::Duration>,
+) {
     let result = util::prompt_list(
         "Which cached items would you like to clear?",
         "choice",
@@ -1213,6 +3594,7 @@ fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
             ("All of the above".into(), ClearChoice::All),
         ],
         false,
+        prompt_timeout,
     );
 
     // todo: DRY
@@ -1235,6 +3617,7 @@ fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
         }
         ClearChoice::PyInstalls => {}
         ClearChoice::All => {
+            fs::remove_dir_all(&cache_path).ok();
             if fs::remove_dir_all(&pyflow_path).is_err() {
                 abort(&format!(
                     "Problem removing the Pyflow path: {:?}",
@@ -1245,135 +3628,2115 @@ fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
     }
 }
 
-/// We process input commands in a deliberate order, to ensure the required, and only the required
-/// setup steps are accomplished before each.
-fn main() {
-    let cfg_filename = "pyproject.toml";
-    let lock_filename = "pyflow.lock";
+/// Create a `pyproject.toml` from a legacy `setup.py` or `setup.cfg`. We read `setup.cfg`
+/// directly. For `setup.py`, we run it to generate `egg-info` metadata in a temporary directory,
+/// since it may compute its metadata in arbitrary ways.
+fn migrate(proj_dir: &Path, file: &Path, cfg_filename: &str, temp_root: &Path) {
+    let cfg_path = proj_dir.join(cfg_filename);
+    if cfg_path.exists() {
+        abort("pyproject.toml already exists - not overwriting.")
+    }
+    let file = proj_dir.join(file);
+    if !file.exists() {
+        abort(&format!("Can't find {:?}", file));
+    }
 
-    let base_dir = directories::BaseDirs::new();
-    let pyflow_path = base_dir
-        .expect("Problem finding base directory")
-        .data_dir()
-        .to_owned()
-        .join("pyflow");
+    let mut cfg = Config::default();
+    let skipped = match file.file_name().and_then(|f| f.to_str()) {
+        Some("setup.cfg") => files::migrate_setup_cfg(&mut cfg, &file),
+        Some("setup.py") => {
+            let py_alias = ["python3", "python"]
+                .iter()
+                .find(|alias| commands::find_py_version(alias).is_some())
+                .unwrap_or_else(|| {
+                    util::abort_with(
+                        util::ExitCode::MissingInterpreter,
+                        "Can't find a Python installation to run `setup.py` with",
+                    );
+                    unreachable!()
+                });
+
+            let temp = util::TempDir::new_in_root(temp_root, "migrate");
+            let egg_base = temp.path();
+            let setup_dir = file.parent().expect("Can't find the `setup.py` directory");
+            if commands::run_egg_info(py_alias, setup_dir, egg_base).is_err() {
+                abort("Problem running `setup.py`");
+            }
 
-    let dep_cache_path = pyflow_path.join("dependency-cache");
-    let script_env_path = pyflow_path.join("script-envs");
-    let git_path = pyflow_path.join("git");
+            let egg_info = util::find_folders(egg_base)
+                .into_iter()
+                .find(|f| f.ends_with(".egg-info"))
+                .unwrap_or_else(|| {
+                    abort("`setup.py egg_info` didn't create any metadata");
+                    unreachable!()
+                });
+            files::migrate_egg_info(&mut cfg, &egg_base.join(egg_info))
+        }
+        _ => {
+            abort("`migrate` must be passed a `setup.py` or `setup.cfg` file");
+            unreachable!()
+        }
+    };
 
-    #[cfg(target_os = "windows")]
-    let os = Os::Windows;
-    #[cfg(target_os = "linux")]
-    let os = Os::Linux;
-    #[cfg(target_os = "macos")]
-    let os = Os::Mac;
+    cfg.write_file(&cfg_path);
+    util::print_color(
+        &format!("Created `pyproject.toml` from {:?}", file),
+        Color::Green,
+    );
 
-    // Handle commands that don't involve operating out of a project before one that do, with setup
-    // code in-between.
-    let opt = Opt::from_args();
-    let subcmd = match opt.subcmds {
-        Some(sc) => sc,
-        None => {
-            // This branch runs when none of the specified subcommands are used
-            if opt.script.is_empty() || opt.script[0].ends_with("py") {
-                // Nothing's specified, eg `pyflow`, or a script is specified; run `python`.
-                SubCommand::Python { args: opt.script }
-            } else {
-                //                println!("ARGS: {:?}", &opt.script);
-                if opt.script[0] == "m" {
-                    //                    println!("HMM");
-                }
-                // A command is specified, eg `pyflow black`
-                SubCommand::Run { args: opt.script }
-            }
+    if !skipped.is_empty() {
+        util::print_color(
+            "These items weren't translated; check if you need to add them by hand:",
+            Color::DarkYellow,
+        );
+        for item in skipped {
+            println!("  {}", item);
         }
+    }
+}
+
+/// Whether a package's environment markers apply to an environment; `None` if we can't parse
+/// them.
+fn marker_outcome(markers: &str, env: &MarkerEnv) -> Option<bool> {
+    Marker::from_str(markers).ok().map(|m| m.evaluate(env))
+}
+
+/// Whether to install a package with these markers. Include it if we can't tell.
+fn markers_apply(markers: &Option<String>, env: &MarkerEnv) -> bool {
+    match markers {
+        Some(m) => marker_outcome(m, env).unwrap_or(true),
+        None => true,
+    }
+}
+
+/// The marker values of the environment at `paths`, from its interpreter. If we can't run it,
+/// use what we know of its OS and Python version.
+fn venv_marker_env(
+    paths: &util::Paths,
+    os: Os,
+    py_vers: &Version,
+    implementation: util::Implementation,
+) -> MarkerEnv {
+    MarkerEnv::from_interpreter(&paths.bin.join("python"))
+        .unwrap_or_else(|| MarkerEnv::new(os, py_vers, implementation))
+}
+
+/// Explain which packages were installed or skipped because of their environment markers, for
+/// `pyflow sync --explain-markers`. `packages` are names, versions, and markers.
+fn explain_markers(packages: &[(String, String, Option<String>)], env: &MarkerEnv) -> String {
+    let platform = match env.sys_platform.as_ref() {
+        "linux" => "Linux",
+        "win32" => "Windows",
+        "darwin" => "macOS",
+        "" => "any platform",
+        other => other,
     };
+    let mut result = format!(
+        "Environment markers, evaluated for {} and Python {}:\n",
+        platform, env.python_full_version
+    );
 
-    // Run this before parsing the config.
-    if let SubCommand::Script { mut args } = subcmd {
-        run_script(
-            &script_env_path,
-            &dep_cache_path,
-            os,
-            &mut args,
-            &pyflow_path,
-        );
-        return;
+    let mut unmarked = 0;
+    for (name, version, markers) in packages {
+        let markers = match markers {
+            Some(m) => m,
+            None => {
+                unmarked += 1;
+                continue;
+            }
+        };
+        let (outcome, note) = match marker_outcome(markers, env) {
+            Some(true) => ("installed", ""),
+            Some(false) => ("skipped", ""),
+            None => ("installed", " (we can't evaluate these, so it's installed)"),
+        };
+        result.push_str(&format!(
+            "  {:<10} {} {}: {}{}\n",
+            outcome, name, version, markers, note
+        ));
     }
 
-    if let SubCommand::New { name } = subcmd {
-        if new(&name).is_err() {
-            abort(
-                "Problem creating the project. This may be due to a permissions problem. \
-                 If on linux, please try again with `sudo`.",
-            );
-        }
-        util::print_color(
-            &format!("Created a new Python project named {}", name),
-            Color::Green,
+    if unmarked == packages.len() {
+        result = format!(
+            "None of the locked packages have environment markers, so all {} are installed\n",
+            unmarked
         );
-        return;
+    } else if unmarked == 1 {
+        result.push_str("1 other package has no markers, so it's installed everywhere\n");
+    } else if unmarked > 1 {
+        result.push_str(&format!(
+            "{} other packages have no markers, so they're installed everywhere\n",
+            unmarked
+        ));
     }
+    result
+}
 
-    if let SubCommand::Init {} = subcmd {
-        let cfg_path = PathBuf::from(cfg_filename);
-        if cfg_path.exists() {
-            abort("pyproject.toml already exists - not overwriting.")
-        }
+/// Find the locked packages needed on a target OS and Python version: start from the packages
+/// `pyproject.toml` requires, and follow the lock's dependencies, skipping ones whose markers
+/// don't apply to the target.
+fn packages_for_target<'a>(
+    lockpacks: &'a [LockPackage],
+    reqs: &[Req],
+    os: Os,
+    py_vers: &Version,
+    implementation: util::Implementation,
+) -> Vec<&'a LockPackage> {
+    let env = MarkerEnv::new(os, py_vers, implementation);
+    let applies = |lp: &LockPackage| markers_apply(&lp.markers, &env);
+    // We control the lock format, so this regex will always match
+    let dep_re = Regex::new(r"^(.*?)\s(.*?)\s.*$").unwrap();
 
-        let mut cfg = match PathBuf::from("Pipfile").exists() {
-            true => Config::from_pipfile(&PathBuf::from("Pipfile")).unwrap_or_default(),
-            false => Config::default(),
-        };
+    let mut to_visit: Vec<&LockPackage> = lockpacks
+        .iter()
+        .filter(|lp| reqs.iter().any(|r| util::compare_names(&r.name, &lp.name)))
+        .filter(|lp| applies(lp))
+        .collect();
+    let mut result: Vec<&LockPackage> = vec![];
 
-        cfg.py_version = Some(util::prompt_py_vers());
+    while let Some(lp) = to_visit.pop() {
+        if result.iter().any(|r| r.id == lp.id) {
+            continue;
+        }
+        for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+            if let Some(caps) = dep_re.captures(dep) {
+                let name = caps.get(1).unwrap().as_str();
+                let vers = Version::from_str(caps.get(2).unwrap().as_str()).ok();
+                if let Some(dep_lp) = lockpacks.iter().find(|p| {
+                    util::compare_names(&p.name, name) && Version::from_str(&p.version).ok() == vers
+                }) {
+                    if applies(dep_lp) {
+                        to_visit.push(dep_lp);
+                    }
+                }
+            }
+        }
+        result.push(lp);
+    }
 
-        files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.txt"));
+    result.sort_by_key(|lp| lp.name.to_lowercase());
+    result
+}
 
-        cfg.write_file(&cfg_path);
-        util::print_color("Created `pyproject.toml`", Color::Green);
-        // Don't return here; let the normal logic create the venv now.
+/// Render locked dependencies for a target, in `requirements.txt` or `pylock.toml` format.
+fn render_export(
+    cfg: &Config,
+    index: &dep_resolution::Index,
+    lockpacks: &[LockPackage],
+    os: Os,
+    py_vers: &Version,
+    dev: bool,
+    format: pylock::ExportFormat,
+    git_path: &Path,
+) -> String {
+    let mut reqs = cfg.reqs.clone();
+    if dev {
+        reqs.extend(cfg.dev_reqs.iter().cloned());
     }
 
-    // We need access to the config from here on; throw an error if we can't find it.
-    let mut cfg_path = PathBuf::from(cfg_filename);
-    if !&cfg_path.exists() {
-        //        if let SubCommand::Python { args: _ } = subcmd {
-        // Try looking recursively in parent directories for a config file.
-        let recursion_limit = 8; // How my levels to look up
-        let mut current_level = env::current_dir().expect("Can't access current directory");
-        for _ in 0..recursion_limit {
-            if let Some(parent) = current_level.parent() {
-                let parent_cfg_path = parent.join(cfg_filename);
-                if parent_cfg_path.exists() {
-                    cfg_path = parent_cfg_path;
-                    break;
+    let packages = packages_for_target(lockpacks, &reqs, os, py_vers, index.implementation);
+    match format {
+        pylock::ExportFormat::Requirements => {
+            // pip only has one list of indexes for every package, so packages from other sources
+            // are marked with theirs.
+            let mut extra_indexes = vec![];
+            let mut lines = String::new();
+            for lp in packages {
+                match lp.source.as_deref().and_then(dep_types::parse_url_source) {
+                    Some((url, sha256)) => {
+                        lines.push_str(&format!("{} @ {} --hash=sha256:{}\n", lp.name, url, sha256))
+                    }
+                    None if index.source_for(&lp.name).is_some() => {
+                        let url = index.simple_url(&lp.name);
+                        lines.push_str(&format!("{}=={}  # index: {}\n", lp.name, lp.version, url));
+                        if !extra_indexes.contains(&url) {
+                            extra_indexes.push(url);
+                        }
+                    }
+                    None => lines.push_str(&format!("{}=={}\n", lp.name, lp.version)),
                 }
-                current_level = parent.to_owned();
             }
+
+            let mut result = String::new();
+            if !extra_indexes.is_empty() {
+                result.push_str("--index-url https://pypi.org/simple\n");
+                for url in extra_indexes {
+                    result.push_str(&format!("--extra-index-url {}\n", url));
+                }
+            }
+            result + &lines
         }
+        pylock::ExportFormat::Pylock => pylock::render(
+            &pylock_packages(index, &packages, &reqs, git_path),
+            cfg.python_requires.as_deref(),
+        ),
+    }
+}
 
-        if !&cfg_path.exists() {
-            // ie still can't find it after searching parents.
-            util::print_color(
-                "To get started, run `pyflow new projname` to create a project folder, or \
-            `pyflow init` to start a project in this folder. For a list of what you can do, run \
-            `pyflow help`.",
-                Color::DarkCyan,
+/// Write locked dependencies in `requirements.txt` format.
+#[allow(clippy::too_many_arguments)]
+fn export(
+    cfg: &Config,
+    index: &dep_resolution::Index,
+    lock_path: &Path,
+    os: Os,
+    py_vers: &Version,
+    dev: bool,
+    format: pylock::ExportFormat,
+    git_path: &Path,
+    output: Option<&Path>,
+) {
+    let lockpacks = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(_) => {
+            abort("Can't find `pyflow.lock`. Run `pyflow install` to create it.");
+            unreachable!()
+        }
+    };
+
+    let result = render_export(cfg, index, &lockpacks, os, py_vers, dev, format, git_path);
+    match output {
+        Some(path) => {
+            if fs::write(path, result).is_err() {
+                abort(&format!("Problem writing {:?}", path));
+            }
+            util::print_color(&format!("Exported to {:?}", path), Color::Green);
+        }
+        None => print!("{}", result),
+    }
+}
+
+/// Download the files the lock needs for each combination of target OS and Python version into
+/// the cache. `targets` are the OSes, with the names the user gave them.
+#[allow(clippy::too_many_arguments)]
+fn fetch_for_targets(
+    cfg: &Config,
+    index: &dep_resolution::Index,
+    py: &py_versions::Settings,
+    lock_path: &Path,
+    cache: &Path,
+    targets: &[(String, Os)],
+    py_versions: &[Version],
+    dev: bool,
+) {
+    let lockpacks = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_default(),
+        Err(_) => {
+            abort("Can't find `pyflow.lock`. Run `pyflow install` to create it.");
+            unreachable!()
+        }
+    };
+    let mut reqs = cfg.reqs.clone();
+    if dev {
+        reqs.extend(cfg.dev_reqs.iter().cloned());
+    }
+
+    let mut artifacts = vec![];
+    let mut missing = vec![];
+    for (os_name, os) in targets {
+        for py_vers in py_versions {
+            let packages =
+                packages_for_target(&lockpacks, &reqs, *os, py_vers, index.implementation);
+            // Git, path and URL packages don't come from PyPI.
+            for lp in packages
+                .into_iter()
+                .filter(|lp| lp.source.as_deref().is_none_or(|s| s.starts_with("pypi+")))
+            {
+                let version = match Version::from_str(&lp.version) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        abort(&format!("Problem parsing {}'s locked version", lp.name));
+                        unreachable!()
+                    }
+                };
+                let releases = match warehouse_releases(index, &lp.name, &version) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        missing.push(e);
+                        continue;
+                    }
+                };
+                let (best, _) =
+                    util::find_best_release(&releases, &lp.name, &version, *os, py_vers, py);
+                fetch::add(
+                    &mut artifacts,
+                    fetch::Artifact {
+                        filename: best.filename,
+                        url: best.url,
+                        sha256: best.digests.sha256,
+                    },
+                );
+            }
+            println!(
+                "Found the files for {}, Python {}",
+                os_name,
+                py_vers.to_string_no_patch()
             );
-            return;
         }
-        //        }
     }
 
-    // Base pypackages_path and lock_path on the `pyproject.toml` folder.
-    let proj_path = cfg_path.parent().expect("Can't find proj pathw via parent");
-    let pypackages_path = proj_path.join("__pypackages__");
-    let lock_path = &proj_path.join(lock_filename);
+    let summary = fetch::fetch(&index.net, &artifacts, cache);
+    util::print_color(
+        &format!(
+            "Downloaded {}, and {} were cached already",
+            summary.downloaded, summary.cached
+        ),
+        Color::Green,
+    );
+    missing.sort();
+    missing.dedup();
+    for e in &missing {
+        util::print_color(e, Color::DarkYellow);
+    }
+    for (filename, e) in &summary.failed {
+        util::print_color(
+            &format!("Problem downloading {}: {}", filename, e),
+            Color::Red,
+        );
+    }
+    if !summary.failed.is_empty() {
+        util::abort_with(
+            util::ExitCode::Network,
+            &format!("{} downloads failed", summary.failed.len()),
+        );
+    }
+}
+
+/// Read constraints files, from `constraints` in `pyproject.toml`, and `pyflow install -c`.
+fn read_constraint_files(paths: &[PathBuf]) -> Vec<Req> {
+    let mut result = vec![];
+    for path in paths {
+        match files::read_constraints(path) {
+            Ok(c) => result.extend(c),
+            Err(e) => abort(&e),
+        }
+    }
+    result
+}
+
+/// A file from `[tool.pyflow.sync-exports]`, regenerated whenever the lock's written.
+#[derive(Clone, Debug, PartialEq)]
+struct SyncExport {
+    path: PathBuf, // Relative to the project.
+    format: pylock::ExportFormat,
+    dev: bool,
+    platform: Option<Os>,
+    python: Option<Version>,
+}
+
+impl SyncExport {
+    fn from_spec(path: String, spec: files::SyncExportComponent) -> Self {
+        let problem = |e: DependencyError| {
+            abort(&format!(
+                "Problem parsing `sync-exports` for {}: {}",
+                path, e.details
+            ));
+        };
+        // `pylock.toml`, or eg `pylock.dev.toml`, as PEP 751 names them.
+        let file_name = Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let format = match &spec.format {
+            Some(f) => pylock::ExportFormat::from_str(f).unwrap_or_else(|e| {
+                problem(e);
+                unreachable!()
+            }),
+            None if file_name.starts_with("pylock.") && file_name.ends_with(".toml") => {
+                pylock::ExportFormat::Pylock
+            }
+            None => pylock::ExportFormat::Requirements,
+        };
+        Self {
+            format,
+            dev: spec.dev.unwrap_or(false),
+            platform: spec.platform.map(|p| {
+                Os::from_str(&p).unwrap_or_else(|e| {
+                    problem(e);
+                    unreachable!()
+                })
+            }),
+            python: spec.python.map(|v| {
+                Version::from_str(&v).unwrap_or_else(|e| {
+                    problem(e);
+                    unreachable!()
+                })
+            }),
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+/// Regenerate the files in `[tool.pyflow.sync-exports]` from the lock, eg after it's updated.
+/// We re-read `pyproject.toml`, since installing or uninstalling packages changes it. Files
+/// whose contents are already current aren't touched.
+fn write_sync_exports(
+    index: &dep_resolution::Index,
+    cfg_path: &Path,
+    lock_path: &Path,
+    os: Os,
+    py_vers: &Version,
+    git_path: &Path,
+) {
+    let cfg = match Config::from_file(cfg_path, &index.net) {
+        Some(c) => c,
+        None => return,
+    };
+    if cfg.sync_exports.is_empty() {
+        return;
+    }
+    let lockpacks = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(_) => return,
+    };
+    let proj_path = cfg_path.parent().unwrap_or_else(|| Path::new("."));
+
+    for export in &cfg.sync_exports {
+        let text = render_export(
+            &cfg,
+            index,
+            &lockpacks,
+            export.platform.unwrap_or(os),
+            export
+                .python
+                .as_ref()
+                .or_else(|| cfg.py_version.as_ref())
+                .unwrap_or(py_vers),
+            export.dev,
+            export.format,
+            git_path,
+        );
+        let path = proj_path.join(&export.path);
+        if fs::read_to_string(&path).ok().as_deref() == Some(text.as_ref()) {
+            continue;
+        }
+        if fs::write(&path, text).is_err() {
+            abort(&format!("Problem writing {:?}", path));
+        }
+        util::print_color(&format!("Updated {}", export.path.display()), Color::Green);
+    }
+}
+
+/// Find locked packages' files and hashes on PyPI, and where path and git dependencies come from,
+/// for `pylock.toml`.
+fn pylock_packages(
+    index: &dep_resolution::Index,
+    lockpacks: &[&LockPackage],
+    reqs: &[Req],
+    git_path: &Path,
+) -> Vec<pylock::Package> {
+    let mut result = vec![];
+    // Git and URL packages are found from `reqs`, below.
+    for lp in lockpacks.iter().filter(|lp| !is_direct(lp)) {
+        let version = Version::from_str(&lp.version).expect("Problem parsing a locked version");
+        let releases = match dep_resolution::get_warehouse_release(index, &lp.name, &version) {
+            Ok(r) if r.is_empty() => {
+                abort(&format!(
+                    "{} {} has been removed from PyPI, so it can't be exported",
+                    lp.name, lp.version
+                ));
+                unreachable!()
+            }
+            Ok(r) => r,
+            Err(e) => {
+                util::abort_with(
+                    util::ExitCode::Network,
+                    &format!("Problem getting release data for {}: {}", lp.name, e),
+                );
+                unreachable!()
+            }
+        };
+        let file = |r: &dep_resolution::WarehouseRelease| pylock::File {
+            name: r.filename.clone(),
+            url: r.url.clone(),
+            size: r.size,
+            sha256: r.digests.sha256.to_lowercase(),
+        };
+
+        result.push(pylock::Package {
+            name: lp.name.clone(),
+            version: Some(lp.version.clone()),
+            marker: lp.markers.clone(),
+            requires_python: releases.iter().find_map(|r| r.requires_python.clone()),
+            source: pylock::Source::Index {
+                index: index.simple_url(&lp.name),
+                sdist: releases.iter().find(|r| r.packagetype == "sdist").map(file),
+                wheels: releases
+                    .iter()
+                    .filter(|r| r.packagetype == "bdist_wheel")
+                    .map(file)
+                    .collect(),
+            },
+        });
+    }
+
+    for req in reqs {
+        let mut version = None;
+        let source = if let Some(path) = &req.path {
+            pylock::Source::Directory(path.clone())
+        } else if let Some(url) = &req.url {
+            let locked = lockpacks.iter().find_map(|lp| {
+                let (locked_url, sha256) = dep_types::parse_url_source(lp.source.as_ref()?)?;
+                if &locked_url == url && util::compare_names(&lp.name, &req.name) {
+                    Some((lp, sha256))
+                } else {
+                    None
+                }
+            });
+            match locked {
+                Some((lp, sha256)) => {
+                    version = Some(lp.version.clone());
+                    pylock::Source::Archive(pylock::File {
+                        name: url.rsplit('/').next().unwrap_or_default().to_owned(),
+                        url: url.clone(),
+                        size: 0,
+                        sha256,
+                    })
+                }
+                None => {
+                    util::print_color(
+                        &format!(
+                            "Skipping {}, since it isn't locked yet; run `pyflow install` first \
+                             to export it",
+                            req.name
+                        ),
+                        Color::DarkYellow,
+                    );
+                    continue;
+                }
+            }
+        } else if let Some(url) = &req.git {
+            // The commit we installed, from our clone of the repo.
+            let commit = commands::git_head(&git_path.join(util::standardize_name(&req.name)));
+            match commit {
+                Some(commit) => pylock::Source::Vcs {
+                    url: url.clone(),
+                    commit,
+                },
+                None => {
+                    util::print_color(
+                        &format!(
+                            "Skipping {}, since it hasn't been cloned yet; run `pyflow install` \
+                             first to export it",
+                            req.name
+                        ),
+                        Color::DarkYellow,
+                    );
+                    continue;
+                }
+            }
+        } else {
+            continue;
+        };
+        result.push(pylock::Package {
+            name: req.name.clone(),
+            version,
+            marker: None,
+            requires_python: None,
+            source,
+        });
+    }
+    result
+}
+
+/// How a lock differs from a baseline lock.
+#[derive(Debug, Default, PartialEq)]
+struct LockDiff {
+    added: Vec<String>,                     // Locked here, but not in the baseline.
+    changed: Vec<(String, String, String)>, // (name, local version, baseline version)
+    removed: Vec<String>,                   // In the baseline, but not locked here.
+}
+
+fn diff_locks(local: &[LockPackage], baseline: &[LockPackage]) -> LockDiff {
+    let mut result = LockDiff::default();
+    for lp in local {
+        match baseline
+            .iter()
+            .find(|b| util::compare_names(&b.name, &lp.name))
+        {
+            Some(b) if b.version != lp.version => {
+                result
+                    .changed
+                    .push((lp.name.clone(), lp.version.clone(), b.version.clone()))
+            }
+            Some(_) => (),
+            None => result.added.push(lp.name.clone()),
+        }
+    }
+    for b in baseline {
+        if !local
+            .iter()
+            .any(|lp| util::compare_names(&lp.name, &b.name))
+        {
+            result.removed.push(b.name.clone());
+        }
+    }
+    result
+}
+
+/// Fetch a baseline lock file from a URL or path.
+fn fetch_baseline(net: &net::Settings, baseline: &str) -> Result<Lock, Box<dyn Error>> {
+    let data = if baseline.starts_with("http://") || baseline.starts_with("https://") {
+        net::get(net, baseline)?.text()?
+    } else {
+        fs::read_to_string(baseline)?
+    };
+    Ok(toml::from_str(&data)?)
+}
+
+/// Compare `pyflow.lock` with a baseline, for compliance checks. Packages the baseline has that
+/// we don't are fine; additions, and different versions, aren't.
+fn check_baseline(net: &net::Settings, lock_path: &Path, baseline: &str) {
+    let local = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(_) => {
+            abort("Can't find `pyflow.lock`. Run `pyflow install` to create it.");
+            unreachable!()
+        }
+    };
+    let baseline_packs = match fetch_baseline(net, baseline) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(e) => {
+            abort(&format!(
+                "Problem reading the baseline lock {}: {}",
+                baseline, e
+            ));
+            unreachable!()
+        }
+    };
+
+    let diff = diff_locks(&local, &baseline_packs);
+    for name in &diff.added {
+        util::print_color(&format!("+ {} (not in the baseline)", name), Color::Red);
+    }
+    for (name, local_vers, baseline_vers) in &diff.changed {
+        util::print_color(
+            &format!("~ {} {} (baseline: {})", name, local_vers, baseline_vers),
+            Color::Red,
+        );
+    }
+    for name in &diff.removed {
+        util::print_color(
+            &format!("- {} (only in the baseline)", name),
+            Color::DarkBlue,
+        );
+    }
+
+    if diff.added.is_empty() && diff.changed.is_empty() {
+        util::print_color("`pyflow.lock` matches the baseline", Color::Green);
+    } else {
+        abort(&format!(
+            "`pyflow.lock` differs from the baseline: {} unexpected packages, {} different versions",
+            diff.added.len(),
+            diff.changed.len()
+        ));
+    }
+}
+
+/// Check if a project type-checks with mypy, from its dev dependencies or config files.
+fn uses_mypy(cfg: &Config, proj_path: &Path) -> bool {
+    let contains = |file: &str, text: &str| {
+        fs::read_to_string(proj_path.join(file))
+            .map(|d| d.contains(text))
+            .unwrap_or(false)
+    };
+    dep_names(cfg)
+        .iter()
+        .any(|n| util::compare_names(n, "mypy"))
+        || proj_path.join("mypy.ini").exists()
+        || proj_path.join(".mypy.ini").exists()
+        || contains("pyproject.toml", "[tool.mypy")
+        || contains("setup.cfg", "[mypy")
+}
+
+/// Find `types-*` stub packages on `PyPi` for dependencies that don't have them yet, and stubs
+/// in dev dependencies whose runtime package isn't a dependency anymore. Returns
+/// (names to add, names to remove).
+fn find_stub_changes(
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    index_names: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let is_stub = |name: &str| util::standardize_name(name).starts_with("types_");
+    let runtime: Vec<&Req> = reqs
+        .iter()
+        .chain(dev_reqs.iter())
+        .filter(|r| !is_stub(&r.name))
+        .collect();
+    let stubs: Vec<&Req> = dev_reqs.iter().filter(|r| is_stub(&r.name)).collect();
+
+    let mut to_add = vec![];
+    for req in &runtime {
+        let stub_name = format!("types-{}", req.name);
+        if stubs
+            .iter()
+            .any(|s| util::compare_names(&s.name, &stub_name))
+        {
+            continue;
+        }
+        if let Some(found) = index_names
+            .iter()
+            .find(|n| util::compare_names(n, &stub_name))
+        {
+            to_add.push(found.clone());
+        }
+    }
+
+    let to_remove = stubs
+        .iter()
+        .filter(|s| {
+            let runtime_name = &util::standardize_name(&s.name)["types_".len()..];
+            !runtime
+                .iter()
+                .any(|r| util::standardize_name(&r.name) == runtime_name)
+        })
+        .map(|s| s.name.clone())
+        .collect();
+
+    (to_add, to_remove)
+}
+
+/// Suggest `types-*` stub packages for a project using mypy, and with `fix`, update
+/// `dev-dependencies` to match.
+fn check_stubs(
+    cfg: &Config,
+    index: &dep_resolution::Index,
+    proj_path: &Path,
+    cfg_path: &Path,
+    pyflow_path: &Path,
+    fix: bool,
+) {
+    if !uses_mypy(cfg, proj_path) {
+        util::print_color(
+            "This project doesn't appear to use mypy; add it to `dev-dependencies` to check stubs",
+            Color::DarkBlue,
+        );
+        return;
+    }
+
+    let index_names =
+        dep_resolution::cached_package_names(&index.net, &pyflow_path.join("package-names.txt"));
+    if index_names.is_empty() {
+        abort("Problem getting the list of packages on PyPi");
+    }
+    let (to_add, to_remove) = find_stub_changes(&cfg.reqs, &cfg.dev_reqs, &index_names);
+
+    if to_add.is_empty() && to_remove.is_empty() {
+        util::print_color("Type stubs are up to date", Color::Green);
+        return;
+    }
+    for name in &to_add {
+        util::print_color(&format!("+ {} is available", name), Color::Cyan);
+    }
+    for name in &to_remove {
+        util::print_color(
+            &format!("- {} is for a package that's no longer a dependency", name),
+            Color::DarkYellow,
+        );
+    }
+
+    if !fix {
+        println!("Run `pyflow check --stubs --fix` to update `dev-dependencies`");
+        return;
+    }
+
+    let added: Vec<Req> = to_add
+        .iter()
+        .map(|name| {
+            let (fmtd_name, version, _) = dep_resolution::get_version_info(index, name)
+                .unwrap_or_else(|_| panic!("Problem getting version info for {}", name));
+            Req::new(fmtd_name, vec![Constraint::new(ReqType::Caret, version)])
+        })
+        .collect();
+    if !added.is_empty() {
+        files::add_reqs_to_cfg(cfg_path, &[], &added);
+    }
+    if !to_remove.is_empty() {
+        files::remove_reqs_from_cfg(cfg_path, &to_remove);
+    }
+    util::print_color(
+        "Updated `dev-dependencies`. Run `pyflow install` to sync.",
+        Color::Green,
+    );
+}
+
+/// Split locked packages into those required directly by `pyproject.toml`, and transitive ones.
+/// Returns (direct, transitive) counts.
+fn count_locked(lockpacks: &[LockPackage], reqs: &[Req]) -> (usize, usize) {
+    let direct = lockpacks
+        .iter()
+        .filter(|lp| reqs.iter().any(|r| util::compare_names(&r.name, &lp.name)))
+        .count();
+    (direct, lockpacks.len() - direct)
+}
+
+/// Before adding packages from the CLI, check for names one typo away from a popular package or
+/// an existing dependency, eg `reqeusts`, and ask before installing them.
+fn check_typosquats(packages: &[String], cfg: &Config, prompt_timeout: Option<time::Duration>) {
+    let existing = dep_names(cfg);
+
+    for package in packages {
+        // Unparsable reqs are reported by `merge_reqs`.
+        let name = match Req::from_str(&package.replace(',', ""), false) {
+            Ok(r) => r.name,
+            Err(_) => continue,
+        };
+        if warnings::is_suppressed(&cfg.warnings, warnings::WarningCode::Typosquat, Some(&name)) {
+            continue;
+        }
+        if let Some(target) = dep_resolution::find_typosquat_target(&name, &existing) {
+            util::print_color(
+                &format!(
+                    "Warning: `{}` is very similar to `{}`. Check the name carefully; packages \
+                     with misspelled names are sometimes malicious. [{}]",
+                    name,
+                    target,
+                    warnings::WarningCode::Typosquat.name()
+                ),
+                Color::Red,
+            );
+            if !util::confirm(&format!("Install `{}` anyway?", name), prompt_timeout) {
+                util::abort_with(
+                    util::ExitCode::UserAbort,
+                    &format!("Didn't install `{}`", name),
+                );
+            }
+        }
+    }
+}
+
+/// Guard against dependency confusion: packages come from PyPI, unless they're from one of
+/// `[tool.pyflow.sources]`, so an internal name showing up from there means someone's published a
+/// package to take its place. `packages` are names, with versions.
+fn check_internal_names(packages: &[(String, String)], policy: &SyncPolicy) {
+    for (name, version) in packages
+        .iter()
+        .filter(|(n, _)| policy.index.source_for(n).is_none())
+    {
+        if let Some(prefix) = dep_resolution::internal_prefix(name, &policy.internal_prefixes) {
+            util::abort_with(
+                util::ExitCode::InternalNameOnPypi,
+                &format!(
+                    "{} {} was found on PyPI, but matches the internal name `{}` in \
+                     `internal_prefixes`. This may be a dependency confusion attack; specify it \
+                     as a `path` or `git` dependency instead, or map `{}` to the source it comes \
+                     from, eg `internal_prefixes = {{ \"{}\" = \"internal\" }}`.",
+                    name, version, prefix, prefix, prefix
+                ),
+            );
+        }
+    }
+}
+
+/// Abort if any packages haven't been approved in `pyflow.approved`.
+fn check_approved(unapproved: &[String]) {
+    if !unapproved.is_empty() {
+        abort(&format!(
+            "These packages haven't been approved: {}. Review them, then run \
+             `pyflow approve {}`.",
+            unapproved.join(", "),
+            unapproved.join(" ")
+        ));
+    }
+}
+
+/// Read the package names in `pyflow.approved`: one per line, with `#` for comments.
+fn read_approved(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(data) => data
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ToOwned::to_owned)
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Find resolved packages that weren't in the lock before, and haven't been approved. With
+/// `--frozen`, `lockpacks` is empty, since the lock's what's being checked.
+fn find_unapproved(
+    names: &[String],
+    lockpacks: &[LockPackage],
+    approved: &[String],
+) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| {
+            !lockpacks
+                .iter()
+                .any(|lp| util::compare_names(&lp.name, name))
+        })
+        .filter(|name| !approved.iter().any(|a| util::compare_names(a, name)))
+        .cloned()
+        .collect()
+}
+
+/// Check packages about to be locked or installed against `[tool.pyflow.policy]`, and write its
+/// report, if it's set. Details of those from PyPI are looked up there.
+fn check_policy(rules: &policy::Policy, index: &dep_resolution::Index, packages: &[LockPackage]) {
+    if rules.is_empty() {
+        return;
+    }
+    let mut facts = vec![];
+    for lp in packages.iter().filter(|lp| !rules.exempts(&lp.name)) {
+        let source = lp.source.clone().unwrap_or_default();
+        if (source.is_empty() || source.starts_with("pypi+")) && rules.needs_release_data() {
+            let version = Version::from_str(&lp.version).expect("Problem parsing lock version");
+            match dep_resolution::policy_facts(index, &lp.name, &version) {
+                Ok(f) => facts.push(f),
+                Err(e) => util::abort_with(
+                    util::ExitCode::Network,
+                    &format!(
+                        "Problem checking {} against `[tool.pyflow.policy]`: {}",
+                        lp.name, e
+                    ),
+                ),
+            }
+            continue;
+        }
+        let (url, hashed) = match dep_types::parse_url_source(&source) {
+            Some((url, _)) => (url, true),
+            None if source.starts_with("git+") => {
+                let url = source.trim_start_matches("git+");
+                (
+                    url.split(['?', '#']).next().unwrap_or(url).to_owned(),
+                    false,
+                )
+            }
+            None if source.is_empty() => ("https://pypi.org".to_owned(), true),
+            None => (source.trim_start_matches("pypi+").to_owned(), true),
+        };
+        facts.push(policy::Facts {
+            name: lp.name.clone(),
+            version: lp.version.clone(),
+            source: url,
+            hashed,
+            ..policy::Facts::default()
+        });
+    }
+
+    let violations = policy::evaluate(rules, &facts, dep_resolution::today());
+    if let Some(report) = &rules.report {
+        if let Err(e) = fs::write(report, policy::report_json(&violations)) {
+            abort(&format!(
+                "Problem writing the policy report to {}: {}",
+                report.display(),
+                e
+            ));
+        }
+    }
+    if !violations.is_empty() {
+        util::abort_with(
+            util::ExitCode::Policy,
+            &format!(
+                "These packages break the rules in `[tool.pyflow.policy]`:\n{}",
+                policy::describe(&violations)
+            ),
+        );
+    }
+}
+
+/// Add packages to `pyflow.approved`, so `sync` will install them.
+fn approve(path: &Path, packages: &[String]) {
+    if packages.is_empty() {
+        abort("Specify packages to approve, eg `pyflow approve numpy`");
+    }
+    let mut approved = read_approved(path);
+    let mut added = vec![];
+    for package in packages {
+        if !approved.iter().any(|a| util::compare_names(a, package)) {
+            approved.push(package.clone());
+            added.push(package.clone());
+        }
+    }
+
+    let mut data =
+        "# Packages approved for installation. See `require_approval` in `pyproject.toml`.\n"
+            .to_owned();
+    for name in &approved {
+        data.push_str(name);
+        data.push('\n');
+    }
+    if fs::write(path, data).is_err() {
+        abort("Problem writing `pyflow.approved`");
+    }
+
+    if added.is_empty() {
+        util::print_color("These packages were already approved", Color::DarkBlue);
+    } else {
+        util::print_color(&format!("Approved {}", added.join(", ")), Color::Green);
+    }
+}
+
+/// Display a one-screen summary of the project, without modifying the environment.
+fn status(
+    cfg: &Config,
+    proj_path: &Path,
+    pypackages_path: &Path,
+    lock_path: &Path,
+    net: &net::Settings,
+) {
+    let name = cfg.name.clone().unwrap_or_else(|| "(unnamed)".into());
+    let version = match cfg.version {
+        Some(v) => v.to_string2(),
+        None => "(no version)".into(),
+    };
+    println!(
+        "Project: {}{}{} {}",
+        util::fg(Color::Cyan),
+        name,
+        util::fg(Color::Reset),
+        version
+    );
+
+    if let Some(root) = util::find_workspace_root(proj_path, "pyproject.toml", net) {
+        println!("Workspace member of: {}", root.to_string_lossy());
+    }
+    if !cfg.members.is_empty() {
+        println!(
+            "Workspace members: {}",
+            util::expand_members(proj_path, &cfg.members, "pyproject.toml").len()
+        );
+    }
+
+    match cfg.py_version {
+        Some(v) => println!("Python version: {}", v.to_string_no_patch()),
+        None => println!("Python version: (not specified in `pyproject.toml`)"),
+    }
+
+    let venvs = util::find_venvs(pypackages_path);
+    let venv = cfg.py_version.and_then(|v| {
+        venvs
+            .iter()
+            .find(|(ma, mi)| v.major == *ma && v.minor == *mi)
+            .copied()
+    });
+    let vers_path = match venv {
+        Some((ma, mi)) => {
+            let vers_path = pypackages_path.join(&format!("{}.{}", ma, mi));
+            println!("Environment: {}", vers_path.join(".venv").to_string_lossy());
+            if let Some(home) = venv_home(&vers_path) {
+                println!("Interpreter: {}", home.to_string_lossy());
+            }
+            Some(vers_path)
+        }
+        None => {
+            util::print_color(
+                "Environment: not created yet. Run `pyflow install` to set it up.",
+                Color::DarkYellow,
+            );
+            None
+        }
+    };
+
+    let lockpacks = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(_) => {
+            util::print_color(
+                "Lock: no `pyflow.lock` found. Run `pyflow install` to create one.",
+                Color::DarkYellow,
+            );
+            return;
+        }
+    };
+
+    let reqs = [cfg.reqs.as_slice(), cfg.dev_reqs.as_slice()].concat();
+    let (direct, transitive) = count_locked(&lockpacks, &reqs);
+    println!(
+        "Locked dependencies: {} direct, {} transitive",
+        direct, transitive
+    );
+
+    let report = vers_path.as_ref().and_then(|p| {
+        fs::read_to_string(p.join(SYNC_REPORT_FILENAME))
+            .ok()
+            .map(|r| SyncReport::from_text(&r))
+    });
+
+    if let Some(vers_path) = &vers_path {
+        let installed = util::find_installed(&vers_path.join("lib"));
+        let missing = lockpacks
+            .iter()
+            .filter(|lp| {
+                let locked_vers = Version::from_str(&lp.version).ok();
+                !installed.iter().any(|(name, vers, _)| {
+                    util::compare_names(name, &lp.name) && Some(*vers) == locked_vers
+                })
+            })
+            .count();
+
+        if missing == 0 {
+            util::print_color("Environment is in sync with the lock file", Color::Green);
+        } else {
+            util::print_color(
+                &format!(
+                    "Environment is out of sync: {} locked packages aren't installed. \
+                     Run `pyflow install` to sync.",
+                    missing
+                ),
+                Color::DarkYellow,
+            );
+        }
+    }
+
+    if let Some(r) = &report {
+        println!(
+            "Last sync: {} packages from the download cache, {} downloaded",
+            r.cache_hits, r.cache_misses
+        );
+    }
+
+    // Only warn about versions that are still locked.
+    let locked = |name: &str, version: &Version| {
+        lockpacks.iter().any(|lp| {
+            util::compare_names(&lp.name, name)
+                && Version::from_str(&lp.version).ok() == Some(*version)
+        })
+    };
+    let mut warnings = vec![];
+    for (name, version, reason) in report.map(|r| r.yanked).unwrap_or_default() {
+        if locked(&name, &version) {
+            warnings.push(format!(
+                "{} {} has been yanked: {}",
+                name,
+                version.to_string2(),
+                reason
+            ));
+        }
+    }
+    for (name, version, ids) in read_audit(&pypackages_path.join(AUDIT_FILENAME)) {
+        if locked(&name, &version) {
+            warnings.push(format!(
+                "{} {} has known vulnerabilities: {}",
+                name,
+                version.to_string2(),
+                ids.join(", ")
+            ));
+        }
+    }
+    if !warnings.is_empty() {
+        util::print_color("Warnings:", Color::DarkYellow);
+        for warning in warnings {
+            util::print_color(&format!("  - {}", warning), Color::DarkYellow);
+        }
+    }
+}
+
+/// The interpreter an environment was created from, from its `pyvenv.cfg`.
+fn venv_home(vers_path: &Path) -> Option<PathBuf> {
+    let data = fs::read_to_string(vers_path.join(".venv").join("pyvenv.cfg")).ok()?;
+    data.lines()
+        .filter_map(|l| l.split_once('='))
+        .find(|(k, _)| k.trim() == "home")
+        .map(|(_, v)| PathBuf::from(v.trim()))
+}
+
+/// Upgrade the project's Python to the latest patch version we can install, and recreate its
+/// environment with it. For `pyflow python upgrade`.
+fn upgrade_python(
+    cfg: &Config,
+    py: &mut py_versions::Settings,
+    pypackages_path: &Path,
+    pyflow_path: &Path,
+    dep_cache_path: &Path,
+    temp_root: &Path,
+) {
+    let version = match cfg.py_version {
+        Some(v) => v,
+        None => {
+            abort("Set `py_version` in `pyproject.toml` to upgrade it");
+            unreachable!()
+        }
+    };
+
+    match py_versions::upgrade(py, pyflow_path, &version, &cfg.py_constraints) {
+        Some(v) => util::print_color(
+            &format!("Installed Python {}", v.to_string2()),
+            Color::Green,
+        ),
+        None => util::print_color(
+            &format!(
+                "The latest Python {} we can install is already installed",
+                version.to_string_no_patch()
+            ),
+            Color::Green,
+        ),
+    }
+
+    // Only environments created from a Python pyflow installed move to the new one.
+    let vers_path = pypackages_path.join(version.to_string_no_patch());
+    let managed = venv_home(&vers_path).map_or(false, |h| h.starts_with(pyflow_path));
+    if !managed {
+        if vers_path.join(".venv").exists() {
+            util::print_color(
+                "This project's environment uses a Python pyflow didn't install, so it's \
+                 unchanged",
+                Color::DarkBlue,
+            );
+        }
+        return;
+    }
+
+    let bin = util::find_bin_path(&vers_path).join("python");
+    let before = commands::find_py_version(&bin.to_string_lossy());
+    if fs::remove_dir_all(vers_path.join(".venv")).is_err() {
+        abort("Problem removing the old environment");
+    }
+    util::find_or_create_venv(
+        py,
+        &version,
+        &cfg.py_constraints,
+        pypackages_path,
+        pyflow_path,
+        dep_cache_path,
+        temp_root,
+    );
+    let after = commands::find_py_version(&bin.to_string_lossy());
+
+    if let (Some(b), Some(a)) = (before, after) {
+        if a != b {
+            util::print_color(
+                &format!(
+                    "Recreated the environment with Python {}. Python {} is still installed, for \
+                     other projects' environments; remove it with `pyflow python uninstall {}` \
+                     once they're upgraded.",
+                    a.to_string2(),
+                    b.to_string2(),
+                    b.to_string2()
+                ),
+                Color::Green,
+            );
+        }
+    }
+}
+
+/// Load a workspace's member graph, aborting if this project isn't a workspace root.
+fn load_workspace(
+    proj_path: &Path,
+    cfg: &Config,
+    cmd: &str,
+    net: &net::Settings,
+) -> workspace::Graph {
+    if cfg.members.is_empty() {
+        abort(&format!(
+            "`{}` works on workspace members, but this project has no `members` in `pyproject.toml`",
+            cmd
+        ));
+    }
+    workspace::Graph::load(proj_path, &cfg.members, "pyproject.toml", net)
+}
+
+/// Build all members of a workspace, in dependency order, by running `pyflow package` in each.
+fn build_workspace(proj_path: &Path, cfg: &Config, extras: &[String], net: &net::Settings) {
+    let graph = load_workspace(proj_path, cfg, "pyflow package --all", net);
+    let order = match graph.build_order() {
+        Ok(o) => o,
+        Err(e) => {
+            abort(&e.details);
+            unreachable!()
+        }
+    };
+
+    let exe = env::current_exe().unwrap_or_else(|_| PathBuf::from("pyflow"));
+    for i in order {
+        util::print_color(&format!("Building {}", graph.names[i]), Color::Cyan);
+        let status = process::Command::new(&exe)
+            .arg("package")
+            .args(extras)
+            .current_dir(&graph.paths[i])
+            .status();
+        if !status.map_or(false, |s| s.success()) {
+            abort(&format!("Problem building {}; stopping", graph.names[i]));
+        }
+    }
+    util::print_color(
+        &format!("Built {} workspace members", graph.paths.len()),
+        Color::Green,
+    );
+}
+
+/// A member's path, relative to the workspace root, for display.
+fn member_path(proj_path: &Path, path: &Path) -> String {
+    path.strip_prefix(proj_path)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Show which workspace members depend on which, in build order.
+fn show_workspace_graph(proj_path: &Path, cfg: &Config, net: &net::Settings) {
+    let graph = load_workspace(proj_path, cfg, "pyflow workspace graph", net);
+    let order = match graph.build_order() {
+        Ok(o) => o,
+        Err(e) => {
+            abort(&e.details);
+            unreachable!()
+        }
+    };
+
+    for i in order {
+        println!(
+            "{}{}{} ({})",
+            util::fg(Color::Cyan),
+            graph.names[i],
+            util::fg(Color::Reset),
+            member_path(proj_path, &graph.paths[i])
+        );
+        for dep in &graph.deps[i] {
+            println!("  → {}", graph.names[*dep]);
+        }
+    }
+}
+
+/// Print the paths of members affected by changes since a git revision, one per line, eg for CI
+/// to only test those.
+fn show_changed_members(proj_path: &Path, cfg: &Config, since: &str, net: &net::Settings) {
+    let graph = load_workspace(proj_path, cfg, "pyflow workspace changed", net);
+    // Include uncommitted changes, and files that aren't tracked yet.
+    let run_git = |args: &[&str]| -> Vec<String> {
+        match process::Command::new("git")
+            .args(args)
+            .current_dir(proj_path)
+            .output()
+        {
+            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .map(str::to_owned)
+                .collect(),
+            _ => {
+                abort(&format!(
+                    "Problem running `git {}`. Check that `{}` is a revision in this repository.",
+                    args.join(" "),
+                    since
+                ));
+                unreachable!()
+            }
+        }
+    };
+    let mut changed = run_git(&["diff", "--name-only", "--relative", since]);
+    changed.append(&mut run_git(&[
+        "ls-files",
+        "--others",
+        "--exclude-standard",
+    ]));
+
+    for i in graph.affected(proj_path, &changed) {
+        println!("{}", member_path(proj_path, &graph.paths[i]));
+    }
+}
+
+/// Report operations an interrupted sync didn't finish, and optionally roll them back.
+fn doctor(cfg: &Config, pypackages_path: &Path, rollback: bool) {
+    let vers_path = match cfg.py_version {
+        Some(v) => pypackages_path.join(v.to_string_no_patch()),
+        None => {
+            abort("Set `py_version` in `pyproject.toml` to check its environment");
+            unreachable!()
+        }
+    };
+    if !vers_path.exists() {
+        util::print_color(
+            "There's no environment to check yet. Run `pyflow install` to set it up.",
+            Color::DarkBlue,
+        );
+        return;
+    }
+
+    let _lock = lock_env(&vers_path);
+    let journal_path = vers_path.join(JOURNAL_FILENAME);
+    let unfinished = match journal::find_unfinished(&journal_path) {
+        Some(u) => u,
+        None => {
+            util::print_color("No unfinished operations found", Color::Green);
+            return;
+        }
+    };
+
+    util::print_color(
+        "The last `pyflow install` was interrupted. Unfinished operations:",
+        Color::DarkYellow,
+    );
+    for op in &unfinished {
+        let action = match op.action {
+            journal::Action::Install => "install",
+            journal::Action::Uninstall => "uninstall",
+        };
+        println!("  {} {} {}", action, op.name, op.version);
+    }
+
+    if !rollback {
+        println!(
+            "Run `pyflow install` to finish them, or `pyflow doctor --rollback` to remove \
+             packages left partly installed."
+        );
+        return;
+    }
+
+    // An uninstall can't be undone, so we finish those; partial installs are removed.
+    let lib_path = vers_path.join("lib");
+    for op in &unfinished {
+        if let Ok(version) = Version::from_str(&op.version) {
+            install::uninstall(&op.name, &version, &lib_path);
+        }
+    }
+    match journal::Journal::begin(&journal_path) {
+        Ok(j) => j.end(),
+        Err(e) => abort(&format!("Problem writing the sync journal: {}", e)),
+    }
+    util::print_color(
+        "Removed partly installed packages. Run `pyflow install` to install them again.",
+        Color::Green,
+    );
+}
+
+/// Find the health of locked packages; only those in `names`, unless it's empty.
+fn find_health(
+    lock_path: &Path,
+    index: &dep_resolution::Index,
+    names: &[String],
+) -> Vec<dep_resolution::Health> {
+    let lockpacks = match read_lock(lock_path) {
+        Ok(l) => l.package.unwrap_or_else(Vec::new),
+        Err(_) => {
+            abort("Can't find `pyflow.lock`. Run `pyflow install` to create one.");
+            unreachable!()
+        }
+    };
+
+    let mut result = vec![];
+    for name in names {
+        if !lockpacks
+            .iter()
+            .any(|lp| util::compare_names(&lp.name, name))
+        {
+            abort(&format!("{} isn't in `pyflow.lock`", name));
+        }
+    }
+    for lp in &lockpacks {
+        if !names.is_empty() && !names.iter().any(|n| util::compare_names(n, &lp.name)) {
+            continue;
+        }
+        let version = match Version::from_str(&lp.version) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        match dep_resolution::find_health(index, &lp.name, &version) {
+            Ok(h) => result.push(h),
+            Err(e) => util::print_color(
+                &format!("Problem getting data for {}: {}", lp.name, e.details),
+                Color::DarkRed,
+            ),
+        }
+    }
+    result
+}
+
+/// Format a number of days, eg `3 days`, or `2.5 years`.
+fn format_days(days: Option<i64>) -> String {
+    match days {
+        Some(d) if d >= 365 => format!("{:.1} years", d as f32 / 365.),
+        Some(1) => "1 day".into(),
+        Some(d) => format!("{} days", d),
+        None => "unknown".into(),
+    }
+}
+
+/// A latest release older than this suggests a package isn't maintained.
+const STALE_DAYS: i64 = 2 * 365;
+
+/// Read the vulnerabilities `pyflow outdated` found: name, version, and advisory ids.
+fn read_audit(path: &Path) -> Vec<(String, Version, Vec<String>)> {
+    let data = fs::read_to_string(path).unwrap_or_default();
+    data.lines()
+        .filter_map(|l| {
+            let mut parts = l.split(' ');
+            let name = parts.next()?;
+            let version = Version::from_str(parts.next()?).ok()?;
+            let ids = parts.next()?.split(',').map(str::to_owned).collect();
+            Some((name.to_owned(), version, ids))
+        })
+        .collect()
+}
+
+/// Save the vulnerabilities found in packages we checked, keeping those found before in others.
+fn save_audit(path: &Path, health: &[dep_resolution::Health]) {
+    let mut data = String::new();
+    for (name, version, ids) in read_audit(path) {
+        if !health.iter().any(|h| util::compare_names(&h.name, &name)) {
+            data.push_str(&format!(
+                "{} {} {}\n",
+                name,
+                version.to_string2(),
+                ids.join(",")
+            ));
+        }
+    }
+    for h in health.iter().filter(|h| !h.vulnerabilities.is_empty()) {
+        data.push_str(&format!(
+            "{} {} {}\n",
+            h.name,
+            h.locked.to_string2(),
+            h.vulnerabilities.join(",")
+        ));
+    }
+    fs::write(path, data).ok();
+}
+
+fn outdated(
+    lock_path: &Path,
+    pypackages_path: &Path,
+    index: &dep_resolution::Index,
+    names: &[String],
+) {
+    let mut health = find_health(lock_path, index, names);
+    dep_resolution::sort_by_priority(&mut health);
+    if pypackages_path.exists() {
+        save_audit(&pypackages_path.join(AUDIT_FILENAME), &health);
+    }
+    let today = dep_resolution::today();
+
+    let mut up_to_date = 0;
+    for h in &health {
+        let stale = h.latest_age(today).map_or(false, |a| a > STALE_DAYS);
+        if h.locked >= h.latest && h.vulnerabilities.is_empty() && !stale {
+            up_to_date += 1;
+            continue;
+        }
+
+        let mut notes = vec![];
+        if !h.vulnerabilities.is_empty() {
+            notes.push(format!("{} known vulnerabilities", h.vulnerabilities.len()));
+        }
+        if h.locked < h.latest {
+            notes.push(format!("{} behind", format_days(h.days_behind())));
+        }
+        if stale {
+            notes.push(format!(
+                "no releases in {}",
+                format_days(h.latest_age(today))
+            ));
+        }
+        let color = if h.vulnerabilities.is_empty() {
+            Color::DarkYellow
+        } else {
+            Color::DarkRed
+        };
+        println!(
+            "{}{}{} {} → {}: {}",
+            util::fg(color),
+            h.name,
+            util::fg(Color::Reset),
+            h.locked,
+            h.latest,
+            notes.join(", ")
+        );
+    }
+
+    if up_to_date == health.len() {
+        util::print_color("All locked packages are up to date", Color::Green);
+    } else if up_to_date > 0 {
+        println!("{} other packages are up to date", up_to_date);
+    }
+}
+
+fn info(lock_path: &Path, index: &dep_resolution::Index, name: &str) {
+    let h = match find_health(lock_path, index, &[name.to_owned()])
+        .into_iter()
+        .next()
+    {
+        Some(h) => h,
+        None => {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem getting data for {}", name),
+            );
+            unreachable!()
+        }
+    };
+    let today = dep_resolution::today();
+
+    util::print_color(&h.name, Color::Cyan);
+    println!(
+        "Locked: {} (released {})",
+        h.locked,
+        h.locked_uploaded.as_deref().unwrap_or("on an unknown date")
+    );
+    println!(
+        "Latest: {} (released {}, {} ago)",
+        h.latest,
+        h.latest_uploaded.as_deref().unwrap_or("on an unknown date"),
+        format_days(h.latest_age(today))
+    );
+    if h.locked < h.latest {
+        println!("Behind latest by: {}", format_days(h.days_behind()));
+    }
+    if h.latest_age(today).map_or(false, |a| a > STALE_DAYS) {
+        util::print_color(
+            "There haven't been any releases in over 2 years; it may not be maintained",
+            Color::DarkYellow,
+        );
+    }
+    if h.vulnerabilities.is_empty() {
+        util::print_color("No known vulnerabilities", Color::Green);
+    } else {
+        util::print_color(
+            &format!("Known vulnerabilities: {}", h.vulnerabilities.join(", ")),
+            Color::DarkRed,
+        );
+    }
+}
+
+/// We process input commands in a deliberate order, to ensure the required, and only the required
+/// setup steps are accomplished before each.
+fn main() {
+    let cfg_filename = "pyproject.toml";
+    let lock_filename = "pyflow.lock";
+
+    let dirs = paths::Dirs::find();
+    paths::migrate_cache(&dirs);
+    let pyflow_path = dirs.data;
+
+    let dep_cache_path = dirs.cache;
+    let script_env_path = pyflow_path.join("script-envs");
+    let git_path = pyflow_path.join("git");
+    let global_cfg = files::GlobalConfig::load(&pyflow_path.join("config.toml"));
+    let net_settings = net::Settings {
+        proxy: global_cfg.proxy.clone().unwrap_or_default(),
+        timeouts: global_cfg.timeouts.clone().unwrap_or_default(),
+        contact: global_cfg.user_agent_contact.clone(),
+    };
+    py_versions::clean_partial_installs(&pyflow_path);
+
+    // Handle commands that don't involve operating out of a project before one that do, with setup
+    // code in-between.
+    let opt = Opt::from_args();
+    util::set_color_choice(opt.color);
+    // Until we've read `pyproject.toml`, only `PYFLOW_PROMPT_TIMEOUT` sets it.
+    let env_prompt_timeout = env::var("PYFLOW_PROMPT_TIMEOUT").ok();
+    let mut py_settings = py_versions::Settings {
+        arch: opt.arch.as_deref().map(py_versions::parse_arch),
+        alias: opt.py_alias.clone(),
+        mirror: global_cfg.python_mirror.clone(),
+        signing: py_versions::Signing::new(
+            &global_cfg.python_signing_keys.clone().unwrap_or_default(),
+            global_cfg.require_python_signatures,
+        ),
+        migrate: opt.migrate,
+        net: net_settings.clone(),
+        prompt_timeout: util::prompt_timeout(env_prompt_timeout.as_deref(), None),
+        ..Default::default()
+    };
+
+    // 32-bit Python needs `win32` wheels.
+    #[cfg(target_os = "windows")]
+    let os = if py_versions::host_arch(&py_settings) == "x86" {
+        Os::Windows32
+    } else {
+        Os::Windows
+    };
+    #[cfg(target_os = "linux")]
+    let os = Os::Linux;
+    #[cfg(target_os = "macos")]
+    let os = Os::Mac;
+    let temp_root = opt.temp_dir.clone().unwrap_or_else(env::temp_dir);
+
+    let subcmd = match opt.subcmds {
+        Some(sc) => sc,
+        None => {
+            // This branch runs when none of the specified subcommands are used
+            if opt.script.is_empty() || opt.script[0].ends_with("py") {
+                // Nothing's specified, eg `pyflow`, or a script is specified; run `python`.
+                SubCommand::Python {
+                    args: opt.script,
+                    from_file: None,
+                    dry_run: false,
+                }
+            } else {
+                //                println!("ARGS: {:?}", &opt.script);
+                if opt.script[0] == "m" {
+                    //                    println!("HMM");
+                }
+                // A command is specified, eg `pyflow black`
+                SubCommand::Run {
+                    with_env: None,
+                    args: opt.script,
+                }
+            }
+        }
+    };
+
+    // Completion output is read by shells and editors, and lock merges run from git, so don't
+    // prompt or print setup info.
+    match subcmd {
+        SubCommand::CompletePackage { prefix: _ }
+        | SubCommand::Debug { cmd: _ }
+        | SubCommand::Lock { cmd: _ }
+        | SubCommand::Schema { name: _ } => (),
+        _ => bootstrap::first_run(
+            &pyflow_path,
+            &[&dep_cache_path, &script_env_path, &git_path],
+            py_settings.prompt_timeout,
+        ),
+    }
+
+    // Run this before parsing the config.
+    if let SubCommand::Script { mut args } = subcmd {
+        util::guard_active_env(if opt.ignore_active_env {
+            util::ActiveEnvPolicy::Ignore
+        } else {
+            util::ActiveEnvPolicy::Warn
+        });
+        run_script(
+            &script_env_path,
+            &dep_cache_path,
+            os,
+            &mut args,
+            &pyflow_path,
+            &mut py_settings,
+            &temp_root,
+        );
+        return;
+    }
+
+    if let SubCommand::Schema { name } = &subcmd {
+        let names: Vec<&str> = schema::NAMES.iter().map(|(n, _)| *n).collect();
+        match name {
+            Some(n) => match schema::find(n) {
+                Some(schema) => println!("{}", serde_json::to_string_pretty(&schema).unwrap()),
+                None => abort(&format!(
+                    "There's no schema named `{}`. Choose from: {}",
+                    n,
+                    names.join(", ")
+                )),
+            },
+            None => {
+                for (name, description) in schema::NAMES {
+                    println!("{:<10} {}", name, description);
+                }
+            }
+        }
+        return;
+    }
+
+    if let SubCommand::CompletePackage { prefix } = subcmd {
+        if !pyflow_path.exists() && fs::create_dir_all(&pyflow_path).is_err() {
+            abort("Problem creating the Pyflow directory");
+        }
+        let names = dep_resolution::cached_package_names(
+            &net_settings,
+            &pyflow_path.join("package-names.txt"),
+        );
+        for name in dep_resolution::complete_package_name(&names, &prefix) {
+            println!("{}", name);
+        }
+        return;
+    }
+
+    if let SubCommand::Debug {
+        cmd: DebugCommand::Metadata { package },
+    } = subcmd
+    {
+        let mut parts = package.splitn(2, "==");
+        let name = parts.next().unwrap_or_default().trim();
+        let version = parts.next().map(|v| util::fallible_v_parse(v.trim()));
+        dep_resolution::show_metadata(&Default::default(), name, version.as_ref());
+        return;
+    }
+
+    if let SubCommand::Python {
+        args,
+        from_file,
+        dry_run,
+    } = &subcmd
+    {
+        let cmd = args.get(0).map(String::as_str);
+        match cmd {
+            Some("list") => {
+                py_versions::list_installed(&py_settings, &pyflow_path);
+                return;
+            }
+            Some("pin") => {
+                let entered = match args.get(1) {
+                    Some(v) => v,
+                    None => {
+                        abort("Specify the Python version, eg `pyflow python pin 3.11`");
+                        unreachable!()
+                    }
+                };
+                let dir = match &opt.project {
+                    Some(p) => p.clone(),
+                    None => env::current_dir().expect("Can't access current directory"),
+                };
+                py_versions::pin(&dir, entered);
+                return;
+            }
+            Some("shims") => {
+                py_versions::write_shims(&py_settings, &pyflow_path, true);
+                return;
+            }
+            Some("prune") => {
+                py_versions::prune(&py_settings, &pyflow_path, *dry_run);
+                return;
+            }
+            Some("exec") => {
+                let entered = match args.get(1) {
+                    Some(v) => v,
+                    None => {
+                        abort("Specify the Python version, eg `pyflow python exec 3.10 -- app.py`");
+                        unreachable!()
+                    }
+                };
+                let (implementation, entered) = util::Implementation::split_version(entered);
+                py_settings.implementation = implementation;
+                let version = util::fallible_v_parse(entered);
+                let entered_patch = entered.matches('.').count() >= 2;
+                py_versions::exec(
+                    &py_settings,
+                    &pyflow_path,
+                    &version,
+                    entered_patch,
+                    &args[2..],
+                );
+                return;
+            }
+            Some("install") if from_file.is_some() => {
+                let path = from_file.as_ref().unwrap();
+                let version = args.get(1).map(|entered| {
+                    let (implementation, entered) = util::Implementation::split_version(entered);
+                    py_settings.implementation = implementation;
+                    util::fallible_v_parse(entered)
+                });
+                py_versions::install_archive(&py_settings, &pyflow_path, path, version.as_ref());
+                return;
+            }
+            Some("install") | Some("uninstall") => {
+                let entered = match args.get(1) {
+                    Some(v) => v,
+                    None => {
+                        abort(&format!(
+                            "Specify the Python version, eg `pyflow python {} 3.11`",
+                            cmd.unwrap()
+                        ));
+                        unreachable!()
+                    }
+                };
+                // Eg `pyflow python install pypy3.10`.
+                let (implementation, entered) = util::Implementation::split_version(entered);
+                py_settings.implementation = implementation;
+                let version = util::fallible_v_parse(entered);
+                let entered_patch = entered.matches('.').count() >= 2;
+                if cmd == Some("install") {
+                    py_versions::install(&py_settings, &pyflow_path, &version, entered_patch);
+                } else {
+                    py_versions::uninstall(&py_settings, &pyflow_path, &version, entered_patch);
+                }
+                return;
+            }
+            _ => (),
+        }
+    }
+
+    if let SubCommand::Lock {
+        cmd:
+            LockCommand::Merge {
+                ours,
+                theirs,
+                base,
+                output,
+            },
+    } = subcmd
+    {
+        merge_lock_files(
+            &ours,
+            &theirs,
+            &base,
+            &output.unwrap_or_else(|| ours.clone()),
+        );
+        return;
+    }
+
+    if let SubCommand::New { name } = subcmd {
+        if new(&name, py_settings.prompt_timeout).is_err() {
+            abort(
+                "Problem creating the project. This may be due to a permissions problem. \
+                 If on linux, please try again with `sudo`.",
+            );
+        }
+        util::print_color(
+            &format!("Created a new Python project named {}", name),
+            Color::Green,
+        );
+        return;
+    }
+
+    // Commands operate relative to the project root; `--project` specifies it explicitly, and
+    // otherwise we search from the current directory.
+    let start_dir = match &opt.project {
+        Some(p) => {
+            if !p.is_dir() {
+                abort(&format!("Can't find the project directory {:?}", p));
+            }
+            p.clone()
+        }
+        None => env::current_dir().expect("Can't access current directory"),
+    };
+
+    if let SubCommand::Init {} = subcmd {
+        let cfg_path = start_dir.join(cfg_filename);
+        if cfg_path.exists() {
+            abort("pyproject.toml already exists - not overwriting.")
+        }
+
+        let pipfile_path = start_dir.join("Pipfile");
+        let mut cfg = match pipfile_path.exists() {
+            true => Config::from_pipfile(&pipfile_path).unwrap_or_default(),
+            false => Config::default(),
+        };
+
+        cfg.py_version = Some(util::prompt_py_vers(py_settings.prompt_timeout));
+
+        files::parse_req_dot_text(&mut cfg, &start_dir.join("requirements.txt"));
+
+        cfg.write_file(&cfg_path);
+        util::print_color("Created `pyproject.toml`", Color::Green);
+        // Don't return here; let the normal logic create the venv now.
+    }
+
+    if let SubCommand::Migrate { file } = subcmd {
+        migrate(&start_dir, &file, cfg_filename, &temp_root);
+        return;
+    }
+
+    // We need access to the config from here on; throw an error if we can't find it.
+    let proj_root = if opt.project.is_some() {
+        // Don't search parents of an explicitly-specified project.
+        Some(start_dir.clone()).filter(|p| p.join(cfg_filename).exists())
+    } else {
+        util::find_project_root(&start_dir, cfg_filename)
+    };
+
+    if let SubCommand::Debug {
+        cmd: DebugCommand::Bundle { output },
+    } = &subcmd
+    {
+        let proj_path = proj_root.as_ref().map(|r| r.as_path());
+        let py_version = proj_path
+            .and_then(|p| Config::from_file(&p.join(cfg_filename), &net_settings))
+            .and_then(|c| c.py_version)
+            .map(|v| v.to_string2());
+        let files = bundle::collect(
+            &pyflow_path,
+            proj_path,
+            py_version.as_deref(),
+            &[JOURNAL_FILENAME, RESOLUTION_TRACE_FILENAME],
+        );
+        let path = output
+            .clone()
+            .unwrap_or_else(|| bundle::default_path(&start_dir));
+        bundle::create(&path, &files);
+        return;
+    }
+    let cfg_path = if let Some(root) = proj_root {
+        root.join(cfg_filename)
+    } else if let SubCommand::Sync { from: Some(_), .. } = subcmd {
+        // Syncing from another lock file doesn't need a project.
+        start_dir.join(cfg_filename)
+    } else {
+        util::print_color(
+            "To get started, run `pyflow new projname` to create a project folder, or \
+            `pyflow init` to start a project in this folder. For a list of what you can do, run \
+            `pyflow help`.",
+            Color::DarkCyan,
+        );
+        return;
+    };
+
+    // Base pypackages_path and lock_path on the `pyproject.toml` folder.
+    let proj_path = cfg_path.parent().expect("Can't find proj pathw via parent");
+    let pypackages_path = proj_path.join("__pypackages__");
+    let lock_path = &proj_path.join(lock_filename);
+    let approved_path = proj_path.join("pyflow.approved");
+
+    if let SubCommand::Config {
+        cmd: ConfigCommand::Resolve,
+    } = &subcmd
+    {
+        let pyflow = match files::read_pyproject(&cfg_path, &net_settings) {
+            Ok(value) => value
+                .get("tool")
+                .and_then(|t| t.get("pyflow"))
+                .cloned()
+                .unwrap_or_else(|| toml::Value::Table(Default::default())),
+            Err(e) => {
+                abort(&e);
+                unreachable!()
+            }
+        };
+        let mut tool = toml::value::Table::new();
+        tool.insert("pyflow".into(), pyflow);
+        let mut root = toml::value::Table::new();
+        root.insert("tool".into(), toml::Value::Table(tool));
+        print!(
+            "{}",
+            toml::to_string(&root).expect("Problem formatting the config")
+        );
+        return;
+    }
+
+    let mut cfg = Config::from_file(&cfg_path, &net_settings).unwrap_or_default();
+    cfg.populate_path_subreqs(&net_settings);
+    if cfg.py_version.is_none() && cfg.py_constraints.is_empty() {
+        if let Some((implementation, v)) = py_versions::find_pinned(proj_path) {
+            cfg.py_implementation = implementation;
+            cfg.py_version = Some(v);
+        }
+    }
+    py_settings.implementation = cfg.py_implementation;
+    py_settings.variant = cfg.py_variant;
+    py_settings.update_policy = cfg.python_update_policy;
+    py_settings.local_archive = cfg.python_archive.as_ref().map(|a| proj_path.join(a));
+    py_settings.prompt_timeout =
+        util::prompt_timeout(env_prompt_timeout.as_deref(), cfg.prompt_timeout);
+    let mut index = dep_resolution::Index {
+        cache: Some(dep_cache_path.join("index")),
+        net: net_settings.clone(),
+        ..cfg.index()
+    };
+    index.prereleases.allow = opt.pre;
+    if cfg.py_version.is_none() && !cfg.py_constraints.is_empty() {
+        cfg.py_version = Some(py_versions::pick_version(
+            &py_settings,
+            &cfg.py_constraints,
+            &pyflow_path,
+            &pypackages_path,
+        ));
+    }
+    if let SubCommand::Devcontainer {
+        cmd: DevcontainerCommand::Init,
+    } = &subcmd
+    {
+        let name = cfg.name.clone().unwrap_or_else(|| {
+            proj_path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "python".into())
+        });
+        devcontainer::init(proj_path, &name, cfg.py_version.as_ref());
+        return;
+    }
+
+    // `--temp-dir` overrides `temp_dir` in `pyproject.toml`.
+    let temp_root = match (&opt.temp_dir, &cfg.temp_dir) {
+        (None, Some(dir)) => proj_path.join(dir),
+        _ => temp_root,
+    };
 
-    let mut cfg = Config::from_file(&cfg_path).unwrap_or_default();
-    cfg.populate_path_subreqs();
+    util::guard_active_env(if opt.ignore_active_env {
+        util::ActiveEnvPolicy::Ignore
+    } else {
+        cfg.active_env.unwrap_or(util::ActiveEnvPolicy::Warn)
+    });
 
     // Run subcommands that don't require info about the environment.
     match &subcmd {
@@ -1405,7 +5768,277 @@ fn main() {
             // Don't return; now that we've changed the cfg version, let's run the normal flow.
         }
         SubCommand::Clear {} => {
-            clear(&pyflow_path, &dep_cache_path, &script_env_path);
+            clear(
+                &pyflow_path,
+                &dep_cache_path,
+                &script_env_path,
+                py_settings.prompt_timeout,
+            );
+            return;
+        }
+        SubCommand::Env {
+            cmd: EnvCommand::Vars { check, with_env },
+        } => {
+            if let Some(path) = with_env {
+                env_vars::load_env_file(path);
+            }
+            env_vars::show(&cfg.env_vars, *check, proj_path);
+            return;
+        }
+        SubCommand::Env {
+            cmd: EnvCommand::Pack { output },
+        } => {
+            let version = match cfg.py_version {
+                Some(v) => v.to_string_no_patch(),
+                None => {
+                    abort("Set `py_version` in `pyproject.toml` to pack its environment");
+                    unreachable!()
+                }
+            };
+            let name = cfg.name.clone().unwrap_or_else(|| {
+                proj_path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "project".into())
+            });
+            let output = output
+                .clone()
+                .unwrap_or_else(|| env_pack::default_path(proj_path, &name, &version));
+            env_pack::pack(&pypackages_path.join(&version), &pyflow_path, &output);
+            return;
+        }
+        SubCommand::Env {
+            cmd: EnvCommand::Unpack { archive },
+        } => {
+            env_pack::unpack(archive, &pypackages_path, &pyflow_path);
+            return;
+        }
+        SubCommand::Export {
+            format,
+            platform,
+            python,
+            dev,
+            output,
+        } => {
+            let target_os = match platform {
+                Some(p) => Os::from_str(p).unwrap_or_else(|e| {
+                    abort(&e.details);
+                    unreachable!()
+                }),
+                None => os,
+            };
+            let target_py = match python {
+                Some(v) => util::fallible_v_parse(v),
+                None => cfg.py_version.unwrap_or_else(|| {
+                    abort("Specify a Python version with `--python`, or in `pyproject.toml`");
+                    unreachable!()
+                }),
+            };
+            let format = match format {
+                Some(f) => pylock::ExportFormat::from_str(f).unwrap_or_else(|e| {
+                    abort(&e.details);
+                    unreachable!()
+                }),
+                None => pylock::ExportFormat::Requirements,
+            };
+            export(
+                &cfg,
+                &index,
+                &lock_path,
+                target_os,
+                &target_py,
+                *dev,
+                format,
+                &git_path,
+                output.as_deref(),
+            );
+            return;
+        }
+        SubCommand::Fetch {
+            platform,
+            python,
+            dev,
+        } => {
+            let targets: Vec<(String, Os)> = if platform.is_empty() {
+                vec![("this platform".to_owned(), os)]
+            } else {
+                platform
+                    .iter()
+                    .map(|p| {
+                        let target = Os::from_str(p).unwrap_or_else(|e| {
+                            abort(&e.details);
+                            unreachable!()
+                        });
+                        (p.clone(), target)
+                    })
+                    .collect()
+            };
+            let py_versions: Vec<Version> = if python.is_empty() {
+                vec![cfg.py_version.unwrap_or_else(|| {
+                    abort("Specify Python versions with `--python`, or in `pyproject.toml`");
+                    unreachable!()
+                })]
+            } else {
+                python.iter().map(|v| util::fallible_v_parse(v)).collect()
+            };
+            fetch_for_targets(
+                &cfg,
+                &index,
+                &py_settings,
+                lock_path,
+                &dep_cache_path,
+                &targets,
+                &py_versions,
+                *dev,
+            );
+            return;
+        }
+        SubCommand::Status {} => {
+            status(&cfg, proj_path, &pypackages_path, &lock_path, &net_settings);
+            return;
+        }
+        SubCommand::Rename { name, move_package } => {
+            let old = match &cfg.name {
+                Some(n) => n,
+                None => {
+                    abort("`pyproject.toml` doesn't have a `name` to change");
+                    unreachable!()
+                }
+            };
+            rename::rename(
+                proj_path,
+                &cfg_path,
+                lock_path,
+                &pypackages_path,
+                old,
+                name,
+                *move_package,
+            );
+            return;
+        }
+        SubCommand::Package { extras, all: true } => {
+            build_workspace(proj_path, &cfg, extras, &net_settings);
+            return;
+        }
+        SubCommand::Workspace { cmd } => {
+            match cmd {
+                WorkspaceCommand::Graph => show_workspace_graph(proj_path, &cfg, &net_settings),
+                WorkspaceCommand::Changed { since } => {
+                    show_changed_members(proj_path, &cfg, since, &net_settings)
+                }
+            }
+            return;
+        }
+        SubCommand::Python { args, .. } if args.get(0).map(String::as_str) == Some("upgrade") => {
+            upgrade_python(
+                &cfg,
+                &mut py_settings,
+                &pypackages_path,
+                &pyflow_path,
+                &dep_cache_path,
+                &temp_root,
+            );
+            return;
+        }
+        SubCommand::Doctor { rollback } => {
+            doctor(&cfg, &pypackages_path, *rollback);
+            return;
+        }
+        SubCommand::Logs {
+            package,
+            last_failure,
+        } => {
+            match cfg.py_version {
+                Some(v) => build_logs::show(
+                    &pypackages_path.join(v.to_string_no_patch()),
+                    package.as_deref(),
+                    *last_failure,
+                ),
+                None => abort("Set `py_version` in `pyproject.toml` to see its build logs"),
+            }
+            return;
+        }
+        SubCommand::Tree {
+            package,
+            depth,
+            invert,
+        } => {
+            let lockpacks = match read_lock(lock_path) {
+                Ok(l) => l.package.unwrap_or_default(),
+                Err(_) => {
+                    abort("Can't find `pyflow.lock`. Run `pyflow install` to create one.");
+                    unreachable!()
+                }
+            };
+            let direct: Vec<String> = cfg
+                .reqs
+                .iter()
+                .chain(cfg.dev_reqs.iter())
+                .map(|r| r.name.clone())
+                .collect();
+            match tree::render(&lockpacks, &direct, package.as_deref(), *invert, *depth) {
+                Ok(tree) => println!("{}", tree),
+                Err(e) => abort(&e),
+            }
+            return;
+        }
+        SubCommand::Ui {} => {
+            tui::run(proj_path, lock_path);
+            return;
+        }
+        SubCommand::Why { package } => {
+            let lockpacks = match read_lock(lock_path) {
+                Ok(l) => l.package.unwrap_or_default(),
+                Err(_) => {
+                    abort("Can't find `pyflow.lock`. Run `pyflow install` to create one.");
+                    unreachable!()
+                }
+            };
+            let direct: Vec<String> = cfg
+                .reqs
+                .iter()
+                .chain(cfg.dev_reqs.iter())
+                .map(|r| r.name.clone())
+                .collect();
+            match tree::why(&lockpacks, &direct, package) {
+                Ok(chains) => println!("{}", chains),
+                Err(e) => abort(&e),
+            }
+            return;
+        }
+        SubCommand::Outdated { packages } => {
+            outdated(&lock_path, &pypackages_path, &index, packages);
+            return;
+        }
+        SubCommand::Info { package } => {
+            info(&lock_path, &index, package);
+            return;
+        }
+        SubCommand::Check {
+            baseline,
+            stubs,
+            fix,
+        } => {
+            if baseline.is_none() && !stubs {
+                abort("Specify what to check, eg `--baseline <url>` or `--stubs`");
+            }
+            if let Some(b) = baseline {
+                check_baseline(&index.net, &lock_path, b);
+            }
+            if *stubs {
+                check_stubs(&cfg, &index, proj_path, &cfg_path, &pyflow_path, *fix);
+            }
+            return;
+        }
+        SubCommand::Approve { packages } => {
+            approve(&approved_path, packages);
+            if !cfg.require_approval {
+                util::print_color(
+                    "Note: approvals are only checked when `require_approval = true` is set in \
+                     `pyproject.toml`",
+                    Color::DarkYellow,
+                );
+            }
             return;
         }
         SubCommand::List => {
@@ -1423,10 +6056,18 @@ fn main() {
         _ => (),
     }
 
-    let cfg_vers = if let Some(v) = cfg.py_version {
+    let cfg_vers = if let SubCommand::Sync {
+        python: Some(v), ..
+    } = &subcmd
+    {
+        util::fallible_v_parse(v)
+    } else if let Some(v) = cfg.py_version {
         v
+    } else if let SubCommand::Sync { from: Some(_), .. } = subcmd {
+        abort("Specify a Python version with `--python`, or in `pyproject.toml`");
+        unreachable!()
     } else {
-        let specified = util::prompt_py_vers();
+        let specified = util::prompt_py_vers(py_settings.prompt_timeout);
 
         if !cfg_path.exists() {
             cfg.write_file(&cfg_path);
@@ -1437,24 +6078,82 @@ fn main() {
     };
 
     // Check for environments. Create one if none exist. Set `vers_path`.
-    let (vers_path, py_vers) =
-        util::find_or_create_venv(&cfg_vers, &pypackages_path, &pyflow_path, &dep_cache_path);
+    let (vers_path, py_vers) = util::find_or_create_venv(
+        &mut py_settings,
+        &cfg_vers,
+        &cfg.py_constraints,
+        &pypackages_path,
+        &pyflow_path,
+        &dep_cache_path,
+        &temp_root,
+    );
+
+    if let Some(requires) = &cfg.python_requires {
+        if !dep_resolution::supports_python(Some(requires), &py_vers) {
+            warnings::warn(
+                &cfg.warnings,
+                warnings::WarningCode::PythonRequires,
+                None,
+                &format!(
+                    "This environment uses Python {}, but `python_requires` is \"{}\". Change \
+                     it with `pyflow switch`.",
+                    py_vers.to_string_no_patch(),
+                    requires
+                ),
+            );
+        }
+    }
 
     let paths = util::Paths {
         bin: util::find_bin_path(&vers_path),
         lib: vers_path.join("lib"),
         entry_pt: vers_path.join("bin"),
         cache: dep_cache_path,
+        temp: temp_root,
     };
 
     // Add all path reqs to the PYTHONPATH; this is the way we make these packages accessible when
     // running `pyflow`.
+    // Editable ones are too: `lib` isn't a site directory, so Python doesn't read their `.pth`
+    // files from it.
     let mut pythonpath = vec![paths.lib.clone()];
-    for r in cfg.reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
-    }
-    for r in cfg.dev_reqs.iter().filter(|r| r.path.is_some()) {
-        pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
+    pythonpath.append(&mut path_reqs_to_import(&cfg.reqs, proj_path, &index.net));
+    pythonpath.append(&mut path_reqs_to_import(
+        &cfg.dev_reqs,
+        proj_path,
+        &index.net,
+    ));
+
+    let build_options = match &subcmd {
+        SubCommand::Sync {
+            build_jobs,
+            keep_going,
+            ..
+        } => install::BuildOptions {
+            jobs: (*build_jobs).max(1),
+            keep_going: *keep_going,
+        },
+        _ => install::BuildOptions::default(),
+    };
+
+    if let SubCommand::Sync {
+        from: Some(from),
+        explain_markers,
+        ..
+    } = &subcmd
+    {
+        sync_from(
+            &paths,
+            &index,
+            &py_settings,
+            from,
+            os,
+            &py_vers,
+            *explain_markers,
+            build_options,
+        );
+        util::print_color("Installation complete", Color::Green);
+        return;
     }
 
     let mut found_lock = false;
@@ -1468,7 +6167,36 @@ fn main() {
 
     let lockpacks = lock.package.unwrap_or_else(Vec::new);
 
-    sync(
+    let constraint_files: Vec<PathBuf> =
+        cfg.constraints.iter().map(|p| proj_path.join(p)).collect();
+
+    let mut policy = SyncPolicy {
+        internal_prefixes: cfg.internal_prefixes.clone(),
+        approved: if cfg.require_approval {
+            Some(read_approved(&approved_path))
+        } else {
+            None
+        },
+        lock_mode: if opt.frozen {
+            LockMode::Frozen
+        } else if opt.locked {
+            LockMode::Locked
+        } else {
+            LockMode::Update
+        },
+        rules: cfg.policy.load(proj_path).unwrap_or_else(|e| {
+            abort(&e);
+            unreachable!()
+        }),
+        index: dep_resolution::Index {
+            constraints: read_constraint_files(&constraint_files),
+            ..index.clone()
+        },
+        python: py_settings.clone(),
+        builds: build_options,
+    };
+
+    if sync(
         &paths,
         &lockpacks,
         &cfg.reqs,
@@ -1477,7 +6205,11 @@ fn main() {
         os,
         &py_vers,
         &lock_path,
-    );
+        &git_path,
+        &policy,
+    ) {
+        write_sync_exports(&index, &cfg_path, &lock_path, os, &py_vers, &git_path);
+    }
 
     // Now handle subcommands that require info about the environment
     match subcmd {
@@ -1486,7 +6218,24 @@ fn main() {
         // We use data from three sources: `pyproject.toml`, `pyflow.lock`, and
         // the currently-installed packages, found by crawling metadata in the `lib` path.
         // See the readme section `How installation and locking work` for details.
-        SubCommand::Install { packages, dev } => {
+        SubCommand::Install {
+            packages,
+            dev,
+            constraints,
+        } => {
+            if !packages.is_empty() && policy.lock_mode != LockMode::Update {
+                abort(
+                    "Can't add packages with `--locked` or `--frozen`, since the lock would change",
+                );
+            }
+            if !constraints.is_empty() {
+                let files: Vec<PathBuf> = constraint_files
+                    .iter()
+                    .chain(constraints.iter())
+                    .cloned()
+                    .collect();
+                policy.index.constraints = read_constraint_files(&files);
+            }
             if !cfg_path.exists() {
                 cfg.write_file(&cfg_path);
             }
@@ -1495,59 +6244,15 @@ fn main() {
                 util::print_color("Found lockfile", Color::Green);
             }
 
+            check_typosquats(&packages, &cfg, py_settings.prompt_timeout);
+
             // Merge reqs added via cli with those in `pyproject.toml`.
-            let (updated_reqs, up_dev_reqs) = util::merge_reqs(&packages, dev, &cfg, &cfg_path);
+            let (updated_reqs, up_dev_reqs) =
+                util::merge_reqs(&packages, dev, &cfg, &policy.index, &cfg_path);
 
             let dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs);
 
-            // git_reqs is used to store requirements from packages installed via git.
-            let mut git_reqs = vec![]; // For path reqs too.
-            let mut git_reqs_dev = vec![];
-            for req in updated_reqs.iter().filter(|r| r.git.is_some()) {
-                // todo: as_ref() would be better than clone, if we can get it working.
-                let mut metadata = install::download_and_install_git(
-                    &req.name,
-                    //                    util::GitPath::Git(req.git.clone().unwrap()),
-                    &req.git.clone().unwrap(),
-                    &git_path,
-                    &paths,
-                );
-
-                git_reqs.append(&mut metadata.requires_dist);
-            }
-
-            // todo: lots of DRY between reqs and dev reqs
-            for req in up_dev_reqs.iter().filter(|r| r.git.is_some()) {
-                let mut metadata = install::download_and_install_git(
-                    &req.name,
-                    //                    util::GitPath::Git(req.git.clone().unwrap()),
-                    &req.git.clone().unwrap(),
-                    &git_path,
-                    &paths,
-                );
-
-                git_reqs_dev.append(&mut metadata.requires_dist);
-            }
-
-            // We don't pass the git requirement itself, since we've directly installed it,
-            // but we do pass its requirements.
-            let mut updated_reqs: Vec<Req> = updated_reqs
-                .into_iter()
-                .filter(|r| r.git.is_none() && r.path.is_none())
-                .collect();
-            let mut up_dev_reqs: Vec<Req> = up_dev_reqs
-                .into_iter()
-                .filter(|r| r.git.is_none() && r.path.is_none())
-                .collect();
-
-            for r in git_reqs {
-                updated_reqs.push(r);
-            }
-            for r in git_reqs_dev {
-                up_dev_reqs.push(r);
-            }
-
-            sync(
+            if sync(
                 &paths,
                 &lockpacks,
                 &updated_reqs,
@@ -1556,11 +6261,41 @@ fn main() {
                 os,
                 &py_vers,
                 &lock_path,
-            );
+                &git_path,
+                &policy,
+            ) {
+                write_sync_exports(&index, &cfg_path, &lock_path, os, &py_vers, &git_path);
+            }
+            util::print_color("Installation complete", Color::Green);
+        }
+
+        SubCommand::Sync {
+            explain_markers: explain,
+            ..
+        } => {
+            if explain {
+                let listed: Vec<(String, String, Option<String>)> = read_lock(&lock_path)
+                    .ok()
+                    .and_then(|l| l.package)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|lp| (lp.name, lp.version, lp.markers))
+                    .collect();
+                print!(
+                    "{}",
+                    explain_markers(
+                        &listed,
+                        &venv_marker_env(&paths, os, &py_vers, py_settings.implementation)
+                    )
+                );
+            }
             util::print_color("Installation complete", Color::Green);
         }
 
         SubCommand::Uninstall { packages } => {
+            if policy.lock_mode != LockMode::Update {
+                abort("Can't remove packages with `--locked` or `--frozen`, since the lock would change");
+            }
             // todo: uninstall dev?
             // Remove dependencies specified in the CLI from the config, then lock and sync.
 
@@ -1582,7 +6317,7 @@ fn main() {
                 .filter(|req| !removed_reqs.contains(&req.name))
                 .collect();
 
-            sync(
+            if sync(
                 &paths,
                 &lockpacks,
                 &updated_reqs,
@@ -1591,16 +6326,20 @@ fn main() {
                 os,
                 &py_vers,
                 &lock_path,
-            );
+                &git_path,
+                &policy,
+            ) {
+                write_sync_exports(&index, &cfg_path, &lock_path, os, &py_vers, &git_path);
+            }
             util::print_color("Uninstall complete", Color::Green);
         }
 
-        SubCommand::Python { args } => {
+        SubCommand::Python { args, .. } => {
             if commands::run_python(&paths.bin, &pythonpath, &args).is_err() {
                 abort("Problem running Python");
             }
         }
-        SubCommand::Package { extras } => {
+        SubCommand::Package { extras, all: _ } => {
             sync(
                 &paths,
                 &lockpacks,
@@ -1610,13 +6349,104 @@ fn main() {
                 os,
                 &py_vers,
                 &lock_path,
+                &git_path,
+                &policy,
             );
 
-            build::build(&lockpacks, &paths, &cfg, &extras)
+            build::build(&lockpacks, &paths, &cfg, &lock_path, &extras, &net_settings)
         }
         SubCommand::Publish {} => build::publish(&paths.bin, &cfg),
-        SubCommand::Run { args } => {
-            run_cli_tool(&paths.lib, &paths.bin, &vers_path, &cfg, args);
+        SubCommand::Test {
+            args,
+            coverage,
+            fail_under,
+            python,
+        } => {
+            let dont_uninstall = util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs);
+            let cache_path = paths.cache.clone();
+            let temp_path = paths.temp.clone();
+            let mut envs = vec![];
+            if python.is_empty() {
+                envs.push(TestEnv {
+                    paths,
+                    vers_path,
+                    py_vers,
+                });
+            }
+            for vers in &python {
+                let vers = util::fallible_v_parse(vers);
+                let mut env_policy = policy.clone();
+                let (env_vers_path, env_py_vers) = util::find_or_create_venv(
+                    &mut env_policy.python,
+                    &vers,
+                    &[],
+                    &pypackages_path,
+                    &pyflow_path,
+                    &cache_path,
+                    &temp_path,
+                );
+                let env_paths = util::Paths {
+                    bin: util::find_bin_path(&env_vers_path),
+                    lib: env_vers_path.join("lib"),
+                    entry_pt: env_vers_path.join("bin"),
+                    cache: cache_path.clone(),
+                    temp: temp_path.clone(),
+                };
+                // Dependencies can resolve differently for each Python version, so each
+                // environment has its own lock, starting from the project's, which is left as
+                // it is. With `--locked` or `--frozen`, the project's lock is used as-is.
+                let env_lock_path = env_vers_path.join(lock_filename);
+                let env_lockpacks = match read_lock(&env_lock_path) {
+                    Ok(l) if policy.lock_mode == LockMode::Update => l.package.unwrap_or_default(),
+                    _ => read_lock(lock_path)
+                        .ok()
+                        .and_then(|l| l.package)
+                        .unwrap_or_default(),
+                };
+                sync(
+                    &env_paths,
+                    &env_lockpacks,
+                    &cfg.reqs,
+                    &cfg.dev_reqs,
+                    &dont_uninstall,
+                    os,
+                    &env_py_vers,
+                    &env_lock_path,
+                    &git_path,
+                    &env_policy,
+                );
+                envs.push(TestEnv {
+                    paths: env_paths,
+                    vers_path: env_vers_path,
+                    py_vers: env_py_vers,
+                });
+            }
+            run_tests(&envs, &args, coverage, fail_under, os);
+        }
+        SubCommand::Lint { args } => {
+            let signals = dev_tools::find_signals(proj_path, &dep_names(&cfg));
+            let tool = dev_tools::choose_linter(signals);
+            run_dev_tool(tool, args, &paths, &vers_path, os, &py_vers);
+        }
+        SubCommand::FmtPy { args } => {
+            let signals = dev_tools::find_signals(proj_path, &dep_names(&cfg));
+            let tool = dev_tools::choose_formatter(signals);
+            run_dev_tool(tool, args, &paths, &vers_path, os, &py_vers);
+        }
+        SubCommand::Run { with_env, args } => {
+            if let Some(path) = with_env {
+                env_vars::load_env_file(&path);
+            }
+            run_cli_tool(
+                &paths.lib,
+                &paths.bin,
+                &vers_path,
+                proj_path,
+                &cfg,
+                os,
+                args,
+                global_cfg.allow_env_commands.unwrap_or(false),
+            );
         }
         //        SubCommand::M { args } => {
         //            run_cli_tool(&paths.lib, &paths.bin, &vers_path, &cfg, args);
@@ -1634,4 +6464,483 @@ fn main() {
 }
 
 #[cfg(test)]
-pub mod tests {}
+pub mod tests {
+    use super::*;
+
+    fn lockpack(id: u32, name: &str) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.into(),
+            version: "1.0.0".into(),
+            source: None,
+            dependencies: None,
+            rename: None,
+            markers: None,
+            extras: None,
+        }
+    }
+
+    #[test]
+    fn export_for_target() {
+        let mut pywin32 = lockpack(2, "pywin32");
+        pywin32.markers = Some("sys_platform == \"win32\"".into());
+        let mut colorama = lockpack(3, "colorama");
+        colorama.markers = Some("python_version < \"3.5.0\"".into());
+        let mut click = lockpack(1, "click");
+        click.dependencies = Some(vec![
+            "pywin32 1.0.0 pypi+https://pypi.org/pypi/pywin32/1.0.0/json".into(),
+            "colorama 1.0.0 pypi+https://pypi.org/pypi/colorama/1.0.0/json".into(),
+        ]);
+        let unrelated = lockpack(4, "unrelated");
+        let lockpacks = vec![click, pywin32, colorama, unrelated];
+        let reqs = vec![Req::new("click".into(), vec![])];
+
+        let names = |os, py_vers| -> Vec<String> {
+            packages_for_target(
+                &lockpacks,
+                &reqs,
+                os,
+                &py_vers,
+                util::Implementation::CPython,
+            )
+            .iter()
+            .map(|lp| lp.name.clone())
+            .collect()
+        };
+
+        assert_eq!(names(Os::Linux, Version::new_short(3, 8)), vec!["click"]);
+        assert_eq!(
+            names(Os::Windows, Version::new_short(3, 4)),
+            vec!["click", "colorama", "pywin32"]
+        );
+    }
+
+    #[test]
+    fn export_with_sources() {
+        let cfg = Config {
+            reqs: vec![
+                Req::new("acme-utils".into(), vec![]),
+                Req::new("click".into(), vec![]),
+            ],
+            sources: vec![dep_resolution::Source {
+                name: "internal".into(),
+                url: "https://pypi.acme.example/".into(),
+                packages: vec!["acme-*".into()],
+            }],
+            ..Config::default()
+        };
+        let lockpacks = vec![lockpack(1, "acme-utils"), lockpack(2, "click")];
+
+        assert_eq!(
+            render_export(
+                &cfg,
+                &cfg.index(),
+                &lockpacks,
+                Os::Linux,
+                &Version::new_short(3, 8),
+                false,
+                pylock::ExportFormat::Requirements,
+                Path::new("."),
+            ),
+            "--index-url https://pypi.org/simple\n\
+             --extra-index-url https://pypi.acme.example/simple\n\
+             acme-utils==1.0.0  # index: https://pypi.acme.example/simple\n\
+             click==1.0.0\n"
+        );
+    }
+
+    #[test]
+    fn editable_path_reqs() {
+        let tmp = util::TempDir::new("editable");
+        let dir = tmp.path();
+        let lib_path = dir
+            .join("proj")
+            .join("__pypackages__")
+            .join("3.8")
+            .join("lib");
+        let mylib = dir.join("mylib");
+        fs::create_dir_all(mylib.join("src").join("mylib")).unwrap();
+        fs::create_dir_all(&lib_path).unwrap();
+        fs::write(
+            mylib.join("pyproject.toml"),
+            "[tool.pyflow]\nname = \"mylib\"\nversion = \"0.2.0\"\n",
+        )
+        .unwrap();
+        let paths = util::Paths {
+            bin: dir.join("bin"),
+            lib: lib_path.clone(),
+            entry_pt: dir.join("entry"),
+            cache: dir.join("cache"),
+            temp: dir.join("tmp"),
+        };
+        let proj_path = dir.join("proj");
+        let mut req = Req::new("mylib".into(), vec![]);
+        req.path = Some("../mylib".into());
+        req.editable = true;
+
+        sync_path_reqs(
+            &[req.clone()],
+            &[],
+            &proj_path,
+            &paths,
+            &net::Settings::default(),
+        );
+        let installed = util::find_installed(&lib_path);
+        assert_eq!(installed[0].0, "mylib");
+        assert_eq!(installed[0].1, Version::new(0, 2, 0));
+        let pth = fs::read_to_string(lib_path.join("__editable__.mylib-0.2.0.pth")).unwrap();
+        assert!(pth.trim_end().ends_with("src"));
+        assert_eq!(
+            path_reqs_to_import(&[req.clone()], &proj_path, &net::Settings::default()),
+            vec![proj_path.join("../mylib").join("src")]
+        );
+
+        // Without `editable`, and without a build system, it's imported from its source instead.
+        req.editable = false;
+        sync_path_reqs(
+            &[req.clone()],
+            &installed,
+            &proj_path,
+            &paths,
+            &net::Settings::default(),
+        );
+        assert!(util::find_installed(&lib_path).is_empty());
+        assert!(!lib_path.join("__editable__.mylib-0.2.0.pth").exists());
+        assert_eq!(
+            path_reqs_to_import(&[req], &proj_path, &net::Settings::default()).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn sources_from_config() {
+        let tmp = util::TempDir::new("sources");
+        let dir = tmp.path();
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[tool.pyflow.sources]\n\
+             internal = \"https://pypi.acme.example\"\n\
+             vendor = { url = \"https://pypi.vendor.example\", packages = [\"vendor-*\"] }\n\
+             [tool.pyflow.internal_prefixes]\n\
+             \"acme-auth-*\" = \"internal\"\n\
+             [tool.pyflow.dependencies]\n\
+             requests = \"^2.31\"\n\
+             \"acme-utils[cli]\" = { version = \"^1.2\", source = \"internal\" }\n\
+             [tool.pyflow.dev-dependencies]\n\
+             acme-testing = { version = \"*\", source = \"internal\" }\n",
+        )
+        .unwrap();
+
+        let mut cfg =
+            Config::from_file(&dir.join("pyproject.toml"), &net::Settings::default()).unwrap();
+        cfg.sources[0].packages.sort();
+        assert_eq!(
+            cfg.sources,
+            vec![
+                dep_resolution::Source {
+                    name: "internal".into(),
+                    url: "https://pypi.acme.example".into(),
+                    packages: vec![
+                        "acme-auth-*".into(),
+                        "acme-testing".into(),
+                        "acme-utils".into()
+                    ],
+                },
+                dep_resolution::Source {
+                    name: "vendor".into(),
+                    url: "https://pypi.vendor.example".into(),
+                    packages: vec!["vendor-*".into()],
+                },
+            ]
+        );
+        assert_eq!(cfg.reqs.len(), 2);
+        assert_eq!(cfg.internal_prefixes, vec!["acme-auth-*".to_owned()]);
+        assert_eq!(
+            cfg.index().url("acme-auth-sso"),
+            "https://pypi.acme.example"
+        );
+    }
+
+    #[test]
+    fn releases_from_cache() {
+        let tmp = util::TempDir::new("cached-releases");
+        let dir = tmp.path();
+        for name in &[
+            "six-1.12.0-py2.py3-none-any.whl",
+            "six-1.12.0.tar.gz",
+            "six-1.13.0-py2.py3-none-any.whl",
+            "sixer-1.12.0.tar.gz",
+        ] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let mut found: Vec<(String, String)> = cached_releases(dir, "Six", &Version::new(1, 12, 0))
+            .into_iter()
+            .map(|r| (r.filename, r.packagetype))
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "six-1.12.0-py2.py3-none-any.whl".into(),
+                    "bdist_wheel".into()
+                ),
+                ("six-1.12.0.tar.gz".into(), "sdist".into()),
+            ]
+        );
+        assert!(cached_releases(dir, "six", &Version::new(2, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn sync_exports_regenerated() {
+        let tmp = util::TempDir::new("sync-exports");
+        let dir = tmp.path();
+        let cfg_path = dir.join("pyproject.toml");
+        fs::write(
+            &cfg_path,
+            r#"[tool.pyflow]
+name = "demo"
+py_version = "3.8"
+
+[tool.pyflow.dependencies]
+click = "^1.0"
+
+[tool.pyflow.dev-dependencies]
+pytest = "^1.0"
+
+[tool.pyflow.sync-exports]
+"requirements.txt" = {}
+"requirements-dev.txt" = { dev = true }
+"#,
+        )
+        .unwrap();
+        let lock_path = dir.join("pyflow.lock");
+        let lock = Lock {
+            package: Some(vec![lockpack(1, "click"), lockpack(2, "pytest")]),
+            metadata: HashMap::new(),
+        };
+        write_lock(&lock_path, &lock).unwrap();
+
+        write_sync_exports(
+            &dep_resolution::Index::default(),
+            &cfg_path,
+            &lock_path,
+            Os::Linux,
+            &Version::new_short(3, 8),
+            dir,
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("requirements.txt")).unwrap(),
+            "click==1.0.0\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("requirements-dev.txt")).unwrap(),
+            "click==1.0.0\npytest==1.0.0\n"
+        );
+
+        let spec = |format: Option<&str>| files::SyncExportComponent {
+            format: format.map(str::to_owned),
+            dev: None,
+            platform: Some("windows".into()),
+            python: None,
+        };
+        let export = SyncExport::from_spec("locks/pylock.toml".into(), spec(None));
+        assert_eq!(export.format, pylock::ExportFormat::Pylock);
+        assert_eq!(export.platform, Some(Os::Windows));
+        assert_eq!(
+            SyncExport::from_spec("reqs.txt".into(), spec(None)).format,
+            pylock::ExportFormat::Requirements
+        );
+        assert_eq!(
+            SyncExport::from_spec("deps.toml".into(), spec(Some("pylock"))).format,
+            pylock::ExportFormat::Pylock
+        );
+    }
+
+    #[test]
+    fn explain_marker_outcomes() {
+        let package = |name: &str, markers: Option<&str>| {
+            (
+                name.to_owned(),
+                "1.0.0".to_owned(),
+                markers.map(str::to_owned),
+            )
+        };
+        let packages = vec![
+            package("click", None),
+            package("pywin32", Some("sys_platform == \"win32\"")),
+            package("colorama", Some("python_version >= \"3.5\"")),
+            package("odd", Some("not a marker")),
+        ];
+
+        let env = MarkerEnv::new(
+            Os::Linux,
+            &Version::new_short(3, 8),
+            util::Implementation::CPython,
+        );
+        assert_eq!(
+            explain_markers(&packages, &env),
+            "Environment markers, evaluated for Linux and Python 3.8.0:\n\
+             \x20 skipped    pywin32 1.0.0: sys_platform == \"win32\"\n\
+             \x20 installed  colorama 1.0.0: python_version >= \"3.5\"\n\
+             \x20 installed  odd 1.0.0: not a marker (we can't evaluate these, so it's installed)\n\
+             1 other package has no markers, so it's installed everywhere\n"
+        );
+        assert_eq!(
+            explain_markers(&[package("click", None), package("six", None)], &env),
+            "None of the locked packages have environment markers, so all 2 are installed\n"
+        );
+    }
+
+    #[test]
+    fn count_direct_and_transitive() {
+        let lockpacks = vec![
+            lockpack(1, "Requests"),
+            lockpack(2, "urllib3"),
+            lockpack(3, "idna"),
+        ];
+        let reqs = vec![Req::new("requests".into(), vec![])];
+
+        assert_eq!(count_locked(&lockpacks, &reqs), (1, 2));
+    }
+
+    #[test]
+    fn unapproved_packages() {
+        let lockpacks = vec![lockpack(1, "requests"), lockpack(2, "urllib3")];
+        let names: Vec<String> = vec![
+            "Requests".into(),
+            "urllib3".into(),
+            "numpy".into(),
+            "reqeusts".into(),
+        ];
+        let approved = vec!["NumPy".to_owned()];
+
+        assert_eq!(
+            find_unapproved(&names, &lockpacks, &approved),
+            vec!["reqeusts".to_owned()]
+        );
+        // With `--frozen`, the lock doesn't approve its own packages.
+        assert_eq!(
+            find_unapproved(&names, &[], &approved),
+            vec![
+                "Requests".to_owned(),
+                "urllib3".to_owned(),
+                "reqeusts".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn lock_diff() {
+        let mut local = vec![
+            lockpack(1, "requests"),
+            lockpack(2, "urllib3"),
+            lockpack(3, "leftpad"),
+        ];
+        local[1].version = "1.25.8".into();
+        let baseline = vec![
+            lockpack(1, "Requests"),
+            lockpack(2, "urllib3"),
+            lockpack(3, "idna"),
+        ];
+
+        assert_eq!(
+            diff_locks(&local, &baseline),
+            LockDiff {
+                added: vec!["leftpad".into()],
+                changed: vec![("urllib3".into(), "1.25.8".into(), "1.0.0".into())],
+                removed: vec!["idna".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn unlocked_reqs() {
+        let package = |name: &str, version| Package {
+            id: 0,
+            parent: 0,
+            name: name.into(),
+            version,
+            deps: vec![],
+            rename: Rename::No,
+            markers: None,
+            extras: vec![],
+        };
+        let locked = vec![
+            package("Requests", Version::new(2, 22, 0)),
+            package("urllib3", Version::new(1, 25, 8)),
+        ];
+        let reqs = vec![
+            Req::new("requests".into(), vec![]),
+            Req::new(
+                "urllib3".into(),
+                vec![Constraint::new(ReqType::Gte, Version::new(1, 26, 0))],
+            ),
+            Req::new("numpy".into(), vec![]),
+        ];
+
+        assert_eq!(find_unlocked(&reqs, &locked), vec!["urllib3", "numpy"]);
+    }
+
+    #[test]
+    fn stub_changes() {
+        let reqs = vec![
+            Req::new("requests".into(), vec![]),
+            Req::new("PyYAML".into(), vec![]),
+            Req::new("numpy".into(), vec![]),
+        ];
+        let dev_reqs = vec![
+            Req::new("mypy".into(), vec![]),
+            Req::new("types-PyYAML".into(), vec![]),
+            Req::new("types-six".into(), vec![]),
+        ];
+        let index_names: Vec<String> = vec![
+            "types-requests".into(),
+            "types-PyYAML".into(),
+            "types-six".into(),
+            "numpy".into(),
+        ];
+
+        assert_eq!(
+            find_stub_changes(&reqs, &dev_reqs, &index_names),
+            (vec!["types-requests".into()], vec!["types-six".into()])
+        );
+    }
+
+    #[test]
+    fn sync_delta() {
+        let requests_new = ("requests".to_owned(), Version::new(2, 22, 0));
+        let numpy = ("numpy".to_owned(), Version::new(1, 17, 2));
+        let requests_old = ("Requests".to_owned(), Version::new(2, 21, 0));
+        let six = ("six".to_owned(), Version::new(1, 12, 0));
+
+        assert_eq!(
+            find_sync_delta(&[&requests_new, &numpy], &[&requests_old, &six]),
+            SyncDelta {
+                added: vec![numpy.clone()],
+                upgraded: vec![(
+                    "requests".into(),
+                    Version::new(2, 21, 0),
+                    Version::new(2, 22, 0)
+                )],
+                removed: vec![six.clone()],
+            }
+        );
+    }
+
+    #[test]
+    fn sync_report() {
+        let report = SyncReport {
+            cache_hits: 3,
+            cache_misses: 1,
+            yanked: vec![(
+                "urllib3".into(),
+                Version::new(1, 25, 0),
+                "broken on Python 2".into(),
+            )],
+        };
+        assert_eq!(SyncReport::from_text(&report.to_text()), report);
+        assert_eq!(SyncReport::from_text("not a report"), SyncReport::default());
+    }
+}
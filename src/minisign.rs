@@ -0,0 +1,213 @@
+//! Verifying [minisign](https://jedisct1.github.io/minisign/) signatures, eg on Python archives.
+//! A checksum only shows an archive is the one its host published; a signature shows it came
+//! from someone holding a key we trust, even if the host, or the network, is compromised.
+
+use data_encoding::{BASE64, HEXUPPER};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::{fs, io::Read, path::Path};
+
+/// Signature algorithms: `Ed` signs the file itself; `ED`, the default since minisign 0.10,
+/// signs its BLAKE2b-512 hash.
+const ALG_LEGACY: &[u8] = b"Ed";
+const ALG_HASHED: &[u8] = b"ED";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    id: [u8; 8],
+    key: [u8; 32],
+}
+
+impl PublicKey {
+    /// Decode a key, eg `RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3`. That's its
+    /// `.pub` file's second line; the whole file works too.
+    pub fn decode(text: &str) -> Result<Self, String> {
+        let line = text
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with("untrusted comment:"))
+            .unwrap_or_default();
+        let bytes = BASE64
+            .decode(line.as_bytes())
+            .map_err(|_| format!("`{}` isn't a minisign public key", line))?;
+        if bytes.len() != 42 || &bytes[..2] != ALG_LEGACY {
+            return Err(format!("`{}` isn't a minisign public key", line));
+        }
+
+        let mut result = Self {
+            id: [0; 8],
+            key: [0; 32],
+        };
+        result.id.copy_from_slice(&bytes[2..10]);
+        result.key.copy_from_slice(&bytes[10..]);
+        Ok(result)
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ED25519, &self.key[..])
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+/// A parsed `.minisig` file.
+struct Signature {
+    alg: Vec<u8>,
+    key_id: [u8; 8],
+    signature: Vec<u8>,
+    trusted_comment: String,
+    global_signature: Vec<u8>,
+}
+
+fn parse_signature(text: &str) -> Result<Signature, String> {
+    let problem = || "The signature file isn't in minisign's format".to_owned();
+    let lines: Vec<&str> = text.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let (sig_line, trusted_comment, global_line) = match lines.as_slice() {
+        [_untrusted, sig, trusted, global, ..] => (
+            sig,
+            trusted
+                .strip_prefix("trusted comment: ")
+                .ok_or_else(problem)?,
+            global,
+        ),
+        _ => return Err(problem()),
+    };
+
+    let sig_bytes = BASE64.decode(sig_line.as_bytes()).map_err(|_| problem())?;
+    let global_signature = BASE64
+        .decode(global_line.as_bytes())
+        .map_err(|_| problem())?;
+    if sig_bytes.len() != 74 || global_signature.len() != 64 {
+        return Err(problem());
+    }
+
+    let mut key_id = [0; 8];
+    key_id.copy_from_slice(&sig_bytes[2..10]);
+    Ok(Signature {
+        alg: sig_bytes[..2].to_vec(),
+        key_id,
+        signature: sig_bytes[10..].to_vec(),
+        trusted_comment: trusted_comment.to_owned(),
+        global_signature,
+    })
+}
+
+fn blake2b_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut file =
+        fs::File::open(path).map_err(|e| format!("Problem opening {:?}: {}", path, e))?;
+    let mut state = blake2b_simd::State::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                state.update(&buf[..n]);
+            }
+            Err(e) => return Err(format!("Problem reading {:?}: {}", path, e)),
+        }
+    }
+    Ok(state.finalize().as_bytes().to_vec())
+}
+
+/// Check a file's signature, made by one of `keys`. Returns the signature's trusted comment,
+/// eg `timestamp:1699999999	file:python-3.12.7.tar.gz`.
+pub fn verify(path: &Path, signature: &str, keys: &[PublicKey]) -> Result<String, String> {
+    let sig = parse_signature(signature)?;
+    let key = match keys.iter().find(|k| k.id == sig.key_id) {
+        Some(k) => k,
+        None => {
+            // Minisign shows key ids in little-endian hex.
+            let mut id = sig.key_id;
+            id.reverse();
+            return Err(format!(
+                "It's signed with key {}, which isn't trusted",
+                HEXUPPER.encode(&id)
+            ));
+        }
+    };
+
+    let message = if sig.alg == ALG_HASHED {
+        blake2b_file(path)?
+    } else if sig.alg == ALG_LEGACY {
+        fs::read(path).map_err(|e| format!("Problem reading {:?}: {}", path, e))?
+    } else {
+        return Err("The signature uses an algorithm we don't support".into());
+    };
+    if !key.verify(&message, &sig.signature) {
+        return Err("The signature doesn't match the file".into());
+    }
+
+    // The trusted comment is signed too, so it can't be changed without us noticing.
+    let mut global_message = sig.signature.clone();
+    global_message.extend(sig.trusted_comment.as_bytes());
+    if !key.verify(&global_message, &sig.global_signature) {
+        return Err("The signature's trusted comment has been changed".into());
+    }
+    Ok(sig.trusted_comment)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    /// Sign a file the way `minisign -S` does.
+    fn sign(pair: &Ed25519KeyPair, key_id: &[u8; 8], path: &Path, comment: &str) -> String {
+        let signature = pair.sign(&blake2b_file(path).unwrap());
+        let mut sig_bytes = ALG_HASHED.to_vec();
+        sig_bytes.extend(key_id);
+        sig_bytes.extend(signature.as_ref());
+
+        let mut global_message = signature.as_ref().to_vec();
+        global_message.extend(comment.as_bytes());
+        format!(
+            "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {}\n{}\n",
+            BASE64.encode(&sig_bytes),
+            comment,
+            BASE64.encode(pair.sign(&global_message).as_ref())
+        )
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key_id = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut pub_bytes = ALG_LEGACY.to_vec();
+        pub_bytes.extend(&key_id);
+        pub_bytes.extend(pair.public_key().as_ref());
+        let pub_file = format!(
+            "untrusted comment: minisign public key\n{}\n",
+            BASE64.encode(&pub_bytes)
+        );
+        let key = PublicKey::decode(&pub_file).unwrap();
+
+        let dir = crate::util::TempDir::new("minisign");
+        let path = dir.path().join("python.tar.gz");
+        fs::write(&path, b"python archive").unwrap();
+        let signature = sign(&pair, &key_id, &path, "file:python.tar.gz");
+
+        assert_eq!(
+            verify(&path, &signature, &[key.clone()]),
+            Ok("file:python.tar.gz".to_owned())
+        );
+        assert!(verify(&path, &signature, &[]).is_err());
+        assert!(verify(
+            &path,
+            &signature.replace("file:python.tar.gz", "file:other.tar.gz"),
+            &[key.clone()]
+        )
+        .is_err());
+
+        fs::write(&path, b"tampered archive").unwrap();
+        assert_eq!(
+            verify(&path, &signature, &[key]),
+            Err("The signature doesn't match the file".to_owned())
+        );
+
+        assert!(PublicKey::decode("not a key").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Where pyflow keeps its files. Python installs, script environments, and settings go in the
+//! platform's data directory, and downloaded packages in its cache directory: eg
+//! `~/.local/share/pyflow` and `~/.cache/pyflow` on Linux (or `$XDG_DATA_HOME` and
+//! `$XDG_CACHE_HOME`), `~/Library/Application Support/pyflow` and `~/Library/Caches/pyflow` on
+//! macOS, and `AppData\Roaming\pyflow` and `AppData\Local\pyflow` on Windows. Setting
+//! `PYFLOW_HOME` keeps everything in that directory instead.
+
+use crate::util;
+use crossterm::Color;
+use std::{env, fs, path::PathBuf};
+
+pub const HOME_VAR: &str = "PYFLOW_HOME";
+
+/// The folder in the data directory downloaded packages were kept in, before they moved to the
+/// cache directory. It's still used with `PYFLOW_HOME`.
+const LEGACY_CACHE: &str = "dependency-cache";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dirs {
+    pub data: PathBuf,
+    pub cache: PathBuf,
+}
+
+impl Dirs {
+    pub fn find() -> Self {
+        let home = env::var_os(HOME_VAR)
+            .filter(|h| !h.is_empty())
+            .map(PathBuf::from)
+            .map(|h| {
+                if h.is_relative() {
+                    env::current_dir().map(|d| d.join(&h)).unwrap_or(h)
+                } else {
+                    h
+                }
+            });
+        let base = directories::BaseDirs::new()
+            .map(|d| (d.data_dir().to_owned(), d.cache_dir().to_owned()));
+        match from_parts(home, base) {
+            Some(dirs) => dirs,
+            None => {
+                util::abort(&format!(
+                    "Can't find your home directory. Set `{}` to the directory pyflow should \
+                     keep its files in.",
+                    HOME_VAR
+                ));
+                unreachable!()
+            }
+        }
+    }
+}
+
+/// Pick the directories from `PYFLOW_HOME`, if set, or else the platform's data and cache
+/// directories.
+fn from_parts(home: Option<PathBuf>, base: Option<(PathBuf, PathBuf)>) -> Option<Dirs> {
+    match (home, base) {
+        (Some(home), _) => Some(Dirs {
+            cache: home.join(LEGACY_CACHE),
+            data: home,
+        }),
+        (None, Some((data, cache))) => Some(Dirs {
+            data: data.join("pyflow"),
+            cache: cache.join("pyflow"),
+        }),
+        (None, None) => None,
+    }
+}
+
+/// Move downloaded packages from the data directory, where older versions of pyflow kept them,
+/// to the cache directory. If they can't be moved, eg since the directories are on different
+/// drives, they're downloaded again as needed.
+pub fn migrate_cache(dirs: &Dirs) {
+    let legacy = dirs.data.join(LEGACY_CACHE);
+    if legacy == dirs.cache || !legacy.is_dir() || dirs.cache.exists() {
+        return;
+    }
+    if let Some(parent) = dirs.cache.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    if fs::rename(&legacy, &dirs.cache).is_err() {
+        util::print_color(
+            &format!(
+                "Problem moving downloaded packages from {:?} to {:?}. They'll be downloaded \
+                 again as needed, so you can remove the old folder.",
+                legacy, dirs.cache
+            ),
+            Color::DarkYellow,
+        );
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn dirs_from_home_or_platform() {
+        let platform = Some(("/data".into(), "/cache".into()));
+        assert_eq!(
+            from_parts(None, platform.clone()),
+            Some(Dirs {
+                data: "/data/pyflow".into(),
+                cache: "/cache/pyflow".into(),
+            })
+        );
+        assert_eq!(
+            from_parts(Some("/opt/pyflow".into()), platform),
+            Some(Dirs {
+                data: "/opt/pyflow".into(),
+                cache: "/opt/pyflow/dependency-cache".into(),
+            })
+        );
+        assert_eq!(from_parts(None, None), None);
+    }
+
+    #[test]
+    fn migrate_legacy_cache() {
+        let dir = util::TempDir::new("paths");
+        let root = dir.path();
+        let dirs = Dirs {
+            data: root.join("data"),
+            cache: root.join("cache").join("pyflow"),
+        };
+        fs::create_dir_all(dirs.data.join(LEGACY_CACHE)).unwrap();
+        fs::write(dirs.data.join(LEGACY_CACHE).join("six.whl"), "").unwrap();
+
+        migrate_cache(&dirs);
+        assert!(dirs.cache.join("six.whl").exists());
+        assert!(!dirs.data.join(LEGACY_CACHE).exists());
+    }
+}
@@ -0,0 +1,395 @@
+//! Organizational rules for what may be locked and installed, from `[tool.pyflow.policy]`, or a
+//! policy file it points to, eg one shared by every project in an organization. They're checked
+//! each time `pyflow install` or `pyflow sync` locks or installs packages.
+
+use crate::util;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The rules. Unset ones aren't checked; those set in `pyproject.toml` take precedence over
+/// those in `file`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// A policy file, relative to the project, with the same keys.
+    pub file: Option<PathBuf>,
+    /// Releases must have been uploaded at least this many days ago.
+    pub min_age_days: Option<i64>,
+    /// Licenses, or patterns like `*GPL*`, that packages may not use.
+    pub deny_licenses: Option<Vec<String>>,
+    /// Every package must have a published hash to check its files against.
+    pub require_hashes: Option<bool>,
+    /// URLs packages must come from one of, eg `https://pypi.org`.
+    pub allowed_indexes: Option<Vec<String>>,
+    /// Packages the rules don't apply to.
+    pub exempt: Option<Vec<String>>,
+    /// Write violations here as JSON, eg for CI, relative to the project.
+    pub report: Option<PathBuf>,
+}
+
+impl Policy {
+    /// Add the rules from `file`, if set, under these ones, and make paths absolute.
+    pub fn load(&self, proj_path: &Path) -> Result<Self, String> {
+        let mut result = self.clone();
+        result.report = self.report.as_ref().map(|r| proj_path.join(r));
+        let file = match &self.file {
+            Some(f) => proj_path.join(f),
+            None => return Ok(result),
+        };
+        let data = fs::read_to_string(&file)
+            .map_err(|e| format!("Problem reading {}: {}", file.display(), e))?;
+        let base: Self = toml::from_str(&data)
+            .map_err(|e| format!("Problem parsing {}: {}", file.display(), e))?;
+        if base.file.is_some() {
+            return Err(format!(
+                "{} sets `file`; policy files can't include others",
+                file.display()
+            ));
+        }
+        let base_dir = file.parent().unwrap_or(proj_path);
+
+        result.min_age_days = result.min_age_days.or(base.min_age_days);
+        result.deny_licenses = result.deny_licenses.or(base.deny_licenses);
+        result.require_hashes = result.require_hashes.or(base.require_hashes);
+        result.allowed_indexes = result.allowed_indexes.or(base.allowed_indexes);
+        result.exempt = result.exempt.or(base.exempt);
+        if result.report.is_none() {
+            result.report = base.report.map(|r| base_dir.join(r));
+        }
+        Ok(result)
+    }
+
+    /// Whether checking packages needs their release data from the index.
+    pub fn needs_release_data(&self) -> bool {
+        self.min_age_days.is_some()
+            || self.deny_licenses.as_ref().is_some_and(|l| !l.is_empty())
+            || self.require_hashes == Some(true)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.needs_release_data() && self.allowed_indexes.is_none()
+    }
+
+    pub fn exempts(&self, name: &str) -> bool {
+        self.exempt
+            .iter()
+            .flatten()
+            .any(|e| util::compare_names(e, name))
+    }
+}
+
+/// What the rules check about a package.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Facts {
+    pub name: String,
+    pub version: String,
+    /// Where it comes from, eg `https://pypi.org/pypi/requests/2.31.0/json`, or a git or archive
+    /// URL.
+    pub source: String,
+    pub uploaded: Option<String>, // eg `2019-05-16`
+    pub licenses: Vec<String>,
+    /// Whether its files have published hashes. Git dependencies don't.
+    pub hashed: bool,
+}
+
+/// A package that breaks a rule. `rule` is the rule's key, eg `min_age_days`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Violation {
+    pub rule: &'static str,
+    pub package: String,
+    pub version: String,
+    pub message: String,
+}
+
+/// The licenses a release declares: the identifiers in its SPDX license expression, if it has
+/// one, or else its `license` field, if that's a name rather than the license text, and those in
+/// its `License ::` classifiers.
+pub fn licenses(
+    expression: Option<&str>,
+    license: Option<&str>,
+    classifiers: &[String],
+) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    match expression.map(str::trim).filter(|e| !e.is_empty()) {
+        Some(expression) => result.extend(
+            expression
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .filter(|id| !id.is_empty() && !["AND", "OR", "WITH"].contains(id))
+                .map(str::to_owned),
+        ),
+        None => {
+            if let Some(license) = license.map(str::trim) {
+                if !license.is_empty() && license.len() <= 64 && !license.contains('\n') {
+                    result.push(license.to_owned());
+                }
+            }
+        }
+    }
+    for classifier in classifiers {
+        if let Some(name) = classifier.strip_prefix("License :: ") {
+            let name = name.rsplit(" :: ").next().unwrap_or(name);
+            if name != "OSI Approved" && !result.iter().any(|l| l == name) {
+                result.push(name.to_owned());
+            }
+        }
+    }
+    result
+}
+
+/// Whether a value matches a pattern, ignoring case, where `*` matches anything.
+fn matches(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let value = value.to_lowercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || value.len() < first.len() + last.len() {
+        return false;
+    }
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    if !value.ends_with(last) {
+        return false;
+    }
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Whether a source URL is under an allowed one, eg `https://pypi.org/pypi/six/1.16.0/json`
+/// under `https://pypi.org`.
+fn allowed_source(source: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|a| {
+        let a = a.trim_end_matches('/');
+        source == a || source.starts_with(&format!("{}/", a))
+    })
+}
+
+/// Check packages against the rules. `today` is in days since the Unix epoch.
+pub fn evaluate(policy: &Policy, packages: &[Facts], today: i64) -> Vec<Violation> {
+    let mut result = vec![];
+    for facts in packages.iter().filter(|f| !policy.exempts(&f.name)) {
+        let mut violate = |rule, message: String| {
+            result.push(Violation {
+                rule,
+                package: facts.name.clone(),
+                version: facts.version.clone(),
+                message,
+            })
+        };
+
+        if let Some(min_age) = policy.min_age_days {
+            let uploaded = facts
+                .uploaded
+                .as_ref()
+                .and_then(|u| crate::dep_resolution::days_from_date(u));
+            if let Some(uploaded) = uploaded {
+                let age = today - uploaded;
+                if age < min_age {
+                    violate(
+                        "min_age_days",
+                        format!(
+                            "It was released {} day{} ago; the minimum is {}",
+                            age.max(0),
+                            if age == 1 { "" } else { "s" },
+                            min_age
+                        ),
+                    );
+                }
+            }
+        }
+
+        for pattern in policy.deny_licenses.iter().flatten() {
+            if let Some(license) = facts.licenses.iter().find(|l| matches(pattern, l)) {
+                violate(
+                    "deny_licenses",
+                    format!("Its license, {}, is denied by `{}`", license, pattern),
+                );
+            }
+        }
+
+        if policy.require_hashes == Some(true) && !facts.hashed {
+            violate(
+                "require_hashes",
+                "It doesn't have a published hash to check it against".into(),
+            );
+        }
+
+        if let Some(allowed) = &policy.allowed_indexes {
+            if !allowed_source(&facts.source, allowed) {
+                violate(
+                    "allowed_indexes",
+                    format!(
+                        "It comes from {}, which isn't an allowed index",
+                        facts.source
+                    ),
+                );
+            }
+        }
+    }
+    result
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    violations: &'a [Violation],
+}
+
+/// The violations as JSON, eg `{"violations": [{"rule": "min_age_days", ..}]}`.
+pub fn report_json(violations: &[Violation]) -> String {
+    serde_json::to_string_pretty(&Report { violations }).unwrap()
+}
+
+/// The violations, one per line, for the terminal.
+pub fn describe(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(|v| format!("{} {}: {} ({})", v.package, v.version, v.message, v.rule))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn facts(name: &str, uploaded: &str, licenses: &[&str], source: &str) -> Facts {
+        Facts {
+            name: name.into(),
+            version: "1.0.0".into(),
+            source: source.into(),
+            uploaded: Some(uploaded.into()),
+            licenses: licenses.iter().map(|l| l.to_string()).collect(),
+            hashed: !source.starts_with("https://github.com"),
+        }
+    }
+
+    #[test]
+    fn reads_licenses() {
+        assert_eq!(
+            licenses(
+                Some("(MIT OR Apache-2.0) AND BSD-3-Clause"),
+                Some("MIT"),
+                &[]
+            ),
+            vec!["MIT", "Apache-2.0", "BSD-3-Clause"]
+        );
+        assert_eq!(
+            licenses(
+                None,
+                Some("BSD"),
+                &[
+                    "License :: OSI Approved :: BSD License".into(),
+                    "Programming Language :: Python".into()
+                ]
+            ),
+            vec!["BSD", "BSD License"]
+        );
+        // Some packages put the whole text in `license`.
+        assert!(licenses(None, Some("Copyright (c) 2024\n\nPermission is..."), &[]).is_empty());
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches("gpl-3.0", "GPL-3.0"));
+        assert!(matches("*GPL*", "LGPL-2.1"));
+        assert!(matches("AGPL-*", "AGPL-3.0-only"));
+        assert!(!matches("AGPL-*", "GPL-3.0"));
+        assert!(!matches("*-only", "GPL-3.0-or-later"));
+        assert!(allowed_source(
+            "https://pypi.org/pypi/six/1.16.0/json",
+            &["https://pypi.org/".into()]
+        ));
+        assert!(!allowed_source(
+            "https://pypi.org.example.com/six",
+            &["https://pypi.org".into()]
+        ));
+    }
+
+    #[test]
+    fn finds_violations() {
+        let policy = Policy {
+            min_age_days: Some(14),
+            deny_licenses: Some(vec!["*GPL*".into()]),
+            require_hashes: Some(true),
+            allowed_indexes: Some(vec!["https://pypi.org".into()]),
+            exempt: Some(vec!["Internal_Tools".into()]),
+            ..Policy::default()
+        };
+        let today = crate::dep_resolution::days_from_date("2024-03-20").unwrap();
+        let pypi = "https://pypi.org/pypi/x/1.0.0/json";
+        let packages = vec![
+            facts("requests", "2024-01-02", &["Apache-2.0"], pypi),
+            facts("fresh", "2024-03-18", &["MIT"], pypi),
+            facts("copyleft", "2023-01-01", &["GPL-3.0-only"], pypi),
+            facts(
+                "forked",
+                "2023-01-01",
+                &[],
+                "https://github.com/acme/forked",
+            ),
+            facts(
+                "internal-tools",
+                "2024-03-20",
+                &["GPL"],
+                "https://acme.dev/internal",
+            ),
+        ];
+        let violations = evaluate(&policy, &packages, today);
+        let rules: Vec<(&str, &str)> = violations
+            .iter()
+            .map(|v| (v.package.as_str(), v.rule))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("fresh", "min_age_days"),
+                ("copyleft", "deny_licenses"),
+                ("forked", "require_hashes"),
+                ("forked", "allowed_indexes"),
+            ]
+        );
+        assert!(evaluate(&Policy::default(), &packages, today).is_empty());
+
+        let violations = evaluate(&policy, &packages[1..2], today);
+        assert_eq!(
+            violations[0].message,
+            "It was released 2 days ago; the minimum is 14"
+        );
+        let json: serde_json::Value = serde_json::from_str(&report_json(&violations)).unwrap();
+        assert_eq!(json["violations"][0]["rule"], "min_age_days");
+        assert_eq!(json["violations"][0]["package"], "fresh");
+    }
+
+    #[test]
+    fn policy_files_are_overridden() {
+        let tmp = util::TempDir::new("policy");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("org")).unwrap();
+        fs::write(
+            dir.join("org/policy.toml"),
+            "min_age_days = 14\nrequire_hashes = true\nreport = \"report.json\"\n",
+        )
+        .unwrap();
+        let policy = Policy {
+            file: Some("org/policy.toml".into()),
+            min_age_days: Some(7),
+            ..Policy::default()
+        };
+        let loaded = policy.load(dir).unwrap();
+        assert_eq!(loaded.min_age_days, Some(7));
+        assert_eq!(loaded.require_hashes, Some(true));
+        assert_eq!(loaded.report, Some(dir.join("org/report.json")));
+
+        fs::write(dir.join("org/policy.toml"), "min_age = 14\n").unwrap();
+        assert!(policy.load(dir).is_err());
+    }
+}
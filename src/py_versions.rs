@@ -1,19 +1,19 @@
 //! Manages Python installations
 
 use crate::commands;
-use crate::dep_types::Version;
-use crate::{install, util};
+use crate::dep_types::{Constraint, DependencyError, Version};
+use crate::{install, journal, minisign, net, util};
 use crossterm::Color;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::error::Error;
+use std::str::FromStr;
 #[allow(unused_imports)]
-use std::{fmt, fs, io, path::Path, path::PathBuf};
+use std::{env, fmt, fs, io, path::Path, path::PathBuf, process, time};
 
-/// Only versions we've built and hosted
+/// Only versions we've built and hosted, in pybin. 3.8 and newer come from python-build-standalone.
 #[derive(Clone, Copy, Debug)]
 enum PyVers {
-    V3_12_0, // unreleased
-    V3_11_0, // unreleased
-    V3_10_0, // unreleased
     V3_9_0,  // either Os
     V3_8_0,  // either Os
     V3_7_4,  // Either Os
@@ -26,24 +26,35 @@ enum PyVers {
 
 /// Reduces code repetition for error messages related to Python binaries we don't support.
 fn abort_helper(version: &str, os: &str) {
-    util::abort(&format!(
-        "Automatic installation of Python {} on {} is currently unsupported. If you'd like \
+    util::abort_with(
+        util::ExitCode::MissingInterpreter,
+        &format!(
+            "Automatic installation of Python {} on {} is currently unsupported. If you'd like \
          to use this version of Python, please install it.",
-        version, os
-    ))
+            version, os
+        ),
+    )
 }
 
 impl From<(Version, Os)> for PyVers {
     fn from(v_o: (Version, Os)) -> Self {
-        let unsupported = "Unsupported python version requested; only Python ≥ 3.4 is supported. \
-        to fix this, edit the `py_version` line of `pyproject.toml`, or run `pyflow switch 3.7`";
+        let unsupported = &format!(
+            "Automatic installation of Python {} is unsupported. Install it yourself, or to use \
+             one we can install, edit the `py_version` line of `pyproject.toml`, or run eg \
+             `pyflow switch 3.12`",
+            v_o.0.to_string_no_patch()
+        );
         if v_o.0.major != 3 {
-            util::abort(unsupported);
+            util::abort_with(util::ExitCode::MissingInterpreter, unsupported);
+            unreachable!()
+        }
+        if let Os::Win32 = v_o.1 {
+            abort_helper(&v_o.0.to_string_no_patch(), "32-bit Windows");
             unreachable!()
         }
         match v_o.0.minor {
             4 => match v_o.1 {
-                Os::Windows => {
+                Os::Windows | Os::Win32 => {
                     abort_helper("3.4", "Windows");
                     unreachable!()
                 }
@@ -90,29 +101,8 @@ impl From<(Version, Os)> for PyVers {
                     unreachable!()
                 }
             },
-            10 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_10_0,
-                _ => {
-                    abort_helper("3.10", "Mac");
-                    unreachable!()
-                }
-            },
-            11 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_11_0,
-                _ => {
-                    abort_helper("3.11", "Mac");
-                    unreachable!()
-                }
-            },
-            12 => match v_o.1 {
-                Os::Windows | Os::Ubuntu | Os::Centos => Self::V3_12_0,
-                _ => {
-                    abort_helper("3.12", "Mac");
-                    unreachable!()
-                }
-            },
             _ => {
-                util::abort(unsupported);
+                util::abort_with(util::ExitCode::MissingInterpreter, unsupported);
                 unreachable!()
             }
         }
@@ -122,9 +112,6 @@ impl From<(Version, Os)> for PyVers {
 impl ToString for PyVers {
     fn to_string(&self) -> String {
         match self {
-            Self::V3_12_0 => "3.12.0".into(),
-            Self::V3_11_0 => "3.11.0".into(),
-            Self::V3_10_0 => "3.10.0".into(),
             Self::V3_9_0 => "3.9.0".into(),
             Self::V3_8_0 => "3.8.0".into(),
             Self::V3_7_4 => "3.7.4".into(),
@@ -140,9 +127,6 @@ impl ToString for PyVers {
 impl PyVers {
     fn to_vers(self) -> Version {
         match self {
-            Self::V3_12_0 => Version::new(3, 12, 0),
-            Self::V3_11_0 => Version::new(3, 11, 0),
-            Self::V3_10_0 => Version::new(3, 10, 0),
             Self::V3_9_0 => Version::new(3, 9, 0),
             Self::V3_8_0 => Version::new(3, 8, 0),
             Self::V3_7_4 => Version::new(3, 7, 4),
@@ -157,13 +141,13 @@ impl PyVers {
 
 /// Only Oses we've built and hosted
 /// todo: How cross-compat are these? Eg work across diff versions of Ubuntu?
-/// todo: 32-bit
 #[derive(Clone, Copy, Debug)]
 enum Os {
     // Don't confuse with crate::Os
     Ubuntu, // Builds on Ubuntu 18.04 work on Ubuntu 19.04, Debian, Arch, and Kali
     Centos, // Will this work on Red Hat and Fedora as well?
     Windows,
+    Win32, // Only python-build-standalone has these.
     Mac,
 }
 
@@ -177,23 +161,869 @@ impl fmt::Display for Os {
                 Self::Ubuntu => "Ubuntu",
                 Self::Centos => "Centos",
                 Self::Windows => "Windows",
+                Self::Win32 => "32-bit Windows",
                 Self::Mac => "Mac",
             }
         )
     }
 }
 
-fn download(py_install_path: &Path, version: &Version) {
+/// Python builds from [python-build-standalone](https://github.com/indygreg/python-build-standalone):
+/// the version we install for each minor version, and the release it's from. Add to this as new
+/// versions are released.
+const STANDALONE_BUILDS: &[((u32, u32, u32), &str)] = &[
+    ((3, 13, 0), "20241016"),
+    ((3, 12, 7), "20241016"),
+    ((3, 11, 10), "20241016"),
+    ((3, 10, 15), "20241016"),
+    ((3, 9, 20), "20241016"),
+    ((3, 8, 20), "20241016"),
+];
+
+/// How Python's found and installed: from `pyproject.toml`, the global config, and the command
+/// line.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    /// From `py_version`, eg `pypy3.10`.
+    pub implementation: util::Implementation,
+    /// From `py_variant` in `pyproject.toml`.
+    pub variant: Variant,
+    /// From `python-update-policy` in `pyproject.toml`.
+    pub update_policy: UpdatePolicy,
+    /// Install Python for a different architecture than the one we'd pick, from `--arch`. Eg
+    /// `x86` for 32-bit Python on 64-bit Windows.
+    pub arch: Option<&'static str>,
+    /// Use this interpreter for new environments, instead of searching for one, or prompting if
+    /// we find several. From `--py-alias` or `PYFLOW_PYTHON`, eg `python3.11` or a full path.
+    pub alias: Option<String>,
+    /// Download Python builds from this URL, instead of where they're published. From
+    /// `python-mirror` in the global config, eg for an internal mirror.
+    pub mirror: Option<String>,
+    pub signing: Signing,
+    /// Install Python from this archive instead of downloading it, from `python-archive` in
+    /// `pyproject.toml`.
+    pub local_archive: Option<PathBuf>,
+    /// Rebuild environments that don't match the project's Python version, from `--migrate`.
+    pub migrate: bool,
+    pub net: net::Settings,
+    /// How long to wait for an answer when we ask which build or interpreter to use.
+    pub prompt_timeout: Option<time::Duration>,
+}
+
+/// Parse `--arch`, eg `amd64`, into the name we use for it, eg `x86_64`.
+pub fn parse_arch(arch: &str) -> &'static str {
+    match arch.to_lowercase().as_ref() {
+        "x86" | "i686" | "win32" => "x86",
+        "x86_64" | "x64" | "amd64" => "x86_64",
+        "aarch64" | "arm64" => "aarch64",
+        _ => {
+            util::abort(&format!(
+                "Unknown architecture `{}`. Use `x86_64`, `x86`, or `aarch64`.",
+                arch
+            ));
+            unreachable!()
+        }
+    }
+}
+
+/// Whether projects move to newer patch versions of the Python they use, from
+/// `python-update-policy` in `pyproject.toml`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum UpdatePolicy {
+    /// Use the latest patch version installed, and upgrade with `pyflow python upgrade`.
+    Patch,
+    /// Keep using the patch version the environment was created with.
+    Exact,
+}
+
+impl Default for UpdatePolicy {
+    fn default() -> Self {
+        Self::Patch
+    }
+}
+
+impl FromStr for UpdatePolicy {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "patch" => Self::Patch,
+            "exact" => Self::Exact,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing `python-update-policy`: {}. Must be `patch` or `exact`",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+/// Which build of CPython to install, from `py_variant` in `pyproject.toml`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum Variant {
+    Default,
+    /// Built with debug assertions; its ABI tag ends with `d`, eg `cp312d`.
+    Debug,
+    /// Without the GIL, from Python 3.13; its ABI tag ends with `t`, eg `cp313t`.
+    Freethreaded,
+}
+
+impl Variant {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Debug => "debug",
+            Self::Freethreaded => "freethreaded",
+        }
+    }
+
+    fn abi_suffix(self) -> &'static str {
+        match self {
+            Self::Default => "",
+            Self::Debug => "d",
+            Self::Freethreaded => "t",
+        }
+    }
+
+    /// The build flavor in python-build-standalone's archive names.
+    fn standalone_flavor(self) -> &'static str {
+        match (self, cfg!(target_os = "windows")) {
+            (Self::Default, _) => "install_only",
+            (Self::Debug, _) => "debug-full",
+            (Self::Freethreaded, true) => "freethreaded+pgo-full",
+            (Self::Freethreaded, false) => "freethreaded+pgo+lto-full",
+        }
+    }
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl FromStr for Variant {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Self::Default,
+            "debug" => Self::Debug,
+            "freethreaded" | "free-threaded" => Self::Freethreaded,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing `py_variant`: {}. Must be `default`, `debug`, or \
+                     `freethreaded`",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+/// The ABI tag wheels must have for a debug or free-threaded build, eg `cp313t`. `None` for
+/// default builds, whose wheels are checked once the interpreter's found.
+pub fn variant_abi(settings: &Settings, version: &Version) -> Option<String> {
+    match (settings.variant, settings.implementation) {
+        (Variant::Default, _) | (_, util::Implementation::PyPy) => None,
+        (v, util::Implementation::CPython) => Some(format!(
+            "cp{}{}{}",
+            version.major,
+            version.minor,
+            v.abi_suffix()
+        )),
+    }
+}
+
+/// The variant an environment was created with, from its `pyvenv.cfg`.
+fn env_variant(vers_path: &Path) -> Variant {
+    fs::read_to_string(vers_path.join(".venv").join("pyvenv.cfg"))
+        .unwrap_or_default()
+        .lines()
+        .find_map(|l| {
+            let (key, value) = l.split_at(l.find('=')?);
+            if key.trim() == "pyflow-variant" {
+                Variant::from_str(value[1..].trim()).ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/// Warn if an environment was created with a different variant than `py_variant` asks for. We
+/// select wheels for the one it has, since that's what will import them.
+pub fn check_env_variant(settings: &mut Settings, vers_path: &Path) {
+    let (configured, actual) = (settings.variant, env_variant(vers_path));
+    if configured != actual {
+        util::print_color(
+            &format!(
+                "This environment uses a {} build of Python, but `py_variant` is `{}`. Run \
+                 `pyflow reset` to recreate it.",
+                actual.name(),
+                configured.name()
+            ),
+            Color::DarkYellow,
+        );
+        settings.variant = actual;
+    }
+}
+
+/// Whether an interpreter's full version satisfies `py_version`'s constraints, from a
+/// `py_version` like `>=3.7.4`. Without any, any version will do.
+pub fn satisfies_constraints(constraints: &[Constraint], version: &Version) -> bool {
+    constraints.iter().all(|c| c.is_compatible(version))
+}
+
+/// `py_version`'s constraints, for messages, eg `>=3.7.4`.
+pub fn constraints_text(constraints: &[Constraint]) -> String {
+    constraints
+        .iter()
+        .map(|c| format!("{}{}", c.type_.to_string(), c.version.to_string2()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The highest of `candidates` that satisfies all of `constraints`.
+fn highest_satisfying(constraints: &[Constraint], candidates: &[Version]) -> Option<Version> {
+    candidates
+        .iter()
+        .filter(|v| satisfies_constraints(constraints, v))
+        .max()
+        .copied()
+}
+
+/// Pick the Python version to use for a `py_version` that's a range, eg `>=3.9,<3.12`: an
+/// existing environment's, while it satisfies it, or else the highest that's installed, or we
+/// can install.
+pub fn pick_version(
+    settings: &Settings,
+    constraints: &[Constraint],
+    pyflow_dir: &Path,
+    pypackages_dir: &Path,
+) -> Version {
+    let existing: Vec<Version> = util::find_venvs(pypackages_dir)
+        .iter()
+        .filter_map(|(ma, mi)| {
+            util::venv_python_version(&pypackages_dir.join(format!("{}.{}", ma, mi)))
+        })
+        .collect();
+    if let Some(v) = highest_satisfying(constraints, &existing) {
+        return v;
+    }
+
+    let builds = if settings.implementation == util::Implementation::PyPy {
+        PYPY_BUILDS
+    } else {
+        STANDALONE_BUILDS
+    };
+    let candidates: Vec<Version> = find_installed_versions(settings, pyflow_dir)
+        .into_iter()
+        .chain(
+            builds
+                .iter()
+                .map(|((ma, mi, pa), _)| Version::new(*ma, *mi, *pa)),
+        )
+        .collect();
+    match highest_satisfying(constraints, &candidates) {
+        Some(v) => v,
+        None => {
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "No Python that's installed, or that we can install, satisfies this \
+                     project's `py_version`, `{}`. Install one that does, and set `py_version` \
+                     to its version, eg `3.7`.",
+                    constraints_text(constraints)
+                ),
+            );
+            unreachable!()
+        }
+    }
+}
+
+fn mirror(settings: &Settings) -> Option<&str> {
+    settings.mirror.as_deref().map(|m| m.trim_end_matches('/'))
+}
+
+/// The URL to download a file from: `path` is relative to its release, eg
+/// `20241016/cpython-3.12.7+20241016-x86_64-unknown-linux-gnu-install_only.tar.gz`. It's
+/// under `base` unless there's a mirror.
+fn download_url(settings: &Settings, base: &str, path: &str) -> String {
+    format!("{}/{}", mirror(settings).unwrap_or(base), path)
+}
+
+/// Where to find the SHA256 checksum of the file at `path`, in order: where it's published
+/// under `base`, so a mirror can't vouch for its own files, then the mirror, for when that's
+/// unreachable.
+fn checksum_urls(settings: &Settings, base: &str, path: &str) -> Vec<String> {
+    let mut result = vec![format!("{}/{}.sha256", base, path)];
+    if let Some(m) = mirror(settings) {
+        result.push(format!("{}/{}.sha256", m, path));
+    }
+    result
+}
+
+/// Public keys of the hosts that sign the Python builds they publish, by the URL their releases
+/// are under. Signatures are required on archives from them, unless `require-python-signatures`
+/// is `false`. pybin's key goes here once its releases are signed; python-build-standalone and
+/// PyPy don't sign theirs, so they're checked against their checksums instead.
+const PUBLISHER_KEYS: &[(&str, &str)] = &[];
+
+/// The key the host at `base` signs its builds with, if it does.
+fn publisher_key(base: &str) -> Option<&'static str> {
+    PUBLISHER_KEYS
+        .iter()
+        .find(|(url, _)| *url == base)
+        .map(|(_, key)| *key)
+}
+
+/// The keys to check Python archives' signatures with, and whether archives must be signed. From
+/// the global config; these add to the keys in `PUBLISHER_KEYS`.
+#[derive(Clone, Debug, Default)]
+pub struct Signing {
+    keys: Vec<minisign::PublicKey>,
+    /// From `require-python-signatures`. If it's not set, archives must be signed if their
+    /// publisher signs its builds.
+    required: Option<bool>,
+}
+
+impl Signing {
+    pub fn new(keys: &[String], required: Option<bool>) -> Self {
+        let mut parsed = vec![];
+        for key in keys {
+            match minisign::PublicKey::decode(key) {
+                Ok(k) => parsed.push(k),
+                Err(e) => util::abort(&format!("Problem parsing `python-signing-keys`: {}", e)),
+            }
+        }
+        Self {
+            keys: parsed,
+            required,
+        }
+    }
+
+    /// Whether an archive must be signed, given the key its publisher signs with, if any.
+    fn required(&self, publisher_key: Option<&str>) -> bool {
+        self.required.unwrap_or_else(|| publisher_key.is_some())
+    }
+}
+
+/// Check an archive's minisign signature: `<archive>.minisig`, next to it, or downloaded from
+/// `signature_url`, against the configured keys and `publisher_key`. Without any keys, and
+/// unless signatures are required, there's nothing to check it with, so we don't.
+fn verify_signature(
+    settings: &Settings,
+    archive_path: &Path,
+    signature_url: Option<&str>,
+    publisher_key: Option<&str>,
+) {
+    let mut keys = settings.signing.keys.clone();
+    if let Some(k) = publisher_key {
+        keys.push(minisign::PublicKey::decode(k).expect("Problem decoding a built-in key"));
+    }
+    let required = settings.signing.required(publisher_key);
+    if keys.is_empty() && !required {
+        return;
+    }
+    let archive_name = archive_path.file_name().unwrap().to_string_lossy();
+    let sig_path = archive_path.with_file_name(format!("{}.minisig", archive_name));
+
+    let signature = match (fs::read_to_string(&sig_path), signature_url) {
+        (Ok(s), _) => Some(s),
+        (Err(_), Some(url)) => {
+            match net::get(&settings.net, url)
+                .and_then(|mut r| r.text().map_err(|e| net::NetError::new(url, &e)))
+            {
+                Ok(s) => {
+                    fs::write(&sig_path, &s).ok();
+                    Some(s)
+                }
+                Err(e) if e.status == Some(reqwest::StatusCode::NOT_FOUND) => None,
+                Err(e) => {
+                    util::abort_with(
+                        util::ExitCode::Network,
+                        &format!("Problem downloading the Python archive's signature: {}", e),
+                    );
+                    unreachable!()
+                }
+            }
+        }
+        (Err(_), None) => None,
+    };
+
+    let result = match signature {
+        Some(s) => minisign::verify(archive_path, &s, &keys),
+        None if required => Err("It isn't signed".to_owned()),
+        None => {
+            util::print_color(
+                &format!(
+                    "The Python archive {} isn't signed, so its signature can't be checked",
+                    archive_name
+                ),
+                Color::DarkYellow,
+            );
+            return;
+        }
+    };
+    match result {
+        Ok(_) => util::print_color(
+            &format!("Verified the signature of {}", archive_name),
+            Color::DarkBlue,
+        ),
+        Err(e) => {
+            // Remove downloads, so the next run fetches them again; not archives the user gave us.
+            if signature_url.is_some() {
+                fs::remove_file(archive_path).ok();
+                fs::remove_file(&sig_path).ok();
+            }
+            util::abort_with(
+                util::ExitCode::HashMismatch,
+                &format!(
+                    "The Python archive {} failed signature verification: {}. Signatures are \
+                     checked against `python-signing-keys` in the global config, and the keys \
+                     of publishers that sign their builds.",
+                    archive_name, e
+                ),
+            );
+        }
+    }
+}
+
+/// Choices we remember for a project, in `.pyflow` in its directory. These are specific to this
+/// machine, so aren't committed.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ProjectState {
+    /// The interpreter used for each Python version, eg `"3.11" = "/usr/bin/python3.11"`.
+    #[serde(default)]
+    python_aliases: HashMap<String, String>,
+}
+
+fn state_path(pypackages_dir: &Path) -> PathBuf {
+    pypackages_dir
+        .parent()
+        .unwrap_or(pypackages_dir)
+        .join(".pyflow")
+}
+
+/// Identifies a Python version in `python-aliases`, eg `3.11`, `3.13t`, or `pypy3.10`.
+fn alias_key(settings: &Settings, version: &Version) -> String {
+    match settings.implementation {
+        util::Implementation::CPython => format!(
+            "{}{}",
+            version.to_string_no_patch(),
+            settings.variant.abi_suffix()
+        ),
+        util::Implementation::PyPy => format!("pypy{}", version.to_string_no_patch()),
+    }
+}
+
+fn read_state(path: &Path) -> ProjectState {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|data| toml::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Remember the interpreter a project's environment was created with, so we use it again when
+/// recreating it, instead of prompting, or picking another.
+fn remember_alias(settings: &Settings, path: &Path, version: &Version, alias: &str) {
+    let mut state = read_state(path);
+    state
+        .python_aliases
+        .insert(alias_key(settings, version), alias.to_owned());
+    if let Ok(data) = toml::to_string(&state) {
+        fs::write(path, data).ok();
+    }
+}
+
+/// The file pyenv, and other tools, pin a directory's Python version with.
+const PIN_FILENAME: &str = ".python-version";
+
+/// Find the Python version pinned in a `.python-version` file, in `dir` or its parents, like
+/// pyenv does. Its first line we understand is used, eg `3.11`, `3.11.4`, or `pypy3.10`; others,
+/// like `system`, are skipped.
+pub fn find_pinned(dir: &Path) -> Option<(util::Implementation, Version)> {
+    dir.ancestors().find_map(|d| {
+        let data = fs::read_to_string(d.join(PIN_FILENAME)).ok()?;
+        data.lines().find_map(|line| {
+            let (implementation, v) = util::Implementation::split_version(line);
+            Version::from_str(v).ok().map(|v| (implementation, v))
+        })
+    })
+}
+
+/// Pin a directory's Python version in `.python-version`, for projects that don't set
+/// `py_version`, and tools like pyenv. For `pyflow python pin 3.11`.
+pub fn pin(dir: &Path, entered: &str) {
+    let (_, v) = util::Implementation::split_version(entered);
+    util::fallible_v_parse(v);
+
+    let path = dir.join(PIN_FILENAME);
+    if fs::write(&path, format!("{}\n", entered.trim())).is_err() {
+        util::abort(&format!("Problem writing {:?}", path));
+    }
+    util::print_color(
+        &format!("Pinned Python {} in {}", entered.trim(), path.display()),
+        Color::Green,
+    );
+}
+
+/// The CPU architecture to install Python for, eg `x86_64` or `aarch64`: the one set with `--arch`,
+/// or else the one pyflow's built for. An x86_64 pyflow running under Rosetta on Apple Silicon
+/// gets an arm64 Python, which runs natively; a 32-bit pyflow gets a 32-bit Python.
+pub fn host_arch(settings: &Settings) -> &'static str {
+    if let Some(arch) = settings.arch {
+        return arch;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        if env::consts::ARCH == "x86_64" {
+            let translated = std::process::Command::new("sysctl")
+                .args(&["-n", "sysctl.proc_translated"])
+                .output()
+                .map(|o| String::from_utf8_lossy(&o.stdout).trim() == "1")
+                .unwrap_or(false);
+            if translated {
+                return "aarch64";
+            }
+        }
+    }
+    env::consts::ARCH
+}
+
+/// Check if this Linux system uses musl libc instead of glibc, eg Alpine. Builds linked against
+/// glibc won't run there.
+fn is_musl() -> bool {
+    cfg!(target_env = "musl") || has_musl_loader(Path::new("/lib"))
+}
+
+/// Whether the musl dynamic loader is in `lib_dir`, eg `/lib/ld-musl-x86_64.so.1`.
+fn has_musl_loader(lib_dir: &Path) -> bool {
+    fs::read_dir(lib_dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|e| e.file_name().to_string_lossy().starts_with("ld-musl-"))
+        })
+        .unwrap_or(false)
+}
+
+/// Records the C library an install was built against, on Linux: `glibc` or `musl`. Pyflow's
+/// directory can be shared between containers, eg mounted as a devcontainer volume, and an
+/// install made in one may not run in another.
+const LIBC_MARKER: &str = ".pyflow-libc";
+
+fn libc_name() -> Option<&'static str> {
+    if env::consts::OS != "linux" {
+        None
+    } else if is_musl() {
+        Some("musl")
+    } else {
+        Some("glibc")
+    }
+}
+
+fn write_libc_marker(install_path: &Path) {
+    if let Some(libc) = libc_name() {
+        fs::write(install_path.join(LIBC_MARKER), libc).ok();
+    }
+}
+
+/// Whether an install was built for this system's C library. Installs from before we recorded
+/// it are assumed to be.
+fn libc_compatible(install_path: &Path) -> bool {
+    match fs::read_to_string(install_path.join(LIBC_MARKER)) {
+        Ok(built_for) => libc_name().map_or(true, |libc| built_for.trim() == libc),
+        Err(_) => true,
+    }
+}
+
+/// The python-build-standalone target triple for this platform, if it has builds for it.
+fn standalone_triple(settings: &Settings) -> Option<&'static str> {
+    let musl = env::consts::OS == "linux" && is_musl();
+    triple_for(env::consts::OS, host_arch(settings), musl)
+}
+
+/// The python-build-standalone target triple for an OS and architecture, eg `linux` and
+/// `x86_64`, if it has builds for it.
+fn triple_for(os: &str, arch: &str, linux_musl: bool) -> Option<&'static str> {
+    match (os, arch) {
+        ("linux", "x86_64") if linux_musl => Some("x86_64-unknown-linux-musl"),
+        ("linux", _) if linux_musl => None,
+        ("linux", "x86_64") => Some("x86_64-unknown-linux-gnu"),
+        ("linux", "aarch64") => Some("aarch64-unknown-linux-gnu"),
+        ("macos", "x86_64") => Some("x86_64-apple-darwin"),
+        ("macos", "aarch64") => Some("aarch64-apple-darwin"),
+        ("windows", "x86_64") => Some("x86_64-pc-windows-msvc"),
+        ("windows", "x86") => Some("i686-pc-windows-msvc"),
+        _ => None,
+    }
+}
+
+/// The folder a Python version's installed in, eg `python-3.12.7`. 32-bit builds, and debug
+/// and free-threaded ones, eg `python-3.13.0-freethreaded`, get their own, so they can be
+/// installed alongside default ones.
+fn install_dir_name(settings: &Settings, version: &Version) -> String {
+    let name = match settings.implementation {
+        util::Implementation::CPython => "python",
+        util::Implementation::PyPy => "pypy",
+    };
+    let mut result = format!("{}-{}", name, version.to_string2());
+    if settings.variant != Variant::Default {
+        result.push_str(&format!("-{}", settings.variant.name()));
+    }
+    if host_arch(settings) == "x86" {
+        result.push_str("-x86");
+    }
+    result
+}
+
+/// The interpreter's path in an installation, relative to its folder.
+fn interpreter_name(settings: &Settings) -> &'static str {
+    match (settings.implementation, cfg!(target_os = "windows")) {
+        (util::Implementation::CPython, true) => "python",
+        (util::Implementation::CPython, false) => "bin/python3",
+        (util::Implementation::PyPy, true) => "pypy3",
+        (util::Implementation::PyPy, false) => "bin/pypy3",
+    }
+}
+
+/// PyPy builds from [pypy.org](https://www.pypy.org/download.html): the Python version each
+/// supports, and the PyPy release with it.
+const PYPY_BUILDS: &[((u32, u32, u32), &str)] = &[
+    ((3, 10, 14), "7.3.17"),
+    ((3, 9, 19), "7.3.16"),
+    ((3, 8, 16), "7.3.11"),
+];
+
+/// SHA256 checksums of the builds whose hosts don't publish one next to each archive, by archive
+/// name. PyPy's are from [pypy.org](https://www.pypy.org/checksums.html); it doesn't list them
+/// for Windows. pybin doesn't publish any.
+const KNOWN_SHA256: &[(&str, &str)] = &[
+    (
+        "pypy3.10-v7.3.17-linux64.tar.bz2",
+        "fdcdb9b24f1a7726003586503fdeb264fd68fc37fbfcea022dcfe825a7fee18b",
+    ),
+    (
+        "pypy3.10-v7.3.17-aarch64.tar.bz2",
+        "53b6e5907df869c49e4eae7aca09fba16d150741097efb245892c1477d2395f2",
+    ),
+    (
+        "pypy3.10-v7.3.17-macos_x86_64.tar.bz2",
+        "6c2c5f2300d7564e711421b4968abd63243cb96f76e363975dd648ebf4a362ee",
+    ),
+    (
+        "pypy3.10-v7.3.17-macos_arm64.tar.bz2",
+        "a050e25e8d686853dd5afc363e55625165825dacfb55f8753d8225ebe417cfd2",
+    ),
+    (
+        "pypy3.9-v7.3.16-linux64.tar.bz2",
+        "16f9c5b808c848516e742986e826b833cdbeda09ad8764e8704595adbe791b23",
+    ),
+    (
+        "pypy3.9-v7.3.16-aarch64.tar.bz2",
+        "de3f2ed3581b30555ac0dd3e4df78a262ec736a36fb2e8f28259f8539b278ef4",
+    ),
+    (
+        "pypy3.9-v7.3.16-macos_x86_64.tar.bz2",
+        "fda015431621e7e5aa16359d114f2c45a77ed936992c1efff86302e768a6b21c",
+    ),
+    (
+        "pypy3.9-v7.3.16-macos_arm64.tar.bz2",
+        "88f824e7a2d676440d09bc90fc959ae0fd3557d7e2f14bfbbe53d41d159a47fe",
+    ),
+    (
+        "pypy3.8-v7.3.11-linux64.tar.bz2",
+        "470330e58ac105c094041aa07bb05676b06292bc61409e26f5c5593ebb2292d9",
+    ),
+    (
+        "pypy3.8-v7.3.11-aarch64.tar.bz2",
+        "9a2fa0b8d92b7830aa31774a9a76129b0ff81afbd22cd5c41fbdd9119e859f55",
+    ),
+    (
+        "pypy3.8-v7.3.11-macos_x86_64.tar.bz2",
+        "194ca0b4d91ae409a9cb1a59eb7572d7affa8a451ea3daf26539aa515443433a",
+    ),
+    (
+        "pypy3.8-v7.3.11-macos_arm64.tar.bz2",
+        "78cdc79ff964c4bfd13eb45a7d43a011cbe8d8b513323d204891f703fdc4fa1a",
+    ),
+];
+
+fn known_sha256(archive_name: &str) -> Option<&'static str> {
+    KNOWN_SHA256
+        .iter()
+        .find(|(name, _)| *name == archive_name)
+        .map(|(_, sha256)| *sha256)
+}
+
+/// Find a PyPy build with the same major and minor version as `version`.
+fn find_pypy_build(settings: &Settings, version: &Version) -> PyBuild {
+    let platform = match (env::consts::OS, host_arch(settings)) {
+        ("linux", _) if is_musl() => None,
+        ("linux", "x86_64") => Some("linux64"),
+        ("linux", "aarch64") => Some("aarch64"),
+        ("macos", "x86_64") => Some("macos_x86_64"),
+        ("macos", "aarch64") => Some("macos_arm64"),
+        ("windows", "x86_64") => Some("win64"),
+        _ => None,
+    };
+    let found = PYPY_BUILDS
+        .iter()
+        .find(|((major, minor, _), _)| *major == version.major && *minor == version.minor);
+
+    let (platform, &((major, minor, patch), release)) = match (platform, found) {
+        (Some(p), Some(f)) => (p, f),
+        _ => {
+            let available: Vec<String> = PYPY_BUILDS
+                .iter()
+                .map(|((major, minor, _), _)| format!("pypy{}.{}", major, minor))
+                .collect();
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "Automatic installation of PyPy {} on {} {} is unsupported. We can install \
+                     {} on 64-bit Linux, Mac, and Windows. Install it yourself, or use one of \
+                     those.",
+                    version.to_string_no_patch(),
+                    env::consts::OS,
+                    host_arch(settings),
+                    available.join(", ")
+                ),
+            );
+            unreachable!()
+        }
+    };
+
+    // eg `pypy3.10-v7.3.17-linux64`
+    let extracted_name = format!("pypy{}.{}-v{}-{}", major, minor, release, platform);
+    let ext = if platform == "win64" {
+        "zip"
+    } else {
+        "tar.bz2"
+    };
+    let archive_name = format!("{}.{}", extracted_name, ext);
+    let base = "https://downloads.python.org/pypy";
+    PyBuild {
+        version: Version::new(major, minor, patch),
+        url: download_url(settings, base, &archive_name),
+        sha256: known_sha256(&archive_name),
+        archive_name,
+        extracted_name,
+        // PyPy publishes checksums on its website, rather than alongside each archive.
+        sha256_urls: vec![],
+        publisher_key: publisher_key(base),
+    }
+}
+
+/// A Python build to download.
+struct PyBuild {
+    version: Version,
+    url: String,
+    /// The file name to save the archive as.
+    archive_name: String,
+    /// The folder the archive unpacks to.
+    extracted_name: String,
+    /// The archive's SHA256 checksum, if it's in `KNOWN_SHA256`.
+    sha256: Option<&'static str>,
+    /// Where the archive's SHA256 checksum is published, if it is, in the order to try them.
+    sha256_urls: Vec<String>,
+    /// The key its publisher signs it with, if it does.
+    publisher_key: Option<&'static str>,
+}
+
+/// Find a python-build-standalone build with the same major and minor version as `version`.
+fn find_standalone_build(settings: &Settings, version: &Version) -> Option<PyBuild> {
+    let triple = standalone_triple(settings)?;
+    let variant = settings.variant;
+    let &((major, minor, patch), tag) = STANDALONE_BUILDS
+        .iter()
+        .find(|((major, minor, _), _)| *major == version.major && *minor == version.minor)?;
+    let version = Version::new(major, minor, patch);
+
+    let archive_name = standalone_archive_name(&version, tag, triple, variant);
+    let base = "https://github.com/indygreg/python-build-standalone/releases/download";
+    let path = format!("{}/{}", tag, archive_name);
+    Some(PyBuild {
+        version,
+        url: download_url(settings, base, &path),
+        sha256: None,
+        sha256_urls: checksum_urls(settings, base, &path),
+        publisher_key: publisher_key(base),
+        archive_name,
+        extracted_name: if variant == Variant::Default {
+            "python".into()
+        } else {
+            "python/install".into()
+        },
+    })
+}
+
+/// The name of a python-build-standalone archive, eg
+/// `cpython-3.12.7+20241016-x86_64-unknown-linux-gnu-install_only.tar.gz`. The `install_only`
+/// archives contain just the Python installation, in a `python` folder. Debug and free-threaded
+/// builds only come in `full` ones, which have it in `python/install`, alongside build artifacts.
+fn standalone_archive_name(version: &Version, tag: &str, triple: &str, variant: Variant) -> String {
+    format!(
+        "cpython-{}+{}-{}-{}.{}",
+        version.to_string2(),
+        tag,
+        triple,
+        variant.standalone_flavor(),
+        if variant == Variant::Default {
+            "tar.gz"
+        } else {
+            "tar.zst"
+        }
+    )
+}
+
+/// Find a build hosted in pybin, for versions older than python-build-standalone has.
+fn find_pybin_build(settings: &Settings, version: &Version) -> PyBuild {
+    let arch = host_arch(settings);
+    let win32 = cfg!(target_os = "windows") && arch == "x86";
+    if arch != "x86_64" && !win32 {
+        let msg = if standalone_triple(settings).is_some() {
+            format!(
+                "Automatic installation of Python {} on {} is unsupported; we can install \
+                 Python 3.8 or newer. Install it yourself, or use one of those.",
+                version.to_string_no_patch(),
+                arch
+            )
+        } else {
+            format!(
+                "Automatic installation of Python on {} {} is unsupported. Please install it.",
+                env::consts::OS,
+                arch
+            )
+        };
+        util::abort_with(util::ExitCode::MissingInterpreter, &msg);
+    }
+
     // We use the `.xz` format due to its small size compared to `.zip`. On order half the size.
     let os;
     let os_str;
     #[cfg(target_os = "windows")]
     {
-        os = Os::Windows;
+        os = if win32 { Os::Win32 } else { Os::Windows };
         os_str = "windows";
     }
     #[cfg(target_os = "linux")]
     {
+        if is_musl() {
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "Automatic installation of Python {} on musl-based Linux, eg Alpine, is \
+                     unsupported; we can install Python 3.8 or newer. Install it yourself, eg \
+                     with `apk add python3`, or use one of those.",
+                    version.to_string_no_patch()
+                ),
+            );
+        }
         let result = util::prompt_list(
             "Please enter the number corresponding to your Linux distro:",
             "Linux distro",
@@ -209,13 +1039,15 @@ fn download(py_install_path: &Path, version: &Version) {
                 ),
             ],
             false,
+            settings.prompt_timeout,
         );
         os = result.1;
         os_str = match os {
             Os::Ubuntu => "ubuntu",
             Os::Centos => "centos",
             _ => {
-                util::abort(
+                util::abort_with(
+                    util::ExitCode::MissingInterpreter,
                     "Unfortunately, we don't yet support other Operating systems.\
                      It's worth trying the other options, to see if one works anyway.",
                 );
@@ -234,168 +1066,1468 @@ fn download(py_install_path: &Path, version: &Version) {
     }
 
     // Match up our version to the closest match (major+minor will match) we've built.
-    let vers_to_dl2: PyVers = (*version, os).into();
-    let vers_to_dl = vers_to_dl2.to_string();
+    let vers_to_dl: PyVers = (*version, os).into();
+    let vers_str = vers_to_dl.to_string();
+    // eg `python-3.7.4-ubuntu.tar.xz`
+    let archive_name = format!("python-{}-{}.tar.xz", vers_str, os_str);
+    let base = "https://github.com/David-OConnor/pybin/releases/download";
 
-    let url = format!(
-        "https://github.com/David-OConnor/pybin/releases/\
-         download/{}/python-{}-{}.tar.xz",
-        vers_to_dl, vers_to_dl, os_str
-    );
+    PyBuild {
+        version: vers_to_dl.to_vers(),
+        url: download_url(
+            settings,
+            base,
+            &format!("{}/python-{}-{}.tar.xz", vers_str, vers_str, os_str),
+        ),
+        sha256: known_sha256(&archive_name),
+        archive_name,
+        extracted_name: format!("python-{}-{}", vers_str, os_str),
+        sha256_urls: vec![],
+        publisher_key: publisher_key(base),
+    }
+}
 
-    // eg `python-3.7.4-ubuntu.tar.xz`
-    let archive_path = py_install_path.join(&format!("python-{}-{}.tar.xz", vers_to_dl, os_str));
-    if !archive_path.exists() {
-        // Save the file
-        util::print_color(
-            &format!("Downloading Python {}...", vers_to_dl),
-            Color::Cyan,
+/// Download a file to `dest`. It's saved as `dest` with `.part` appended until it's complete; if
+/// that's there from an interrupted download, we resume from where it stopped.
+fn download_resumable(net: &net::Settings, url: &str, dest: &Path) -> Result<(), Box<dyn Error>> {
+    let part_path = dest.with_file_name(format!(
+        "{}.part",
+        dest.file_name().unwrap().to_string_lossy()
+    ));
+    let existing = fs::metadata(&part_path).map_or(0, |m| m.len());
+
+    let mut resp = match net::send(net, url, |client| {
+        let request = client.get(url);
+        if existing > 0 {
+            request.header(reqwest::header::RANGE, format!("bytes={}-", existing))
+        } else {
+            request
+        }
+    }) {
+        Ok(r) => r,
+        // The part we have is as big as the file; it may be complete, or not the same file.
+        // Either way, verifying it will tell.
+        Err(e) if e.status == Some(reqwest::StatusCode::RANGE_NOT_SATISFIABLE) => {
+            fs::rename(&part_path, dest)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut out = match resp.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => {
+            util::print_color(
+                &format!("Resuming the download, from {} bytes", existing),
+                Color::DarkBlue,
+            );
+            fs::OpenOptions::new().append(true).open(&part_path)?
+        }
+        // The server sent the whole file.
+        _ => fs::File::create(&part_path)?,
+    };
+    let expected_len = resp.content_length();
+    let resumed_from = if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        existing
+    } else {
+        0
+    };
+
+    let written = {
+        let mut progress = util::Progress::new(
+            &mut resp,
+            resumed_from,
+            expected_len.map(|l| l + resumed_from),
         );
-        let mut resp = reqwest::get(&url).expect("Problem downloading Python"); // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded Python archive");
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the Python archive: {:?}", e));
+        io::copy(&mut progress, &mut out)?
+    };
+    if let Some(len) = expected_len {
+        if written < len {
+            return Err(format!("the connection closed after {} of {} bytes", written, len).into());
         }
     }
-    util::print_color(&format!("Installing Python {}...", vers_to_dl), Color::Cyan);
 
-    util::unpack_tar_xz(&archive_path, py_install_path);
+    fs::rename(&part_path, dest)?;
+    Ok(())
+}
 
-    // Strip the OS tag from the extracted Python folder name
-    let extracted_path = py_install_path.join(&format!("python-{}", vers_to_dl));
+/// Find the published SHA256 checksum of a build's archive, from the first of `sha256_urls`
+/// we can reach. The publisher's is saved next to the archive, so we only download it once; a
+/// mirror's isn't, so it can't stand in for the publisher's next time.
+fn find_expected_sha256(
+    net: &net::Settings,
+    archive_path: &Path,
+    sha256_urls: &[String],
+) -> String {
+    let checksum_path = archive_path.with_file_name(format!(
+        "{}.sha256",
+        archive_path.file_name().unwrap().to_string_lossy()
+    ));
+    if let Ok(d) = fs::read_to_string(&checksum_path) {
+        return parse_checksum(&d);
+    }
 
-    fs::rename(
-        py_install_path.join(&format!("python-{}-{}", vers_to_dl, os_str)),
-        &extracted_path,
-    )
-    .expect("Problem renaming extracted Python folder");
+    for (i, url) in sha256_urls.iter().enumerate() {
+        match net::get(net, url).and_then(|mut r| r.text().map_err(|e| net::NetError::new(url, &e)))
+        {
+            Ok(d) => {
+                if i == 0 {
+                    fs::write(&checksum_path, &d).ok();
+                }
+                return parse_checksum(&d);
+            }
+            Err(e) if i + 1 < sha256_urls.len() => util::print_color(
+                &format!(
+                    "Problem downloading the Python archive's checksum from where it's \
+                     published: {}. Using the mirror's, which only shows the archive is the one \
+                     the mirror has.",
+                    e
+                ),
+                Color::DarkYellow,
+            ),
+            Err(e) => util::abort_with(
+                util::ExitCode::Network,
+                &format!("Problem downloading the Python archive's checksum: {}", e),
+            ),
+        }
+    }
+    unreachable!()
+}
+
+/// Eg `4f3a…  cpython-3.12.7+20241016-x86_64-unknown-linux-gnu-install_only.tar.gz`
+fn parse_checksum(data: &str) -> String {
+    data.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn archive_sha256(archive_path: &Path) -> String {
+    // Hash it as it's read, instead of loading the whole archive into memory.
+    match install::HashingReader::new(util::open_archive(archive_path), None).finish() {
+        Ok(h) => h,
+        Err(e) => {
+            util::abort(&format!("Problem reading the Python archive: {}", e));
+            unreachable!()
+        }
+    }
+}
+
+/// Check a downloaded archive against its published checksum before unpacking it, so a
+/// corrupted or tampered download can't be installed.
+fn verify_archive(net: &net::Settings, archive_path: &Path, build: &PyBuild) {
+    let expected = match build.sha256 {
+        Some(s) => s.to_owned(),
+        None => find_expected_sha256(net, archive_path, &build.sha256_urls),
+    };
+
+    let actual = archive_sha256(archive_path);
+    if actual != expected {
+        // Remove it, so the next run downloads it again.
+        fs::remove_file(archive_path).ok();
+        util::abort_with(
+            util::ExitCode::HashMismatch,
+            &format!(
+                "The downloaded Python archive {} doesn't match its published checksum; it may \
+                 be corrupted or tampered with, so it's been removed. Expected SHA256: {}, \
+                 actual: {}. Try again; if this persists, check your network, or any proxy.",
+                build.archive_name, expected, actual
+            ),
+        );
+    }
+}
+
+/// About how much room downloading and unpacking a Python build takes, in bytes.
+const PYTHON_INSTALL_SPACE: u64 = 300_000_000;
+
+/// Download and install a Python build with the same major and minor version as `version`,
+/// into `python-{version}` in `py_install_path`, with `-x86` appended for 32-bit builds. Returns the version installed.
+fn download(
+    settings: &Settings,
+    py_install_path: &Path,
+    version: &Version,
+    constraints: &[Constraint],
+) -> Version {
+    if let Some(archive) = &settings.local_archive {
+        return install_archive(settings, py_install_path, archive, Some(version));
+    }
+
+    let variant = settings.variant;
+    let build = if variant != Variant::Default {
+        if variant == Variant::Freethreaded && (version.major, version.minor) < (3, 13) {
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                "Free-threaded builds are only available for Python 3.13 and later",
+            );
+        }
+        match find_standalone_build(settings, version) {
+            Some(b) if settings.implementation == util::Implementation::CPython => b,
+            _ => {
+                util::abort_with(
+                    util::ExitCode::MissingInterpreter,
+                    &format!(
+                        "There's no {} build of Python {} for this platform",
+                        variant.name(),
+                        version.to_string_no_patch()
+                    ),
+                );
+                unreachable!()
+            }
+        }
+    } else if settings.implementation == util::Implementation::PyPy {
+        find_pypy_build(settings, version)
+    } else {
+        match find_standalone_build(settings, version) {
+            Some(b) => b,
+            None => find_pybin_build(settings, version),
+        }
+    };
+    let vers_str = build.version.to_string2();
+    if !satisfies_constraints(constraints, &build.version) {
+        util::abort_with(
+            util::ExitCode::MissingInterpreter,
+            &format!(
+                "No Python we found satisfies this project's `py_version`, `{}`, and the one we \
+                 can install is {}. Install one that does, and pyflow will find it, or pick it \
+                 with `--py-alias`.",
+                constraints_text(constraints),
+                vers_str
+            ),
+        );
+    }
+
+    // Don't download what we can't verify.
+    let signed = settings.signing.required(build.publisher_key);
+    if build.sha256.is_none() && build.sha256_urls.is_empty() && !signed {
+        util::abort_with(
+            util::ExitCode::MissingInterpreter,
+            &format!(
+                "No checksum is known for {}, so we can't check it isn't corrupted or tampered \
+                 with, and won't install it. Install Python {} yourself, and pyflow will find \
+                 it, or download it and set `python-archive` in `pyproject.toml` to its path.",
+                build.archive_name,
+                version.to_string_no_patch()
+            ),
+        );
+    }
+
+    let archive_path = py_install_path.join(&build.archive_name);
+    util::check_disk_space(
+        py_install_path,
+        PYTHON_INSTALL_SPACE,
+        &format!("Installing Python {}", vers_str),
+    );
+    if !archive_path.exists() {
+        util::print_color(&format!("Downloading Python {}...", vers_str), Color::Cyan);
+        if let Err(e) = download_resumable(&settings.net, &build.url, &archive_path) {
+            util::abort_with(
+                util::ExitCode::Network,
+                &format!(
+                    "Problem downloading the Python archive: {}\nRun this again to resume \
+                     the download.",
+                    e
+                ),
+            );
+        }
+    }
+    verify_archive(&settings.net, &archive_path, &build);
+    verify_signature(
+        settings,
+        &archive_path,
+        Some(&format!("{}.minisig", build.url)),
+        build.publisher_key,
+    );
+    util::print_color(&format!("Installing Python {}...", vers_str), Color::Cyan);
+
+    let staging = create_staging(py_install_path);
+    if build.archive_name.ends_with(".zip") {
+        util::extract_zip(&util::open_archive(&archive_path), &staging, &None);
+    } else if build.archive_name.ends_with(".tar.zst") {
+        util::unpack_tar_zst(&archive_path, &staging);
+    } else {
+        util::unpack_tar(&archive_path, &staging);
+    }
+    let unpacked = staging.join(&build.extracted_name);
+
+    // Free-threaded builds name their interpreter eg `python3.13t`; give it the name we expect.
+    #[cfg(unix)]
+    if variant == Variant::Freethreaded && !unpacked.join(interpreter_name(settings)).exists() {
+        std::os::unix::fs::symlink(
+            format!("python{}.{}t", build.version.major, build.version.minor),
+            unpacked.join(interpreter_name(settings)),
+        )
+        .ok();
+    }
+    if !unpacked.join(interpreter_name(settings)).exists() {
+        fs::remove_dir_all(&staging).ok();
+        util::abort_with(
+            util::ExitCode::MissingInterpreter,
+            &format!(
+                "The Python {} archive doesn't contain the installation we expected, `{}`",
+                vers_str, build.extracted_name
+            ),
+        );
+    }
+
+    // Name the extracted folder by its version, so we can find it later. Anything already there
+    // is an install we couldn't run, or we'd have used it.
+    let install_path = py_install_path.join(install_dir_name(settings, &build.version));
+    write_libc_marker(&unpacked);
+    fs::remove_dir_all(&install_path).ok();
+    if let Err(e) = fs::rename(&unpacked, &install_path) {
+        fs::remove_dir_all(&staging).ok();
+        util::abort(&format!(
+            "Problem moving the Python installation into {:?}: {}",
+            install_path, e
+        ));
+    }
+    fs::remove_dir_all(&staging).ok();
+    refresh_shims(settings, py_install_path);
+
+    build.version
+}
+
+/// Find the installation in an unpacked archive: the archive's top level, or a folder in it.
+/// Returns its path, and its Python version.
+fn find_unpacked_install(settings: &Settings, dir: &Path) -> Option<(PathBuf, Version)> {
+    let mut candidates = vec![dir.to_owned()];
+    if let Ok(entries) = dir.read_dir() {
+        candidates.extend(entries.filter_map(Result::ok).map(|e| e.path()));
+    }
+    candidates.into_iter().find_map(|path| {
+        let version = commands::find_py_version(path.join(interpreter_name(settings)).to_str()?)?;
+        Some((path, version))
+    })
+}
+
+/// Install Python from an archive on disk, instead of downloading it; eg on networks where GitHub
+/// is blocked. It can be one of the archives we download, or a similar build. If there's a
+/// `.sha256` file next to it, the archive's checked against that. Returns the version installed.
+pub fn install_archive(
+    settings: &Settings,
+    py_install_path: &Path,
+    archive_path: &Path,
+    expected: Option<&Version>,
+) -> Version {
+    if !archive_path.is_file() {
+        util::abort_with(
+            util::ExitCode::MissingInterpreter,
+            &format!("Can't find the Python archive {:?}", archive_path),
+        );
+    }
+    let archive_name = archive_path.file_name().unwrap().to_string_lossy();
+
+    let checksum_path = archive_path.with_file_name(format!("{}.sha256", archive_name));
+    match fs::read_to_string(&checksum_path) {
+        Ok(data) => {
+            let (expected, actual) = (parse_checksum(&data), archive_sha256(archive_path));
+            if actual != expected {
+                util::abort_with(
+                    util::ExitCode::HashMismatch,
+                    &format!(
+                        "The Python archive {} doesn't match the checksum in {:?}. Expected \
+                         SHA256: {}, actual: {}",
+                        archive_name, checksum_path, expected, actual
+                    ),
+                );
+            }
+        }
+        Err(_) => util::print_color(
+            &format!(
+                "There's no {:?}, so the archive isn't being verified",
+                checksum_path
+            ),
+            Color::DarkYellow,
+        ),
+    }
+
+    verify_signature(settings, archive_path, None, None);
+
+    if !py_install_path.exists() && fs::create_dir_all(py_install_path).is_err() {
+        util::abort("Problem creating the Pyflow directory")
+    }
+    util::check_disk_space(
+        py_install_path,
+        PYTHON_INSTALL_SPACE,
+        &format!("Installing Python from {}", archive_name),
+    );
+    util::print_color(
+        &format!("Installing Python from {}...", archive_name),
+        Color::Cyan,
+    );
+
+    // Unpack it separately, since we don't know what it contains until we look.
+    let staging = create_staging(py_install_path);
+    if archive_name.ends_with(".zip") {
+        util::extract_zip(&util::open_archive(archive_path), &staging, &None);
+    } else {
+        util::unpack_tar(archive_path, &staging);
+    }
+
+    let (unpacked, version) = match find_unpacked_install(settings, &staging) {
+        Some(found) => found,
+        None => {
+            fs::remove_dir_all(&staging).ok();
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "Can't find a Python installation we can run in {}. It should contain `{}`, \
+                     either at its top level or in a folder.",
+                    archive_name,
+                    interpreter_name(settings)
+                ),
+            );
+            unreachable!()
+        }
+    };
+    if let Some(v) = expected {
+        if version.major != v.major || version.minor != v.minor {
+            fs::remove_dir_all(&staging).ok();
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "{} contains Python {}, not {}",
+                    archive_name,
+                    version.to_string2(),
+                    v.to_string_no_patch()
+                ),
+            );
+        }
+    }
+
+    let dest = py_install_path.join(install_dir_name(settings, &version));
+    if dest.exists() {
+        util::print_color(
+            &format!("Python {} is already installed", version.to_string2()),
+            Color::Green,
+        );
+    } else {
+        write_libc_marker(&unpacked);
+        if let Err(e) = fs::rename(&unpacked, &dest) {
+            fs::remove_dir_all(&staging).ok();
+            util::abort(&format!(
+                "Problem moving the Python installation into {:?}: {}",
+                dest, e
+            ));
+        }
+        util::print_color(
+            &format!("Installed Python {}", version.to_string2()),
+            Color::Green,
+        );
+    }
+    fs::remove_dir_all(&staging).ok();
+    refresh_shims(settings, py_install_path);
+    version
+}
+
+#[derive(Debug)]
+pub struct AliasError {
+    pub details: String,
+}
+
+impl Error for AliasError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl fmt::Display for AliasError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+/// Make an educated guess at the command needed to execute python the
+/// current system.  An alternative approach is trying to find python
+/// installations.
+pub fn find_py_aliases(
+    settings: &Settings,
+    version: &Version,
+    constraints: &[Constraint],
+) -> Vec<(String, Version)> {
+    if settings.implementation == util::Implementation::PyPy {
+        let minor_alias = format!("pypy{}.{}", version.major, version.minor);
+        let mut result = vec![];
+        for alias in &[minor_alias.as_str(), "pypy3", "pypy"] {
+            if let Some(v) = commands::find_py_version(alias) {
+                if v.major == version.major && v.minor == version.minor && commands::is_pypy(alias)
+                {
+                    result.push((alias.to_string(), v));
+                    break;
+                }
+            }
+        }
+        result.retain(|(_, v)| satisfies_constraints(constraints, v));
+        if result.is_empty() {
+            result = find_pyenv_interpreters(settings, version);
+            result.retain(|(_, v)| satisfies_constraints(constraints, v));
+        }
+        return result;
+    }
+
+    let possible_aliases = &[
+        "python3.19",
+        "python3.18",
+        "python3.17",
+        "python3.16",
+        "python3.15",
+        "python3.14",
+        "python3.13",
+        "python3.12",
+        "python3.11",
+        "python3.10",
+        "python3.9",
+        "python3.8",
+        "python3.7",
+        "python3.6",
+        "python3.5",
+        "python3.4",
+        "python3.3",
+        "python3.2",
+        "python3.1",
+        "python3",
+        "python",
+        "python2",
+    ];
+
+    let mut result = Vec::new();
+    let mut found_dets = Vec::new();
+
+    for alias in possible_aliases {
+        // We use the --version command as a quick+effective way to determine if
+        // this command is associated with Python.
+        let dets = commands::find_py_dets(alias);
+        if let Some(v) = commands::find_py_version(alias) {
+            if v.major == version.major && v.minor == version.minor && !found_dets.contains(&dets) {
+                result.push((alias.to_string(), v));
+                found_dets.push(dets);
+            }
+        }
+    }
+
+    // Interpreters on the `PATH` may be pyenv shims for these, or the same installs the registry
+    // or conda lists, so skip versions we've found.
+    let others = find_pyenv_interpreters(settings, version)
+        .into_iter()
+        .chain(find_registered_interpreters(settings, version))
+        .chain(find_conda_interpreters(version));
+    for (path, v) in others {
+        if !result.iter().any(|(_, found)| *found == v) {
+            result.push((path, v));
+        }
+    }
+    // Eg skip 3.7.0 when `py_version` is `>=3.7.4`.
+    result.retain(|(_, v)| satisfies_constraints(constraints, v));
+    result
+}
+
+/// Find the directories of conda (or mamba) environments: from `~/.conda/environments.txt`, which
+/// conda updates when it creates one, or if that's missing, by asking conda.
+fn find_conda_envs() -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = vec![];
+    // The active environment, which may not be listed if it's from another user's install.
+    if let Some(prefix) = env::var_os("CONDA_PREFIX") {
+        result.push(PathBuf::from(prefix));
+    }
+
+    let envs_file =
+        directories::BaseDirs::new().map(|d| d.home_dir().join(".conda").join("environments.txt"));
+    match envs_file.and_then(|f| fs::read_to_string(f).ok()) {
+        Some(data) => result.extend(parse_conda_envs(&data)),
+        None => {
+            #[derive(serde::Deserialize)]
+            struct EnvList {
+                envs: Vec<PathBuf>,
+            }
+
+            for cmd in &["conda", "mamba"] {
+                let output = match std::process::Command::new(cmd)
+                    .args(&["env", "list", "--json"])
+                    .output()
+                {
+                    Ok(o) if o.status.success() => o,
+                    _ => continue,
+                };
+                if let Ok(list) = serde_json::from_slice::<EnvList>(&output.stdout) {
+                    result.extend(list.envs);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut unique = vec![];
+    for env in result {
+        if !unique.contains(&env) {
+            unique.push(env);
+        }
+    }
+    unique
+}
+
+/// The environments in conda's `environments.txt`, one folder per line.
+fn parse_conda_envs(data: &str) -> Vec<PathBuf> {
+    data.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Find interpreters in conda environments matching `version`'s major and minor version. In
+/// data-science setups, these may be the only Pythons on the machine. Returns their full paths,
+/// which we use as aliases.
+fn find_conda_interpreters(version: &Version) -> Vec<(String, Version)> {
+    conda_interpreters_in(&find_conda_envs(), version)
+}
+
+fn conda_interpreters_in(envs: &[PathBuf], version: &Version) -> Vec<(String, Version)> {
+    let py_name = if cfg!(target_os = "windows") {
+        "python.exe"
+    } else {
+        "bin/python3"
+    };
+
+    let mut result = vec![];
+    for env in envs {
+        let path = env.join(py_name);
+        if !path.exists() {
+            continue;
+        }
+        let path = path.to_string_lossy().to_string();
+        if let Some(v) = commands::find_py_version(&path) {
+            if v.major == version.major && v.minor == version.minor {
+                result.push((path, v));
+            }
+        }
+    }
+    result.sort_by(|a, b| b.1.cmp(&a.1));
+    result
+}
+
+/// Find CPython interpreters registered on Windows as described in
+/// [PEP 514](https://www.python.org/dev/peps/pep-0514/), matching `version`'s major and minor
+/// version. The python.org installers and the Microsoft Store register here, whether or not they
+/// add Python to the `PATH`. Returns their full paths, which we use as aliases.
+#[cfg(target_os = "windows")]
+fn find_registered_interpreters(settings: &Settings, version: &Version) -> Vec<(String, Version)> {
+    use winreg::{
+        enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, KEY_READ, KEY_WOW64_32KEY},
+        RegKey,
+    };
+
+    // 32-bit installs for all users are in a separate view of `HKLM`; those for the current
+    // user are tagged eg `3.11-32`.
+    let roots = [
+        (RegKey::predef(HKEY_CURRENT_USER), KEY_READ, false),
+        (RegKey::predef(HKEY_LOCAL_MACHINE), KEY_READ, false),
+        (
+            RegKey::predef(HKEY_LOCAL_MACHINE),
+            KEY_READ | KEY_WOW64_32KEY,
+            true,
+        ),
+    ];
+
+    let mut result: Vec<(String, Version)> = vec![];
+    for (root, flags, view_32) in &roots {
+        let core = match root.open_subkey_with_flags(r"Software\Python\PythonCore", *flags) {
+            Ok(k) => k,
+            Err(_) => continue,
+        };
+        for tag in core.enum_keys().filter_map(Result::ok) {
+            if !registry_tag_matches(&tag, *view_32, host_arch(settings)) {
+                continue;
+            }
+            let install = match core.open_subkey(format!(r"{}\InstallPath", tag)) {
+                Ok(k) => k,
+                Err(_) => continue,
+            };
+            let path = match registered_executable(
+                install.get_value::<String, _>("ExecutablePath").ok(),
+                install.get_value::<String, _>("").ok(),
+            ) {
+                Some(p) => p,
+                None => continue,
+            };
+            if !path.exists() {
+                continue;
+            }
+            let path = path.to_string_lossy().to_string();
+            if result.iter().any(|(p, _)| *p == path) {
+                continue;
+            }
+            if let Some(v) = commands::find_py_version(&path) {
+                if v.major == version.major && v.minor == version.minor {
+                    result.push((path, v));
+                }
+            }
+        }
+    }
+    result.sort_by(|a, b| b.1.cmp(&a.1));
+    result
+}
+
+/// Whether a PEP 514 tag, eg `3.11`, `3.11-32`, or `3.11-arm64`, is for the architecture we're
+/// installing for. Those in the 32-bit view of `HKLM` are all 32-bit, whatever their tag.
+#[cfg(any(target_os = "windows", test))]
+fn registry_tag_matches(tag: &str, view_32: bool, arch: &str) -> bool {
+    let is_32 = view_32 || tag.ends_with("-32");
+    is_32 == (arch == "x86") && tag.ends_with("-arm64") == (arch == "aarch64")
+}
+
+/// A registered install's interpreter: its `InstallPath` key's `ExecutablePath`, or else
+/// `python.exe` in the folder that's the key's default value. Older installers only set that.
+#[cfg(any(target_os = "windows", test))]
+fn registered_executable(
+    executable_path: Option<String>,
+    install_dir: Option<String>,
+) -> Option<PathBuf> {
+    let non_empty = |v: Option<String>| v.filter(|s| !s.is_empty());
+    non_empty(executable_path)
+        .map(PathBuf::from)
+        .or_else(|| non_empty(install_dir).map(|d| PathBuf::from(d).join("python.exe")))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn find_registered_interpreters(
+    _settings: &Settings,
+    _version: &Version,
+) -> Vec<(String, Version)> {
+    vec![]
+}
+
+/// Find interpreters installed with [pyenv](https://github.com/pyenv/pyenv) (or pyenv-win)
+/// matching `version`'s major and minor version, whether or not they're on the `PATH`. Returns
+/// their full paths, which we use as aliases.
+fn find_pyenv_interpreters(settings: &Settings, version: &Version) -> Vec<(String, Version)> {
+    let root = match env::var_os("PYENV_ROOT") {
+        Some(r) => PathBuf::from(r),
+        None => match directories::BaseDirs::new() {
+            Some(d) => d.home_dir().join(".pyenv"),
+            None => return vec![],
+        },
+    };
+    let versions_dir = if cfg!(target_os = "windows") {
+        root.join("pyenv-win").join("versions")
+    } else {
+        root.join("versions")
+    };
+    pyenv_interpreters_in(settings, &versions_dir, version)
+}
+
+/// Find interpreters in pyenv's `versions` folder matching `version`'s major and minor version.
+fn pyenv_interpreters_in(
+    settings: &Settings,
+    versions_dir: &Path,
+    version: &Version,
+) -> Vec<(String, Version)> {
+    let py_name = if cfg!(target_os = "windows") {
+        "python.exe"
+    } else {
+        "bin/python3"
+    };
+
+    let mut result = vec![];
+    let entries = match versions_dir.read_dir() {
+        Ok(e) => e,
+        Err(_) => return result,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        // CPython versions are named by their version, eg `3.11.9`; skip others, like
+        // `pypy3.10-7.3.17`, unless we're looking for PyPy.
+        let name = entry.file_name().to_string_lossy().to_string();
+        let (py_name, wanted) = match settings.implementation {
+            util::Implementation::CPython => {
+                (py_name, name.starts_with(|c: char| c.is_ascii_digit()))
+            }
+            util::Implementation::PyPy => (
+                if cfg!(target_os = "windows") {
+                    "pypy3.exe"
+                } else {
+                    "bin/pypy3"
+                },
+                name.starts_with("pypy"),
+            ),
+        };
+        let path = entry.path().join(py_name);
+        if !wanted || !path.exists() {
+            continue;
+        }
+        let path = path.to_string_lossy().to_string();
+        if let Some(v) = commands::find_py_version(&path) {
+            if v.major == version.major && v.minor == version.minor {
+                result.push((path, v));
+            }
+        }
+    }
+    result.sort_by(|a, b| b.1.cmp(&a.1));
+    result
+}
+
+// Find versions installed with this tool.
+fn find_installed_versions(settings: &Settings, pyflow_dir: &Path) -> Vec<Version> {
+    let py_name = interpreter_name(settings);
+
+    if !&pyflow_dir.exists() && fs::create_dir_all(&pyflow_dir).is_err() {
+        util::abort("Problem creating the Pyflow directory")
+    }
+
+    let mut result = vec![];
+    for entry in pyflow_dir
+        .read_dir()
+        .expect("Can't open python installs path")
+    {
+        if let Ok(entry) = entry {
+            if !entry.path().is_dir() {
+                continue;
+            }
+            if !libc_compatible(&entry.path()) {
+                if util::in_container() {
+                    util::print_color(
+                        &format!(
+                            "Skipping {:?}, which was installed in a container with a different C \
+                             library than this one",
+                            entry.path()
+                        ),
+                        Color::DarkYellow,
+                    );
+                }
+                continue;
+            }
+
+            if let Some(v) = commands::find_py_version(entry.path().join(py_name).to_str().unwrap())
+            {
+                // Skip installs for other architectures and implementations, eg 32-bit ones if
+                // we're using 64-bit.
+                if entry.file_name().to_string_lossy() == install_dir_name(settings, &v) {
+                    result.push(v);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Check if an installed version matches one the user entered: its major and minor versions,
+/// and its patch version, if entered.
+fn matches_entered(installed: &Version, entered: &Version, entered_patch: bool) -> bool {
+    installed.major == entered.major
+        && installed.minor == entered.minor
+        && (!entered_patch || installed.patch == entered.patch)
+}
+
+/// Display the Python versions pyflow's installed, and those it can install. For
+/// `pyflow python list`.
+pub fn list_installed(settings: &Settings, pyflow_dir: &Path) {
+    let mut installed = find_installed_versions(settings, pyflow_dir);
+    installed.sort();
+    if installed.is_empty() {
+        println!("No Python versions are installed by pyflow");
+    } else {
+        util::print_color("Installed by pyflow:", Color::Cyan);
+        for v in installed.iter().rev() {
+            println!(
+                "  {}  {}",
+                v.to_string2(),
+                pyflow_dir.join(install_dir_name(settings, v)).display()
+            );
+        }
+    }
+
+    if standalone_triple(settings).is_some() {
+        let available: Vec<String> = STANDALONE_BUILDS
+            .iter()
+            .map(|((major, minor, patch), _)| format!("{}.{}.{}", major, minor, patch))
+            .collect();
+        util::print_color("Available to install:", Color::Cyan);
+        println!("  {}", available.join(", "));
+    }
+}
+
+/// Install a Python version, without a project. For `pyflow python install 3.11`.
+pub fn install(settings: &Settings, pyflow_dir: &Path, version: &Version, entered_patch: bool) {
+    if let Some(v) = find_installed_versions(settings, pyflow_dir)
+        .iter()
+        .find(|v| matches_entered(v, version, entered_patch))
+    {
+        util::print_color(
+            &format!("Python {} is already installed", v.to_string2()),
+            Color::Green,
+        );
+        return;
+    }
+
+    let installed = download(settings, pyflow_dir, version, &[]);
+    if entered_patch && installed != *version {
+        util::print_color(
+            &format!(
+                "Python {} isn't available, so we installed {}",
+                version.to_string2(),
+                installed.to_string2()
+            ),
+            Color::DarkYellow,
+        );
+    }
+    util::print_color(
+        &format!("Installed Python {}", installed.to_string2()),
+        Color::Green,
+    );
+}
+
+/// The latest build we can install with the same major and minor version as `version`.
+fn latest_build(settings: &Settings, version: &Version) -> Option<Version> {
+    let builds = if settings.implementation == util::Implementation::PyPy {
+        PYPY_BUILDS
+    } else if standalone_triple(settings).is_some() {
+        STANDALONE_BUILDS
+    } else {
+        return None;
+    };
+    builds
+        .iter()
+        .map(|((major, minor, patch), _)| Version::new(*major, *minor, *patch))
+        .find(|v| v.major == version.major && v.minor == version.minor)
+}
+
+/// Which installed version to make an environment for `version` with: with the `patch` policy,
+/// the latest patch version; with `exact`, the earliest, so installing a newer one doesn't move
+/// projects to it.
+fn choose_installed(
+    policy: UpdatePolicy,
+    installed: &[Version],
+    version: &Version,
+    constraints: &[Constraint],
+) -> Option<Version> {
+    let matching = installed.iter().filter(|iv| {
+        iv.major == version.major
+            && iv.minor == version.minor
+            && satisfies_constraints(constraints, iv)
+    });
+    match policy {
+        UpdatePolicy::Patch => matching.max(),
+        UpdatePolicy::Exact => matching.min(),
+    }
+    .copied()
+}
+
+/// Install the latest patch version of a Python version, if it's newer than those installed.
+/// Returns the version installed, or `None` if we already have the latest. For
+/// `pyflow python upgrade`.
+pub fn upgrade(
+    settings: &Settings,
+    pyflow_dir: &Path,
+    version: &Version,
+    constraints: &[Constraint],
+) -> Option<Version> {
+    if settings.update_policy == UpdatePolicy::Exact {
+        util::abort(
+            "This project's `python-update-policy` is `exact`, so its Python version isn't \
+             upgraded. Set it to `patch` to allow this.",
+        );
+    }
+
+    let installed = find_installed_versions(settings, pyflow_dir)
+        .into_iter()
+        .filter(|v| v.major == version.major && v.minor == version.minor)
+        .max();
+    let latest = match latest_build(settings, version) {
+        Some(v) => v,
+        None => {
+            util::abort(&format!(
+                "There's no build of Python {} for this platform to upgrade to",
+                version.to_string_no_patch()
+            ));
+            unreachable!()
+        }
+    };
+
+    match installed {
+        Some(v) if v >= latest => None,
+        _ => Some(download(settings, pyflow_dir, &latest, constraints)),
+    }
+}
+
+/// Remove a Python version pyflow installed, and its downloaded archive. For
+/// `pyflow python uninstall 3.9`.
+pub fn uninstall(settings: &Settings, pyflow_dir: &Path, version: &Version, entered_patch: bool) {
+    let matching: Vec<Version> = find_installed_versions(settings, pyflow_dir)
+        .into_iter()
+        .filter(|v| matches_entered(v, version, entered_patch))
+        .collect();
+    if matching.is_empty() {
+        util::abort(&format!(
+            "Python {} isn't installed by pyflow. Run `pyflow python list` to see the ones that are.",
+            if entered_patch {
+                version.to_string2()
+            } else {
+                version.to_string_no_patch()
+            }
+        ));
+    }
+
+    for v in &matching {
+        remove_install(
+            pyflow_dir,
+            &pyflow_dir.join(install_dir_name(settings, v)),
+            v,
+        );
+        util::print_color(
+            &format!("Uninstalled Python {}", v.to_string2()),
+            Color::Green,
+        );
+    }
+    refresh_shims(settings, pyflow_dir);
+    util::print_color(
+        "Projects whose environments used it will need them recreated, eg with `pyflow reset`",
+        Color::DarkBlue,
+    );
+}
+
+/// Remove an installed Python, and the archives it was installed from.
+fn remove_install(pyflow_dir: &Path, install_path: &Path, version: &Version) {
+    let vers_str = version.to_string2();
+    if fs::remove_dir_all(install_path).is_err() {
+        util::abort(&format!("Problem removing Python {}", vers_str));
+    }
+
+    // Eg `cpython-3.9.20+20241016-…tar.gz`, its checksum, or `python-3.7.4-ubuntu.tar.xz`.
+    let archive_prefixes = [
+        format!("cpython-{}+", vers_str),
+        format!("python-{}-", vers_str),
+    ];
+    if let Ok(entries) = pyflow_dir.read_dir() {
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_file() && archive_prefixes.iter().any(|p| name.starts_with(p)) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+    }
+}
+
+/// Lists the environments pyflow's made or used, one path per line, eg
+/// `/home/me/proj/__pypackages__/3.12`, so `pyflow python prune` knows which installs they use.
+const ENV_REGISTRY: &str = "environments.txt";
+
+fn read_env_registry(pyflow_dir: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(pyflow_dir.join(ENV_REGISTRY))
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn write_env_registry(pyflow_dir: &Path, envs: &[PathBuf]) {
+    let text: String = envs
+        .iter()
+        .map(|p| format!("{}\n", p.to_string_lossy()))
+        .collect();
+    fs::write(pyflow_dir.join(ENV_REGISTRY), text).ok();
+}
+
+/// Record an environment in the registry, if it isn't already.
+pub fn register_env(pyflow_dir: &Path, vers_path: &Path) {
+    let mut envs = read_env_registry(pyflow_dir);
+    if !envs.iter().any(|p| p == vers_path) {
+        envs.push(vers_path.to_owned());
+        write_env_registry(pyflow_dir, &envs);
+    }
+}
+
+/// The folder of the Python an environment was made with, from the `home` in its `pyvenv.cfg`;
+/// eg `~/.local/share/pyflow/python-3.12.7/bin`.
+pub fn env_home(vers_path: &Path) -> Option<PathBuf> {
+    let data = fs::read_to_string(vers_path.join(".venv").join("pyvenv.cfg")).ok()?;
+    data.lines().find_map(|l| {
+        let (key, value) = l.split_at(l.find('=')?);
+        if key.trim() == "home" {
+            let home = PathBuf::from(value[1..].trim());
+            Some(fs::canonicalize(&home).unwrap_or(home))
+        } else {
+            None
+        }
+    })
+}
+
+/// Remove installed Pythons that no environment in the registry uses, for `pyflow python prune`.
+/// Environments that no longer exist are dropped from the registry. With `dry_run`, only show
+/// what would be removed.
+pub fn prune(settings: &Settings, pyflow_dir: &Path, dry_run: bool) {
+    let envs: Vec<PathBuf> = read_env_registry(pyflow_dir)
+        .into_iter()
+        .filter(|p| p.join(".venv").exists())
+        .collect();
+    let homes: Vec<PathBuf> = envs.iter().filter_map(|p| env_home(p)).collect();
+    let unused = unused_installs(settings, pyflow_dir, &homes);
+
+    if unused.is_empty() {
+        util::print_color(
+            "Every Python pyflow's installed is used by an environment",
+            Color::Green,
+        );
+    }
+    for (install_path, managed) in &unused {
+        let name = shim_name(managed);
+        if dry_run {
+            println!(
+                "Would remove {} ({}), at {:?}",
+                name,
+                managed.version.to_string2(),
+                install_path
+            );
+        } else {
+            remove_install(pyflow_dir, install_path, &managed.version);
+            util::print_color(
+                &format!("Removed {} ({})", name, managed.version.to_string2()),
+                Color::Green,
+            );
+        }
+    }
+
+    if !dry_run {
+        write_env_registry(pyflow_dir, &envs);
+        if !unused.is_empty() {
+            refresh_shims(settings, pyflow_dir);
+        }
+    }
+}
+
+/// The installs no environment uses: those that none of `homes`, the interpreter folders from
+/// environments' `pyvenv.cfg`, are in.
+fn unused_installs(
+    settings: &Settings,
+    pyflow_dir: &Path,
+    homes: &[PathBuf],
+) -> Vec<(PathBuf, Managed)> {
+    let mut unused = vec![];
+    for managed in find_managed_interpreters(settings, pyflow_dir) {
+        let depth = Path::new(managed_interpreter(managed.implementation))
+            .components()
+            .count();
+        let install_path = match managed.interpreter.ancestors().nth(depth) {
+            Some(p) => p.to_owned(),
+            None => continue,
+        };
+        let canonical = fs::canonicalize(&install_path).unwrap_or_else(|_| install_path.clone());
+        if !homes.iter().any(|h| h.starts_with(&canonical)) {
+            unused.push((install_path, managed));
+        }
+    }
+    unused
+}
+
+/// Lists the shims we've made in pyflow's `bin` directory, so we only replace or remove those,
+/// not other tools installed there.
+const SHIM_MANIFEST: &str = ".python-shims";
+
+/// A Python installation pyflow's made.
+struct Managed {
+    implementation: util::Implementation,
+    variant: Variant,
+    version: Version,
+    interpreter: PathBuf,
+}
+
+/// Python installations pyflow's made for this architecture, of any implementation and variant.
+/// Found by folder name, eg `python-3.11.9`, so we don't need to run each one.
+fn find_managed_interpreters(settings: &Settings, pyflow_dir: &Path) -> Vec<Managed> {
+    let mut result = vec![];
+    let entries = match pyflow_dir.read_dir() {
+        Ok(e) => e,
+        Err(_) => return result,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let (implementation, variant, version, x86) = match parse_install_dir(&name) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if x86 != (host_arch(settings) == "x86") {
+            continue;
+        }
+
+        let path = entry.path().join(managed_interpreter(implementation));
+        if path.exists() {
+            result.push(Managed {
+                implementation,
+                variant,
+                version,
+                interpreter: path,
+            });
+        }
+    }
+    result
 }
 
-#[derive(Debug)]
-pub struct AliasError {
-    pub details: String,
+/// Parse an install folder's name, eg `python-3.13.1-freethreaded-x86`: its implementation,
+/// variant, version, and whether it's a 32-bit build.
+fn parse_install_dir(name: &str) -> Option<(util::Implementation, Variant, Version, bool)> {
+    let (implementation, rest) = if let Some(r) = name.strip_prefix("python-") {
+        (util::Implementation::CPython, r)
+    } else if let Some(r) = name.strip_prefix("pypy-") {
+        (util::Implementation::PyPy, r)
+    } else {
+        return None;
+    };
+    let (rest, x86) = match rest.strip_suffix("-x86") {
+        Some(r) => (r, true),
+        None => (rest, false),
+    };
+    let (rest, variant) = match rest.rfind('-') {
+        Some(i) => (&rest[..i], Variant::from_str(&rest[i + 1..]).ok()?),
+        None => (rest, Variant::Default),
+    };
+    Some((implementation, variant, Version::from_str(rest).ok()?, x86))
 }
 
-impl Error for AliasError {
-    fn description(&self) -> &str {
-        &self.details
+/// The interpreter's path in an install folder for `implementation`.
+fn managed_interpreter(implementation: util::Implementation) -> &'static str {
+    match (implementation, cfg!(target_os = "windows")) {
+        (util::Implementation::CPython, true) => "python.exe",
+        (util::Implementation::CPython, false) => "bin/python3",
+        (util::Implementation::PyPy, true) => "pypy3.exe",
+        (util::Implementation::PyPy, false) => "bin/pypy3",
     }
 }
 
-impl fmt::Display for AliasError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.details)
+/// The folder prefix archives are unpacked in, before the installation's moved into place.
+const STAGING_PREFIX: &str = ".unpacking";
+
+/// A folder in `py_install_path` to unpack an archive in, named by our process id so a
+/// concurrent pyflow doesn't mistake it for one left behind. Since it's on the same filesystem
+/// as the installs, moving the installation from it into place is atomic: an install folder is
+/// either complete, or not there.
+fn create_staging(py_install_path: &Path) -> PathBuf {
+    let staging = py_install_path.join(format!("{}-{}", STAGING_PREFIX, process::id()));
+    fs::remove_dir_all(&staging).ok();
+    if fs::create_dir_all(&staging).is_err() {
+        util::abort("Problem creating a folder to unpack the Python archive in")
     }
+    staging
 }
 
-/// Make an educated guess at the command needed to execute python the
-/// current system.  An alternative approach is trying to find python
-/// installations.
-pub fn find_py_aliases(version: &Version) -> Vec<(String, Version)> {
-    let possible_aliases = &[
-        "python3.19",
-        "python3.18",
-        "python3.17",
-        "python3.16",
-        "python3.15",
-        "python3.14",
-        "python3.13",
-        "python3.12",
-        "python3.11",
-        "python3.10",
-        "python3.9",
-        "python3.8",
-        "python3.7",
-        "python3.6",
-        "python3.5",
-        "python3.4",
-        "python3.3",
-        "python3.2",
-        "python3.1",
-        "python3",
-        "python",
-        "python2",
-    ];
+/// Remove what an interrupted or failed install left behind: folders archives were being
+/// unpacked in, and install folders without an interpreter, eg from older versions of pyflow,
+/// which unpacked in place. Otherwise, they'd be mistaken for installs, or block reinstalling.
+pub fn clean_partial_installs(pyflow_dir: &Path) {
+    let entries = match pyflow_dir.read_dir() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
 
-    let mut result = Vec::new();
-    let mut found_dets = Vec::new();
+        if let Some(rest) = name.strip_prefix(STAGING_PREFIX) {
+            let owner = rest.strip_prefix('-').and_then(|p| p.parse::<u32>().ok());
+            match owner {
+                Some(pid) if pid != process::id() && journal::is_running(pid) => (),
+                _ => {
+                    fs::remove_dir_all(&path).ok();
+                }
+            }
+            continue;
+        }
 
-    for alias in possible_aliases {
-        // We use the --version command as a quick+effective way to determine if
-        // this command is associated with Python.
-        let dets = commands::find_py_dets(alias);
-        if let Some(v) = commands::find_py_version(alias) {
-            if v.major == version.major && v.minor == version.minor && !found_dets.contains(&dets) {
-                result.push((alias.to_string(), v));
-                found_dets.push(dets);
+        if let Some((implementation, _, version, _)) = parse_install_dir(&name) {
+            if !path.join(managed_interpreter(implementation)).exists() {
+                util::print_color(
+                    &format!(
+                        "Removing an incomplete install of Python {}, at {:?}",
+                        version.to_string2(),
+                        path
+                    ),
+                    Color::DarkYellow,
+                );
+                if fs::remove_dir_all(&path).is_err() {
+                    util::print_color(
+                        &format!("Problem removing {:?}; remove it manually", path),
+                        Color::DarkYellow,
+                    );
+                }
             }
         }
     }
-    result
 }
 
-// Find versions installed with this tool.
-fn find_installed_versions(pyflow_dir: &Path) -> Vec<Version> {
-    #[cfg(target_os = "windows")]
-    let py_name = "python";
-    #[cfg(target_os = "linux")]
-    let py_name = "bin/python3";
-    #[cfg(target_os = "macos")]
-    let py_name = "bin/python3";
+/// A shim's name, eg `python3.11`, `python3.13t`, or `pypy3.10`.
+fn shim_name(managed: &Managed) -> String {
+    let prefix = match managed.implementation {
+        util::Implementation::CPython => "python",
+        util::Implementation::PyPy => "pypy",
+    };
+    format!(
+        "{}{}{}",
+        prefix,
+        managed.version.to_string_no_patch(),
+        managed.variant.abi_suffix()
+    )
+}
 
-    if !&pyflow_dir.exists() && fs::create_dir_all(&pyflow_dir).is_err() {
-        util::abort("Problem creating the Pyflow directory")
+/// Make version-named shims in pyflow's `bin` directory, eg `python3.11`, each running the latest
+/// patch version of it we've installed. Shims for versions that have been uninstalled are
+/// removed. For `pyflow python shims`.
+pub fn write_shims(settings: &Settings, pyflow_dir: &Path, report: bool) {
+    let bin_path = pyflow_dir.join("bin");
+    if fs::create_dir_all(&bin_path).is_err() {
+        util::abort(&format!("Problem creating {:?}", bin_path));
     }
-
-    let mut result = vec![];
-    for entry in pyflow_dir
-        .read_dir()
-        .expect("Can't open python installs path")
+    let manifest_path = bin_path.join(SHIM_MANIFEST);
+    for old in fs::read_to_string(&manifest_path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| !l.is_empty() && !l.contains(|c| c == '/' || c == '\\'))
     {
-        if let Ok(entry) = entry {
-            if !entry.path().is_dir() {
-                continue;
+        fs::remove_file(bin_path.join(old)).ok();
+    }
+
+    let mut latest: HashMap<String, (Version, PathBuf)> = HashMap::new();
+    for managed in find_managed_interpreters(settings, pyflow_dir) {
+        let name = shim_name(&managed);
+        if latest
+            .get(&name)
+            .map_or(true, |(v, _)| managed.version > *v)
+        {
+            latest.insert(name, (managed.version, managed.interpreter));
+        }
+    }
+    let mut shims: Vec<(String, Version, PathBuf)> =
+        latest.into_iter().map(|(n, (v, p))| (n, v, p)).collect();
+    shims.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut written = vec![];
+    for (name, version, interpreter) in &shims {
+        #[cfg(target_os = "windows")]
+        let (file_name, result) = {
+            let file_name = format!("{}.cmd", name);
+            let script = format!("@\"{}\" %*\r\n", interpreter.display());
+            let result = fs::write(bin_path.join(&file_name), script);
+            (file_name, result)
+        };
+        // A symlink, rather than a script, so the interpreter finds its installation from it.
+        #[cfg(not(target_os = "windows"))]
+        let (file_name, result) = (
+            name.clone(),
+            std::os::unix::fs::symlink(interpreter, bin_path.join(name)),
+        );
+
+        match result {
+            Ok(_) => {
+                if report {
+                    println!(
+                        "  {}  Python {}",
+                        bin_path.join(&file_name).display(),
+                        version.to_string2()
+                    );
+                }
+                written.push(file_name);
             }
+            Err(e) => util::print_color(
+                &format!("Problem creating the shim {}: {}", file_name, e),
+                Color::Red,
+            ),
+        }
+    }
 
-            if let Some(v) = commands::find_py_version(entry.path().join(py_name).to_str().unwrap())
-            {
-                result.push(v);
+    if fs::write(&manifest_path, written.join("\n")).is_err() {
+        util::abort(&format!("Problem writing {:?}", manifest_path));
+    }
+    if report {
+        if written.is_empty() {
+            println!("No Python versions are installed by pyflow, so there are no shims yet");
+        }
+        util::print_color(
+            "Shims are updated as Python versions are installed and uninstalled",
+            Color::DarkBlue,
+        );
+    }
+}
+
+/// Update the shims, if they've been made.
+fn refresh_shims(settings: &Settings, pyflow_dir: &Path) {
+    if pyflow_dir.join("bin").join(SHIM_MANIFEST).exists() {
+        write_shims(settings, pyflow_dir, false);
+    }
+}
+
+/// Run a Python version pyflow's installed, outside any project, with `args`, and exit with its
+/// exit code. For `pyflow python exec 3.10 -- script.py`.
+pub fn exec(
+    settings: &Settings,
+    pyflow_dir: &Path,
+    version: &Version,
+    entered_patch: bool,
+    args: &[String],
+) {
+    let implementation = settings.implementation;
+    let found = find_managed_interpreters(settings, pyflow_dir)
+        .into_iter()
+        .filter(|m| {
+            m.implementation == implementation
+                && m.variant == settings.variant
+                && matches_entered(&m.version, version, entered_patch)
+        })
+        .max_by(|a, b| a.version.cmp(&b.version));
+    let interpreter = match found {
+        Some(m) => m.interpreter,
+        None => {
+            let mut entered = if entered_patch {
+                version.to_string2()
+            } else {
+                version.to_string_no_patch()
+            };
+            if implementation == util::Implementation::PyPy {
+                entered = format!("pypy{}", entered);
             }
+            util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "Python {} isn't installed by pyflow. Install it with \
+                     `pyflow python install {}`",
+                    entered, entered
+                ),
+            );
+            unreachable!()
+        }
+    };
+
+    match std::process::Command::new(&interpreter).args(args).status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            util::abort(&format!("Problem running {:?}: {}", interpreter, e));
         }
     }
-    result
 }
 
 /// Create a new virtual environment, and install `wheel`.
 pub fn create_venv(
+    settings: &Settings,
     cfg_v: &Version,
+    constraints: &[Constraint],
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    temp_root: &Path,
 ) -> Version {
-    let os;
     let python_name;
     #[allow(unused_mut)]
     let mut py_name;
     #[cfg(target_os = "windows")]
     {
-        py_name = "python".to_string();
-        os = Os::Windows;
+        py_name = interpreter_name(settings).to_string();
         python_name = "python.exe";
     }
     #[cfg(target_os = "linux")]
     {
-        py_name = "bin/python3".to_string();
-        os = Os::Ubuntu;
+        py_name = interpreter_name(settings).to_string();
         python_name = "python";
     }
     #[cfg(target_os = "macos")]
     {
-        py_name = "bin/python3".to_string();
-        os = Os::Mac;
+        py_name = interpreter_name(settings).to_string();
         python_name = "python";
     }
 
@@ -403,16 +2535,99 @@ pub fn create_venv(
     let mut alias_path = None;
     let mut py_ver = None;
 
+    // An interpreter the user picked overrides any we'd find.
+    if let Some(chosen) = settings.alias.clone() {
+        match commands::find_py_version(&chosen) {
+            Some(v) if !satisfies_constraints(constraints, &v) => util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "`{}` is Python {}, which doesn't satisfy this project's `py_version`, `{}`",
+                    chosen,
+                    v.to_string2(),
+                    constraints_text(constraints)
+                ),
+            ),
+            Some(v) if v.major == cfg_v.major && v.minor == cfg_v.minor => {
+                alias = Some(chosen);
+                py_ver = Some(v);
+            }
+            Some(v) => util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "`{}` is Python {}, but this project uses Python {}",
+                    chosen,
+                    v.to_string_no_patch(),
+                    cfg_v.to_string_no_patch()
+                ),
+            ),
+            None => util::abort_with(
+                util::ExitCode::MissingInterpreter,
+                &format!(
+                    "Can't run `{}`, from `--py-alias` or `PYFLOW_PYTHON`. Is it a Python \
+                     interpreter on the `PATH`, or its full path?",
+                    chosen
+                ),
+            ),
+        }
+    }
+
+    // Otherwise, use the one picked last time.
+    let state_path = state_path(pypackages_dir);
+    if py_ver.is_none() {
+        if let Some(remembered) = read_state(&state_path)
+            .python_aliases
+            .get(&alias_key(settings, cfg_v))
+        {
+            match commands::find_py_version(remembered) {
+                Some(v) if v.major == cfg_v.major && v.minor == cfg_v.minor => {
+                    if satisfies_constraints(constraints, &v) {
+                        alias = Some(remembered.clone());
+                        py_ver = Some(v);
+                    } else {
+                        util::print_color(
+                            &format!(
+                                "`{}`, which this project used before, is Python {}, which \
+                                 doesn't satisfy `py_version`; finding another",
+                                remembered,
+                                v.to_string2()
+                            ),
+                            Color::DarkYellow,
+                        );
+                    }
+                }
+                _ => util::print_color(
+                    &format!(
+                        "`{}`, which this project used before, is no longer Python {}; finding \
+                         another",
+                        remembered,
+                        cfg_v.to_string_no_patch()
+                    ),
+                    Color::DarkYellow,
+                ),
+            }
+        }
+    }
+
     // If we find both a system alias, and internal version installed, go with the internal.
     // One's this tool installed
-    let installed_versions = find_installed_versions(pyflow_dir);
-    for iv in &installed_versions {
-        if iv.major == cfg_v.major && iv.minor == cfg_v.minor {
-            let folder_name = format!("python-{}", iv.to_string2());
-            alias_path = Some(pyflow_dir.join(folder_name).join(&py_name));
-            py_ver = Some(*iv);
-            break;
-        }
+    let installed_versions = if py_ver.is_none() {
+        find_installed_versions(settings, pyflow_dir)
+    } else {
+        vec![]
+    };
+    let chosen = choose_installed(
+        settings.update_policy,
+        &installed_versions,
+        cfg_v,
+        constraints,
+    );
+    if let Some(iv) = chosen {
+        alias_path = Some(
+            pyflow_dir
+                .join(install_dir_name(settings, &iv))
+                .join(&py_name),
+        );
+        py_ver = Some(iv);
     }
 
     // todo perhaps move alias finding back into create_venv, or make a
@@ -420,8 +2635,10 @@ pub fn create_venv(
     // Only search for a system Python if we don't have an internal one.
     // todo: Why did we choose to prioritize portable over system? Perhaps do the
     // todo other way around.
-    if py_ver.is_none() {
-        let aliases = find_py_aliases(cfg_v);
+    // We can't tell which system interpreters are debug or free-threaded builds, so only use
+    // ones we've installed for those.
+    if py_ver.is_none() && settings.variant == Variant::Default {
+        let aliases = find_py_aliases(settings, cfg_v, constraints);
         match aliases.len() {
             0 => (),
             1 => {
@@ -429,6 +2646,22 @@ pub fn create_venv(
                 alias = Some(r.0);
                 py_ver = Some(r.1);
             }
+            // There's no one to ask, eg in CI; use the latest patch version.
+            _ if !atty::is(atty::Stream::Stdin) => {
+                let latest = aliases.iter().map(|a| a.1).max().unwrap();
+                let r = aliases.iter().find(|a| a.1 == latest).cloned().unwrap();
+                util::print_color(
+                    &format!(
+                        "Found multiple compatible Python versions; using {} ({}). Pick one \
+                         with `--py-alias` or `PYFLOW_PYTHON`.",
+                        r.0,
+                        r.1.to_string2()
+                    ),
+                    Color::DarkBlue,
+                );
+                alias = Some(r.0);
+                py_ver = Some(r.1);
+            }
             _ => {
                 //                let r = prompt_alias(&aliases);
                 let r = util::prompt_list(
@@ -436,6 +2669,7 @@ pub fn create_venv(
                     "Python alias",
                     &aliases,
                     true,
+                    settings.prompt_timeout,
                 );
                 alias = Some(r.0);
                 py_ver = Some(r.1);
@@ -446,18 +2680,18 @@ pub fn create_venv(
     if py_ver.is_none() {
         // Download and install the appropriate Python binary, if we can't find either a
         // custom install, or on the Path.
-        download(pyflow_dir, cfg_v);
-        let py_ver2: PyVers = (*cfg_v, os).into();
-        py_ver = Some(py_ver2.to_vers());
+        let installed = download(settings, pyflow_dir, cfg_v, constraints);
+        py_ver = Some(installed);
 
-        let folder_name = format!("python-{}", py_ver2.to_string());
+        let folder_name = install_dir_name(settings, &installed);
 
         // We appear to have symlink issues on some builds, where `python3` won't work, but
         // `python3.7` (etc) will. Note that this is no longer applicable once the venv is built,
         // and we're using its `python`.
         #[cfg(target_os = "linux")]
-        {
+        if settings.implementation == util::Implementation::CPython {
             match py_ver.unwrap().minor {
+                13 => py_name += ".13",
                 12 => py_name += ".12",
                 11 => py_name += ".11",
                 10 => py_name += ".10",
@@ -469,6 +2703,7 @@ pub fn create_venv(
                 4 => py_name += ".4",
                 _ => panic!("Invalid python minor version"),
             }
+            py_name += settings.variant.abi_suffix();
         }
 
         alias_path = Some(pyflow_dir.join(folder_name).join(py_name));
@@ -493,6 +2728,7 @@ pub fn create_venv(
 
     // For an alias on the PATH
     if let Some(alias) = alias {
+        remember_alias(settings, &state_path, cfg_v, &alias);
         if commands::create_venv(&alias, &lib_path, ".venv").is_err() {
             util::abort("Problem creating virtual environment");
         }
@@ -508,6 +2744,23 @@ pub fn create_venv(
     util::wait_for_dirs(&[bin_path.join(python_name)])
         .expect("Timed out waiting for venv to be created.");
 
+    // Record the variant, so we pick wheels for it, and notice if `py_variant` changes.
+    if settings.variant != Variant::Default {
+        let cfg_path = vers_path.join(".venv").join("pyvenv.cfg");
+        let recorded = fs::OpenOptions::new()
+            .append(true)
+            .open(&cfg_path)
+            .and_then(|mut f| {
+                io::Write::write_all(
+                    &mut f,
+                    format!("pyflow-variant = {}\n", settings.variant.name()).as_bytes(),
+                )
+            });
+        if recorded.is_err() {
+            util::abort(&format!("Problem writing {:?}", cfg_path));
+        }
+    }
+
     // Try 64 first; if not, use 32.
     #[allow(unused_variables)]
     let lib = if vers_path.join(".venv").join("lib64").exists() {
@@ -533,6 +2786,7 @@ pub fn create_venv(
             .join("site-packages"),
         entry_pt: bin_path,
         cache: dep_cache_path.to_owned(),
+        temp: temp_root.to_owned(),
     };
 
     // We need `wheel` installed to build wheels from source.
@@ -549,8 +2803,505 @@ pub fn create_venv(
         &paths,
         install::PackageType::Wheel,
         &None,
+        &settings.net,
+        settings.prompt_timeout,
     )
-    .expect("Problem installing `wheel`");
+    .unwrap_or_else(|e| {
+        util::abort_with(
+            util::ExitCode::Network,
+            &format!("Problem installing `wheel`: {}", e),
+        )
+    });
 
     py_ver
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn pick(py_version: &str, candidates: &[Version]) -> Option<Version> {
+        let constraints = Constraint::from_str_multiple(py_version).unwrap();
+        highest_satisfying(&constraints, candidates)
+    }
+
+    /// A script at `path` that reports `version` like Python's `--version` does.
+    #[cfg(unix)]
+    fn fake_python(path: &Path, version: &str) {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("#!/bin/sh\necho \"Python {}\"\n", version)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn signatures_required() {
+        let key = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+        // By default, only if the publisher signs its builds.
+        assert!(Signing::new(&[], None).required(Some(key)));
+        assert!(!Signing::new(&[], None).required(None));
+        // The config overrides that either way.
+        assert!(!Signing::new(&[], Some(false)).required(Some(key)));
+        assert!(Signing::new(&[key.to_owned()], Some(true)).required(None));
+
+        for (base, key) in PUBLISHER_KEYS {
+            assert!(minisign::PublicKey::decode(key).is_ok(), "{}", base);
+        }
+    }
+
+    #[test]
+    fn pypy_checksums() {
+        for ((major, minor, _), release) in PYPY_BUILDS {
+            for platform in &["linux64", "aarch64", "macos_x86_64", "macos_arm64"] {
+                let name = format!("pypy{}.{}-v{}-{}.tar.bz2", major, minor, release, platform);
+                assert!(known_sha256(&name).is_some(), "{}", name);
+            }
+        }
+        assert!(KNOWN_SHA256
+            .iter()
+            .all(|(_, s)| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())));
+        assert_eq!(known_sha256("python-3.7.4-ubuntu.tar.xz"), None);
+    }
+
+    #[test]
+    fn standalone_assets() {
+        assert_eq!(
+            triple_for("linux", "x86_64", false),
+            Some("x86_64-unknown-linux-gnu")
+        );
+        assert_eq!(
+            triple_for("macos", "x86_64", false),
+            Some("x86_64-apple-darwin")
+        );
+        assert_eq!(
+            triple_for("windows", "x86_64", false),
+            Some("x86_64-pc-windows-msvc")
+        );
+        assert_eq!(triple_for("freebsd", "x86_64", false), None);
+
+        assert_eq!(
+            standalone_archive_name(
+                &Version::new(3, 12, 7),
+                "20241016",
+                "x86_64-unknown-linux-gnu",
+                Variant::Default
+            ),
+            "cpython-3.12.7+20241016-x86_64-unknown-linux-gnu-install_only.tar.gz"
+        );
+        // One build for each minor version, so there's no question which to download.
+        for (i, ((major, minor, _), _)) in STANDALONE_BUILDS.iter().enumerate() {
+            assert!(!STANDALONE_BUILDS[i + 1..]
+                .iter()
+                .any(|((ma, mi, _), _)| ma == major && mi == minor));
+        }
+    }
+
+    #[test]
+    fn arm64_triples() {
+        assert_eq!(
+            triple_for("linux", "aarch64", false),
+            Some("aarch64-unknown-linux-gnu")
+        );
+        assert_eq!(
+            triple_for("macos", "aarch64", false),
+            Some("aarch64-apple-darwin")
+        );
+        assert_eq!(triple_for("windows", "aarch64", false), None);
+    }
+
+    #[test]
+    fn win32_builds() {
+        assert_eq!(parse_arch("win32"), "x86");
+        assert_eq!(parse_arch("AMD64"), "x86_64");
+        assert_eq!(parse_arch("arm64"), "aarch64");
+
+        let settings = Settings {
+            arch: Some("x86"),
+            ..Default::default()
+        };
+        assert_eq!(host_arch(&settings), "x86");
+        assert_eq!(
+            install_dir_name(&settings, &Version::new(3, 12, 7)),
+            "python-3.12.7-x86"
+        );
+        assert_eq!(
+            triple_for("windows", "x86", false),
+            Some("i686-pc-windows-msvc")
+        );
+        assert_eq!(triple_for("linux", "x86", false), None);
+    }
+
+    #[test]
+    fn musl_builds() {
+        let lib = util::TempDir::new("musl");
+        fs::write(lib.path().join("ld-linux-x86-64.so.2"), "").unwrap();
+        assert!(!has_musl_loader(lib.path()));
+        fs::write(lib.path().join("ld-musl-x86_64.so.1"), "").unwrap();
+        assert!(has_musl_loader(lib.path()));
+
+        assert_eq!(
+            triple_for("linux", "x86_64", true),
+            Some("x86_64-unknown-linux-musl")
+        );
+        // There are no musl builds for ARM, and glibc ones won't run.
+        assert_eq!(triple_for("linux", "aarch64", true), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pyenv_discovery() {
+        let versions = util::TempDir::new("pyenv");
+        let dir = versions.path();
+        fake_python(&dir.join("3.11.4/bin/python3"), "3.11.4");
+        fake_python(&dir.join("3.11.9/bin/python3"), "3.11.9");
+        fake_python(&dir.join("3.12.7/bin/python3"), "3.12.7");
+        fake_python(&dir.join("pypy3.11-7.3.19/bin/pypy3"), "3.11.11");
+        // Eg a virtualenv made with pyenv-virtualenv, or a failed build.
+        fake_python(&dir.join("tools/bin/python3"), "3.11.9");
+        fs::create_dir_all(dir.join("3.11.2")).unwrap();
+
+        let found = |settings: &Settings| -> Vec<(String, Version)> {
+            pyenv_interpreters_in(settings, dir, &Version::new(3, 11, 0))
+        };
+        let cpython = found(&Settings::default());
+        assert_eq!(
+            cpython,
+            vec![
+                (
+                    dir.join("3.11.9/bin/python3").to_string_lossy().to_string(),
+                    Version::new(3, 11, 9)
+                ),
+                (
+                    dir.join("3.11.4/bin/python3").to_string_lossy().to_string(),
+                    Version::new(3, 11, 4)
+                ),
+            ]
+        );
+
+        let pypy = Settings {
+            implementation: util::Implementation::PyPy,
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&pypy),
+            vec![(
+                dir.join("pypy3.11-7.3.19/bin/pypy3")
+                    .to_string_lossy()
+                    .to_string(),
+                Version::new(3, 11, 11)
+            )]
+        );
+    }
+
+    #[test]
+    fn registry_entries() {
+        assert!(registry_tag_matches("3.11", false, "x86_64"));
+        assert!(!registry_tag_matches("3.11-32", false, "x86_64"));
+        assert!(!registry_tag_matches("3.11-arm64", false, "x86_64"));
+        assert!(registry_tag_matches("3.11-32", false, "x86"));
+        assert!(!registry_tag_matches("3.11", false, "x86"));
+        assert!(registry_tag_matches("3.12-arm64", false, "aarch64"));
+        // 32-bit installs for all users aren't tagged as such.
+        assert!(registry_tag_matches("3.11", true, "x86"));
+        assert!(!registry_tag_matches("3.11", true, "x86_64"));
+
+        let exe = r"C:\Python311\python.exe";
+        assert_eq!(
+            registered_executable(Some(exe.into()), Some(r"C:\Other".into())),
+            Some(PathBuf::from(exe))
+        );
+        assert_eq!(
+            registered_executable(Some(String::new()), Some(r"C:\Python311".into())),
+            Some(PathBuf::from(r"C:\Python311").join("python.exe"))
+        );
+        assert_eq!(registered_executable(None, None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn conda_discovery() {
+        assert_eq!(
+            parse_conda_envs("/opt/conda\n\n  /home/me/.conda/envs/ml  \n"),
+            vec![
+                PathBuf::from("/opt/conda"),
+                PathBuf::from("/home/me/.conda/envs/ml")
+            ]
+        );
+
+        let conda = util::TempDir::new("conda");
+        let envs: Vec<PathBuf> = ["base", "ml", "web", "removed"]
+            .iter()
+            .map(|e| conda.path().join(e))
+            .collect();
+        fake_python(&envs[0].join("bin/python3"), "3.12.7");
+        fake_python(&envs[1].join("bin/python3"), "3.11.4");
+        fake_python(&envs[2].join("bin/python3"), "3.11.9");
+        assert_eq!(
+            conda_interpreters_in(&envs, &Version::new(3, 11, 0)),
+            vec![
+                (
+                    envs[2].join("bin/python3").to_string_lossy().to_string(),
+                    Version::new(3, 11, 9)
+                ),
+                (
+                    envs[1].join("bin/python3").to_string_lossy().to_string(),
+                    Version::new(3, 11, 4)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn update_policy() {
+        assert_eq!(UpdatePolicy::default(), UpdatePolicy::Patch);
+        assert_eq!(
+            UpdatePolicy::from_str("exact").unwrap(),
+            UpdatePolicy::Exact
+        );
+        assert!(UpdatePolicy::from_str("latest").is_err());
+
+        let installed = [
+            Version::new(3, 11, 9),
+            Version::new(3, 11, 4),
+            Version::new(3, 12, 7),
+            Version::new(3, 11, 6),
+        ];
+        let choose = |policy, constraints: &str| {
+            let constraints = Constraint::from_str_multiple(constraints).unwrap();
+            choose_installed(policy, &installed, &Version::new(3, 11, 0), &constraints)
+        };
+        assert_eq!(
+            choose(UpdatePolicy::Patch, ">=3.11"),
+            Some(Version::new(3, 11, 9))
+        );
+        assert_eq!(
+            choose(UpdatePolicy::Exact, ">=3.11"),
+            Some(Version::new(3, 11, 4))
+        );
+        assert_eq!(
+            choose(UpdatePolicy::Exact, ">=3.11.5"),
+            Some(Version::new(3, 11, 6))
+        );
+        assert_eq!(choose(UpdatePolicy::Patch, ">3.11.9,<3.12"), None);
+
+        // PyPy's builds are the same on every platform we install it on.
+        let pypy = Settings {
+            implementation: util::Implementation::PyPy,
+            ..Default::default()
+        };
+        assert_eq!(
+            latest_build(&pypy, &Version::new(3, 10, 0)),
+            Some(Version::new(3, 10, 14))
+        );
+        assert_eq!(latest_build(&pypy, &Version::new(3, 7, 0)), None);
+    }
+
+    #[test]
+    fn variant_builds() {
+        assert_eq!(
+            Variant::from_str("free-threaded").unwrap(),
+            Variant::Freethreaded
+        );
+        assert!(Variant::from_str("nogil").is_err());
+
+        let v = Version::new(3, 13, 0);
+        let settings = |variant| Settings {
+            variant,
+            ..Default::default()
+        };
+        assert_eq!(variant_abi(&settings(Variant::Default), &v), None);
+        assert_eq!(
+            variant_abi(&settings(Variant::Freethreaded), &v),
+            Some("cp313t".into())
+        );
+        assert_eq!(
+            variant_abi(&settings(Variant::Debug), &v),
+            Some("cp313d".into())
+        );
+        let pypy = Settings {
+            implementation: util::Implementation::PyPy,
+            ..settings(Variant::Debug)
+        };
+        assert_eq!(variant_abi(&pypy, &v), None);
+
+        assert_eq!(
+            install_dir_name(&settings(Variant::Freethreaded), &v),
+            "python-3.13.0-freethreaded"
+        );
+        assert_eq!(alias_key(&settings(Variant::Freethreaded), &v), "3.13t");
+        assert_eq!(
+            standalone_archive_name(&v, "20241016", "aarch64-apple-darwin", Variant::Debug),
+            "cpython-3.13.0+20241016-aarch64-apple-darwin-debug-full.tar.zst"
+        );
+    }
+
+    #[test]
+    fn pinned_versions() {
+        let dir = util::TempDir::new("pinned");
+        let nested = dir.path().join("services").join("api");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_pinned(&nested), None);
+
+        // It's found in a parent, skipping entries we don't understand.
+        fs::write(dir.path().join(PIN_FILENAME), "system\n3.11\n").unwrap();
+        assert_eq!(
+            find_pinned(&nested),
+            Some((util::Implementation::CPython, Version::new_short(3, 11)))
+        );
+
+        // The nearest wins.
+        pin(&nested, "pypy3.10");
+        assert_eq!(
+            fs::read_to_string(nested.join(PIN_FILENAME)).unwrap(),
+            "pypy3.10\n"
+        );
+        assert_eq!(
+            find_pinned(&nested),
+            Some((util::Implementation::PyPy, Version::new_short(3, 10)))
+        );
+    }
+
+    #[test]
+    fn install_dir_names() {
+        assert_eq!(
+            parse_install_dir("python-3.12.7"),
+            Some((
+                util::Implementation::CPython,
+                Variant::Default,
+                Version::new(3, 12, 7),
+                false
+            ))
+        );
+        assert_eq!(
+            parse_install_dir("python-3.13.1-freethreaded-x86"),
+            Some((
+                util::Implementation::CPython,
+                Variant::Freethreaded,
+                Version::new(3, 13, 1),
+                true
+            ))
+        );
+        assert_eq!(
+            parse_install_dir("pypy-3.10.14"),
+            Some((
+                util::Implementation::PyPy,
+                Variant::Default,
+                Version::new(3, 10, 14),
+                false
+            ))
+        );
+        assert_eq!(parse_install_dir("python-3.12.7-nogil"), None);
+        assert_eq!(parse_install_dir("script-envs"), None);
+        assert_eq!(parse_install_dir(".unpacking-1234"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn partial_installs_cleaned() {
+        let dir = util::TempDir::new("python-installs");
+        let installs = dir.path();
+        let pid_of = |cmd: &str| {
+            let child = process::Command::new(cmd).arg("10").spawn().unwrap();
+            let pid = child.id();
+            (child, pid)
+        };
+        let (mut running, running_pid) = pid_of("sleep");
+        let (mut finished, stale_pid) = pid_of("true");
+        finished.wait().unwrap();
+
+        let complete = installs.join("python-3.12.7");
+        fs::create_dir_all(complete.join("bin")).unwrap();
+        fs::write(complete.join("bin/python3"), "").unwrap();
+        let incomplete = installs.join("python-3.11.9");
+        fs::create_dir_all(incomplete.join("lib")).unwrap();
+        let other = installs.join("script-envs");
+        fs::create_dir_all(&other).unwrap();
+        let stale = installs.join(format!("{}-{}", STAGING_PREFIX, stale_pid));
+        let in_use = installs.join(format!("{}-{}", STAGING_PREFIX, running_pid));
+        for staging in &[&stale, &in_use] {
+            fs::create_dir_all(staging.join("python")).unwrap();
+        }
+
+        clean_partial_installs(installs);
+        running.kill().ok();
+        running.wait().ok();
+
+        assert!(complete.exists());
+        assert!(!incomplete.exists());
+        assert!(other.exists());
+        assert!(!stale.exists());
+        // Another pyflow's still unpacking there.
+        assert!(in_use.exists());
+    }
+
+    #[test]
+    fn prune_selection() {
+        let dir = util::TempDir::new("prune");
+        let installs = dir.path().join("pyflow");
+        let interpreter = |folder: &str, implementation| {
+            let path = installs
+                .join(folder)
+                .join(managed_interpreter(implementation));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        };
+        interpreter("python-3.11.9", util::Implementation::CPython);
+        interpreter("python-3.12.7", util::Implementation::CPython);
+        interpreter("pypy-3.10.14", util::Implementation::PyPy);
+        // Another architecture's aren't ours to prune.
+        interpreter("python-3.12.7-x86", util::Implementation::CPython);
+
+        // An environment made with 3.12.7.
+        let env = dir.path().join("proj/__pypackages__/3.12");
+        fs::create_dir_all(env.join(".venv")).unwrap();
+        let home = installs.join("python-3.12.7").join("bin");
+        fs::write(
+            env.join(".venv/pyvenv.cfg"),
+            format!("home = {}\nversion = 3.12.7\n", home.display()),
+        )
+        .unwrap();
+        let homes: Vec<PathBuf> = env_home(&env).into_iter().collect();
+        assert_eq!(homes, vec![fs::canonicalize(&home).unwrap()]);
+
+        let settings = Settings {
+            arch: Some("x86_64"),
+            ..Default::default()
+        };
+        let mut unused: Vec<String> = unused_installs(&settings, &installs, &homes)
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        unused.sort();
+        assert_eq!(unused, vec!["pypy-3.10.14", "python-3.11.9"]);
+    }
+
+    #[test]
+    fn version_from_constraints() {
+        let candidates = [
+            Version::new(3, 8, 20),
+            Version::new(3, 9, 20),
+            Version::new(3, 10, 15),
+            Version::new(3, 11, 10),
+            Version::new(3, 12, 7),
+        ];
+
+        assert_eq!(pick(">=3.9", &candidates), Some(Version::new(3, 12, 7)));
+        // Not the version in the constraint, which it excludes.
+        assert_eq!(pick("<3.10", &candidates), Some(Version::new(3, 9, 20)));
+        assert_eq!(pick("!=3.12.7", &candidates), Some(Version::new(3, 11, 10)));
+        assert_eq!(pick("~=3.10.2", &candidates), Some(Version::new(3, 10, 15)));
+        // Like `~3.9`: it stays on 3.9.
+        assert_eq!(pick("~=3.9", &candidates), Some(Version::new(3, 9, 20)));
+        assert_eq!(
+            pick(">=3.9,<3.12", &candidates),
+            Some(Version::new(3, 11, 10))
+        );
+        assert_eq!(
+            pick(">=3.8.1,<3.12,!=3.11.10", &candidates),
+            Some(Version::new(3, 10, 15))
+        );
+        assert_eq!(pick(">=3.13", &candidates), None);
+        assert_eq!(pick(">3.12.7", &candidates), None);
+    }
+}
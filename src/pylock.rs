@@ -0,0 +1,518 @@
+//! Writing locked dependencies as a [PEP 751](https://peps.python.org/pep-0751/) `pylock.toml`,
+//! the standard lock format, so installers like pip can install exactly what `pyflow.lock` pins.
+//! We read them too, along with `requirements.txt` files pinned with hashes, for
+//! `pyflow sync --from`.
+
+use crate::dep_types::DependencyError;
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr};
+
+/// The formats `pyflow export` writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Requirements,
+    Pylock,
+}
+
+impl FromStr for ExportFormat {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "requirements" | "requirements.txt" => Self::Requirements,
+            "pylock" | "pylock.toml" => Self::Pylock,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing the export format: {}. Must be `requirements` or `pylock`",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+/// A wheel or sdist, from the index.
+#[derive(Clone, Debug)]
+pub struct File {
+    pub name: String,
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum Source {
+    Index {
+        /// The simple API's URL, eg `https://pypi.org/simple`.
+        index: String,
+        sdist: Option<File>,
+        wheels: Vec<File>,
+    },
+    /// A local project, relative to the lock file.
+    Directory(String),
+    Vcs {
+        url: String,
+        commit: String,
+    },
+    /// A wheel or source archive from a URL, rather than an index.
+    Archive(File),
+}
+
+#[derive(Clone, Debug)]
+pub struct Package {
+    pub name: String,
+    pub version: Option<String>,
+    pub marker: Option<String>,
+    pub requires_python: Option<String>,
+    pub source: Source,
+}
+
+/// A TOML string, quoted and escaped.
+fn quote(s: &str) -> String {
+    toml::Value::String(s.to_owned()).to_string()
+}
+
+fn render_file(file: &File) -> String {
+    let size = if file.size > 0 {
+        format!(", size = {}", file.size)
+    } else {
+        String::new()
+    };
+    format!(
+        "{{ name = {}, url = {}{}, hashes = {{ sha256 = {} }} }}",
+        quote(&file.name),
+        quote(&file.url),
+        size,
+        quote(&file.sha256)
+    )
+}
+
+pub fn render(packages: &[Package], requires_python: Option<&str>) -> String {
+    let mut result = String::from("lock-version = \"1.0\"\ncreated-by = \"pyflow\"\n");
+    if let Some(r) = requires_python {
+        result.push_str(&format!("requires-python = {}\n", quote(r)));
+    }
+
+    for package in packages {
+        result.push_str(&format!(
+            "\n[[packages]]\nname = {}\n",
+            quote(&package.name)
+        ));
+        if let Some(v) = &package.version {
+            result.push_str(&format!("version = {}\n", quote(v)));
+        }
+        if let Some(m) = &package.marker {
+            result.push_str(&format!("marker = {}\n", quote(m)));
+        }
+        if let Some(r) = &package.requires_python {
+            result.push_str(&format!("requires-python = {}\n", quote(r)));
+        }
+
+        match &package.source {
+            Source::Index {
+                index,
+                sdist,
+                wheels,
+            } => {
+                result.push_str(&format!("index = {}\n", quote(index)));
+                if let Some(s) = sdist {
+                    result.push_str(&format!("sdist = {}\n", render_file(s)));
+                }
+                if !wheels.is_empty() {
+                    result.push_str("wheels = [\n");
+                    for wheel in wheels {
+                        result.push_str(&format!("    {},\n", render_file(wheel)));
+                    }
+                    result.push_str("]\n");
+                }
+            }
+            Source::Directory(path) => {
+                result.push_str(&format!("directory = {{ path = {} }}\n", quote(path)));
+            }
+            Source::Vcs { url, commit } => {
+                result.push_str(&format!(
+                    "vcs = {{ type = \"git\", url = {}, commit-id = {} }}\n",
+                    quote(url),
+                    quote(commit)
+                ));
+            }
+            Source::Archive(file) => {
+                result.push_str(&format!(
+                    "archive = {{ url = {}, hashes = {{ sha256 = {} }} }}\n",
+                    quote(&file.url),
+                    quote(&file.sha256)
+                ));
+            }
+        }
+    }
+    result
+}
+
+#[derive(Deserialize)]
+struct RawFile {
+    name: Option<String>,
+    url: Option<String>,
+    size: Option<u64>,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct RawDirectory {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct RawVcs {
+    url: Option<String>,
+    #[serde(rename = "commit-id")]
+    commit_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawPackage {
+    name: String,
+    version: Option<String>,
+    marker: Option<String>,
+    requires_python: Option<String>,
+    index: Option<String>,
+    sdist: Option<RawFile>,
+    #[serde(default)]
+    wheels: Vec<RawFile>,
+    directory: Option<RawDirectory>,
+    vcs: Option<RawVcs>,
+    archive: Option<RawFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawLock {
+    lock_version: String,
+    #[serde(default)]
+    packages: Vec<RawPackage>,
+}
+
+fn read_file(package: &str, file: RawFile) -> Result<File, DependencyError> {
+    let url = file.url.ok_or_else(|| {
+        DependencyError::new(&format!(
+            "A file for {} has no `url`; only files from an index are supported",
+            package
+        ))
+    })?;
+    let sha256 = file.hashes.get("sha256").ok_or_else(|| {
+        DependencyError::new(&format!("A file for {} has no sha256 hash", package))
+    })?;
+    Ok(File {
+        // The name's optional, when the URL ends with it.
+        name: file
+            .name
+            .unwrap_or_else(|| url.rsplit('/').next().unwrap_or_default().to_owned()),
+        url,
+        size: file.size.unwrap_or(0),
+        sha256: sha256.to_lowercase(),
+    })
+}
+
+/// Read a `pylock.toml`.
+pub fn read(text: &str) -> Result<Vec<Package>, DependencyError> {
+    let lock: RawLock = toml::from_str(text)
+        .map_err(|e| DependencyError::new(&format!("Problem parsing the lock file: {}", e)))?;
+    // Minor versions are backwards-compatible.
+    if lock.lock_version.split('.').next() != Some("1") {
+        return Err(DependencyError::new(&format!(
+            "Lock version {} isn't supported; only 1.x is",
+            lock.lock_version
+        )));
+    }
+
+    let mut result = vec![];
+    for package in lock.packages {
+        let source = if let Some(dir) = package.directory {
+            Source::Directory(dir.path)
+        } else if let Some(vcs) = package.vcs {
+            match (vcs.url, vcs.commit_id) {
+                (Some(url), Some(commit)) => Source::Vcs { url, commit },
+                _ => {
+                    return Err(DependencyError::new(&format!(
+                        "{}'s `vcs` needs a `url` and `commit-id`",
+                        package.name
+                    )))
+                }
+            }
+        } else if let Some(file) = package.archive {
+            Source::Archive(read_file(&package.name, file)?)
+        } else if package.sdist.is_some() || !package.wheels.is_empty() {
+            let name = &package.name;
+            Source::Index {
+                index: package
+                    .index
+                    .unwrap_or_else(|| "https://pypi.org/simple".to_owned()),
+                sdist: package.sdist.map(|f| read_file(name, f)).transpose()?,
+                wheels: package
+                    .wheels
+                    .into_iter()
+                    .map(|f| read_file(name, f))
+                    .collect::<Result<_, _>>()?,
+            }
+        } else {
+            return Err(DependencyError::new(&format!(
+                "{} has no sdist, wheels, directory, vcs, or archive to install it from",
+                package.name
+            )));
+        };
+
+        result.push(Package {
+            name: package.name,
+            version: package.version,
+            marker: package.marker,
+            requires_python: package.requires_python,
+            source,
+        });
+    }
+    Ok(result)
+}
+
+/// A `requirements.txt` line pinned to one version, with the hashes of the files it may install,
+/// eg `six==1.16.0 --hash=sha256:1e61c374...`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashedReq {
+    pub name: String,
+    pub version: String,
+    pub marker: Option<String>,
+    pub hashes: Vec<String>,
+}
+
+/// Read a `requirements.txt` where every requirement is pinned with `==`, and has hashes, like
+/// `pip install --require-hashes` expects.
+pub fn read_hashed_requirements(text: &str) -> Result<Vec<HashedReq>, DependencyError> {
+    let mut result = vec![];
+    // Requirements are often split over lines, one per hash, with a `\` at each line's end.
+    for line in text.replace("\\\r\n", " ").replace("\\\n", " ").lines() {
+        let line = match line.find(" #") {
+            Some(i) => &line[..i],
+            None => line,
+        }
+        .trim();
+        // Options like `--index-url` don't affect what's installed, since the hashes pin it.
+        if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+            continue;
+        }
+
+        let mut hashes = vec![];
+        let mut spec = vec![];
+        for part in line.split_whitespace() {
+            if let Some(hash) = part.strip_prefix("--hash=") {
+                match hash.strip_prefix("sha256:") {
+                    Some(h) => hashes.push(h.to_lowercase()),
+                    None => {
+                        return Err(DependencyError::new(&format!(
+                            "Problem parsing `{}`: only sha256 hashes are supported",
+                            line
+                        )))
+                    }
+                }
+            } else {
+                spec.push(part);
+            }
+        }
+        let spec = spec.join(" ");
+        let (spec, marker) = match spec.find(';') {
+            Some(i) => (&spec[..i], Some(spec[i + 1..].trim().to_owned())),
+            None => (&spec[..], None),
+        };
+
+        let pinned = spec.find("==").map(|i| (&spec[..i], &spec[i + 2..]));
+        let (name, version) = match pinned {
+            Some((n, v)) if !hashes.is_empty() => (n, v.trim()),
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "`{}` must be pinned with `==`, and have a `--hash`",
+                    line
+                )))
+            }
+        };
+        // Extras, eg `requests[socks]`, are listed as their own requirements.
+        let name = name.split('[').next().unwrap_or_default().trim();
+
+        result.push(HashedReq {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            marker,
+            hashes,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn render_pylock() {
+        let packages = vec![
+            Package {
+                name: "colorama".into(),
+                version: Some("0.4.6".into()),
+                marker: Some("sys_platform == \"win32\"".into()),
+                requires_python: Some(">=3.7".into()),
+                source: Source::Index {
+                    index: "https://pypi.org/simple".into(),
+                    sdist: Some(File {
+                        name: "colorama-0.4.6.tar.gz".into(),
+                        url: "https://files.pythonhosted.org/colorama-0.4.6.tar.gz".into(),
+                        size: 27697,
+                        sha256: "08695f5c".into(),
+                    }),
+                    wheels: vec![File {
+                        name: "colorama-0.4.6-py2.py3-none-any.whl".into(),
+                        url: "https://files.pythonhosted.org/colorama-0.4.6-py2.py3-none-any.whl"
+                            .into(),
+                        size: 0,
+                        sha256: "4f1d9991".into(),
+                    }],
+                },
+            },
+            Package {
+                name: "mylib".into(),
+                version: None,
+                marker: None,
+                requires_python: None,
+                source: Source::Directory("../mylib".into()),
+            },
+            Package {
+                name: "saturn".into(),
+                version: Some("0.3.4".into()),
+                marker: None,
+                requires_python: None,
+                source: Source::Archive(File {
+                    name: "saturn-0.3.4.tar.gz".into(),
+                    url: "https://example.com/saturn-0.3.4.tar.gz".into(),
+                    size: 0,
+                    sha256: "ab12".into(),
+                }),
+            },
+        ];
+
+        let text = render(&packages, Some(">=3.8"));
+        assert_eq!(
+            text,
+            r#"lock-version = "1.0"
+created-by = "pyflow"
+requires-python = ">=3.8"
+
+[[packages]]
+name = "colorama"
+version = "0.4.6"
+marker = "sys_platform == \"win32\""
+requires-python = ">=3.7"
+index = "https://pypi.org/simple"
+sdist = { name = "colorama-0.4.6.tar.gz", url = "https://files.pythonhosted.org/colorama-0.4.6.tar.gz", size = 27697, hashes = { sha256 = "08695f5c" } }
+wheels = [
+    { name = "colorama-0.4.6-py2.py3-none-any.whl", url = "https://files.pythonhosted.org/colorama-0.4.6-py2.py3-none-any.whl", hashes = { sha256 = "4f1d9991" } },
+]
+
+[[packages]]
+name = "mylib"
+directory = { path = "../mylib" }
+
+[[packages]]
+name = "saturn"
+version = "0.3.4"
+archive = { url = "https://example.com/saturn-0.3.4.tar.gz", hashes = { sha256 = "ab12" } }
+"#
+        );
+
+        // It's valid TOML.
+        let parsed: toml::Value = toml::from_str(&text).unwrap();
+        assert_eq!(
+            parsed["packages"][1]["directory"]["path"].as_str(),
+            Some("../mylib")
+        );
+        assert_eq!(
+            ExportFormat::from_str("pylock").unwrap(),
+            ExportFormat::Pylock
+        );
+    }
+
+    #[test]
+    fn read_pylock() {
+        let text = r#"lock-version = "1.0"
+created-by = "uv"
+
+[[packages]]
+name = "six"
+version = "1.16.0"
+marker = "python_version >= \"3.8\""
+wheels = [{ url = "https://files.pythonhosted.org/six-1.16.0-py2.py3-none-any.whl", hashes = { sha256 = "8ABC" } }]
+
+[[packages]]
+name = "mylib"
+directory = { path = "../mylib" }
+
+[[packages]]
+name = "saturn"
+version = "0.3.4"
+archive = { url = "https://example.com/saturn-0.3.4.tar.gz", hashes = { sha256 = "AB12" } }
+"#;
+        let packages = read(text).unwrap();
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[0].version.as_deref(), Some("1.16.0"));
+        assert_eq!(
+            packages[0].marker.as_deref(),
+            Some("python_version >= \"3.8\"")
+        );
+        match &packages[0].source {
+            Source::Index {
+                index,
+                sdist,
+                wheels,
+            } => {
+                assert_eq!(index, "https://pypi.org/simple");
+                assert!(sdist.is_none());
+                assert_eq!(wheels[0].name, "six-1.16.0-py2.py3-none-any.whl");
+                assert_eq!(wheels[0].sha256, "8abc");
+            }
+            _ => panic!("Expected an index package"),
+        }
+        match &packages[2].source {
+            Source::Archive(file) => {
+                assert_eq!(file.name, "saturn-0.3.4.tar.gz");
+                assert_eq!(file.sha256, "ab12");
+            }
+            _ => panic!("Expected an archive package"),
+        }
+
+        assert!(read("lock-version = \"2.0\"").is_err());
+        assert!(read("lock-version = \"1.0\"\n[[packages]]\nname = \"six\"\n").is_err());
+    }
+
+    #[test]
+    fn read_requirements() {
+        let text = "# Generated by pip-compile\n\
+                    --index-url https://pypi.org/simple\n\
+                    six==1.16.0 \\\n    --hash=sha256:1E61 \\\n    --hash=sha256:8abc\n\
+                    requests[socks]==2.31.0 ; python_version >= \"3.8\" --hash=sha256:58cd  # via app\n";
+        assert_eq!(
+            read_hashed_requirements(text).unwrap(),
+            vec![
+                HashedReq {
+                    name: "six".into(),
+                    version: "1.16.0".into(),
+                    marker: None,
+                    hashes: vec!["1e61".into(), "8abc".into()],
+                },
+                HashedReq {
+                    name: "requests".into(),
+                    version: "2.31.0".into(),
+                    marker: Some("python_version >= \"3.8\"".into()),
+                    hashes: vec!["58cd".into()],
+                },
+            ]
+        );
+
+        assert!(read_hashed_requirements("six==1.16.0\n").is_err());
+        assert!(read_hashed_requirements("six>=1.0 --hash=sha256:1e61\n").is_err());
+    }
+}
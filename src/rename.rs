@@ -0,0 +1,378 @@
+//! `pyflow rename`: rename the project everywhere its name appears: its name in
+//! `pyproject.toml`, entry points that import its package, its package folder if asked,
+//! editable installs of it in `__pypackages__`, and references to it in `pyflow.lock`.
+
+use crate::{dep_types::Lock, install, util};
+use crossterm::Color;
+use regex::Regex;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The name a project's package is imported by, eg `my_app` for `my-app`.
+fn module_name(name: &str) -> String {
+    name.replace(['-', '.'], "_")
+}
+
+/// Whether `name` can be a project's name, as in PEP 508: letters, digits, `-`, `_` and `.`,
+/// starting and ending with a letter or digit.
+fn is_valid_name(name: &str) -> bool {
+    let edge = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    edge(name.chars().next())
+        && edge(name.chars().last())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn is_entry_point_section(section: &str) -> bool {
+    section == "tool.pyflow.scripts"
+        || section == "project.scripts"
+        || section == "project.gui-scripts"
+        || section.starts_with("project.entry-points")
+}
+
+/// `pyproject.toml`, with the project's name changed, and entry points named after the project
+/// renamed. If its package folder's moved, entry points that call into it, eg
+/// `"my_app.main:run"`, are pointed at the new one. It's edited line by line, so comments and
+/// formatting are kept.
+fn rename_pyproject(text: &str, old: &str, new: &str, package_moved: bool) -> String {
+    let name_re = Regex::new(r#"^(\s*name\s*=\s*)(["'])([^"']*)(["'].*)$"#).unwrap();
+    let entry_re = Regex::new(&format!(
+        r#"(["']\s*){}([.:])"#,
+        regex::escape(&module_name(old))
+    ))
+    .unwrap();
+    let entry_replacement = format!("${{1}}{}${{2}}", module_name(new));
+
+    let mut result = String::new();
+    let mut section = String::new();
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed
+                .trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_owned();
+            result.push_str(line);
+            result.push('\n');
+            continue;
+        }
+
+        let mut line = line.to_owned();
+        if section == "tool.pyflow" || section == "project" {
+            if let Some(caps) = name_re.captures(&line) {
+                if util::compare_names(&caps[3], old) {
+                    line = format!("{}{}{}{}", &caps[1], &caps[2], new, &caps[4]);
+                }
+            }
+        } else if is_entry_point_section(&section) {
+            if let Some(eq) = line.find('=') {
+                let key = line[..eq].trim().trim_matches(|c| c == '"' || c == '\'');
+                if key == old {
+                    line = line.replacen(old, new, 1);
+                }
+            }
+            if package_moved {
+                line = entry_re
+                    .replace_all(&line, entry_replacement.as_str())
+                    .into_owned();
+            }
+        }
+        result.push_str(&line);
+        result.push('\n');
+    }
+    result
+}
+
+/// Rename the lock's entries for the project itself, and dependencies on it; eg when a member of
+/// its workspace depends on it. Packages from an index that happen to share the old name are
+/// left alone. Returns whether anything changed.
+fn rename_lock(lock: &mut Lock, old: &str, new: &str) -> bool {
+    let from_index = |source: Option<&str>| source.is_some_and(|s| s.starts_with("pypi+"));
+    let mut changed = false;
+    for lp in lock.package.iter_mut().flatten() {
+        if util::compare_names(&lp.name, old) && !from_index(lp.source.as_deref()) {
+            lp.name = new.to_owned();
+            changed = true;
+        }
+        for dep in lp.dependencies.iter_mut().flatten() {
+            // Eg `my-app 0.1.0 path+..`.
+            let mut parts = dep.splitn(3, ' ');
+            let (name, rest) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(n), Some(v), source) => (n, (v, source)),
+                _ => continue,
+            };
+            if util::compare_names(name, old) && !from_index(rest.1) {
+                *dep = match rest.1 {
+                    Some(source) => format!("{} {} {}", new, rest.0, source),
+                    None => format!("{} {}", new, rest.0),
+                };
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// The project's package folder: `{name}` or `src/{name}`, as `pyflow new` makes.
+fn find_package_dir(proj_path: &Path, module: &str) -> Option<PathBuf> {
+    [proj_path.join(module), proj_path.join("src").join(module)]
+        .iter()
+        .find(|p| p.join("__init__.py").exists())
+        .cloned()
+}
+
+/// Reinstall an editable install of the project under its new name. Its `.pth` file, and where
+/// its source is, stay the same.
+fn rename_editable(lib: &Path, old: &str, new: &str) -> bool {
+    let (dist_info, version) = match crate::installed_dist_info(lib, old) {
+        Some((path, version)) if install::is_editable(&path) => (path, version),
+        _ => return false,
+    };
+    let direct_url: serde_json::Value = fs::read_to_string(dist_info.join("direct_url.json"))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    let source = match direct_url["url"]
+        .as_str()
+        .and_then(|u| u.strip_prefix("file://"))
+    {
+        // Windows paths are written as eg `/C:/project`.
+        Some(path) if cfg!(windows) => PathBuf::from(path.trim_start_matches('/')),
+        Some(path) => PathBuf::from(path),
+        None => return false,
+    };
+    // As in the folder's name, eg `my_app` for `my_app-0.1.0.dist-info`.
+    let name = dist_info
+        .file_name()
+        .and_then(|n| n.to_str()?.strip_suffix(".dist-info")?.rsplit_once('-'))
+        .map_or_else(|| old.to_owned(), |(n, _)| n.to_owned());
+
+    install::uninstall(&name, &version, lib);
+    install::install_editable(new, &version, &source, &crate::import_root(&source), lib);
+    true
+}
+
+/// The environments' `lib` folders, eg `__pypackages__/3.12/lib`.
+fn env_libs(pypackages_path: &Path) -> Vec<PathBuf> {
+    let mut result: Vec<PathBuf> = fs::read_dir(pypackages_path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.path().join("lib"))
+                .filter(|p| p.is_dir())
+                .collect()
+        })
+        .unwrap_or_default();
+    result.sort();
+    result
+}
+
+pub fn rename(
+    proj_path: &Path,
+    cfg_path: &Path,
+    lock_path: &Path,
+    pypackages_path: &Path,
+    old: &str,
+    new: &str,
+    move_package: bool,
+) {
+    if !is_valid_name(new) {
+        util::abort(&format!(
+            "`{}` isn't a valid project name: use letters, numbers, `-`, `_` and `.`, starting \
+             and ending with a letter or number",
+            new
+        ));
+    }
+    if old == new {
+        util::abort(&format!("The project's already named {}", new));
+    }
+
+    let (old_module, new_module) = (module_name(old), module_name(new));
+    let mut package_moved = false;
+    if let Some(dir) = find_package_dir(proj_path, &old_module).filter(|_| old_module != new_module)
+    {
+        let dest = dir.with_file_name(&new_module);
+        if !move_package {
+            util::print_color(
+                &format!(
+                    "  Left the package folder {:?} as it is; run with `--move-package` to \
+                     rename it too",
+                    dir
+                ),
+                Color::DarkYellow,
+            );
+        } else if dest.exists() {
+            util::abort(&format!("Can't move {:?}, since {:?} exists", dir, dest));
+        } else if fs::rename(&dir, &dest).is_err() {
+            util::abort(&format!("Problem moving {:?} to {:?}", dir, dest));
+        } else {
+            println!("  Moved {:?} to {:?}", dir, dest);
+            util::print_color(
+                &format!(
+                    "  Imports of `{}` in your code aren't changed; update them to `{}`",
+                    old_module, new_module
+                ),
+                Color::DarkYellow,
+            );
+            package_moved = true;
+        }
+    }
+
+    let text = match fs::read_to_string(cfg_path) {
+        Ok(t) => t,
+        Err(_) => {
+            util::abort("Problem reading `pyproject.toml`");
+            unreachable!()
+        }
+    };
+    if fs::write(cfg_path, rename_pyproject(&text, old, new, package_moved)).is_err() {
+        util::abort("Problem writing `pyproject.toml`");
+    }
+    println!("  Renamed the project, and its entry points, in `pyproject.toml`");
+
+    for lib in env_libs(pypackages_path) {
+        if rename_editable(&lib, old, new) {
+            println!("  Reinstalled the editable install in {:?}", lib);
+        }
+    }
+
+    if let Ok(data) = fs::read_to_string(lock_path) {
+        let mut lock: Lock = match toml::from_str(&data) {
+            Ok(l) => l,
+            Err(_) => {
+                util::abort("Problem reading `pyflow.lock`");
+                unreachable!()
+            }
+        };
+        if rename_lock(&mut lock, old, new) {
+            let written = toml::to_string(&lock)
+                .ok()
+                .is_some_and(|text| fs::write(lock_path, text).is_ok());
+            if !written {
+                util::abort("Problem writing `pyflow.lock`");
+            }
+            println!("  Updated references to the project in `pyflow.lock`");
+        }
+    }
+
+    util::print_color(&format!("Renamed {} to {}", old, new), Color::Green);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dep_types::{LockPackage, Version};
+    use std::collections::HashMap;
+
+    #[test]
+    fn rename_pyproject_entries() {
+        let text = r#"[tool.pyflow]
+name = "my-app"  # The name on PyPI.
+version = "0.1.0"
+
+[tool.pyflow.scripts]
+my-app = "my_app.main:run"
+lint = { call = "my_app.tools:lint", depends-on = ["fmt"] }
+other = "my_appendix:run"
+
+[tool.pyflow.dependencies]
+my-app-plugins = "^1.0"
+"#;
+        assert_eq!(
+            rename_pyproject(text, "my-app", "rocket", true),
+            r#"[tool.pyflow]
+name = "rocket"  # The name on PyPI.
+version = "0.1.0"
+
+[tool.pyflow.scripts]
+rocket = "rocket.main:run"
+lint = { call = "rocket.tools:lint", depends-on = ["fmt"] }
+other = "my_appendix:run"
+
+[tool.pyflow.dependencies]
+my-app-plugins = "^1.0"
+"#
+        );
+        assert!(rename_pyproject(text, "my-app", "rocket", false)
+            .contains("rocket = \"my_app.main:run\""));
+        assert!(is_valid_name("rocket.core-2"));
+        assert!(!is_valid_name("-rocket"));
+        assert!(!is_valid_name("rocket app"));
+    }
+
+    #[test]
+    fn rename_lock_references() {
+        let lockpack = |name: &str, source: &str, deps: Vec<&str>| LockPackage {
+            id: 0,
+            name: name.into(),
+            version: "0.1.0".into(),
+            source: Some(source.into()),
+            dependencies: Some(deps.into_iter().map(ToOwned::to_owned).collect()),
+            rename: None,
+            markers: None,
+            extras: None,
+        };
+        let mut lock = Lock {
+            package: Some(vec![
+                lockpack("my-app", "path+.", vec![]),
+                lockpack(
+                    "plugin",
+                    "path+plugin",
+                    vec![
+                        "my-app 0.1.0 path+.",
+                        "my-app 2.0.0 pypi+https://pypi.org/pypi/my-app/2.0.0/json",
+                    ],
+                ),
+            ]),
+            metadata: HashMap::new(),
+        };
+        assert!(rename_lock(&mut lock, "my-app", "rocket"));
+        let packages = lock.package.unwrap();
+        assert_eq!(packages[0].name, "rocket");
+        assert_eq!(
+            packages[1].dependencies.as_ref().unwrap(),
+            &vec![
+                "rocket 0.1.0 path+.".to_owned(),
+                "my-app 2.0.0 pypi+https://pypi.org/pypi/my-app/2.0.0/json".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_project_files() {
+        let dir = util::TempDir::new("rename");
+        let proj = dir.path();
+        let lib = proj.join("__pypackages__").join("3.12").join("lib");
+        fs::create_dir_all(proj.join("my_app")).unwrap();
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(proj.join("my_app").join("__init__.py"), "").unwrap();
+        fs::write(
+            proj.join("pyproject.toml"),
+            "[tool.pyflow]\nname = \"my-app\"\n",
+        )
+        .unwrap();
+        install::install_editable("my-app", &Version::new(0, 1, 0), proj, proj, &lib);
+
+        rename(
+            proj,
+            &proj.join("pyproject.toml"),
+            &proj.join("pyflow.lock"),
+            &proj.join("__pypackages__"),
+            "my-app",
+            "rocket",
+            true,
+        );
+        assert!(proj.join("rocket").join("__init__.py").exists());
+        assert!(!proj.join("my_app").exists());
+        assert!(fs::read_to_string(proj.join("pyproject.toml"))
+            .unwrap()
+            .contains("name = \"rocket\""));
+        let (dist_info, version) = crate::installed_dist_info(&lib, "rocket").unwrap();
+        assert!(install::is_editable(&dist_info));
+        assert_eq!(version, Version::new(0, 1, 0));
+        assert!(crate::installed_dist_info(&lib, "my-app").is_none());
+    }
+}
@@ -0,0 +1,439 @@
+//! JSON Schemas for the files pyflow reads and writes, for `pyflow schema`. Editors can use them
+//! to validate and complete `pyproject.toml` and `config.toml`, eg with
+//! [taplo](https://taplo.tamasfe.dev/), and other tools can code against `pyflow.lock`'s shape.
+//! Keep these in sync with the structs in `files` and `dep_types`.
+
+use crate::warnings;
+use serde_json::{json, Map, Value};
+
+const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// The schemas `pyflow schema` can show, by name, and what each describes.
+pub const NAMES: &[(&str, &str)] = &[
+    ("lock", "pyflow.lock"),
+    ("pyproject", "The `[tool.pyflow]` tables in pyproject.toml"),
+    ("config", "config.toml, in pyflow's directory"),
+];
+
+fn string(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn strings(description: &str) -> Value {
+    json!({ "type": "array", "items": { "type": "string" }, "description": description })
+}
+
+fn boolean(description: &str) -> Value {
+    json!({ "type": "boolean", "description": description })
+}
+
+fn one_of(values: &[&str], description: &str) -> Value {
+    json!({ "type": "string", "enum": values, "description": description })
+}
+
+/// A table of dependencies: `numpy = "^1.17"`, or `numpy = { version = "^1.17", extras = [..] }`.
+fn dependencies(description: &str) -> Value {
+    json!({
+        "type": "object",
+        "description": description,
+        "additionalProperties": {
+            "anyOf": [
+                string("A version constraint, eg `^1.17.0`"),
+                {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {
+                        "version": string("A version constraint, eg `^1.17.0`"),
+                        "extras": strings("Extras to install, eg `[\"socks\"]`"),
+                        "path": string("A local package, relative to the project"),
+                        "editable": boolean(
+                            "With `path`, use its source directly, so changes apply without \
+                             reinstalling"
+                        ),
+                        "git": string("A git repository's URL"),
+                        "branch": string("The branch to use, with `git`"),
+                        "tag": string("The tag to use, with `git`"),
+                        "rev": string("The commit to use, with `git`"),
+                        "url": string(
+                            "A wheel or source archive to install, instead of one from PyPI"
+                        ),
+                        "service": string("The git service, eg `github`"),
+                        "python": string("Python versions to install it for, eg `^3.7`"),
+                        "source": string(
+                            "The index to get it from, named in `[tool.pyflow.sources]`"
+                        ),
+                    }
+                }
+            ]
+        }
+    })
+}
+
+/// `pyflow.lock`.
+pub fn lock() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "pyflow.lock",
+        "type": "object",
+        "required": ["metadata"],
+        "properties": {
+            "package": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["id", "name", "version"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "id": {
+                            "type": "integer",
+                            "minimum": 0,
+                            "description": "Identifies the package, eg for renames"
+                        },
+                        "name": string("The package's name"),
+                        "version": string("The locked version, eg `1.17.0`"),
+                        "source": string(
+                            "Where it's from, eg `pypi+https://pypi.org/pypi/numpy/1.17.0/json`, \
+                             or `path+..`, `git+..` and `url+..` for local, git and direct URL packages"
+                        ),
+                        "dependencies": strings("Its dependencies, eg `six 1.12.0 pypi+..`"),
+                        "rename": string(
+                            "For a version installed alongside another: `{id} {new name}`"
+                        ),
+                        "markers": string(
+                            "Environment markers it's installed for, eg `sys_platform == \"win32\"`"
+                        ),
+                        "extras": strings("Extras it's installed with, eg `[\"socks\"]`"),
+                    }
+                }
+            },
+            "metadata": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+                "description": "Checksums, and other information about the lock"
+            }
+        }
+    })
+}
+
+/// The `[tool.pyflow]` table, as part of `pyproject.toml`.
+pub fn pyproject() -> Value {
+    let warning_names: Vec<&str> = warnings::ALL_CODES.iter().map(|c| c.name()).collect();
+    let mut warning_props = Map::new();
+    for name in &warning_names {
+        warning_props.insert(
+            (*name).to_owned(),
+            json!({
+                "description": "`true` to silence it, or the packages to silence it for",
+                "anyOf": [{ "type": "boolean" }, { "type": "array", "items": { "type": "string" } }]
+            }),
+        );
+    }
+
+    let scripts = json!({
+        "type": "object",
+        "description": "Console scripts, and tasks",
+        "additionalProperties": {
+            "anyOf": [
+                string("A function to call, eg `mypackage.main:run`"),
+                {
+                    "type": "object",
+                    "required": ["call"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "call": string("A function to call, eg `tools.release:sign`"),
+                        "depends-on": strings("Tasks to run before it"),
+                        "run-if": string(
+                            "Eg `files_changed('proto/**')`, to skip it if they haven't"
+                        ),
+                        "platforms": strings("Eg `[\"linux\", \"mac\"]`"),
+                    }
+                }
+            ]
+        }
+    });
+    let env_vars = json!({
+        "type": "object",
+        "description": "Environment variables scripts need",
+        "additionalProperties": {
+            "anyOf": [
+                string("Its default value"),
+                {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {
+                        "default": string("Its default value"),
+                        "secret": boolean("Hide its value in output"),
+                        "from": {
+                            "type": "string",
+                            "pattern": "^(file|env-file|cmd):",
+                            "description": "Where to read its value if it isn't set: \
+                                `file:path`, `env-file:path`, or `cmd:command`"
+                        },
+                        "tasks": strings("Scripts that use it; all of them if omitted"),
+                    }
+                }
+            ]
+        }
+    });
+
+    let sync_exports = json!({
+        "type": "object",
+        "description": "Files to regenerate from the lock whenever it's updated",
+        "additionalProperties": {
+            "type": "object",
+            "additionalProperties": false,
+            "properties": {
+                "format": one_of(&["requirements", "pylock"], "The export's format"),
+                "dev": boolean("Include dev dependencies"),
+                "platform": one_of(&["linux", "windows", "mac"], "The target platform"),
+                "python": string("The target Python version, eg `3.12`"),
+            }
+        }
+    });
+
+    let policy = json!({
+        "type": "object",
+        "description": "Rules for the packages that may be locked and installed",
+        "additionalProperties": false,
+        "properties": {
+            "file": string("A policy file with these keys, relative to the project"),
+            "min_age_days": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Releases must have been uploaded at least this many days ago"
+            },
+            "deny_licenses": strings("Licenses packages may not use, eg `[\"AGPL-*\"]`"),
+            "require_hashes": boolean("Every package must have a published hash"),
+            "allowed_indexes": strings(
+                "URLs packages must come from, eg `[\"https://pypi.org\"]`"
+            ),
+            "exempt": strings("Packages the rules don't apply to"),
+            "report": string("Write violations here as JSON, relative to the project"),
+        }
+    });
+
+    let sources = json!({
+        "type": "object",
+        "description": "Package indexes other than PyPI, by name",
+        "additionalProperties": {
+            "anyOf": [
+                string("The index's URL, eg `https://pypi.internal.example.com`"),
+                {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "required": ["url"],
+                    "properties": {
+                        "url": string("The index's URL"),
+                        "packages": strings(
+                            "Packages to only get from here, including transitive ones, eg \
+                             `[\"acme-*\"]`"
+                        ),
+                    }
+                }
+            ]
+        }
+    });
+
+    let pyflow = json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "extends": string(
+                "A file or URL whose `[tool.pyflow]` settings this inherits, eg \
+                 `../pyflow-defaults.toml`"
+            ),
+            "py_version": string(
+                "The Python version, eg `3.11`, or constraints on it, eg `>=3.10, <3.13`"
+            ),
+            "name": string("The package's name"),
+            "version": string("The package's version, eg `0.1.0`"),
+            "authors": strings("Eg `[\"Jane Doe <jane@example.com>\"]`"),
+            "license": string("Eg `MIT`"),
+            "description": string("A short description"),
+            "classifiers": strings("Trove classifiers, from https://pypi.org/classifiers/"),
+            "keywords": strings("Keywords to find it with on PyPI"),
+            "homepage": string("Its homepage's URL"),
+            "repository": string("Its source repository's URL"),
+            "repo_url": string("Its source repository's URL"),
+            "package_url": string("Its URL on PyPI"),
+            "readme": string("The readme file, eg `README.md`"),
+            "build": string("A script to run before building, eg `build.py`"),
+            "scripts": scripts,
+            "python_requires": string("Python versions the package supports, eg `>=3.7`"),
+            "dependencies": dependencies("The project's dependencies"),
+            "dev-dependencies": dependencies("Dependencies for developing the project"),
+            "extras": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+                "description": "Optional features, and the dependencies they need"
+            },
+            "active_env": string("Eg `3.11`, to choose between environments"),
+            "members": strings("A workspace's members, eg `[\"packages/*\"]`"),
+            "internal_prefixes": {
+                "description": "Package names that must not come from public indexes: eg \
+                                `[\"acme-*\"]`, or `{ \"acme-*\" = \"internal\" }` to get them \
+                                from a source",
+                "anyOf": [
+                    { "type": "array", "items": { "type": "string" } },
+                    { "type": "object", "additionalProperties": { "type": "string" } }
+                ]
+            },
+            "require_approval": boolean("Review packages before they're installed"),
+            "overrides": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+                "description": "Versions to use whatever dependencies require, eg \
+                                `urllib3 = \"1.26.18\"`"
+            },
+            "constraints": strings(
+                "Constraints files in pip's format, relative to the project, eg \
+                 `[\"constraints.txt\"]`"
+            ),
+            "prereleases": {
+                "type": "object",
+                "additionalProperties": { "type": "boolean" },
+                "description": "Packages that may resolve to pre-releases, eg `black = true`, \
+                                or `false` to keep them off with `--pre`"
+            },
+            "temp_dir": string("Where to build packages, instead of the system's temp folder"),
+            "python-update-policy": one_of(
+                &["patch", "exact"],
+                "Whether to move to new patch releases of Python"
+            ),
+            "python-archive": string("A Python archive to install from, relative to the project"),
+            "py_variant": one_of(
+                &["default", "debug", "freethreaded", "free-threaded"],
+                "The build of CPython to use"
+            ),
+            "env-vars": env_vars,
+            "sync-exports": sync_exports,
+            "policy": policy,
+            "sources": sources,
+            "warnings": {
+                "type": "object",
+                "description": "Warnings to silence",
+                "additionalProperties": false,
+                "properties": warning_props
+            }
+        }
+    });
+
+    json!({
+        "$schema": DRAFT,
+        "title": "pyproject.toml, for pyflow",
+        "type": "object",
+        "properties": {
+            "tool": {
+                "type": "object",
+                "properties": { "pyflow": pyflow }
+            }
+        }
+    })
+}
+
+/// The global `config.toml`.
+pub fn config() -> Value {
+    json!({
+        "$schema": DRAFT,
+        "title": "pyflow's config.toml",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "proxy": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "http": string("The proxy for HTTP requests"),
+                    "https": string("The proxy for HTTPS requests"),
+                    "no-proxy": strings(
+                        "Hosts to connect to directly, eg `[\"localhost\", \".internal.example.com\"]`"
+                    ),
+                }
+            },
+            "python-mirror": string("Where to download Python builds from"),
+            "user-agent-contact": string("Added to our user agent, eg an email address"),
+            "python-signing-keys": strings("Minisign public keys to check Python archives with"),
+            "require-python-signatures": boolean(
+                "Refuse Python archives that aren't signed with a trusted key"
+            ),
+            "timeouts": {
+                "type": "object",
+                "additionalProperties": { "type": "integer", "minimum": 1 },
+                "description": "Seconds to wait for each host, eg `\"pypi.internal.example.com\" = 5`"
+            },
+            "allow-env-commands": boolean(
+                "Run the commands `cmd:` sources in `[tool.pyflow.env-vars]` specify"
+            ),
+        }
+    })
+}
+
+pub fn find(name: &str) -> Option<Value> {
+    match name {
+        "lock" => Some(lock()),
+        "pyproject" => Some(pyproject()),
+        "config" => Some(config()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dep_types::{Lock, LockPackage};
+    use std::collections::HashMap;
+
+    fn property_names(schema: &Value) -> Vec<String> {
+        let mut names: Vec<String> = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn schemas_found_by_name() {
+        for (name, _) in NAMES {
+            assert_eq!(find(name).unwrap()["$schema"], DRAFT);
+        }
+        assert!(find("other").is_none());
+    }
+
+    #[test]
+    fn lock_schema_matches_lock() {
+        let locked = Lock {
+            package: Some(vec![LockPackage {
+                id: 1,
+                name: "six".into(),
+                version: "1.12.0".into(),
+                source: Some("pypi+https://pypi.org/pypi/six/1.12.0/json".into()),
+                dependencies: Some(vec![]),
+                rename: Some("2 six_1".into()),
+                markers: Some("python_version < \"3\"".into()),
+                extras: Some(vec!["socks".into()]),
+            }]),
+            metadata: HashMap::new(),
+        };
+        let value = serde_json::to_value(&locked).unwrap();
+        let schema = lock();
+
+        let mut fields: Vec<String> = value["package"][0]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        fields.sort();
+        assert_eq!(
+            fields,
+            property_names(&schema["properties"]["package"]["items"])
+        );
+
+        let mut top: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        top.sort();
+        assert_eq!(top, property_names(&schema));
+    }
+}
@@ -0,0 +1,388 @@
+//! Running `[tool.pyflow.scripts]` entries as tasks: after the tasks they depend on, only on the
+//! platforms they're for, and only when their `run-if` condition holds.
+
+use crate::{
+    dep_types::DependencyError,
+    util::{self, abort, Os},
+};
+use crossterm::Color;
+use data_encoding::HEXLOWER;
+use regex::Regex;
+use ring::digest;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+/// A condition that decides if a task runs, from its `run-if` field.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// `files_changed('proto/**')`: Files matching the pattern changed since the task last ran.
+    FilesChanged(String),
+}
+
+impl FromStr for Condition {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re = Regex::new(r#"^files_changed\(\s*['"](.+)['"]\s*\)$"#).unwrap();
+        match re.captures(s.trim()) {
+            Some(caps) => Ok(Self::FilesChanged(caps.get(1).unwrap().as_str().to_owned())),
+            None => Err(DependencyError::new(&format!(
+                "Problem parsing `run-if = \"{}\"`. Use eg `files_changed('proto/**')`",
+                s
+            ))),
+        }
+    }
+}
+
+/// Options for a script, when it's specified as a table.
+#[derive(Clone, Debug, Default)]
+pub struct TaskOpts {
+    pub depends_on: Vec<String>,
+    pub run_if: Option<Condition>,
+    pub platforms: Vec<Os>, // Empty means all.
+}
+
+/// What tasks run with: the project's scripts, their options, and where they run.
+pub struct TaskContext<'a> {
+    pub scripts: &'a HashMap<String, String>,
+    pub tasks: &'a HashMap<String, TaskOpts>,
+    pub os: Os,
+    pub proj_path: &'a Path,
+    /// Records what each `files_changed` condition saw when its task last succeeded.
+    pub state_path: PathBuf,
+}
+
+/// The tasks to run, in order, and those left out since they're for other platforms.
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    order: Vec<String>,
+    skipped: Vec<String>,
+}
+
+/// Find the tasks to run for `name`, in order: each after the tasks it depends on, and each only
+/// once. Tasks for other platforms are left out, along with the tasks they depend on.
+fn plan(name: &str, ctx: &TaskContext) -> Result<Plan, DependencyError> {
+    fn visit(
+        name: &str,
+        ctx: &TaskContext,
+        visiting: &mut Vec<String>,
+        result: &mut Plan,
+    ) -> Result<(), DependencyError> {
+        if result
+            .order
+            .iter()
+            .chain(&result.skipped)
+            .any(|r| r == name)
+        {
+            return Ok(());
+        }
+        if visiting.iter().any(|v| v == name) {
+            visiting.push(name.to_owned());
+            return Err(DependencyError::new(&format!(
+                "Scripts in `pyproject.toml` depend on each other in a loop: {}",
+                visiting.join(" → ")
+            )));
+        }
+        if !ctx.scripts.contains_key(name) {
+            return Err(DependencyError::new(&format!(
+                "`{}` depends on `{}`, which isn't in `[tool.pyflow.scripts]`",
+                visiting.last().map(String::as_str).unwrap_or_default(),
+                name
+            )));
+        }
+
+        let opts = ctx.tasks.get(name).cloned().unwrap_or_default();
+        if !opts.platforms.is_empty() && !opts.platforms.contains(&ctx.os) {
+            result.skipped.push(name.to_owned());
+            return Ok(());
+        }
+
+        visiting.push(name.to_owned());
+        for dep in &opts.depends_on {
+            visit(dep, ctx, visiting, result)?;
+        }
+        visiting.pop();
+
+        result.order.push(name.to_owned());
+        Ok(())
+    }
+
+    let mut result = Plan::default();
+    visit(name, ctx, &mut vec![], &mut result)?;
+    Ok(result)
+}
+
+/// Check if a path, relative to the project, matches a pattern. `*` matches within a path
+/// component, and `**` matches any number of them.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    fn match_part(pattern: &str, part: &str) -> bool {
+        let re = format!(
+            "^{}$",
+            pattern
+                .split('*')
+                .map(regex::escape)
+                .collect::<Vec<String>>()
+                .join(".*")
+        );
+        Regex::new(&re).map_or(false, |r| r.is_match(part))
+    }
+
+    fn match_parts(pattern: &[&str], path: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => path.is_empty(),
+            Some((&"**", rest)) => (0..=path.len()).any(|i| match_parts(rest, &path[i..])),
+            Some((first, rest)) => match path.split_first() {
+                Some((p, path_rest)) => match_part(first, p) && match_parts(rest, path_rest),
+                None => false,
+            },
+        }
+    }
+
+    let pattern: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    match_parts(&pattern, &path)
+}
+
+/// Find all files in the project, as paths relative to it with `/` separators. Skips
+/// environments and version control.
+fn find_files(dir: &Path, rel: &str, result: &mut Vec<(String, PathBuf)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if ["__pypackages__", ".git", ".venv", "__pycache__"].contains(&file_name.as_str()) {
+            continue;
+        }
+        let rel_path = if rel.is_empty() {
+            file_name
+        } else {
+            format!("{}/{}", rel, file_name)
+        };
+        let path = entry.path();
+        if path.is_dir() {
+            find_files(&path, &rel_path, result);
+        } else {
+            result.push((rel_path, path));
+        }
+    }
+}
+
+/// A digest of the names, sizes and modification times of files matching a pattern.
+fn fingerprint(proj_path: &Path, pattern: &str) -> String {
+    let mut files = vec![];
+    find_files(proj_path, "", &mut files);
+    files.retain(|(rel, _)| glob_match(pattern, rel));
+    files.sort();
+
+    let mut data = String::new();
+    for (rel, path) in &files {
+        let (len, modified) = match fs::metadata(path) {
+            Ok(m) => (
+                m.len(),
+                m.modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_nanos()),
+            ),
+            Err(_) => (0, 0),
+        };
+        data.push_str(&format!("{} {} {}\n", rel, len, modified));
+    }
+    HEXLOWER.encode(digest::digest(&digest::SHA256, data.as_bytes()).as_ref())
+}
+
+fn read_state(path: &Path) -> HashMap<String, String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|data| toml::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Run a script from `[tool.pyflow.scripts]`, after the tasks it depends on. `run_call` runs a
+/// `module:function` entry with arguments; only the requested task gets `args`.
+pub fn run(
+    name: &str,
+    ctx: &TaskContext,
+    args: Vec<String>,
+    run_call: impl Fn(&str, &str, Vec<String>),
+) {
+    let Plan { order, skipped } = match plan(name, ctx) {
+        Ok(p) => p,
+        Err(e) => {
+            abort(&e.details);
+            unreachable!()
+        }
+    };
+    for task in &skipped {
+        util::print_color(
+            &format!("Skipping `{}`; it isn't for this platform", task),
+            Color::DarkBlue,
+        );
+    }
+    if order.is_empty() {
+        return;
+    }
+
+    let mut state = read_state(&ctx.state_path);
+    let mut args = Some(args);
+    for task in &order {
+        let opts = ctx.tasks.get(task).cloned().unwrap_or_default();
+
+        let mut new_fingerprint = None;
+        if let Some(Condition::FilesChanged(pattern)) = &opts.run_if {
+            let current = fingerprint(ctx.proj_path, pattern);
+            if state.get(task) == Some(&current) {
+                util::print_color(
+                    &format!("Skipping `{}`; no files matching {} changed", task, pattern),
+                    Color::DarkBlue,
+                );
+                continue;
+            }
+            new_fingerprint = Some(current);
+        }
+
+        if order.len() > 1 {
+            util::print_color(&format!("Running `{}`", task), Color::Cyan);
+        }
+        let task_args = if task == name {
+            args.take().unwrap_or_default()
+        } else {
+            vec![]
+        };
+        run_call(task, &ctx.scripts[task], task_args);
+
+        if let Some(f) = new_fingerprint {
+            state.insert(task.clone(), f);
+            if let Ok(data) = toml::to_string(&state) {
+                fs::write(&ctx.state_path, data).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn scripts(names: &[&str]) -> HashMap<String, String> {
+        names
+            .iter()
+            .map(|n| (n.to_string(), format!("tools:{}", n)))
+            .collect()
+    }
+
+    fn ctx<'a>(
+        scripts: &'a HashMap<String, String>,
+        tasks: &'a HashMap<String, TaskOpts>,
+        os: Os,
+    ) -> TaskContext<'a> {
+        TaskContext {
+            scripts,
+            tasks,
+            os,
+            proj_path: Path::new("."),
+            state_path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn task_order() {
+        let scripts = scripts(&["proto", "assets", "build", "sign"]);
+        let mut tasks = HashMap::new();
+        tasks.insert(
+            "build".to_owned(),
+            TaskOpts {
+                depends_on: vec!["proto".into(), "assets".into(), "sign".into()],
+                ..Default::default()
+            },
+        );
+        tasks.insert(
+            "assets".to_owned(),
+            TaskOpts {
+                depends_on: vec!["proto".into()],
+                ..Default::default()
+            },
+        );
+        tasks.insert(
+            "sign".to_owned(),
+            TaskOpts {
+                platforms: vec![Os::Windows],
+                ..Default::default()
+            },
+        );
+
+        let linux = plan("build", &ctx(&scripts, &tasks, Os::Linux)).unwrap();
+        assert_eq!(linux.order, vec!["proto", "assets", "build"]);
+        assert_eq!(linux.skipped, vec!["sign"]);
+        let windows = plan("build", &ctx(&scripts, &tasks, Os::Windows)).unwrap();
+        assert_eq!(windows.order, vec!["proto", "assets", "sign", "build"]);
+        assert!(windows.skipped.is_empty());
+        assert_eq!(
+            plan("sign", &ctx(&scripts, &tasks, Os::Linux)).unwrap(),
+            Plan {
+                order: vec![],
+                skipped: vec!["sign".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn task_errors() {
+        let scripts = scripts(&["a", "b"]);
+        let mut tasks = HashMap::new();
+        tasks.insert(
+            "a".to_owned(),
+            TaskOpts {
+                depends_on: vec!["b".into()],
+                ..Default::default()
+            },
+        );
+        tasks.insert(
+            "b".to_owned(),
+            TaskOpts {
+                depends_on: vec!["a".into()],
+                ..Default::default()
+            },
+        );
+        assert!(plan("a", &ctx(&scripts, &tasks, Os::Linux)).is_err());
+
+        tasks.insert(
+            "b".to_owned(),
+            TaskOpts {
+                depends_on: vec!["missing".into()],
+                ..Default::default()
+            },
+        );
+        assert!(plan("a", &ctx(&scripts, &tasks, Os::Linux)).is_err());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("proto/**", "proto/api.proto"));
+        assert!(glob_match("proto/**", "proto/v1/api.proto"));
+        assert!(glob_match("**/*.proto", "proto/v1/api.proto"));
+        assert!(glob_match("src/*.py", "src/main.py"));
+        assert!(!glob_match("src/*.py", "src/pkg/main.py"));
+        assert!(!glob_match("proto/**", "protocols/api.proto"));
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(
+            Condition::from_str("files_changed('proto/**')").unwrap(),
+            Condition::FilesChanged("proto/**".into())
+        );
+        assert_eq!(
+            Condition::from_str("files_changed(\"src/*.py\")").unwrap(),
+            Condition::FilesChanged("src/*.py".into())
+        );
+        assert!(Condition::from_str("always").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+//! `pyflow tree`: the dependency graph in `pyflow.lock`, as an indented tree. Packages shown
+//! earlier in the tree are marked `(*)` rather than shown again, with their dependencies. Also
+//! `pyflow why`, which shows each chain of dependencies that leads to a package.
+
+use crate::{
+    dep_types::{LockPackage, Version},
+    util,
+};
+use std::str::FromStr;
+
+/// The packages each package depends on, by index in the lock, from the `name version source`
+/// entries in its `dependencies`.
+fn dependencies(packages: &[LockPackage]) -> Vec<Vec<usize>> {
+    packages
+        .iter()
+        .map(|lp| {
+            let mut result: Vec<usize> = lp
+                .dependencies
+                .iter()
+                .flatten()
+                .filter_map(|dep| {
+                    let mut parts = dep.split_whitespace();
+                    let name = parts.next()?;
+                    let version = Version::from_str(parts.next()?).ok();
+                    packages.iter().position(|p| {
+                        util::compare_names(&p.name, name)
+                            && Version::from_str(&p.version).ok() == version
+                    })
+                })
+                .collect();
+            result.sort_by_key(|&i| util::standardize_name(&packages[i].name));
+            result.dedup();
+            result
+        })
+        .collect()
+}
+
+struct Tree<'a> {
+    packages: &'a [LockPackage],
+    /// For each package, the ones below it in the tree: its dependencies, or if inverted, the
+    /// packages that depend on it.
+    children: Vec<Vec<usize>>,
+    direct: Vec<bool>,
+    invert: bool,
+    depth: Option<usize>,
+    shown: Vec<bool>,
+    lines: Vec<String>,
+}
+
+impl Tree<'_> {
+    fn label(&self, i: usize) -> String {
+        let lp = &self.packages[i];
+        let mut result = format!("{} {}", lp.name, lp.version);
+        if self.invert && self.direct[i] {
+            result.push_str(" (direct)");
+        }
+        result
+    }
+
+    fn add(&mut self, i: usize, prefix: &str, connector: &str, level: usize) {
+        let expandable = !self.children[i].is_empty() && self.depth.is_none_or(|d| level < d);
+        if self.shown[i] && expandable {
+            self.lines
+                .push(format!("{}{}{} (*)", prefix, connector, self.label(i)));
+            return;
+        }
+        self.lines
+            .push(format!("{}{}{}", prefix, connector, self.label(i)));
+        self.shown[i] = true;
+        if !expandable {
+            return;
+        }
+
+        let child_prefix = format!(
+            "{}{}",
+            prefix,
+            match connector {
+                "" => "",
+                "└── " => "    ",
+                _ => "│   ",
+            }
+        );
+        let children = self.children[i].clone();
+        for (n, &child) in children.iter().enumerate() {
+            let connector = if n + 1 == children.len() {
+                "└── "
+            } else {
+                "├── "
+            };
+            self.add(child, &child_prefix, connector, level + 1);
+        }
+    }
+}
+
+/// Render the lock's dependency tree. It starts from the packages the project requires directly,
+/// named in `direct`, or from `package`. With `invert`, it shows what depends on each package
+/// instead, starting from `package`, or from those with no dependencies of their own. `depth`
+/// limits how many levels below those are shown.
+pub fn render(
+    packages: &[LockPackage],
+    direct: &[String],
+    package: Option<&str>,
+    invert: bool,
+    depth: Option<usize>,
+) -> Result<String, String> {
+    let deps = dependencies(packages);
+    let by_name = |i: &usize| util::standardize_name(&packages[*i].name);
+    let children = if invert {
+        (0..packages.len())
+            .map(|i| {
+                let mut parents: Vec<usize> = (0..packages.len())
+                    .filter(|&p| deps[p].contains(&i))
+                    .collect();
+                parents.sort_by_key(by_name);
+                parents
+            })
+            .collect()
+    } else {
+        deps.clone()
+    };
+    let is_direct: Vec<bool> = packages
+        .iter()
+        .map(|lp| direct.iter().any(|d| util::compare_names(d, &lp.name)))
+        .collect();
+
+    let mut roots: Vec<usize> = match package {
+        Some(name) => {
+            let found: Vec<usize> = (0..packages.len())
+                .filter(|&i| util::compare_names(&packages[i].name, name))
+                .collect();
+            if found.is_empty() {
+                return Err(format!("{} isn't in `pyflow.lock`", name));
+            }
+            found
+        }
+        None if invert => (0..packages.len())
+            .filter(|&i| deps[i].is_empty())
+            .collect(),
+        None => (0..packages.len()).filter(|&i| is_direct[i]).collect(),
+    };
+    // Eg if `pyproject.toml` and the lock are out of sync, start from what nothing depends on.
+    if roots.is_empty() {
+        roots = (0..packages.len())
+            .filter(|&i| !deps.iter().any(|d| d.contains(&i)))
+            .collect();
+    }
+    roots.sort_by_key(by_name);
+
+    let mut tree = Tree {
+        packages,
+        children,
+        direct: is_direct,
+        invert,
+        depth,
+        shown: vec![false; packages.len()],
+        lines: vec![],
+    };
+    for root in roots {
+        tree.add(root, "", "", 0);
+    }
+    let count = tree.shown.iter().filter(|s| **s).count();
+    tree.lines.push(format!(
+        "\n{} package{}",
+        count,
+        if count == 1 { "" } else { "s" }
+    ));
+    Ok(tree.lines.join("\n"))
+}
+
+/// Add each chain that leads from a root down to `chain`'s first package, by walking up through
+/// `parents`. Packages already in the chain are skipped, so cycles end.
+fn find_chains(
+    parents: &[Vec<usize>],
+    is_root: &[bool],
+    chain: &mut Vec<usize>,
+    chains: &mut Vec<Vec<usize>>,
+) {
+    let first = chain[0];
+    if is_root[first] {
+        chains.push(chain.clone());
+    }
+    for &parent in &parents[first] {
+        if chain.contains(&parent) {
+            continue;
+        }
+        chain.insert(0, parent);
+        find_chains(parents, is_root, chain, chains);
+        chain.remove(0);
+    }
+}
+
+/// Explain why a package is in the lock: every chain of dependencies from a package the project
+/// requires directly, named in `direct`, down to it.
+pub fn why(packages: &[LockPackage], direct: &[String], package: &str) -> Result<String, String> {
+    let deps = dependencies(packages);
+    let parents: Vec<Vec<usize>> = (0..packages.len())
+        .map(|i| {
+            (0..packages.len())
+                .filter(|&p| deps[p].contains(&i))
+                .collect()
+        })
+        .collect();
+    let mut is_root: Vec<bool> = packages
+        .iter()
+        .map(|lp| direct.iter().any(|d| util::compare_names(d, &lp.name)))
+        .collect();
+    // As with `render`, if `pyproject.toml` and the lock are out of sync.
+    if !is_root.contains(&true) {
+        is_root = parents.iter().map(|p| p.is_empty()).collect();
+    }
+
+    let targets: Vec<usize> = (0..packages.len())
+        .filter(|&i| util::compare_names(&packages[i].name, package))
+        .collect();
+    if targets.is_empty() {
+        return Err(format!("{} isn't in `pyflow.lock`", package));
+    }
+
+    let mut chains = vec![];
+    for target in targets {
+        find_chains(&parents, &is_root, &mut vec![target], &mut chains);
+    }
+    if chains.is_empty() {
+        return Err(format!(
+            "Nothing the project requires depends on {}",
+            package
+        ));
+    }
+    let label = |i: &usize| format!("{} {}", packages[*i].name, packages[*i].version);
+    let mut lines: Vec<String> = chains
+        .iter()
+        .map(|chain| {
+            let mut line = chain.iter().map(label).collect::<Vec<String>>().join(" → ");
+            if chain.len() == 1 {
+                line.push_str(" (direct)");
+            }
+            line
+        })
+        .collect();
+    lines.sort_by_key(|l| l.to_lowercase());
+    lines.push(format!(
+        "\n{} chain{}",
+        chains.len(),
+        if chains.len() == 1 { "" } else { "s" }
+    ));
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn pack(id: u32, name: &str, version: &str, deps: &[&str]) -> LockPackage {
+        LockPackage {
+            id,
+            name: name.into(),
+            version: version.into(),
+            source: None,
+            dependencies: Some(
+                deps.iter()
+                    .map(|d| format!("{} pypi+https://pypi.org/pypi/x/json", d))
+                    .collect(),
+            ),
+            rename: None,
+            markers: None,
+            extras: None,
+        }
+    }
+
+    fn lock() -> Vec<LockPackage> {
+        vec![
+            pack(
+                1,
+                "requests",
+                "2.31.0",
+                &["urllib3 1.26.18", "idna 3.6.0", "certifi 2024.2.2"],
+            ),
+            pack(2, "urllib3", "1.26.18", &[]),
+            pack(3, "idna", "3.6.0", &[]),
+            pack(4, "certifi", "2024.2.2", &[]),
+            pack(5, "httpx", "0.27.0", &["idna 3.6.0", "certifi 2024.2.2"]),
+        ]
+    }
+
+    #[test]
+    fn tree_dedups_repeats() {
+        let direct = vec!["requests".to_owned(), "httpx".to_owned()];
+        assert_eq!(
+            render(&lock(), &direct, None, false, None).unwrap(),
+            "httpx 0.27.0
+├── certifi 2024.2.2
+└── idna 3.6.0
+requests 2.31.0
+├── certifi 2024.2.2
+├── idna 3.6.0
+└── urllib3 1.26.18
+
+5 packages"
+        );
+        assert_eq!(
+            render(&lock(), &direct, None, false, Some(0)).unwrap(),
+            "httpx 0.27.0\nrequests 2.31.0\n\n2 packages"
+        );
+    }
+
+    #[test]
+    fn inverted_tree() {
+        let direct = vec!["requests".to_owned(), "httpx".to_owned()];
+        assert_eq!(
+            render(&lock(), &direct, Some("IDNA"), true, None).unwrap(),
+            "idna 3.6.0
+├── httpx 0.27.0 (direct)
+└── requests 2.31.0 (direct)
+
+3 packages"
+        );
+        assert!(render(&lock(), &direct, Some("six"), true, None).is_err());
+
+        // Cycles end where a package is shown again.
+        let lock = vec![
+            pack(1, "a", "1.0.0", &["b 1.0.0"]),
+            pack(2, "b", "1.0.0", &["a 1.0.0"]),
+        ];
+        assert_eq!(
+            render(&lock, &["a".into()], None, false, None).unwrap(),
+            "a 1.0.0\n└── b 1.0.0\n    └── a 1.0.0 (*)\n\n2 packages"
+        );
+    }
+
+    #[test]
+    fn why_shows_each_chain() {
+        let mut lock = lock();
+        lock.push(pack(6, "chardet", "5.2.0", &["idna 3.6.0"]));
+        lock[0]
+            .dependencies
+            .as_mut()
+            .unwrap()
+            .push("chardet 5.2.0 pypi+https://pypi.org/pypi/x/json".into());
+        let direct = vec!["requests".to_owned(), "httpx".to_owned(), "idna".to_owned()];
+        assert_eq!(
+            why(&lock, &direct, "idna").unwrap(),
+            "httpx 0.27.0 → idna 3.6.0
+idna 3.6.0 (direct)
+requests 2.31.0 → chardet 5.2.0 → idna 3.6.0
+requests 2.31.0 → idna 3.6.0
+
+4 chains"
+        );
+        assert!(why(&lock, &direct, "six").is_err());
+
+        // Cycles don't repeat.
+        let lock = vec![
+            pack(1, "a", "1.0.0", &["b 1.0.0"]),
+            pack(2, "b", "1.0.0", &["a 1.0.0"]),
+        ];
+        assert_eq!(
+            why(&lock, &["a".into()], "b").unwrap(),
+            "a 1.0.0 → b 1.0.0\n\n1 chain"
+        );
+    }
+}
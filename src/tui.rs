@@ -0,0 +1,471 @@
+//! `pyflow ui`: a terminal dashboard, for exploring a project instead of remembering subcommands.
+//! Its tabs show the output of `pyflow tree`, `pyflow outdated` and `pyflow status`, and keys run
+//! `pyflow info`, `install` and `uninstall` on the selected package. Commands run as separate
+//! processes, so one that fails doesn't leave the terminal in raw mode.
+
+use crate::{dep_types::Version, util};
+use crossterm::{
+    cursor, input, terminal, AlternateScreen, Attribute, ClearType, InputEvent, KeyEvent,
+};
+use std::{
+    env,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tab {
+    Tree = 0,
+    Outdated = 1,
+    Status = 2,
+}
+
+impl Tab {
+    const ALL: [Self; 3] = [Self::Tree, Self::Outdated, Self::Status];
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Tree => "Tree",
+            Self::Outdated => "Outdated",
+            Self::Status => "Status",
+        }
+    }
+
+    /// The subcommand whose output it shows.
+    fn command(self) -> &'static str {
+        match self {
+            Self::Tree => "tree",
+            Self::Outdated => "outdated",
+            Self::Status => "status",
+        }
+    }
+}
+
+/// What a key asks for, besides redrawing.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Quit,
+    /// Load a tab's output, or load it again.
+    Load(Tab),
+    Info(String),
+    /// Let a package move to the newest version its constraints allow.
+    Update(String),
+    Remove(String),
+}
+
+const HELP: &str = "↑↓ move  ←→ tabs  enter info  u update  r remove  f refresh  q quit";
+
+/// The package a line of `pyflow tree` or `pyflow outdated` output is about, eg
+/// `│   └── idna 3.6.0`, or `urllib3 1.26.18 → 2.2.1: 2 known vulnerabilities`.
+fn package_on(line: &str) -> Option<&str> {
+    let mut words = line
+        .trim_start_matches(|c| "│├└─ ".contains(c))
+        .split_whitespace();
+    let name = words.next()?;
+    Version::from_str(words.next()?.trim_end_matches(':')).ok()?;
+    Some(name)
+}
+
+struct Dashboard {
+    tab: Tab,
+    /// Each tab's output, once loaded.
+    output: [Option<Vec<String>>; 3],
+    /// `pyflow info`'s output, shown over the tab until Esc's pressed.
+    details: Option<Vec<String>>,
+    /// The packages at the tree's top level, which the project requires directly.
+    direct: Vec<String>,
+    selected: usize,
+    scroll: usize,
+    /// An action waiting for `y`.
+    confirming: Option<Action>,
+    message: String,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Self {
+            tab: Tab::Tree,
+            output: [None, None, None],
+            details: None,
+            direct: vec![],
+            selected: 0,
+            scroll: 0,
+            confirming: None,
+            message: String::new(),
+        }
+    }
+
+    fn set_output(&mut self, tab: Tab, lines: Vec<String>) {
+        if tab == Tab::Tree {
+            self.direct = lines
+                .iter()
+                .filter(|l| !l.starts_with(|c| "│├└ ".contains(c)))
+                .filter_map(|l| package_on(l))
+                .map(str::to_owned)
+                .collect();
+        }
+        self.output[tab as usize] = Some(lines);
+        if tab == self.tab {
+            self.selected = self.selected.min(self.lines().len().saturating_sub(1));
+        }
+    }
+
+    fn lines(&self) -> &[String] {
+        match (&self.details, &self.output[self.tab as usize]) {
+            (Some(lines), _) | (None, Some(lines)) => lines,
+            (None, None) => &[],
+        }
+    }
+
+    fn selected_package(&self) -> Option<String> {
+        if self.details.is_some() {
+            return None;
+        }
+        self.lines()
+            .get(self.selected)
+            .and_then(|l| package_on(l))
+            .map(str::to_owned)
+    }
+
+    fn switch_to(&mut self, tab: Tab) -> Option<Action> {
+        self.tab = tab;
+        self.details = None;
+        self.selected = 0;
+        self.scroll = 0;
+        if self.output[tab as usize].is_none() {
+            Some(Action::Load(tab))
+        } else {
+            None
+        }
+    }
+
+    fn handle(&mut self, key: KeyEvent) -> Option<Action> {
+        self.message.clear();
+        if let Some(action) = self.confirming.take() {
+            if key == KeyEvent::Char('y') {
+                return Some(action);
+            }
+            self.message = "Cancelled".into();
+            return None;
+        }
+
+        let last = self.lines().len().saturating_sub(1);
+        let tab = self.tab as usize;
+        match key {
+            KeyEvent::Char('q') | KeyEvent::Ctrl('c') => return Some(Action::Quit),
+            KeyEvent::Esc if self.details.is_some() => {
+                self.details = None;
+                self.selected = 0;
+                self.scroll = 0;
+            }
+            KeyEvent::Esc => return Some(Action::Quit),
+            KeyEvent::Up | KeyEvent::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyEvent::Down | KeyEvent::Char('j') => self.selected = (self.selected + 1).min(last),
+            KeyEvent::PageUp => self.selected = self.selected.saturating_sub(10),
+            KeyEvent::PageDown => self.selected = (self.selected + 10).min(last),
+            KeyEvent::Home => self.selected = 0,
+            KeyEvent::End => self.selected = last,
+            KeyEvent::Right | KeyEvent::Tab => return self.switch_to(Tab::ALL[(tab + 1) % 3]),
+            KeyEvent::Left | KeyEvent::BackTab => return self.switch_to(Tab::ALL[(tab + 2) % 3]),
+            KeyEvent::Char(c @ '1'..='3') => {
+                return self.switch_to(Tab::ALL[c as usize - '1' as usize])
+            }
+            KeyEvent::Char('f') => {
+                self.details = None;
+                return Some(Action::Load(self.tab));
+            }
+            KeyEvent::Enter | KeyEvent::Char('i') | KeyEvent::Char('u') | KeyEvent::Char('r') => {
+                let name = match self.selected_package() {
+                    Some(n) => n,
+                    None => {
+                        self.message = "Select a package in the Tree or Outdated tab".into();
+                        return None;
+                    }
+                };
+                match key {
+                    KeyEvent::Char('u') => {
+                        self.message = format!(
+                            "Update {} to the newest version its constraints allow? (y/n)",
+                            name
+                        );
+                        self.confirming = Some(Action::Update(name));
+                    }
+                    KeyEvent::Char('r') => {
+                        if self.direct.iter().any(|d| util::compare_names(d, &name)) {
+                            self.message = format!("Remove {} from the project? (y/n)", name);
+                            self.confirming = Some(Action::Remove(name));
+                        } else {
+                            self.message = format!(
+                                "Other packages require {}; `pyflow why {}` shows which",
+                                name, name
+                            );
+                        }
+                    }
+                    _ => return Some(Action::Info(name)),
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    /// The screen's lines, and which of them is selected.
+    fn render(&mut self, width: usize, height: usize) -> (Vec<String>, Option<usize>) {
+        let rows = height.saturating_sub(4).max(1);
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+
+        let tabs: Vec<String> = Tab::ALL
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                if *t == self.tab {
+                    format!("[{} {}]", i + 1, t.title())
+                } else {
+                    format!(" {} {} ", i + 1, t.title())
+                }
+            })
+            .collect();
+        let mut result = vec![format!("pyflow  {}", tabs.join(" ")), "─".repeat(width)];
+
+        let lines = self.lines();
+        if self.output[self.tab as usize].is_none() {
+            result.push("Loading...".into());
+        }
+        result.extend(lines.iter().skip(self.scroll).take(rows).cloned());
+        let selected = if lines.is_empty() {
+            None
+        } else {
+            Some(self.selected - self.scroll + 2)
+        };
+
+        result.resize(height.saturating_sub(2), String::new());
+        result.push(self.message.clone());
+        result.push(HELP.into());
+        let result = result
+            .into_iter()
+            .enumerate()
+            .map(|(i, l)| {
+                let mut line: String = l.chars().take(width).collect();
+                if Some(i) == selected {
+                    // So the highlight spans the screen.
+                    let len = line.chars().count();
+                    line.push_str(&" ".repeat(width - len));
+                }
+                line
+            })
+            .collect();
+        (result, selected)
+    }
+}
+
+fn pyflow(proj_path: &Path, args: &[&str]) -> Command {
+    let exe = env::current_exe().unwrap_or_else(|_| "pyflow".into());
+    let mut result = Command::new(exe);
+    result.arg("--project").arg(proj_path).args(args);
+    result
+}
+
+/// Run a pyflow command, and return what it printed.
+fn capture(proj_path: &Path, args: &[&str]) -> Vec<String> {
+    let output = pyflow(proj_path, &[&["--color", "never"], args].concat())
+        .stdin(Stdio::null())
+        .output();
+    match output {
+        Ok(o) => String::from_utf8_lossy(&o.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&o.stderr).lines())
+            .map(str::to_owned)
+            .collect(),
+        Err(e) => vec![format!("Problem running pyflow: {}", e)],
+    }
+}
+
+/// Remove a package from the lock, so the next install resolves it again, and the rest stays put.
+fn unlock(lock_path: &Path, name: &str) -> Result<(), String> {
+    let mut lock = crate::read_lock(lock_path).map_err(|e| e.to_string())?;
+    if let Some(packages) = &mut lock.package {
+        packages.retain(|p| !util::compare_names(&p.name, name));
+    }
+    crate::write_lock(lock_path, &lock).map_err(|e| e.to_string())
+}
+
+fn draw(dash: &mut Dashboard) {
+    let (width, height) = match terminal().size() {
+        Ok((w, h)) if w > 0 && h > 0 => (w, h),
+        _ => (80, 24),
+    };
+    let (lines, selected) = dash.render(width as usize, height as usize);
+    terminal().clear(ClearType::All).ok();
+    for (row, line) in lines.iter().enumerate() {
+        cursor().goto(0, row as u16).ok();
+        if Some(row) == selected {
+            print!("{}{}{}", Attribute::Reverse, line, Attribute::Reset);
+        } else {
+            print!("{}", line);
+        }
+    }
+    io::stdout().flush().ok();
+}
+
+/// Show the dashboard until the user quits, or picks an action that runs in the main screen.
+fn show(dash: &mut Dashboard, proj_path: &Path) -> Action {
+    let _screen = match AlternateScreen::to_alternate(true) {
+        Ok(s) => s,
+        Err(e) => {
+            util::abort(&format!("Problem setting up the terminal: {}", e));
+            unreachable!()
+        }
+    };
+    cursor().hide().ok();
+    let mut keys = input().read_sync();
+    // Reads fail if they're interrupted, eg when the terminal's resized, and for keys crossterm
+    // can't parse; only give up if they keep failing.
+    let mut failed_reads = 0;
+    let action = loop {
+        draw(dash);
+        let key = match keys.next() {
+            Some(InputEvent::Keyboard(k)) => k,
+            Some(_) => continue,
+            None if failed_reads < 100 => {
+                failed_reads += 1;
+                continue;
+            }
+            None => break Action::Quit,
+        };
+        failed_reads = 0;
+        match dash.handle(key) {
+            Some(Action::Load(tab)) => {
+                dash.output[tab as usize] = None;
+                draw(dash);
+                let lines = capture(proj_path, &[tab.command()]);
+                dash.set_output(tab, lines);
+            }
+            Some(Action::Info(name)) => {
+                dash.details = Some(capture(proj_path, &["info", &name]));
+                dash.selected = 0;
+                dash.scroll = 0;
+            }
+            Some(a) => break a,
+            None => (),
+        }
+    };
+    cursor().show().ok();
+    action
+}
+
+pub fn run(proj_path: &Path, lock_path: &Path) {
+    if !atty::is(atty::Stream::Stdout) || !atty::is(atty::Stream::Stdin) {
+        util::abort("`pyflow ui` needs a terminal");
+    }
+    let mut dash = Dashboard::new();
+    dash.set_output(Tab::Tree, capture(proj_path, &["tree"]));
+
+    loop {
+        let ran = match show(&mut dash, proj_path) {
+            Action::Update(name) => match unlock(lock_path, &name) {
+                Ok(()) => pyflow(proj_path, &["install"]).status(),
+                Err(e) => {
+                    dash.message = format!("Problem updating `pyflow.lock`: {}", e);
+                    continue;
+                }
+            },
+            Action::Remove(name) => pyflow(proj_path, &["uninstall", &name]).status(),
+            _ => return,
+        };
+        if let Err(e) = ran {
+            util::print_color(
+                &format!("Problem running pyflow: {}", e),
+                crossterm::Color::Red,
+            );
+        }
+        println!("Press Enter to go back to the dashboard");
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).ok();
+
+        // The lock, and so each tab, may have changed.
+        let tab = dash.tab;
+        dash.output = [None, None, None];
+        dash.set_output(Tab::Tree, capture(proj_path, &["tree"]));
+        if tab != Tab::Tree {
+            let lines = capture(proj_path, &[tab.command()]);
+            dash.set_output(tab, lines);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn dashboard() -> Dashboard {
+        let mut dash = Dashboard::new();
+        let tree = "httpx 0.27.0\n├── certifi 2024.2.2\n└── idna 3.6.0\nrequests 2.31.0\n\
+                    └── urllib3 1.26.18\n\n5 packages";
+        dash.set_output(Tab::Tree, tree.lines().map(str::to_owned).collect());
+        dash
+    }
+
+    #[test]
+    fn finds_packages_on_lines() {
+        assert_eq!(package_on("│   └── idna 3.6.0 (*)"), Some("idna"));
+        assert_eq!(
+            package_on("urllib3 1.26.18 → 2.2.1: 2 known vulnerabilities"),
+            Some("urllib3")
+        );
+        assert_eq!(package_on("5 packages"), None);
+        assert_eq!(package_on(""), None);
+        assert_eq!(dashboard().direct, vec!["httpx", "requests"]);
+    }
+
+    #[test]
+    fn keys_select_and_act() {
+        let mut dash = dashboard();
+        assert_eq!(
+            dash.handle(KeyEvent::Enter),
+            Some(Action::Info("httpx".into()))
+        );
+        dash.handle(KeyEvent::Down);
+        dash.handle(KeyEvent::Down);
+        assert_eq!(dash.selected_package().as_deref(), Some("idna"));
+
+        // Only direct dependencies can be removed, and actions wait for `y`.
+        assert_eq!(dash.handle(KeyEvent::Char('r')), None);
+        assert!(dash.message.contains("pyflow why idna"));
+        assert_eq!(dash.handle(KeyEvent::Char('u')), None);
+        assert_eq!(
+            dash.handle(KeyEvent::Char('y')),
+            Some(Action::Update("idna".into()))
+        );
+        dash.handle(KeyEvent::End);
+        assert_eq!(dash.handle(KeyEvent::Char('r')), None);
+        assert_eq!(dash.selected_package(), None);
+
+        assert_eq!(
+            dash.handle(KeyEvent::Right),
+            Some(Action::Load(Tab::Outdated))
+        );
+        assert_eq!(dash.selected, 0);
+        assert_eq!(dash.handle(KeyEvent::Char('1')), None);
+        assert_eq!(dash.handle(KeyEvent::Char('q')), Some(Action::Quit));
+    }
+
+    #[test]
+    fn render_scrolls_to_selection() {
+        let mut dash = dashboard();
+        dash.handle(KeyEvent::End);
+        let (lines, selected) = dash.render(20, 7);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(dash.scroll, 4);
+        assert_eq!(selected, Some(4));
+        assert_eq!(lines[3], "");
+        assert_eq!(lines[4], format!("5 packages{}", " ".repeat(10)));
+        assert!(lines[0].starts_with("pyflow  [1 Tree]"));
+        assert_eq!(lines[1].chars().count(), 20);
+    }
+}
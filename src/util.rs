@@ -3,19 +3,26 @@ use crate::{
     dep_types::{Constraint, DependencyError, Req, ReqType, Version},
     files,
     install::{self, PackageType},
-    py_versions,
+    net, py_versions,
 };
 use crossterm::{Color, Colored};
 use ini::Ini;
 use regex::Regex;
 use serde::Deserialize;
+use std::ffi::OsStr;
 use std::io::{self, BufRead, BufReader, Read};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{
     collections::HashMap,
     env, fs,
     path::{Path, PathBuf},
-    process, thread, time,
+    process,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread, time,
 };
 use tar::Archive;
 use xz2::read::XzDecoder;
@@ -26,19 +33,15 @@ pub struct Paths {
     pub lib: PathBuf,
     pub entry_pt: PathBuf,
     pub cache: PathBuf,
+    /// Where source packages are built, from `--temp-dir` or `temp_dir` in `pyproject.toml`.
+    /// Without either, the system's, eg `/tmp` or `TMPDIR`.
+    pub temp: PathBuf,
 }
 
 /// Used to store a Wheel's metadata, from dist-info/METADATA
 #[derive(Debug, Default)]
 pub struct Metadata {
-    pub name: String,
-    pub summary: Option<String>,
     pub version: Version,
-    pub author: Option<String>,
-    pub author_email: Option<String>,
-    pub license: Option<String>,
-    pub keywords: Vec<String>,
-    pub platform: Option<String>,
     pub requires_dist: Vec<Req>,
 }
 
@@ -87,27 +90,427 @@ impl FromStr for Os {
     }
 }
 
+/// The Python implementation a project uses. Set with eg `py_version = "pypy3.10"` in
+/// `pyproject.toml`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Implementation {
+    CPython,
+    PyPy,
+}
+
+impl Implementation {
+    /// As in the `implementation_name` marker, eg `pypy`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CPython => "cpython",
+            Self::PyPy => "pypy",
+        }
+    }
+
+    /// The prefix of wheel tags for this implementation, eg the `pp` in `pp310`.
+    pub fn wheel_tag(self) -> &'static str {
+        match self {
+            Self::CPython => "cp",
+            Self::PyPy => "pp",
+        }
+    }
+
+    /// Split the implementation from a `py_version` value, eg `pypy3.10` → (PyPy, `3.10`).
+    pub fn split_version(s: &str) -> (Self, &str) {
+        let s = s.trim();
+        if s.to_lowercase().starts_with("pypy") {
+            (Self::PyPy, &s[4..])
+        } else {
+            (Self::CPython, s)
+        }
+    }
+}
+
+impl Default for Implementation {
+    fn default() -> Self {
+        Self::CPython
+    }
+}
+
+/// Set from the `--color` flag. Eg `pyflow --color never install`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "always" => Self::Always,
+            "never" => Self::Never,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing color choice: {}. Must be `auto`, `always`, or `never`",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+// Color is on until `set_color_choice` says otherwise, so output before arg parsing is unchanged.
+static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Decide whether to color output. `always` and `never` win outright; `auto` disables color
+/// if `NO_COLOR` is set and not empty (https://no-color.org), `TERM` is `dumb`, or stdout isn't a
+/// terminal.
+fn color_enabled_for(
+    choice: ColorChoice,
+    no_color: Option<&OsStr>,
+    term: Option<&OsStr>,
+    stdout_is_tty: bool,
+) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            no_color.filter(|v| !v.is_empty()).is_none()
+                && term != Some(OsStr::new("dumb"))
+                && stdout_is_tty
+        }
+    }
+}
+
+/// Decide whether to color output, once `--color` is parsed. The environment's only read here,
+/// not each time we color something.
+pub fn set_color_choice(choice: ColorChoice) {
+    let enabled = color_enabled_for(
+        choice,
+        env::var_os("NO_COLOR").as_deref(),
+        env::var_os("TERM").as_deref(),
+        atty::is(atty::Stream::Stdout),
+    );
+    COLOR_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The escape code to switch to a foreground color, or an empty string if color is disabled.
+/// All colored output should go through this, or `print_color`.
+pub fn fg(color: Color) -> String {
+    if COLOR_ENABLED.load(Ordering::Relaxed) {
+        Colored::Fg(color).to_string()
+    } else {
+        String::new()
+    }
+}
+
 /// Print in a color, then reset formatting.
 pub fn print_color(message: &str, color: Color) {
-    println!(
-        "{}{}{}",
-        Colored::Fg(color),
-        message,
-        Colored::Fg(Color::Reset)
-    );
+    println!("{}{}{}", fg(color), message, fg(Color::Reset));
+}
+
+/// Exit codes, by the kind of failure, so CI pipelines and wrappers can act on them without
+/// parsing output. These are part of the interface; don't renumber them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCode {
+    /// Anything not covered below, including invalid arguments and config.
+    General = 1,
+    /// Dependency constraints can't be satisfied together.
+    Resolution = 2,
+    /// A download or index query failed.
+    Network = 3,
+    /// A downloaded file's hash doesn't match the one published for it.
+    HashMismatch = 4,
+    /// A suitable Python interpreter isn't installed, and can't be installed automatically.
+    MissingInterpreter = 5,
+    /// The user declined to continue at a prompt.
+    UserAbort = 6,
+    /// Packages break the rules in `[tool.pyflow.policy]`.
+    Policy = 7,
+    /// A tool run for a command failed, eg tests with `pyflow test`, or a linter.
+    ToolFailed = 8,
+    /// A package matching `internal_prefixes` was resolved from PyPI: a possible dependency
+    /// confusion attack.
+    InternalNameOnPypi = 9,
 }
 
 /// Used when the program should exit from a condition that may arise normally from program use,
 /// like incorrect info in config files, problems with dependencies, or internet connection problems.
 /// We use `expect`, `panic!` etc for problems that indicate a bug in this program.
+/// Prints a message, and exits with `code`.
+pub fn abort_with(code: ExitCode, message: &str) {
+    println!("{}{}{}", fg(Color::Red), message, fg(Color::Reset));
+    // `process::exit` doesn't run destructors, so clean up temporary directories here.
+    remove_temp_dirs();
+    process::exit(code as i32)
+}
+
 pub fn abort(message: &str) {
-    println!(
-        "{}{}{}",
-        Colored::Fg(Color::Red),
-        message,
-        Colored::Fg(Color::Reset)
-    );
-    process::exit(1)
+    abort_with(ExitCode::General, message)
+}
+
+static TEMP_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+static TEMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether we're running in a container, eg a devcontainer, Codespace, or Docker image.
+pub fn in_container() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || env::var_os("CODESPACES").is_some()
+        || env::var_os("REMOTE_CONTAINERS").is_some()
+}
+
+fn remove_temp_dirs() {
+    if let Ok(mut dirs) = TEMP_DIRS.lock() {
+        for dir in dirs.drain(..) {
+            fs::remove_dir_all(dir).ok();
+        }
+    }
+}
+
+/// A temporary directory for building and extracting packages, removed when dropped, or when
+/// we abort. All temporary files pyflow creates go in one of these.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create a directory in the system's temp directory, named from `purpose`, eg `build`.
+    #[cfg(test)]
+    pub fn new(purpose: &str) -> Self {
+        Self::new_in_root(&env::temp_dir(), purpose)
+    }
+
+    /// Create a directory in `root`, from `--temp-dir` or `temp_dir` in `pyproject.toml`.
+    pub fn new_in_root(root: &Path, purpose: &str) -> Self {
+        Self::create(root, purpose).unwrap_or_else(|| {
+            abort(&format!(
+                "Problem creating a temporary directory in {:?}. Check that it exists and is \
+                 writable, or set a different one with `--temp-dir`, or `temp_dir` in \
+                 `pyproject.toml`.",
+                root
+            ));
+            unreachable!()
+        })
+    }
+
+    /// Create a directory in `parent`, so what's put in it can be moved next to it, rather than
+    /// copied from another filesystem.
+    pub fn new_in(parent: &Path, purpose: &str) -> Self {
+        Self::create(parent, purpose).unwrap_or_else(|| {
+            abort(&format!(
+                "Problem creating a temporary directory in {:?}",
+                parent
+            ));
+            unreachable!()
+        })
+    }
+
+    fn create(root: &Path, purpose: &str) -> Option<Self> {
+        let path = root.join(format!(
+            "pyflow-{}-{}-{}",
+            purpose,
+            process::id(),
+            TEMP_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).ok()?;
+        if let Ok(mut dirs) = TEMP_DIRS.lock() {
+            dirs.push(path.clone());
+        }
+        Some(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+        if let Ok(mut dirs) = TEMP_DIRS.lock() {
+            dirs.retain(|d| d != &self.path);
+        }
+    }
+}
+
+/// What to do when pyflow runs inside an activated virtualenv or conda env. Set with
+/// `active_env` in `pyproject.toml`, or `--ignore-active-env`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum ActiveEnvPolicy {
+    /// Warn, then isolate pyflow and the processes it runs from the active env.
+    Warn,
+    /// Isolate from the active env silently.
+    Ignore,
+    /// Refuse to run until the env is deactivated.
+    Abort,
+}
+
+impl FromStr for ActiveEnvPolicy {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "warn" => Self::Warn,
+            "ignore" => Self::Ignore,
+            "abort" => Self::Abort,
+            _ => {
+                return Err(DependencyError::new(&format!(
+                    "Problem parsing `active_env`: {}. Must be `warn`, `ignore`, or `abort`",
+                    s
+                )))
+            }
+        })
+    }
+}
+
+/// Environment variables set by activating a virtualenv, or a conda env.
+const ACTIVE_ENV_VARS: &[&str] = &["VIRTUAL_ENV", "CONDA_PREFIX"];
+
+/// Find the root of an activated virtualenv or conda env, if there is one.
+fn find_active_env() -> Option<PathBuf> {
+    for var in ACTIVE_ENV_VARS {
+        if let Some(path) = env::var_os(var) {
+            if !path.is_empty() {
+                return Some(PathBuf::from(path));
+            }
+        }
+    }
+    None
+}
+
+/// Remove entries inside `env_path` from a `PATH`-style string, so we don't pick up the
+/// active env's `python` when looking for interpreters.
+fn strip_env_from_path(path_var: &std::ffi::OsStr, env_path: &Path) -> std::ffi::OsString {
+    let kept: Vec<PathBuf> = env::split_paths(path_var)
+        .filter(|p| !p.starts_with(env_path))
+        .collect();
+    env::join_paths(kept).unwrap_or_else(|_| path_var.to_owned())
+}
+
+/// Detect an activated virtualenv or conda env, and handle it according to `policy`. Unless
+/// aborting, we unset its variables and remove it from `PATH`, so the interpreter we find, and the
+/// processes we launch, don't depend on what the user has activated.
+pub fn guard_active_env(policy: ActiveEnvPolicy) {
+    let env_path = match find_active_env() {
+        Some(p) => p,
+        None => return,
+    };
+
+    match policy {
+        ActiveEnvPolicy::Abort => {
+            abort(&format!(
+                "An environment is active at {:?}. Deactivate it before running pyflow, or set \
+                 `active_env = \"ignore\"` in `pyproject.toml`.",
+                env_path
+            ));
+        }
+        ActiveEnvPolicy::Warn => print_color(
+            &format!(
+                "Ignoring the active environment at {:?}; pyflow uses its own. Pass \
+                 `--ignore-active-env`, or set `active_env` in `pyproject.toml` to silence this.",
+                env_path
+            ),
+            Color::DarkYellow,
+        ),
+        ActiveEnvPolicy::Ignore => (),
+    }
+
+    if let Some(path_var) = env::var_os("PATH") {
+        env::set_var("PATH", strip_env_from_path(&path_var, &env_path));
+    }
+    for var in ACTIVE_ENV_VARS
+        .iter()
+        .chain(&["CONDA_DEFAULT_ENV", "PYTHONHOME"])
+    {
+        env::remove_var(var);
+    }
+}
+
+/// Find the project root, by looking for `pyproject.toml` in `start`, then in its parents. Stop
+/// after checking the root of a git repo, or when crossing onto a different filesystem, so we don't
+/// pick up an unrelated project further up.
+pub fn find_project_root(start: &Path, cfg_filename: &str) -> Option<PathBuf> {
+    let start_dev = device_id(start);
+
+    let mut current = Some(start);
+    while let Some(dir) = current {
+        if device_id(dir) != start_dev {
+            break;
+        }
+        if dir.join(cfg_filename).exists() {
+            return Some(dir.to_owned());
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+        current = dir.parent();
+    }
+    None
+}
+
+/// Expand a workspace's `members` list into member directories. A trailing `/*` matches each
+/// subdirectory containing a `pyproject.toml`, eg `members = ["packages/*"]`.
+pub fn expand_members(root: &Path, members: &[String], cfg_filename: &str) -> Vec<PathBuf> {
+    let mut result = vec![];
+    for member in members {
+        if member.ends_with("/*") {
+            let parent = root.join(member.trim_end_matches("/*"));
+            let mut found: Vec<PathBuf> = find_folders(&parent)
+                .into_iter()
+                .map(|f| parent.join(f))
+                .filter(|p| p.join(cfg_filename).exists())
+                .collect();
+            found.sort();
+            result.append(&mut found);
+        } else {
+            result.push(root.join(member));
+        }
+    }
+    result
+}
+
+/// If the project at `proj_root` is a member of a workspace, find the workspace root: the nearest
+/// parent project listing it in `members`. Uses the same stopping rules as `find_project_root`.
+pub fn find_workspace_root(
+    proj_root: &Path,
+    cfg_filename: &str,
+    net: &net::Settings,
+) -> Option<PathBuf> {
+    let canon_proj = proj_root.canonicalize().ok()?;
+
+    let mut start = proj_root.parent().map(Path::to_path_buf);
+    while let Some(dir) = start {
+        let candidate = find_project_root(&dir, cfg_filename)?;
+        if device_id(&candidate) != device_id(proj_root) {
+            return None;
+        }
+        if let Some(cfg) = crate::Config::from_file(&candidate.join(cfg_filename), net) {
+            let is_member = expand_members(&candidate, &cfg.members, cfg_filename)
+                .iter()
+                .any(|m| m.canonicalize().ok().as_ref() == Some(&canon_proj));
+            if is_member {
+                return Some(candidate);
+            }
+        }
+        if candidate.join(".git").exists() {
+            return None;
+        }
+        start = candidate.parent().map(Path::to_path_buf);
+    }
+    None
+}
+
+/// Identifies the filesystem a path is on, so we can detect mount points.
+#[cfg(unix)]
+fn device_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &Path) -> Option<u64> {
+    None
 }
 
 /// Find which virtual environments exist.
@@ -130,6 +533,8 @@ pub fn find_venvs(pypackages_dir: &Path) -> Vec<(u32, u32)> {
         (3, 10),
         (3, 11),
         (3, 12),
+        (3, 13),
+        (3, 14),
     ];
 
     let mut result = vec![];
@@ -202,18 +607,18 @@ pub fn show_installed(lib_path: &Path, path_reqs: &[Req]) {
             //        print_color(&format!("{} == \"{}\"", name, version.to_string()), Color::Magenta);
             println!(
                 "{}{}{} == {}",
-                Colored::Fg(Color::Cyan),
+                fg(Color::Cyan),
                 name,
-                Colored::Fg(Color::Reset),
+                fg(Color::Reset),
                 version
             );
         }
         for req in path_reqs {
             println!(
                 "{}{}{}, at path: {}",
-                Colored::Fg(Color::Cyan),
+                fg(Color::Cyan),
                 req.name,
-                Colored::Fg(Color::Reset),
+                fg(Color::Reset),
                 req.path.as_ref().unwrap(),
             );
         }
@@ -292,6 +697,7 @@ pub fn merge_reqs(
     added: &[String],
     dev: bool,
     cfg: &crate::Config,
+    index: &dep_resolution::Index,
     cfg_path: &Path,
 ) -> (Vec<Req>, Vec<Req>) {
     let mut added_reqs = vec![];
@@ -307,6 +713,33 @@ pub fn merge_reqs(
 
     let existing = if dev { &cfg.dev_reqs } else { &cfg.reqs };
 
+    // Adding extras to a package that's already required, eg with `pyflow install requests[socks]`,
+    // keeps its constraints and other extras.
+    for ar in added_reqs
+        .iter_mut()
+        .filter(|ar| ar.constraints.is_empty() && ar.url.is_none())
+    {
+        let extras = match &ar.install_with_extras {
+            Some(e) => e.clone(),
+            None => continue,
+        };
+        if let Some(cr) = existing.iter().find(|cr| compare_names(&cr.name, &ar.name)) {
+            let mut all = cr.install_with_extras.clone().unwrap_or_default();
+            if extras.iter().all(|e| all.contains(e)) {
+                continue;
+            }
+            all.extend(
+                extras
+                    .into_iter()
+                    .filter(|e| !all.contains(e))
+                    .collect::<Vec<_>>(),
+            );
+            ar.name = cr.name.clone();
+            ar.constraints = cr.constraints.clone();
+            ar.install_with_extras = Some(all);
+        }
+    }
+
     // Reqs to add to `pyproject.toml`
     let mut added_reqs_unique: Vec<Req> = added_reqs
         .into_iter()
@@ -318,7 +751,8 @@ pub fn merge_reqs(
             for cr in existing.iter() {
                 if cr == ar
                     || (cr.name.to_lowercase() == ar.name.to_lowercase()
-                        && ar.constraints.is_empty())
+                        && ar.constraints.is_empty()
+                        && ar.url.is_none())
                 {
                     // Same req/version exists
                     add = false;
@@ -333,11 +767,19 @@ pub fn merge_reqs(
     // version.
     for added_req in &mut added_reqs_unique {
         if added_req.constraints.is_empty() {
-            let (_, vers, _) = if let Ok(r) = dep_resolution::get_version_info(&added_req.name) {
-                r
-            } else {
-                abort("Problem getting latest version of the package you added. Is it spelled correctly? Is the internet OK?");
-                unreachable!()
+            let (_, vers, _) = match dep_resolution::get_version_info(index, &added_req.name) {
+                Ok(r) => r,
+                Err(e) => {
+                    abort_with(
+                        ExitCode::Network,
+                        &format!(
+                            "Problem getting latest version of the package you added. Is it \
+                             spelled correctly?\n{}",
+                            e.details
+                        ),
+                    );
+                    unreachable!()
+                }
             };
 
             added_req.constraints.push(Constraint::new(
@@ -349,14 +791,18 @@ pub fn merge_reqs(
     }
 
     let mut result = vec![]; // Reqs to sync
+    let mut replaced_names = vec![]; // Reqs to replace in `pyproject.toml`
 
-    // Merge reqs from the config and added via CLI. If there's a conflict in version,
+    // Merge reqs from the config and added via CLI. If there's a conflict in version or extras,
     // use the added req.
     for cr in existing.iter() {
         let mut replaced = false;
         for added_req in &added_reqs_unique {
-            if compare_names(&added_req.name, &cr.name) && added_req.constraints != cr.constraints {
-                result.push(added_req.clone());
+            if compare_names(&added_req.name, &cr.name)
+                && (added_req.constraints != cr.constraints
+                    || added_req.install_with_extras != cr.install_with_extras)
+            {
+                replaced_names.push(cr.name.clone());
                 replaced = true;
                 break;
             }
@@ -368,6 +814,9 @@ pub fn merge_reqs(
 
     result.append(&mut added_reqs_unique.clone());
 
+    if !replaced_names.is_empty() {
+        files::remove_reqs_from_cfg(cfg_path, &replaced_names);
+    }
     if dev {
         if !added_reqs_unique.is_empty() {
             files::add_reqs_to_cfg(&cfg_path, &[], &added_reqs_unique);
@@ -390,6 +839,264 @@ pub fn compare_names(name1: &str, name2: &str) -> bool {
     standardize_name(name1) == standardize_name(name2)
 }
 
+/// Archives that unpack to more than this many times their size are treated as decompression
+/// bombs, once they're past `BOMB_MIN_SIZE`. Wheels and Python builds are well under 20x.
+const BOMB_RATIO: u64 = 100;
+const BOMB_MIN_SIZE: u64 = 200_000_000;
+
+/// Check that an archive member's path stays in the directory it's unpacked to: it isn't
+/// absolute, and doesn't contain `..`.
+fn check_member_path(name: &str) -> Result<(), String> {
+    let parts: Vec<&str> = name.split(|c| c == '/' || c == '\\').collect();
+    if name.starts_with('/') || name.starts_with('\\') || parts[0].contains(':') {
+        return Err("has an absolute path".into());
+    }
+    if parts.iter().any(|p| *p == "..") {
+        return Err("has a path outside the directory it's unpacked to".into());
+    }
+    Ok(())
+}
+
+/// Check that a link in an archive points inside the directory it's unpacked to. `name` is the
+/// link's path, and `target` what it points to, relative to the link's directory.
+fn check_link_target(name: &str, target: &str) -> Result<(), String> {
+    if target.starts_with('/') || target.starts_with('\\') || target.contains(':') {
+        return Err(format!("links to the absolute path {}", target));
+    }
+    let mut depth: i32 = name.split('/').filter(|p| !p.is_empty()).count() as i32 - 1;
+    for part in target.split(|c| c == '/' || c == '\\') {
+        match part {
+            "" | "." => (),
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+        if depth < 0 {
+            return Err(format!(
+                "links outside the directory it's unpacked to: {}",
+                target
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_unpacked_size(unpacked: u64, compressed: u64) -> Result<(), String> {
+    if unpacked > BOMB_MIN_SIZE && unpacked > compressed.saturating_mul(BOMB_RATIO) {
+        Err(format!(
+            "it unpacks to {}, over {} times its size",
+            format_size(unpacked),
+            BOMB_RATIO
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Stop on an archive that may be malicious, without unpacking (any more of) it.
+fn reject_archive(archive: &str, problem: &str) {
+    abort(&format!(
+        "Refusing to unpack {}: {}. The archive may have been tampered with; it's safer not to \
+         use it.",
+        archive, problem
+    ))
+}
+
+/// Check that an archive member's name can be created on this system. Windows disallows some
+/// characters and device names, eg `aux.py`, that are valid elsewhere.
+fn check_member_name(name: &str) -> Result<(), String> {
+    if name.chars().any(char::is_control) {
+        return Err("it contains control characters".into());
+    }
+    if cfg!(target_os = "windows") {
+        const RESERVED: &[&str] = &[
+            "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7",
+            "com8", "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+        ];
+        for part in name
+            .split(|c| c == '/' || c == '\\')
+            .filter(|p| !p.is_empty() && *p != "." && *p != "..")
+        {
+            if part.chars().any(|c| "<>:\"|?*".contains(c)) {
+                return Err(format!(
+                    "`{}` contains a character Windows doesn't allow",
+                    part
+                ));
+            }
+            let stem = part.split('.').next().unwrap_or_default().to_lowercase();
+            if RESERVED.contains(&stem.as_str()) {
+                return Err(format!("`{}` is a reserved name on Windows", part));
+            }
+            if part.ends_with('.') || part.ends_with(' ') {
+                return Err(format!("`{}` ends with a dot or space", part));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Windows limits paths to 260 characters unless they're in the `\\?\` form, which some wheels'
+/// deeply-nested files need. `canonicalize` returns that form there.
+fn long_path_base(path: &Path) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Where to extract an archive member, renamed if its package is. Metadata keeps the original
+/// name.
+fn renamed_member(name: &str, rename: &Option<(String, String)>) -> PathBuf {
+    match rename {
+        Some((old, new)) if !name.contains("dist-info") && !name.contains("egg-info") => {
+            name.replace(old, new).into()
+        }
+        _ => name.into(),
+    }
+}
+
+/// Reads from `inner` until its first error, which is kept instead of returned; the stream just
+/// ends there. The zip crate panics on an error while skipping the rest of an entry.
+struct StopOnError<R: Read> {
+    inner: R,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> Read for StopOnError<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.error.is_some() {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => {
+                self.error = Some(e);
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Extract a wheel as it's read, using each entry's local header. Returns `Ok(false)` if it can't
+/// be read that way, eg since its entries' sizes come after their data; extract it with
+/// `extract_zip` once it's complete instead. Permissions are in the central directory at the
+/// end, so they're set with `set_zip_permissions` after.
+pub fn extract_zip_stream(
+    reader: &mut impl Read,
+    out_path: &Path,
+    rename: &Option<(String, String)>,
+) -> Result<bool, io::Error> {
+    let mut reader = StopOnError {
+        inner: reader,
+        error: None,
+    };
+    let archive_name = format!("the archive extracted into {:?}", out_path);
+    let (mut unpacked, mut compressed) = (0_u64, 0_u64);
+    let base = long_path_base(out_path);
+
+    let mut readable = true;
+    loop {
+        let mut file = match zip::read::read_zipfile_from_stream(&mut reader) {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(_) => {
+                readable = false;
+                break;
+            }
+        };
+        let member = file.name().to_owned();
+        if let Err(e) = check_member_path(&member) {
+            reject_archive(&archive_name, &format!("`{}` {}", member, e));
+        }
+        unpacked += file.size();
+        compressed += file.compressed_size();
+        if let Err(e) = check_unpacked_size(unpacked, compressed) {
+            reject_archive(&archive_name, &e);
+        }
+        if let Err(e) = check_member_name(&member) {
+            abort(&format!(
+                "Can't extract `{}` from the archive into {:?}: {}",
+                member, out_path, e
+            ));
+        }
+
+        let outpath = base.join(renamed_member(
+            &file.sanitized_name().to_string_lossy(),
+            rename,
+        ));
+        let written = if member.ends_with('/') {
+            fs::create_dir_all(&outpath)
+        } else {
+            let size = file.size();
+            outpath
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::File::create(&outpath))
+                .and_then(|mut f| io::copy(&mut (&mut file).take(size), &mut f))
+                .map(|_| ())
+        };
+        drop(file);
+        if let Some(e) = reader.error.take() {
+            return Err(e);
+        }
+        if written.is_err() {
+            return Ok(false);
+        }
+    }
+    match reader.error {
+        Some(e) => Err(e),
+        None => Ok(readable),
+    }
+}
+
+/// Set the permissions of files `extract_zip_stream` extracted, from the complete archive.
+pub fn set_zip_permissions(file: &fs::File, out_path: &Path, rename: &Option<(String, String)>) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut archive = match zip::ZipArchive::new(file) {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        for i in 0..archive.len() {
+            if let Ok(entry) = archive.by_index(i) {
+                if let Some(mode) = entry.unix_mode() {
+                    let path = out_path.join(renamed_member(
+                        &entry.sanitized_name().to_string_lossy(),
+                        rename,
+                    ));
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode)).ok();
+                }
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (file, out_path, rename);
+}
+
+/// Move what's in `src` into `dest`, merging directories that are in both, eg a namespace
+/// package's. Files already in `dest` are replaced.
+pub fn move_contents(src: &Path, dest: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() && target.is_dir() {
+            move_contents(&entry.path(), &target)?;
+            fs::remove_dir(entry.path())?;
+        } else {
+            if target.is_dir() {
+                fs::remove_dir_all(&target)?;
+            } else if target.exists() {
+                fs::remove_file(&target)?;
+            }
+            fs::rename(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
 /// Extract the wheel or zip.
 /// From [this example](https://github.com/mvdnes/zip-rs/blob/master/examples/extract.rs#L32)
 pub fn extract_zip(file: &fs::File, out_path: &Path, rename: &Option<(String, String)>) {
@@ -404,40 +1111,81 @@ pub fn extract_zip(file: &fs::File, out_path: &Path, rename: &Option<(String, St
         unreachable!()
     };
 
+    // Check every entry before extracting any, so we don't leave part of a malicious archive.
+    let archive_name = format!("the archive extracted into {:?}", out_path);
+    let (mut unpacked, mut compressed) = (0_u64, 0_u64);
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
+        if let Ok(entry) = archive.by_index(i) {
+            if let Err(e) = check_member_path(entry.name()) {
+                reject_archive(&archive_name, &format!("`{}` {}", entry.name(), e));
+            }
+            unpacked += entry.size();
+            compressed += entry.compressed_size();
+        }
+    }
+    if let Err(e) = check_unpacked_size(unpacked, compressed) {
+        reject_archive(&archive_name, &e);
+    }
+
+    if !out_path.exists() {
+        fs::create_dir_all(out_path).ok();
+    }
+    let base = long_path_base(out_path);
+
+    for i in 0..archive.len() {
+        let mut file = match archive.by_index(i) {
+            Ok(f) => f,
+            Err(e) => {
+                abort(&format!(
+                    "Problem reading entry {} of the wheel archive: {}. Is it corrupted?",
+                    i, e
+                ));
+                unreachable!()
+            }
+        };
+        let member = file.name().to_owned();
+        let member_problem = |problem: &str| {
+            abort(&format!(
+                "Can't extract `{}` from the archive into {:?}: {}",
+                member, out_path, problem
+            ))
+        };
+        if let Err(e) = check_member_name(&member) {
+            member_problem(&e);
+        }
+
         // Change name here instead of after in case we've already installed a non-renamed version.
         // (which would be overwritten by this one.)
-        let file_str2 = file.sanitized_name();
-        let file_str = file_str2.to_str().expect("Problem converting path to str");
-
-        let extracted_file = if !file_str.contains("dist-info") && !file_str.contains("egg-info") {
-            match rename {
-                Some((old, new)) => file
-                    .sanitized_name()
-                    .to_str()
-                    .unwrap()
-                    .to_owned()
-                    .replace(old, new)
-                    .into(),
-                None => file.sanitized_name(),
+        let sanitized = file.sanitized_name();
+        let file_str = match sanitized.to_str() {
+            Some(s) => s.to_owned(),
+            None => {
+                member_problem("its name isn't valid Unicode");
+                unreachable!()
             }
-        } else {
-            file.sanitized_name()
         };
 
-        let outpath = out_path.join(extracted_file);
+        let outpath = base.join(renamed_member(&file_str, rename));
 
-        if (&*file.name()).ends_with('/') {
-            fs::create_dir_all(&outpath).unwrap();
+        if member.ends_with('/') {
+            if let Err(e) = fs::create_dir_all(&outpath) {
+                member_problem(&e.to_string());
+            }
         } else {
             if let Some(p) = outpath.parent() {
                 if !p.exists() {
-                    fs::create_dir_all(&p).unwrap();
+                    if let Err(e) = fs::create_dir_all(&p) {
+                        member_problem(&e.to_string());
+                    }
                 }
             }
-            let mut outfile = fs::File::create(&outpath).unwrap();
-            io::copy(&mut file, &mut outfile).unwrap();
+            // Don't trust the compressed stream to end where the entry's size says it does.
+            let size = file.size();
+            let copied = fs::File::create(&outpath)
+                .and_then(|mut f| io::copy(&mut (&mut file).take(size), &mut f));
+            if let Err(e) = copied {
+                member_problem(&e.to_string());
+            }
         }
 
         // Get and Set permissions
@@ -446,25 +1194,89 @@ pub fn extract_zip(file: &fs::File, out_path: &Path, rename: &Option<(String, St
             use std::os::unix::fs::PermissionsExt;
 
             if let Some(mode) = file.unix_mode() {
-                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).unwrap();
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(mode)).ok();
             }
         }
     }
 }
 
-pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
-    let archive_bytes = fs::read(archive_path).expect("Problem reading archive as bytes");
-
-    let mut tar: Vec<u8> = Vec::new();
-    let mut decompressor = XzDecoder::new(&archive_bytes[..]);
-    if decompressor.read_to_end(&mut tar).is_err() {
-        abort(&format!("Problem decompressing the archive: {:?}. This may be due to a failed downoad. \
+/// Unpack a `.tar.xz`, `.tar.gz`, or `.tar.bz2` archive, eg of a Python build.
+pub fn unpack_tar(archive_path: &Path, dest: &Path) {
+    let decompress_problem = || {
+        abort(&format!(
+            "Problem decompressing the archive: {:?}. This may be due to a failed downoad. \
         Try deleting it, then trying again. Note that Pyflow will only install officially-released \
-        Python versions. If you'd like to use a pre-release, you must install it manually.", archive_path))
+        Python versions. If you'd like to use a pre-release, you must install it manually.",
+            archive_path
+        ))
+    };
+    let open = || -> Box<dyn Read> {
+        let file = match fs::File::open(archive_path) {
+            Ok(f) => io::BufReader::new(f),
+            Err(_) => {
+                abort(&format!("Problem opening the archive: {:?}", archive_path));
+                unreachable!()
+            }
+        };
+        match archive_path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Box::new(flate2::read::GzDecoder::new(file)),
+            Some("bz2") => Box::new(bzip2::read::BzDecoder::new(file)),
+            Some("tar") => Box::new(file),
+            _ => Box::new(XzDecoder::new(file)),
+        }
+    };
+    let compressed = fs::metadata(archive_path).map(|m| m.len()).unwrap_or(0);
+    let archive_name = archive_path.display().to_string();
+
+    // Check every entry before unpacking any. This decompresses the archive twice, but doesn't
+    // keep it in memory or on disk.
+    let mut archive = Archive::new(open());
+    let entries = match archive.entries() {
+        Ok(e) => e,
+        Err(_) => {
+            decompress_problem();
+            unreachable!()
+        }
+    };
+    let mut unpacked = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(_) => {
+                decompress_problem();
+                unreachable!()
+            }
+        };
+        let name = entry
+            .path()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Err(e) = check_member_path(&name) {
+            reject_archive(&archive_name, &format!("`{}` {}", name, e));
+        }
+        if entry.header().entry_type().is_symlink() || entry.header().entry_type().is_hard_link() {
+            let target = entry
+                .link_name()
+                .ok()
+                .and_then(|l| l.map(|l| l.to_string_lossy().to_string()))
+                .unwrap_or_default();
+            // Hard link targets are relative to the archive root, not the link.
+            let checked = if entry.header().entry_type().is_hard_link() {
+                check_member_path(&target)
+            } else {
+                check_link_target(&name, &target)
+            };
+            if let Err(e) = checked {
+                reject_archive(&archive_name, &format!("`{}` {}", name, e));
+            }
+        }
+        unpacked += entry.header().size().unwrap_or(0);
+        if let Err(e) = check_unpacked_size(unpacked, compressed) {
+            reject_archive(&archive_name, &e);
+        }
     }
 
-    // We've decompressed the .xz; now unpack the tar.
-    let mut archive = Archive::new(&tar[..]);
+    let mut archive = Archive::new(open());
     if archive.unpack(dest).is_err() {
         abort(&format!(
             "Problem unpacking tar: {}",
@@ -473,13 +1285,146 @@ pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
     }
 }
 
+/// Unpack a `.tar.zst` archive, eg a debug or free-threaded Python build. We don't have a zstd
+/// decoder, so the `zstd` tool decompresses it to a `.tar` first, which is checked and unpacked
+/// like other archives.
+pub fn unpack_tar_zst(archive_path: &Path, dest: &Path) {
+    // Eg `python.tar.zst` → `python.tar`.
+    let tar_path = archive_path.with_extension("");
+    let decompressed = process::Command::new("zstd")
+        .args(&["-d", "-f", "-q"])
+        .arg(archive_path)
+        .arg("-o")
+        .arg(&tar_path)
+        .status();
+    if !decompressed.map(|s| s.success()).unwrap_or(false) {
+        fs::remove_file(&tar_path).ok();
+        abort(&format!(
+            "Problem decompressing {:?}. It's compressed with zstd; install it, eg with \
+             `apt install zstd` or `brew install zstd`, and try again.",
+            archive_path
+        ));
+    }
+    unpack_tar(&tar_path, dest);
+    fs::remove_file(&tar_path).ok();
+}
+
+/// The Python version an environment was created with, from its `pyvenv.cfg`. Eg
+/// `version = 3.10.12`, or `version_info = 3.10.12.final.0` from other tools.
+pub fn venv_python_version(vers_path: &Path) -> Option<Version> {
+    let data = fs::read_to_string(vers_path.join(".venv").join("pyvenv.cfg")).ok()?;
+    data.lines().find_map(|line| {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next()?.trim();
+        if key != "version" && key != "version_info" {
+            return None;
+        }
+        let value: Vec<&str> = parts.next()?.trim().split('.').take(3).collect();
+        Version::from_str(&value.join(".")).ok()
+    })
+}
+
+/// Check the project's environments against its Python version, so packages built for
+/// different versions aren't mixed. An environment made with a different version than its folder
+/// says, eg if its interpreter was replaced, is rebuilt with `--migrate`; otherwise we stop,
+/// and explain. Environments for versions the project no longer uses are removed with
+/// `--migrate`, and otherwise pointed out.
+fn check_venv_versions(
+    cfg_vers: &Version,
+    constraints: &[Constraint],
+    pypackages_dir: &Path,
+    venvs: &[(u32, u32)],
+    migrate: bool,
+) {
+    for &(major, minor) in venvs {
+        let vers_path = pypackages_dir.join(format!("{}.{}", major, minor));
+        let name = format!("{}.{}", major, minor);
+        let remove = |why: &str| {
+            print_color(
+                &format!("Removing `__pypackages__/{}`, {}", name, why),
+                Color::DarkYellow,
+            );
+            if fs::remove_dir_all(&vers_path).is_err() {
+                abort(&format!("Problem removing {:?}", vers_path));
+            }
+        };
+
+        if (major, minor) != (cfg_vers.major, cfg_vers.minor) {
+            if migrate {
+                remove("since this project no longer uses that Python version");
+            } else {
+                print_color(
+                    &format!(
+                        "`__pypackages__` has an environment for Python {}, but this project uses \
+                         Python {}, so it isn't used. Run `pyflow --migrate install` to remove it.",
+                        name,
+                        cfg_vers.to_string_no_patch()
+                    ),
+                    Color::DarkYellow,
+                );
+            }
+            continue;
+        }
+
+        match venv_python_version(&vers_path) {
+            Some(actual) if (actual.major, actual.minor) != (major, minor) => {
+                if migrate {
+                    remove(&format!(
+                        "since its environment was made with Python {}; it'll be rebuilt, and \
+                         its packages reinstalled",
+                        actual.to_string_no_patch()
+                    ));
+                } else {
+                    abort(&format!(
+                        "`__pypackages__/{}`'s environment was made with Python {}, so packages \
+                         built for different versions would be mixed in it. Run \
+                         `pyflow --migrate install` to rebuild it with Python {}, and reinstall \
+                         its packages.",
+                        name,
+                        actual.to_string_no_patch(),
+                        name
+                    ));
+                }
+            }
+            Some(actual) if !py_versions::satisfies_constraints(constraints, &actual) => {
+                if migrate {
+                    remove(&format!(
+                        "since its environment was made with Python {}, which doesn't satisfy \
+                         `py_version`; it'll be rebuilt, and its packages reinstalled",
+                        actual.to_string2()
+                    ));
+                } else {
+                    abort(&format!(
+                        "`__pypackages__/{}`'s environment was made with Python {}, which doesn't \
+                         satisfy `py_version`, `{}`. Run `pyflow --migrate install` to rebuild it.",
+                        name,
+                        actual.to_string2(),
+                        py_versions::constraints_text(constraints)
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
 /// Find venv info, creating a venv as required.
 pub fn find_or_create_venv(
+    py: &mut py_versions::Settings,
     cfg_vers: &Version,
+    constraints: &[Constraint],
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    temp_root: &Path,
 ) -> (PathBuf, Version) {
+    check_venv_versions(
+        cfg_vers,
+        constraints,
+        pypackages_dir,
+        &find_venvs(pypackages_dir),
+        py.migrate,
+    );
     let venvs = find_venvs(pypackages_dir);
     // The version's explicitly specified; check if an environment for that version
     let compatible_venvs: Vec<&(u32, u32)> = venvs
@@ -491,8 +1436,15 @@ pub fn find_or_create_venv(
     let py_vers;
     match compatible_venvs.len() {
         0 => {
-            let vers =
-                py_versions::create_venv(cfg_vers, pypackages_dir, pyflow_dir, dep_cache_path);
+            let vers = py_versions::create_venv(
+                py,
+                cfg_vers,
+                constraints,
+                pypackages_dir,
+                pyflow_dir,
+                dep_cache_path,
+                temp_root,
+            );
             vers_path = pypackages_dir.join(&format!("{}.{}", vers.major, vers.minor));
             py_vers = Version::new_short(vers.major, vers.minor); // Don't include patch.
         }
@@ -502,6 +1454,7 @@ pub fn find_or_create_venv(
                 compatible_venvs[0].0, compatible_venvs[0].1
             ));
             py_vers = Version::new_short(compatible_venvs[0].0, compatible_venvs[0].1);
+            py_versions::check_env_variant(py, &vers_path);
         }
         _ => {
             abort(
@@ -513,6 +1466,11 @@ pub fn find_or_create_venv(
         }
     }
 
+    py_versions::register_env(
+        pyflow_dir,
+        &fs::canonicalize(&vers_path).unwrap_or_else(|_| vers_path.clone()),
+    );
+
     #[cfg(target_os = "windows")]
     {
         (vers_path, py_vers)
@@ -578,12 +1536,312 @@ pub fn fallible_v_parse(vers: &str) -> Version {
     }
 }
 
+/// Free space on the filesystem holding `path`, in bytes; `None` if we can't tell. `path` needn't
+/// exist yet.
+pub fn free_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+
+    #[cfg(unix)]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let c_path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+            return None;
+        }
+        #[allow(clippy::useless_conversion)]
+        Some(u64::from(stats.f_bavail) * u64::from(stats.f_frsize))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+
+        let wide: Vec<u16> = existing
+            .as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let mut free: winapi::um::winnt::ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+        let ok = unsafe {
+            winapi::um::fileapi::GetDiskFreeSpaceExW(
+                wide.as_ptr(),
+                &mut free,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return None;
+        }
+        Some(unsafe { *free.QuadPart() })
+    }
+}
+
+/// Format a size in bytes for display, eg `1.2 GB`.
+pub fn format_size(bytes: u64) -> String {
+    const MB: f64 = 1_000_000.;
+    let bytes = bytes as f64;
+    if bytes >= 1_000. * MB {
+        format!("{:.1} GB", bytes / (1_000. * MB))
+    } else {
+        format!("{:.0} MB", (bytes / MB).ceil())
+    }
+}
+
+/// Wraps a download, drawing its progress on one line of stderr as it's read, eg
+/// `  [=========>          ]  12.3 / 45.0 MB  1.8 MB/s  ETA 18s`. Nothing's drawn if stderr isn't
+/// a terminal, or for downloads that finish within half a second, like most wheels.
+pub struct Progress<R> {
+    inner: R,
+    read: u64,
+    /// Bytes we had before this download started, eg when resuming.
+    start_at: u64,
+    total: Option<u64>,
+    started: time::Instant,
+    last_drawn: Option<time::Instant>,
+    drawn_len: usize,
+    enabled: bool,
+}
+
+impl<R: Read> Progress<R> {
+    /// `total` is the full size, if known, including the `start_at` bytes we already have.
+    pub fn new(inner: R, start_at: u64, total: Option<u64>) -> Self {
+        Self {
+            inner,
+            read: start_at,
+            start_at,
+            total,
+            started: time::Instant::now(),
+            last_drawn: None,
+            drawn_len: 0,
+            enabled: atty::is(atty::Stream::Stderr),
+        }
+    }
+
+    fn draw(&mut self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let speed = (self.read - self.start_at) as f64 / elapsed.max(0.001);
+        let mb = |b: u64| b as f64 / 1_000_000.;
+
+        let mut line = match self.total {
+            Some(total) if total > 0 => {
+                const WIDTH: usize = 20;
+                let done = ((self.read.min(total) * WIDTH as u64) / total) as usize;
+                let bar = if done >= WIDTH {
+                    "=".repeat(WIDTH)
+                } else {
+                    format!("{}>{}", "=".repeat(done), " ".repeat(WIDTH - done - 1))
+                };
+                format!("  [{}]  {:.1} / {:.1} MB", bar, mb(self.read), mb(total))
+            }
+            _ => format!("  {:.1} MB", mb(self.read)),
+        };
+        line.push_str(&format!("  {:.1} MB/s", mb(speed as u64)));
+        if let Some(total) = self.total {
+            if speed > 0. && total > self.read {
+                let secs = ((total - self.read) as f64 / speed).ceil() as u64;
+                line.push_str(&if secs >= 60 {
+                    format!("  ETA {}m {:02}s", secs / 60, secs % 60)
+                } else {
+                    format!("  ETA {}s", secs)
+                });
+            }
+        }
+
+        // Pad with spaces, to cover a longer line drawn before.
+        let padding = " ".repeat(self.drawn_len.saturating_sub(line.len()));
+        eprint!("\r{}{}", line, padding);
+        io::Write::flush(&mut io::stderr()).ok();
+        self.drawn_len = line.len();
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+
+        let now = time::Instant::now();
+        let due = match self.last_drawn {
+            Some(t) => now.duration_since(t) >= time::Duration::from_millis(200),
+            None => now.duration_since(self.started) >= time::Duration::from_millis(500),
+        };
+        if self.enabled && due {
+            self.draw();
+            self.last_drawn = Some(now);
+        }
+        Ok(n)
+    }
+}
+
+impl<R> Drop for Progress<R> {
+    /// Clear the line, so what's printed next starts on a clean one.
+    fn drop(&mut self) {
+        if self.drawn_len > 0 {
+            eprint!("\r{}\r", " ".repeat(self.drawn_len));
+        }
+    }
+}
+
+/// Check there's room for an operation before starting it, rather than failing partway through
+/// with a full disk, and leaving partial state. `needed` is an estimate, in bytes.
+pub fn check_disk_space(path: &Path, needed: u64, what: &str) {
+    if let Some(free) = free_space(path) {
+        if free < needed {
+            abort(&format!(
+                "{} needs about {} of space in {:?}, but only {} is free. Free up some space, \
+                 and try again.",
+                what,
+                format_size(needed),
+                path,
+                format_size(free)
+            ));
+        }
+    }
+}
+
+/// How often to say we're still waiting at a prompt, so the log of a CI job that's
+/// unexpectedly interactive shows why it's stuck.
+const PROMPT_HEARTBEAT: time::Duration = time::Duration::from_secs(30);
+
+/// Reads lines from stdin for prompts on a separate thread, so we can stop waiting for one. It
+/// reads a line when asked for one, rather than reading ahead, which would take input meant for
+/// processes we run. A line that arrives after its prompt stopped waiting answers the next prompt,
+/// instead of being lost.
+struct StdinReader {
+    requests: mpsc::Sender<()>,
+    lines: mpsc::Receiver<Option<String>>,
+    /// A line's been asked for, and hasn't been received.
+    pending: bool,
+}
+
+impl StdinReader {
+    fn spawn() -> Self {
+        let (requests, request_rx) = mpsc::channel();
+        let (line_tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for () in request_rx {
+                let mut input = String::new();
+                let result = match io::stdin().read_line(&mut input) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(input),
+                };
+                let closed = result.is_none();
+                if line_tx.send(result).is_err() || closed {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            lines,
+            pending: false,
+        }
+    }
+
+    /// Wait for a line, for up to `timeout`.
+    fn read_line(&mut self, timeout: Option<time::Duration>) -> Option<String> {
+        if !self.pending {
+            if self.requests.send(()).is_err() {
+                return None;
+            }
+            self.pending = true;
+        }
+
+        let start = time::Instant::now();
+        loop {
+            let wait = match timeout {
+                Some(t) if start.elapsed() >= t => {
+                    println!();
+                    print_color(
+                        &format!("No answer after {} seconds", t.as_secs()),
+                        Color::DarkYellow,
+                    );
+                    return None;
+                }
+                Some(t) => (t - start.elapsed()).min(PROMPT_HEARTBEAT),
+                None => PROMPT_HEARTBEAT,
+            };
+            match self.lines.recv_timeout(wait) {
+                Ok(input) => {
+                    self.pending = false;
+                    return input;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if timeout.map_or(true, |t| start.elapsed() < t) {
+                        eprintln!("(Still waiting for an answer to the prompt above)");
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+static STDIN_READER: Mutex<Option<StdinReader>> = Mutex::new(None);
+
+/// How long to wait for an answer at a prompt: `PYFLOW_PROMPT_TIMEOUT` seconds, when that's set,
+/// or `prompt_timeout` in `pyproject.toml`. Without either, we wait as long as it takes.
+pub fn prompt_timeout(env_value: Option<&str>, configured: Option<u64>) -> Option<time::Duration> {
+    env_value
+        .and_then(|t| t.trim().parse::<u64>().ok())
+        .or(configured)
+        .map(time::Duration::from_secs)
+}
+
+/// Read a line answering a prompt. Returns `None` if stdin is closed, or if nothing's entered
+/// within `timeout`.
+pub fn read_input(timeout: Option<time::Duration>) -> Option<String> {
+    let mut guard = STDIN_READER.lock().unwrap_or_else(|e| e.into_inner());
+    guard
+        .get_or_insert_with(StdinReader::spawn)
+        .read_line(timeout)
+}
+
+/// Read an answer for a prompt that has no default, aborting if there isn't one.
+pub fn require_input(timeout: Option<time::Duration>) -> String {
+    match read_input(timeout) {
+        Some(input) => input,
+        None => {
+            abort_with(
+                ExitCode::UserAbort,
+                "This needs an answer at a prompt, but none was given: stdin is closed, or the \
+                 prompt timed out. Run it interactively, or where you can, specify what it asks \
+                 for in `pyproject.toml`.",
+            );
+            unreachable!()
+        }
+    }
+}
+
+/// Ask a yes/no question. Defaults to no, including when there's no one to ask, or no answer.
+pub fn confirm(question: &str, timeout: Option<time::Duration>) -> bool {
+    use std::io::Write;
+
+    if !atty::is(atty::Stream::Stdin) {
+        return false;
+    }
+    print!("{} [y/N] ", question);
+    io::stdout().flush().ok();
+
+    match read_input(timeout) {
+        Some(input) => {
+            let input = input.trim().to_lowercase();
+            input == "y" || input == "yes"
+        }
+        None => false,
+    }
+}
+
 /// A generic prompt function, where the user selects from a list
 pub fn prompt_list<T: Clone + ToString>(
     init_msg: &str,
     type_: &str,
     items: &[(String, T)],
     show_item: bool,
+    timeout: Option<time::Duration>,
 ) -> (String, T) {
     print_color(init_msg, Color::Magenta);
     for (i, (name, content)) in items.iter().enumerate() {
@@ -599,10 +1857,7 @@ pub fn prompt_list<T: Clone + ToString>(
         mapping.insert(i + 1, item);
     }
 
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Problem reading input");
+    let input = require_input(timeout);
 
     let input = input
         .chars()
@@ -649,6 +1904,29 @@ fn os_from_wheel_fname(filename: &str) -> Result<Os, DependencyError> {
     Err(DependencyError::new("Problem parsing os from wheel name"))
 }
 
+/// Check that a wheel's ABI tag, eg the `cp38` in `numpy-1.18.1-cp38-cp38-linux_x86_64.whl`,
+/// matches the interpreter's ABI. Returns the wheel's tag if it doesn't. Wheels without
+/// compiled code (`none`) always match; `abi3` wheels match any CPython build except
+/// free-threaded ones.
+pub fn check_wheel_abi(filename: &str, interp_abi: &str) -> Result<(), String> {
+    let tags: Vec<&str> = filename.trim_end_matches(".whl").rsplit('-').collect();
+    let abi_tag = match tags.get(1) {
+        Some(t) => *t,
+        None => return Ok(()),
+    };
+
+    let compatible = abi_tag.split('.').any(|abi| match abi {
+        "none" => true,
+        "abi3" => !interp_abi.ends_with('t'),
+        _ => abi == interp_abi,
+    });
+    if compatible {
+        Ok(())
+    } else {
+        Err(abi_tag.to_owned())
+    }
+}
+
 /// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source.
 pub fn find_best_release(
     data: &[WarehouseRelease],
@@ -656,6 +1934,7 @@ pub fn find_best_release(
     version: &Version,
     os: Os,
     python_vers: &Version,
+    py: &py_versions::Settings,
 ) -> (WarehouseRelease, PackageType) {
     // Find which release we should download. Preferably wheels, and if so, for the right OS and
     // Python version.
@@ -680,6 +1959,26 @@ pub fn find_best_release(
                     }
                 }
 
+                // Wheels tagged only for another implementation, eg `pp310` ones on CPython,
+                // won't load.
+                let impl_tag = py.implementation.wheel_tag();
+                if !rel.python_version.split('.').any(|t| {
+                    t.starts_with("py")
+                        || t.starts_with(impl_tag)
+                        || t.starts_with(|c: char| c.is_ascii_digit())
+                        || t == "any"
+                        || t == "source"
+                }) {
+                    compatible = false;
+                }
+
+                // Debug and free-threaded builds need wheels built for them, eg `cp313t` ones.
+                if let Some(abi) = py_versions::variant_abi(py, python_vers) {
+                    if check_wheel_abi(&rel.filename, &abi).is_err() {
+                        compatible = false;
+                    }
+                }
+
                 let wheel_os =
                     os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
                 if wheel_os != os && wheel_os != Os::Any {
@@ -726,11 +2025,14 @@ pub fn find_best_release(
     // todo: Sort further / try to match exact python_version if able.
     if compatible_releases.is_empty() {
         if source_releases.is_empty() {
-            abort(&format!(
-                "Unable to find a compatible release for {}: {}",
-                name,
-                version.to_string()
-            ));
+            abort_with(
+                ExitCode::Resolution,
+                &format!(
+                    "Unable to find a compatible release for {}: {}",
+                    name,
+                    version.to_string()
+                ),
+            );
             unreachable!()
         } else {
             best_release = source_releases[0].clone();
@@ -851,15 +2153,12 @@ pub fn find_folders(path: &Path) -> Vec<String> {
 }
 
 /// Ask the user what Python version to use.
-pub fn prompt_py_vers() -> Version {
+pub fn prompt_py_vers(timeout: Option<time::Duration>) -> Version {
     print_color(
         "Please enter the Python version for this project: (eg: 3.8)",
         Color::Magenta,
     );
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Unable to read user input for version");
+    let mut input = require_input(timeout);
 
     input.pop(); // Remove trailing newline.
     let input = input.replace("\n", "").replace("\r", "");
@@ -867,13 +2166,13 @@ pub fn prompt_py_vers() -> Version {
     fallible_v_parse(&input)
 }
 
-/// We've removed the git repos from packages to install form pypi, but make
+/// We've removed the git, path and URL reqs from packages to install form pypi, but make
 /// sure we flag them as not-to-uninstall.
 pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<String> {
     let mut result: Vec<String> = reqs
         .iter()
         .filter_map(|r| {
-            if r.git.is_some() || r.path.is_some() {
+            if r.git.is_some() || r.path.is_some() || r.url.is_some() {
                 Some(r.name.to_owned())
             } else {
                 None
@@ -882,7 +2181,7 @@ pub fn find_dont_uninstall(reqs: &[Req], dev_reqs: &[Req]) -> Vec<String> {
         .collect();
 
     for r in dev_reqs {
-        if r.git.is_some() || r.path.is_some() {
+        if r.git.is_some() || r.path.is_some() || r.url.is_some() {
             result.push(r.name.to_owned());
         }
     }
@@ -907,3 +2206,249 @@ pub(crate) fn check_command_output_with(output: &process::Output, f: impl Fn(&st
         f(&stderr)
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn color_choice() {
+        let (set, empty, dumb) = (
+            Some(OsStr::new("1")),
+            Some(OsStr::new("")),
+            Some(OsStr::new("dumb")),
+        );
+        let xterm = Some(OsStr::new("xterm-256color"));
+
+        assert!(color_enabled_for(ColorChoice::Auto, None, xterm, true));
+        assert!(color_enabled_for(ColorChoice::Auto, None, None, true));
+        assert!(!color_enabled_for(ColorChoice::Auto, None, xterm, false));
+        assert!(!color_enabled_for(ColorChoice::Auto, set, xterm, true));
+        // An empty `NO_COLOR` doesn't count.
+        assert!(color_enabled_for(ColorChoice::Auto, empty, xterm, true));
+        assert!(!color_enabled_for(ColorChoice::Auto, None, dumb, true));
+
+        // The flag wins over the environment and the terminal.
+        for (no_color, term, tty) in &[(set, dumb, false), (None, xterm, true)] {
+            assert!(color_enabled_for(
+                ColorChoice::Always,
+                *no_color,
+                *term,
+                *tty
+            ));
+            assert!(!color_enabled_for(
+                ColorChoice::Never,
+                *no_color,
+                *term,
+                *tty
+            ));
+        }
+    }
+
+    #[test]
+    fn prompt_timeouts() {
+        let secs = time::Duration::from_secs;
+        assert_eq!(prompt_timeout(None, None), None);
+        assert_eq!(prompt_timeout(None, Some(300)), Some(secs(300)));
+        // The environment overrides `pyproject.toml`, unless it's not a number.
+        assert_eq!(prompt_timeout(Some(" 60\n"), Some(300)), Some(secs(60)));
+        assert_eq!(prompt_timeout(Some("soon"), Some(300)), Some(secs(300)));
+        assert_eq!(prompt_timeout(Some(""), None), None);
+    }
+
+    #[test]
+    fn prompt_waits() {
+        let (requests, request_rx) = mpsc::channel();
+        let (line_tx, lines) = mpsc::channel();
+        let mut reader = StdinReader {
+            requests,
+            lines,
+            pending: false,
+        };
+        let briefly = Some(time::Duration::from_millis(20));
+
+        // Nothing's entered in time, so it gives up, and the line it asked for is still pending.
+        assert_eq!(reader.read_line(briefly), None);
+        assert!(reader.pending);
+        assert_eq!(request_rx.try_iter().count(), 1);
+
+        // That line answers the next prompt, without asking for another.
+        line_tx.send(Some("y\n".to_owned())).unwrap();
+        assert_eq!(reader.read_line(briefly), Some("y\n".to_owned()));
+        assert!(!reader.pending);
+        assert_eq!(request_rx.try_iter().count(), 0);
+
+        // Without a timeout, it waits for the answer.
+        let sender = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            line_tx.send(Some("2\n".to_owned())).unwrap();
+            line_tx
+        });
+        assert_eq!(reader.read_line(None), Some("2\n".to_owned()));
+        assert_eq!(request_rx.try_iter().count(), 1);
+
+        // Stdin closing ends the prompt.
+        drop(sender.join().unwrap());
+        assert_eq!(reader.read_line(None), None);
+    }
+
+    #[test]
+    fn member_paths() {
+        assert!(check_member_path("requests/__init__.py").is_ok());
+        assert!(check_member_path("requests-2.22.0.dist-info/RECORD").is_ok());
+        // `..` in a name isn't a traversal.
+        assert!(check_member_path("pkg/..data").is_ok());
+
+        assert!(check_member_path("/etc/passwd").is_err());
+        assert!(check_member_path("\\Windows\\System32\\evil.dll").is_err());
+        assert!(check_member_path("C:/Windows/evil.dll").is_err());
+        assert!(check_member_path("c:\\Windows\\evil.dll").is_err());
+        assert!(check_member_path("../evil.py").is_err());
+        assert!(check_member_path("pkg/../../evil.py").is_err());
+        assert!(check_member_path("pkg\\..\\..\\evil.py").is_err());
+    }
+
+    #[test]
+    fn link_targets() {
+        // Symlinks are relative to their directory.
+        assert!(check_link_target("pkg/lib/link", "../data/file").is_ok());
+        assert!(check_link_target("pkg/link", "./sub/../file").is_ok());
+        assert!(check_link_target("link", "file").is_ok());
+
+        assert!(check_link_target("pkg/link", "../../etc/passwd").is_err());
+        assert!(check_link_target("link", "../file").is_err());
+        // Going deeper first doesn't hide leaving the directory after.
+        assert!(check_link_target("link", "a/../../file").is_err());
+        assert!(check_link_target("pkg/link", "/etc/passwd").is_err());
+        assert!(check_link_target("pkg/link", "\\\\server\\share").is_err());
+        assert!(check_link_target("pkg/link", "C:\\Windows").is_err());
+
+        // Hard link targets are relative to the archive root, so are checked as member paths.
+        assert!(check_member_path("pkg/data/file").is_ok());
+        assert!(check_member_path("../outside").is_err());
+        assert!(check_member_path("/etc/shadow").is_err());
+    }
+
+    #[test]
+    fn unpacked_size() {
+        // Small archives can compress well without being suspicious.
+        assert!(check_unpacked_size(BOMB_MIN_SIZE, 1).is_ok());
+        assert!(check_unpacked_size(BOMB_MIN_SIZE + 1, BOMB_MIN_SIZE / 2).is_ok());
+        assert!(check_unpacked_size(BOMB_RATIO * BOMB_MIN_SIZE, BOMB_MIN_SIZE).is_ok());
+
+        assert!(check_unpacked_size(BOMB_MIN_SIZE + 1, 1_000).is_err());
+        assert!(check_unpacked_size(BOMB_RATIO * BOMB_MIN_SIZE + 1, BOMB_MIN_SIZE).is_err());
+        // An empty archive claiming to unpack to a lot.
+        assert!(check_unpacked_size(u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn zip_streamed() {
+        let mut data = io::Cursor::new(vec![]);
+        {
+            let mut zip = zip::ZipWriter::new(&mut data);
+            let options = zip::write::FileOptions::default();
+            zip.start_file("acme/__init__.py", options).unwrap();
+            zip.write_all(b"x = 1").unwrap();
+            zip.start_file("acme-1.0.dist-info/METADATA", options)
+                .unwrap();
+            zip.write_all(b"Name: acme").unwrap();
+            zip.finish().unwrap();
+        }
+
+        let dir = TempDir::new("test");
+        let rename = Some(("acme".to_owned(), "acme_renamed".to_owned()));
+        let mut reader = io::Cursor::new(data.into_inner());
+        assert!(extract_zip_stream(&mut reader, dir.path(), &rename).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("acme_renamed/__init__.py")).unwrap(),
+            "x = 1"
+        );
+        assert!(dir.path().join("acme-1.0.dist-info/METADATA").exists());
+
+        // Not a zip; it's extracted some other way.
+        let mut reader = io::Cursor::new(b"not a zip file".to_vec());
+        assert!(!extract_zip_stream(&mut reader, dir.path(), &None).unwrap());
+    }
+
+    #[test]
+    fn contents_moved() {
+        let (src, dest) = (TempDir::new("test"), TempDir::new("test"));
+        for (dir, file) in &[(&src, "ns/a.py"), (&dest, "ns/b.py"), (&dest, "ns/a.py")] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, dir.path().to_string_lossy().as_bytes()).unwrap();
+        }
+
+        move_contents(src.path(), dest.path()).unwrap();
+        // Namespace packages are merged, and files from `src` replace those in `dest`.
+        assert!(dest.path().join("ns/b.py").exists());
+        assert_eq!(
+            fs::read_to_string(dest.path().join("ns/a.py")).unwrap(),
+            src.path().to_string_lossy()
+        );
+        assert!(!src.path().join("ns").exists());
+    }
+
+    #[test]
+    fn wheel_abi() {
+        let whl = "numpy-1.26.4-cp311-cp311-manylinux_2_17_x86_64.whl";
+        assert_eq!(check_wheel_abi(whl, "cp311"), Ok(()));
+        assert_eq!(check_wheel_abi(whl, "cp312"), Err("cp311".into()));
+
+        let abi3 = "cryptography-42.0.5-cp39-abi3-manylinux_2_28_x86_64.whl";
+        assert_eq!(check_wheel_abi(abi3, "cp312"), Ok(()));
+        assert_eq!(check_wheel_abi(abi3, "cp313t"), Err("abi3".into()));
+
+        let free_threaded = "numpy-2.1.0-cp313-cp313t-win_amd64.whl";
+        assert_eq!(check_wheel_abi(free_threaded, "cp313t"), Ok(()));
+        assert_eq!(
+            check_wheel_abi(free_threaded, "cp313"),
+            Err("cp313t".into())
+        );
+        assert_eq!(check_wheel_abi(whl, "cp313t"), Err("cp311".into()));
+
+        let pure = "requests-2.31.0-py3-none-any.whl";
+        assert_eq!(check_wheel_abi(pure, "cp313t"), Ok(()));
+
+        let compressed = "pyzmq-19.0.0-cp38.cp39-cp38.cp39-macosx_10_9_x86_64.whl";
+        assert_eq!(check_wheel_abi(compressed, "cp38"), Ok(()));
+        assert_eq!(check_wheel_abi(compressed, "cp39"), Ok(()));
+        assert_eq!(
+            check_wheel_abi(compressed, "cp310"),
+            Err("cp38.cp39".into())
+        );
+    }
+
+    #[test]
+    fn project_root() {
+        let dir = TempDir::new("project-root");
+        let outer = dir.path();
+        let repo = outer.join("repo");
+        let pkg = repo.join("packages").join("api");
+        fs::create_dir_all(pkg.join("src")).unwrap();
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::write(outer.join("pyproject.toml"), "").unwrap();
+
+        // The project above the git repo isn't this one's.
+        assert_eq!(find_project_root(&pkg.join("src"), "pyproject.toml"), None);
+
+        // The nearest project wins, including one nested in another.
+        fs::write(repo.join("pyproject.toml"), "").unwrap();
+        assert_eq!(
+            find_project_root(&pkg.join("src"), "pyproject.toml"),
+            Some(repo.clone())
+        );
+        fs::write(pkg.join("pyproject.toml"), "").unwrap();
+        assert_eq!(
+            find_project_root(&pkg.join("src"), "pyproject.toml"),
+            Some(pkg)
+        );
+
+        // Searching up to `/` without finding one.
+        let absent = "pyflow-test-absent.toml";
+        assert_eq!(find_project_root(outer, absent), None);
+        assert_eq!(find_project_root(Path::new("/"), absent), None);
+    }
+}
@@ -0,0 +1,177 @@
+//! Warnings that can be silenced in `[tool.pyflow.warnings]`, by code, and optionally by package.
+
+use crate::{
+    dep_types::DependencyError,
+    files::WarningWrapper,
+    util::{self, abort},
+};
+use crossterm::Color;
+use serde::Deserialize;
+use std::{collections::HashMap, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WarningCode {
+    /// Multiple versions of a package are installed, since dependencies need different ones.
+    MultipleVersions,
+    /// No release matching the constraints declares support for our Python version.
+    NoPythonSupport,
+    /// The environment's Python version doesn't satisfy `python_requires`.
+    PythonRequires,
+    /// A package name is one typo away from a popular package, or an existing dependency.
+    Typosquat,
+    /// A version set in `[tool.pyflow.overrides]` doesn't meet a dependency's requirements.
+    OverrideConflict,
+    /// A version that's been yanked from PyPI was picked for an exact pin, or is locked.
+    Yanked,
+}
+
+pub const ALL_CODES: &[WarningCode] = &[
+    WarningCode::MultipleVersions,
+    WarningCode::NoPythonSupport,
+    WarningCode::PythonRequires,
+    WarningCode::Typosquat,
+    WarningCode::OverrideConflict,
+    WarningCode::Yanked,
+];
+
+impl WarningCode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MultipleVersions => "multiple-versions",
+            Self::NoPythonSupport => "no-python-support",
+            Self::PythonRequires => "python-requires",
+            Self::Typosquat => "typosquat",
+            Self::OverrideConflict => "override-conflict",
+            Self::Yanked => "yanked",
+        }
+    }
+}
+
+impl FromStr for WarningCode {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match ALL_CODES.iter().find(|c| c.name() == s) {
+            Some(code) => Ok(*code),
+            None => {
+                let names: Vec<&str> = ALL_CODES.iter().map(|c| c.name()).collect();
+                Err(DependencyError::new(&format!(
+                    "Unknown warning `{}` in `[tool.pyflow.warnings]`. Valid ones are: {}",
+                    s,
+                    names.join(", ")
+                )))
+            }
+        }
+    }
+}
+
+/// A warning code, and the packages to silence it for. No packages means silence it for all.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Suppression {
+    pub code: WarningCode,
+    pub packages: Vec<String>,
+}
+
+/// Parse `[tool.pyflow.warnings]`, eg `multiple-versions = true`, `typosquat = ["reqeusts"]`.
+pub fn parse_suppressions(table: HashMap<String, WarningWrapper>) -> Vec<Suppression> {
+    let mut result = vec![];
+    for (name, value) in table {
+        let code = match WarningCode::from_str(&name) {
+            Ok(c) => c,
+            Err(e) => {
+                abort(&e.details);
+                unreachable!()
+            }
+        };
+        match value {
+            WarningWrapper::A(true) => result.push(Suppression {
+                code,
+                packages: vec![],
+            }),
+            WarningWrapper::A(false) => (),
+            WarningWrapper::B(packages) => result.push(Suppression { code, packages }),
+        }
+    }
+    result
+}
+
+pub fn is_suppressed(
+    suppressions: &[Suppression],
+    code: WarningCode,
+    package: Option<&str>,
+) -> bool {
+    suppressions.iter().any(|s| {
+        s.code == code
+            && (s.packages.is_empty()
+                || package.map_or(false, |p| {
+                    s.packages.iter().any(|sp| util::compare_names(sp, p))
+                }))
+    })
+}
+
+/// Display a warning, unless it's suppressed. Its code is shown, so it's clear what to put in
+/// `[tool.pyflow.warnings]`.
+pub fn warn(suppressions: &[Suppression], code: WarningCode, package: Option<&str>, message: &str) {
+    if !is_suppressed(suppressions, code, package) {
+        util::print_color(&format!("{} [{}]", message, code.name()), Color::DarkYellow);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn suppression_matching() {
+        let suppressions = vec![
+            Suppression {
+                code: WarningCode::MultipleVersions,
+                packages: vec![],
+            },
+            Suppression {
+                code: WarningCode::NoPythonSupport,
+                packages: vec!["Django".into()],
+            },
+        ];
+
+        assert!(is_suppressed(
+            &suppressions,
+            WarningCode::MultipleVersions,
+            Some("numpy")
+        ));
+        assert!(is_suppressed(
+            &suppressions,
+            WarningCode::MultipleVersions,
+            None
+        ));
+        assert!(is_suppressed(
+            &suppressions,
+            WarningCode::NoPythonSupport,
+            Some("django")
+        ));
+        assert!(!is_suppressed(
+            &suppressions,
+            WarningCode::NoPythonSupport,
+            Some("flask")
+        ));
+        assert!(!is_suppressed(
+            &suppressions,
+            WarningCode::NoPythonSupport,
+            None
+        ));
+        assert!(!is_suppressed(
+            &suppressions,
+            WarningCode::Typosquat,
+            Some("reqeusts")
+        ));
+    }
+
+    #[test]
+    fn warning_codes() {
+        for code in ALL_CODES {
+            assert_eq!(WarningCode::from_str(code.name()).unwrap(), *code);
+        }
+        assert!(WarningCode::from_str("everything").is_err());
+    }
+}
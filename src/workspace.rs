@@ -0,0 +1,161 @@
+//! Workspace members, and how they depend on each other through path dependencies: for building
+//! them in order, and finding which are affected by a change.
+
+use crate::{dep_types::DependencyError, net, util};
+use std::path::{Path, PathBuf};
+
+/// A workspace's members, with the indexes of the members each depends on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Graph {
+    pub paths: Vec<PathBuf>,
+    pub names: Vec<String>,
+    pub deps: Vec<Vec<usize>>,
+}
+
+impl Graph {
+    /// Load the members listed in a workspace root's `members`.
+    pub fn load(root: &Path, members: &[String], cfg_filename: &str, net: &net::Settings) -> Self {
+        let paths = util::expand_members(root, members, cfg_filename);
+        let canon: Vec<Option<PathBuf>> = paths.iter().map(|m| m.canonicalize().ok()).collect();
+
+        let mut result = Self::default();
+        for path in &paths {
+            let cfg = match crate::Config::from_file(&path.join(cfg_filename), net) {
+                Some(c) => c,
+                None => {
+                    util::abort(&format!(
+                        "Can't find a `{}` in the workspace member {:?}",
+                        cfg_filename, path
+                    ));
+                    unreachable!()
+                }
+            };
+            result.names.push(
+                cfg.name
+                    .clone()
+                    .unwrap_or_else(|| path.to_string_lossy().to_string()),
+            );
+            // Path dependencies are relative to the member that declares them.
+            result.deps.push(
+                cfg.reqs
+                    .iter()
+                    .chain(cfg.dev_reqs.iter())
+                    .filter_map(|r| r.path.as_ref())
+                    .filter_map(|p| path.join(p).canonicalize().ok())
+                    .filter_map(|p| canon.iter().position(|c| c.as_ref() == Some(&p)))
+                    .collect(),
+            );
+        }
+        result.paths = paths;
+        result
+    }
+
+    /// The order to build members in: each after the members it depends on.
+    pub fn build_order(&self) -> Result<Vec<usize>, DependencyError> {
+        fn visit(
+            i: usize,
+            graph: &Graph,
+            visiting: &mut Vec<usize>,
+            result: &mut Vec<usize>,
+        ) -> Result<(), DependencyError> {
+            if result.contains(&i) {
+                return Ok(());
+            }
+            if visiting.contains(&i) {
+                visiting.push(i);
+                let cycle: Vec<&str> = visiting.iter().map(|j| graph.names[*j].as_str()).collect();
+                return Err(DependencyError::new(&format!(
+                    "Workspace members depend on each other in a loop: {}",
+                    cycle.join(" → ")
+                )));
+            }
+            visiting.push(i);
+            for dep in &graph.deps[i] {
+                visit(*dep, graph, visiting, result)?;
+            }
+            visiting.pop();
+            result.push(i);
+            Ok(())
+        }
+
+        let mut result = vec![];
+        for i in 0..self.names.len() {
+            visit(i, self, &mut vec![], &mut result)?;
+        }
+        Ok(result)
+    }
+
+    /// Find the members affected by changes to `changed`: those containing a changed file, and
+    /// those that depend on them, directly or not. Paths are relative to `root`.
+    pub fn affected(&self, root: &Path, changed: &[String]) -> Vec<usize> {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_owned());
+        let canon: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+            .collect();
+
+        let mut result: Vec<usize> = (0..canon.len())
+            .filter(|i| changed.iter().any(|f| root.join(f).starts_with(&canon[*i])))
+            .collect();
+
+        // Add dependents until there are no more.
+        loop {
+            let before = result.len();
+            for i in 0..self.deps.len() {
+                if !result.contains(&i) && self.deps[i].iter().any(|d| result.contains(d)) {
+                    result.push(i);
+                }
+            }
+            if result.len() == before {
+                break;
+            }
+        }
+        result.sort();
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn graph() -> Graph {
+        // The CLI depends on the API, which depends on core.
+        Graph {
+            paths: vec![
+                "tools/cli".into(),
+                "packages/core".into(),
+                "packages/api".into(),
+            ],
+            names: vec!["cli".into(), "core".into(), "api".into()],
+            deps: vec![vec![2], vec![], vec![1]],
+        }
+    }
+
+    #[test]
+    fn member_build_order() {
+        let mut graph = graph();
+        assert_eq!(graph.build_order().unwrap(), vec![1, 2, 0]);
+
+        graph.deps = vec![vec![2], vec![0], vec![1]];
+        assert!(graph.build_order().is_err());
+    }
+
+    #[test]
+    fn affected_members() {
+        let graph = graph();
+        let root = Path::new("");
+        assert_eq!(
+            graph.affected(root, &["packages/core/src/core.py".into()]),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            graph.affected(root, &["tools/cli/README.md".into()]),
+            vec![0]
+        );
+        assert!(graph
+            .affected(root, &["README.md".into(), "packages/corelib/x.py".into()])
+            .is_empty());
+    }
+}